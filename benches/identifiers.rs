@@ -0,0 +1,60 @@
+use cpp_parser::lexer::{Lexer, Token};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+// A source with a lot of identifiers and strings (the tokens borrow the source)
+fn get_source(n: usize) -> String {
+    let mut source = String::new();
+    for i in 0..n {
+        source.push_str(&format!(
+            "some_variable_{} = another_variable_{} + call_function(argument_{}, \"string {}\");\n",
+            i,
+            n - i,
+            i % 64,
+            i
+        ));
+    }
+    source
+}
+
+// The same lines given by a macro (the tokens of the expansions own their payloads)
+fn get_expanded_source(n: usize) -> String {
+    let mut source = String::from("#define ASSIGN(a, b, f, x, s) a = b + f(x, s);\n");
+    for i in 0..n {
+        source.push_str(&format!(
+            "ASSIGN(some_variable_{}, another_variable_{}, call_function, argument_{}, \"string {}\")\n",
+            i,
+            n - i,
+            i % 64,
+            i
+        ));
+    }
+    source
+}
+
+fn count_identifiers(source: &[u8]) -> usize {
+    let mut lexer = Lexer::new(source);
+    let mut count = 0;
+    loop {
+        match lexer.next() {
+            Token::Eof => break,
+            Token::Identifier(_) | Token::LiteralString(_) => count += 1,
+            _ => {}
+        }
+    }
+    count
+}
+
+fn bench_identifiers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("identifiers");
+    for (name, source) in [
+        ("source", get_source(10_000)),
+        ("expanded", get_expanded_source(10_000)),
+    ] {
+        group.throughput(Throughput::Bytes(source.len() as u64));
+        group.bench_function(name, |b| b.iter(|| count_identifiers(source.as_bytes())));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_identifiers);
+criterion_main!(benches);
@@ -0,0 +1,83 @@
+use cpp_parser::lexer::{Interner, Lexer, Token};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::collections::HashMap;
+
+// A repetitive source: few identifiers used a lot of times
+fn get_source(n: usize) -> String {
+    let mut source = String::new();
+    for i in 0..n {
+        source.push_str(&format!(
+            "value_{} = value_{} * coefficient + offset_{};\n",
+            i % 16,
+            (i + 1) % 16,
+            i % 8
+        ));
+    }
+    source
+}
+
+// The same source where the identifiers are macros
+fn get_macro_source(n: usize) -> String {
+    let mut source = String::new();
+    for i in 0..16 {
+        source.push_str(&format!("#define value_{} {}\n", i, i));
+    }
+    source.push_str("#define coefficient 2\n");
+    source.push_str(&get_source(n));
+    source
+}
+
+// Count the identifiers with their strings
+fn count_strings(source: &[u8]) -> usize {
+    let mut lexer = Lexer::new(source);
+    let mut counts: HashMap<_, usize> = HashMap::new();
+    loop {
+        match lexer.next() {
+            Token::Eof => break,
+            Token::Identifier(id) => *counts.entry(id).or_default() += 1,
+            _ => {}
+        }
+    }
+    counts.len()
+}
+
+// Count the identifiers with their symbols
+fn count_symbols(source: &[u8]) -> usize {
+    let mut lexer = Lexer::new(source);
+    let mut interner = Interner::new();
+    let mut counts = Vec::new();
+    loop {
+        match lexer.next_interned(&mut interner) {
+            (Token::Eof, _) => break,
+            (_, Some(sym)) => {
+                let i = sym.0 as usize;
+                if i >= counts.len() {
+                    counts.resize(i + 1, 0);
+                }
+                counts[i] += 1;
+            }
+            _ => {}
+        }
+    }
+    interner.len()
+}
+
+fn bench_interner(c: &mut Criterion) {
+    let mut group = c.benchmark_group("interner");
+    for (name, source) in [
+        ("source", get_source(100_000)),
+        ("macros", get_macro_source(100_000)),
+    ] {
+        group.throughput(Throughput::Bytes(source.len() as u64));
+        group.bench_function(format!("{}/strings", name), |b| {
+            b.iter(|| count_strings(source.as_bytes()))
+        });
+        group.bench_function(format!("{}/symbols", name), |b| {
+            b.iter(|| count_symbols(source.as_bytes()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_interner);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+use cpp_parser::lexer::{Lexer, Token};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+// A macro using its parameter a lot of times with an argument made of some macros
+fn get_source(n: usize) -> String {
+    let mut source = String::from(concat!(
+        "#define ONE 1\n",
+        "#define TWO (ONE + ONE)\n",
+        "#define SUM(a, b) ((a) + (b))\n",
+        "#define POLY(x) ((x) * (x) * (x) * (x) + (x) * (x) * (x) + (x) * (x) + (x))\n",
+    ));
+    for i in 0..n {
+        source.push_str(&format!("int x{} = POLY(SUM(TWO, {}));\n", i, i));
+    }
+    source
+}
+
+fn lex(source: &[u8]) {
+    let mut lexer = Lexer::new(source);
+    while lexer.next() != Token::Eof {}
+}
+
+fn bench_macro_args(c: &mut Criterion) {
+    let source = get_source(20_000);
+    let mut group = c.benchmark_group("macro_args");
+    group.throughput(Throughput::Bytes(source.len() as u64));
+    group.bench_function("lex", |b| b.iter(|| lex(source.as_bytes())));
+    group.finish();
+}
+
+criterion_group!(benches, bench_macro_args);
+criterion_main!(benches);
@@ -0,0 +1,34 @@
+use cpp_parser::lexer::{Lexer, Token};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+// A header with a lot of constants defined with some identifiers which aren't macros
+fn get_source(n: usize) -> String {
+    let mut source = String::new();
+    for i in 0..n {
+        source.push_str(&format!(
+            "#define CONST_{} ((unsigned int) FLAG_BASE << {})\n",
+            i,
+            i % 32
+        ));
+    }
+    for i in 0..n {
+        source.push_str(&format!("x = CONST_{} | CONST_{};\n", i, n - i - 1));
+    }
+    source
+}
+
+fn lex(source: &[u8]) {
+    let mut lexer = Lexer::new(source);
+    while lexer.next() != Token::Eof {}
+}
+
+fn bench_object_macros(c: &mut Criterion) {
+    let source = get_source(10_000);
+    let mut group = c.benchmark_group("object_macros");
+    group.throughput(Throughput::Bytes(source.len() as u64));
+    group.bench_function("lex", |b| b.iter(|| lex(source.as_bytes())));
+    group.finish();
+}
+
+criterion_group!(benches, bench_object_macros);
+criterion_main!(benches);
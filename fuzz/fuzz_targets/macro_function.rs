@@ -0,0 +1,43 @@
+#![no_main]
+use cpp_parser::lexer::{Action, MacroFunction, PContext};
+use libfuzzer_sys::fuzz_target;
+
+// Build a function-like macro from arbitrary actions and replacement list:
+// the ones accepted by try_new must be expanded without reading out of the list
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let n_args = (data[0] % 4) as usize;
+    let n_actions = (data[1] % 16) as usize;
+    let data = &data[2..];
+    let n_actions = n_actions.min(data.len() / 2);
+    let (actions, out) = data.split_at(2 * n_actions);
+
+    let actions = actions
+        .chunks(2)
+        .map(|action| {
+            let n = action[1] as usize;
+            match action[0] % 8 {
+                0 => Action::Arg(n),
+                1 => Action::Concat(n),
+                2 => Action::Stringify(n),
+                3 => Action::Chunk(n),
+                4 => Action::VaOpt(vec![Action::Chunk(n)], n + 1),
+                5 => Action::StringifyVaOpt(vec![Action::Chunk(n)], n + 1),
+                6 => Action::VaComma(n),
+                _ => Action::Paste,
+            }
+        })
+        .collect();
+    let va_args = n_args.checked_sub(1);
+    let out = out.to_vec();
+    let text = String::from_utf8_lossy(&out).into_owned();
+
+    if let Ok(mac) = MacroFunction::try_new(out, actions, n_args, va_args, String::new(), text) {
+        let mut context = PContext::default();
+        context.define_function("F", mac);
+        let args = ["a", "b + 1", "", "c"];
+        context.expand_once("F", &args[..n_args]);
+    }
+});
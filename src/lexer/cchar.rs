@@ -78,11 +78,23 @@ pub(crate) enum CharType {
     U8,
 }
 
+impl CharType {
+    /// Max value for the literal type (wchar_t is 32 bits wide)
+    fn get_max(typ: Option<Self>) -> u32 {
+        match typ {
+            None | Some(CharType::U8) => 0xFF,
+            Some(CharType::U) => 0xFFFF,
+            Some(CharType::UU) | Some(CharType::L) => 0xFFFF_FFFF,
+        }
+    }
+}
+
 impl<'a> Lexer<'a> {
     #[inline(always)]
     pub(crate) fn get_oct_char(&mut self, start: u32) -> u32 {
+        // an octal escape has at most 3 digits
         let mut num = start;
-        loop {
+        for _ in 0..2 {
             if self.pos < self.len {
                 let c = self.next_char(0);
                 if b'0' <= c && c <= b'7' {
@@ -99,99 +111,96 @@ impl<'a> Lexer<'a> {
     }
 
     #[inline(always)]
-    pub(crate) fn get_hex_char(&mut self) -> u32 {
+    pub(crate) fn get_hex_char(&mut self) -> Result<u32, String> {
         let mut num = 0;
-        for _ in 0..3 {
-            if self.pos < self.len {
-                let c = self.next_char(0);
-                let n = Self::get_hex_num(c);
-                if n < 16 {
-                    self.pos += 1;
-                    num = 16 * num + n;
-                } else {
-                    break;
+        let spos = self.pos;
+        // the number of digits isn't limited: the range is checked according to the literal type
+        while self.pos < self.len {
+            let n = Self::get_hex_num(self.next_char(0));
+            if n < 16 {
+                self.pos += 1;
+                if num <= u64::from(u32::MAX) {
+                    num = (num << 4) | n;
                 }
             } else {
                 break;
             }
         }
-        num as u32
+        if self.pos == spos {
+            Err("\\x used with no following hex digits".to_string())
+        } else if num > u64::from(u32::MAX) {
+            Err("hex escape sequence out of range".to_string())
+        } else {
+            Ok(num as u32)
+        }
     }
 
     #[inline(always)]
-    pub(crate) fn get_universal_short(&mut self) -> u32 {
-        // it has 4 digits
-        let rem = self.len - self.pos;
-        if rem >= 4 {
-            let c1 = self.next_char(0);
-            let c2 = self.next_char(1);
-            let c3 = self.next_char(2);
-            let c4 = self.next_char(3);
-            self.pos += 4;
-            // TODO: maybe check if we've hex digits...
-            (0x1000 * Self::get_hex_num(c1)
-                + 0x100 * Self::get_hex_num(c2)
-                + 0x10 * Self::get_hex_num(c3)
-                + Self::get_hex_num(c4)) as u32
+    pub(crate) fn get_universal(&mut self, prefix: char, n_digits: usize) -> Result<u32, String> {
+        let mut num = 0;
+        let spos = self.pos;
+        while self.pos < self.len && self.pos - spos < n_digits {
+            let n = Self::get_hex_num(self.next_char(0));
+            if n < 16 {
+                self.pos += 1;
+                num = 16 * num + n;
+            } else {
+                break;
+            }
+        }
+        if self.pos - spos == n_digits {
+            Ok(num as u32)
         } else {
-            0
+            let digits = unsafe { self.buf.get_unchecked(spos..self.pos) };
+            Err(format!(
+                "incomplete universal character name \\{}{}",
+                prefix,
+                String::from_utf8_lossy(digits)
+            ))
         }
     }
 
     #[inline(always)]
-    pub(crate) fn get_universal_long(&mut self) -> u32 {
+    pub(crate) fn get_universal_short(&mut self) -> Result<u32, String> {
+        // it has 4 digits
+        self.get_universal('u', 4)
+    }
+
+    #[inline(always)]
+    pub(crate) fn get_universal_long(&mut self) -> Result<u32, String> {
         // it has 8 digits
-        let rem = self.len - self.pos;
-        if rem >= 8 {
-            let c1 = self.next_char(0);
-            let c2 = self.next_char(1);
-            let c3 = self.next_char(2);
-            let c4 = self.next_char(3);
-            let c5 = self.next_char(4);
-            let c6 = self.next_char(5);
-            let c7 = self.next_char(6);
-            let c8 = self.next_char(7);
-            self.pos += 8;
-            // TODO: maybe check if we've hex digits...
-            (0x10000000 * Self::get_hex_num(c1)
-                + 0x1000000 * Self::get_hex_num(c2)
-                + 0x100000 * Self::get_hex_num(c3)
-                + 0x10000 * Self::get_hex_num(c4)
-                + 0x1000 * Self::get_hex_num(c5)
-                + 0x100 * Self::get_hex_num(c6)
-                + 0x10 * Self::get_hex_num(c7)
-                + Self::get_hex_num(c8)) as u32
-        } else {
-            0
-        }
+        self.get_universal('U', 8)
     }
 
     #[inline(always)]
-    pub(crate) fn get_escape(&mut self) -> u32 {
+    pub(crate) fn get_escape(&mut self) -> Result<u32, String> {
         if self.pos < self.len {
             let c = self.next_char(0);
             self.pos += 1;
             let kind = unsafe { ECHARS.get_unchecked(c as usize) };
             match kind {
-                Kind::SEL => u32::from(c),
-                Kind::AAA => 0x07,
-                Kind::BBB => 0x08,
-                Kind::FFF => 0x0C,
-                Kind::NNN => 0x0A,
-                Kind::RRR => 0x0D,
-                Kind::TTT => 0x09,
-                Kind::VVV => 0x0B,
+                Kind::SEL => Ok(u32::from(c)),
+                Kind::AAA => Ok(0x07),
+                Kind::BBB => Ok(0x08),
+                Kind::FFF => Ok(0x0C),
+                Kind::NNN => Ok(0x0A),
+                Kind::RRR => Ok(0x0D),
+                Kind::TTT => Ok(0x09),
+                Kind::VVV => Ok(0x0B),
                 Kind::OCT => {
                     let first = u32::from(c - b'0');
-                    self.get_oct_char(first)
+                    Ok(self.get_oct_char(first))
                 }
                 Kind::HEX => self.get_hex_char(),
                 Kind::UNS => self.get_universal_short(),
                 Kind::UNL => self.get_universal_long(),
-                _ => unreachable!(),
+                _ => Err(format!(
+                    "unknown escape sequence: '\\{}'",
+                    String::from_utf8_lossy(&[c])
+                )),
             }
         } else {
-            0
+            Ok(0)
         }
     }
 
@@ -205,34 +214,134 @@ impl<'a> Lexer<'a> {
     }
 
     #[inline(always)]
-    pub(crate) fn get_c_char_u32(&mut self) -> u32 {
+    fn get_utf8_char(&mut self) -> u32 {
+        let c = self.next_char(0);
+        let n = match c {
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 1,
+        };
+        if self.pos + n <= self.len {
+            let bytes = unsafe { self.buf.get_unchecked(self.pos..self.pos + n) };
+            if let Some(c) = std::str::from_utf8(bytes)
+                .ok()
+                .and_then(|s| s.chars().next())
+            {
+                self.pos += n;
+                return u32::from(c);
+            }
+        }
+        self.pos += 1;
+        u32::from(c)
+    }
+
+    #[inline(always)]
+    pub(crate) fn get_c_char_u32(&mut self, typ: Option<CharType>) -> Result<u32, String> {
+        let max = CharType::get_max(typ);
+        // a code point in u8'...' must be encoded in one byte
+        let max_char = if typ == Some(CharType::U8) { 0x7F } else { max };
         let mut val: u32 = 0;
+        let mut count = 0;
+        let mut error = None;
         loop {
             if self.pos < self.len {
                 let c = self.next_char(0);
-                if c == b'\\' {
+                let e = if c == b'\\' && (self.pos + 1 >= self.len || self.next_char(1) != b'\n') {
                     self.pos += 1;
-                    let e = self.get_escape();
-                    // TODO: not sure that's correct
-                    // e.g. \x12\x0034 == 1234 or 120034 ?
-                    val = val * Self::get_shift(e) + e;
+                    let kind = if self.pos < self.len {
+                        self.next_char(0)
+                    } else {
+                        0
+                    };
+                    match self.get_escape() {
+                        // an universal char in a char is kept as is (implementation-defined)
+                        Ok(e) if kind | 0x20 == b'u' && typ.is_none() => e,
+                        Ok(e) if e > max || (kind | 0x20 == b'u' && e > max_char) => {
+                            let message = match kind {
+                                b'x' => "hex escape sequence out of range",
+                                b'0'..=b'7' => "octal escape sequence out of range",
+                                _ => "character too large for enclosing character literal type",
+                            };
+                            error = error.or_else(|| Some(message.to_string()));
+                            continue;
+                        }
+                        Ok(e) => e,
+                        Err(e) => {
+                            // keep the first error and go to the end of the literal
+                            error = error.or(Some(e));
+                            continue;
+                        }
+                    }
                 } else if c == b'\'' {
                     self.pos += 1;
                     break;
+                } else if c == b'\n' {
+                    return Err("missing terminating ' character".to_string());
+                } else if c >= 0x80 && typ.is_some() {
+                    // the source is utf-8 and the char is encoded in the prefix encoding
+                    let e = self.get_utf8_char();
+                    if e > max_char {
+                        error = error.or_else(|| {
+                            Some(
+                                "character too large for enclosing character literal type"
+                                    .to_string(),
+                            )
+                        });
+                        continue;
+                    }
+                    e
                 } else {
                     self.pos += 1;
-                    val = val * 0x100 + u32::from(c);
-                }
+                    u32::from(c)
+                };
+
+                count += 1;
+                val = if typ.is_none() {
+                    // multicharacter literal: implementation-defined
+                    // TODO: not sure that's correct
+                    // e.g. \x12\x0034 == 1234 or 120034 ?
+                    val.wrapping_mul(Self::get_shift(e)).wrapping_add(e)
+                } else {
+                    // L'ab' is implementation-defined too: keep the last one (as gcc does)
+                    e
+                };
             } else {
-                break;
+                return Err("missing terminating ' character".to_string());
             }
         }
-        val
+
+        if let Some(error) = error {
+            Err(error)
+        } else if count > 1
+            && matches!(
+                typ,
+                Some(CharType::U8) | Some(CharType::U) | Some(CharType::UU)
+            )
+        {
+            Err("Unicode character literals may not contain multiple characters".to_string())
+        } else {
+            Ok(val)
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn get_char_literal(&mut self, typ: Option<CharType>) -> Token<'a> {
+        match self.get_c_char_u32(typ) {
+            Ok(val) => match typ {
+                None => Token::LiteralChar(val),
+                Some(CharType::L) => Token::LiteralLChar(val),
+                Some(CharType::UU) => Token::LiteralUUChar(val),
+                Some(CharType::U) => Token::LiteralUChar(val),
+                Some(CharType::U8) => Token::LiteralU8Char(val),
+            },
+            Err(message) => self.get_invalid(message),
+        }
     }
 
     #[inline(always)]
     pub(crate) fn get_char(&mut self) -> Token<'a> {
-        Token::LiteralChar(self.get_c_char_u32())
+        self.get_char_literal(None)
     }
 }
 
@@ -268,4 +377,135 @@ mod tests {
         assert_eq!(p.next(), Token::LiteralU8Char(u32::from('c')));
         assert_eq!(p.next(), Token::LiteralLChar(u32::from('\t')));
     }
+
+    #[test]
+    fn test_char_width() {
+        let mut p = Lexer::new(
+            "'\\x1234' L'\\x1234' u'\\x1234' U'\\x12345' u8'\\x12' '\\377' L'\\777' u'é' U'😀' L'ab'"
+                .as_bytes(),
+        );
+        assert_eq!(p.next(), Token::Invalid(b"'\\x1234'".into()));
+        assert_eq!(p.next(), Token::LiteralLChar(0x1234));
+        assert_eq!(p.next(), Token::LiteralUChar(0x1234));
+        assert_eq!(p.next(), Token::LiteralUUChar(0x12345));
+        assert_eq!(p.next(), Token::LiteralU8Char(0x12));
+        assert_eq!(p.next(), Token::LiteralChar(0xFF));
+        assert_eq!(p.next(), Token::LiteralLChar(0o777));
+        assert_eq!(p.next(), Token::LiteralUChar(0xE9));
+        assert_eq!(p.next(), Token::LiteralUUChar(0x1F600));
+        assert_eq!(p.next(), Token::LiteralLChar(u32::from('b')));
+
+        let diagnostics = p.take_diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "hex escape sequence out of range");
+    }
+
+    #[test]
+    fn test_hex_escape() {
+        let mut p =
+            Lexer::new(b"'\\x41' '\\x00000041' U'\\x0000000000000000001F600' '\\x41G' '\\101'");
+        assert_eq!(p.next(), Token::LiteralChar(u32::from('A')));
+        assert_eq!(p.next(), Token::LiteralChar(u32::from('A')));
+        assert_eq!(p.next(), Token::LiteralUUChar(0x1F600));
+        assert_eq!(p.next(), Token::LiteralChar(0x4147));
+        assert_eq!(p.next(), Token::LiteralChar(u32::from('A')));
+        assert!(p.take_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_char_range() {
+        let mut p = Lexer::new(
+            "L'\\x0000000000000041' '\\x000041' U'\\x1FFFFFFFF' '\\777777' u'\\x1FFFF' u8'é' u'\\U0001F600'"
+                .as_bytes(),
+        );
+        assert_eq!(p.next(), Token::LiteralLChar(0x41));
+        assert_eq!(p.next(), Token::LiteralChar(0x41));
+        assert_eq!(p.next(), Token::Invalid(b"U'\\x1FFFFFFFF'".into()));
+        assert_eq!(p.next(), Token::Invalid(b"'\\777777'".into()));
+        assert_eq!(p.next(), Token::Invalid(b"u'\\x1FFFF'".into()));
+        assert_eq!(p.next(), Token::Invalid("u8'é'".as_bytes().into()));
+        assert_eq!(p.next(), Token::Invalid(b"u'\\U0001F600'".into()));
+
+        let messages: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| d.message)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "hex escape sequence out of range",
+                "octal escape sequence out of range",
+                "hex escape sequence out of range",
+                "character too large for enclosing character literal type",
+                "character too large for enclosing character literal type",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multichar() {
+        let mut p = Lexer::new(b"u8'ab' u'ab' U'a\\x12' 'abcde' '\\1234'");
+        assert_eq!(p.next(), Token::Invalid(b"u8'ab'".into()));
+        assert_eq!(p.next(), Token::Invalid(b"u'ab'".into()));
+        assert_eq!(p.next(), Token::Invalid(b"U'a\\x12'".into()));
+        assert_eq!(p.next(), Token::LiteralChar(0x62636465));
+        // an octal escape has at most 3 digits
+        assert_eq!(p.next(), Token::LiteralChar((0o123 << 8) | u32::from('4')));
+
+        let diagnostics = p.take_diagnostics();
+        assert_eq!(diagnostics.len(), 3);
+        assert_eq!(
+            diagnostics[0].message,
+            "Unicode character literals may not contain multiple characters"
+        );
+    }
+
+    #[test]
+    fn test_bad_escape() {
+        let mut p = Lexer::new(b"'\\xZZ' u'\\u12' '\\x' L'a\\q' 'a'\nU'\\U1234");
+        assert_eq!(p.next(), Token::Invalid(b"'\\xZZ'".into()));
+        assert_eq!(p.next(), Token::Invalid(b"u'\\u12'".into()));
+        assert_eq!(p.next(), Token::Invalid(b"'\\x'".into()));
+        assert_eq!(p.next(), Token::Invalid(b"L'a\\q'".into()));
+        assert_eq!(p.next(), Token::LiteralChar(u32::from('a')));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Invalid(b"U'\\U1234".into()));
+        assert_eq!(p.next(), Token::Eof);
+
+        let messages: Vec<_> = p.take_diagnostics().iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "1:1: error: \\x used with no following hex digits",
+                "1:8: error: incomplete universal character name \\u12",
+                "1:16: error: \\x used with no following hex digits",
+                "1:21: error: unknown escape sequence: '\\q'",
+                "2:1: error: missing terminating ' character",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unterminated_char() {
+        let mut p = Lexer::new(b"c = 'a;\n';\n  L'a");
+        assert_eq!(p.next(), Token::Identifier("c".into()));
+        assert_eq!(p.next(), Token::Equal);
+        assert_eq!(p.next(), Token::Invalid(b"'a;".into()));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Invalid(b"';".into()));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Invalid(b"L'a".into()));
+        assert_eq!(p.next(), Token::Eof);
+
+        let messages: Vec<_> = p.take_diagnostics().iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "1:5: error: missing terminating ' character",
+                "2:1: error: missing terminating ' character",
+                "3:3: error: missing terminating ' character",
+            ]
+        );
+    }
 }
@@ -1,3 +1,4 @@
+use crate::lexer::errors::Diagnostic;
 use crate::lexer::lexer::{Lexer, Token};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -25,13 +26,26 @@ pub enum Operator {
     BitOr,
     And,
     Or,
+    // the ? of a conditional waiting for its :
+    Cond,
+    // a ? b : c once the : is met
+    Select,
 }
 
 impl Operator {
+    /// Apply the operator on the operands in the stack
+    /// Return false when there is a division by zero
     #[inline(always)]
-    pub(crate) fn operate(&self, stack: &mut Vec<Int>) {
+    pub(crate) fn operate(&self, stack: &mut Vec<Int>) -> bool {
         use Operator::*;
 
+        if stack.len() < self.arity() {
+            // the missing operands are reported before the evaluation
+            stack.clear();
+            stack.push(Int::Signed(0));
+            return true;
+        }
+
         match *self {
             Plus => {}
             Minus => {
@@ -49,11 +63,11 @@ impl Operator {
             }
             Div => {
                 let b = stack.pop().unwrap();
-                stack.last_mut().unwrap().div(b);
+                return stack.last_mut().unwrap().div(b);
             }
             Mod => {
                 let b = stack.pop().unwrap();
-                stack.last_mut().unwrap().modulo(b);
+                return stack.last_mut().unwrap().modulo(b);
             }
             Add => {
                 let b = stack.pop().unwrap();
@@ -115,8 +129,56 @@ impl Operator {
                 let b = stack.pop().unwrap();
                 stack.last_mut().unwrap().or(b);
             }
+            Select => {
+                let c = stack.pop().unwrap();
+                let b = stack.pop().unwrap();
+                stack.last_mut().unwrap().select(b, c);
+            }
             _ => {}
         }
+        true
+    }
+
+    fn spelling(&self) -> &'static str {
+        use Operator::*;
+
+        match *self {
+            Parenthesis => "(",
+            Plus | Add => "+",
+            Minus | Sub => "-",
+            Not => "!",
+            BitNeg => "~",
+            Mul => "*",
+            Div => "/",
+            Mod => "%",
+            LShift => "<<",
+            RShift => ">>",
+            Lt => "<",
+            Gt => ">",
+            Leq => "<=",
+            Geq => ">=",
+            Eq => "==",
+            Neq => "!=",
+            BitAnd => "&",
+            BitXor => "^",
+            BitOr => "|",
+            And => "&&",
+            Or => "||",
+            Cond => "?",
+            Select => ":",
+        }
+    }
+
+    #[inline(always)]
+    fn arity(&self) -> usize {
+        use Operator::*;
+
+        match *self {
+            Parenthesis | Cond => 0,
+            Plus | Minus | Not | BitNeg => 1,
+            Select => 3,
+            _ => 2,
+        }
     }
 }
 
@@ -138,21 +200,58 @@ pub(crate) enum Int {
     Signed(i64),
 }
 
+// The operands of a binary operator after the usual arithmetic conversions
+enum Operands {
+    Unsigned(u64, u64),
+    Signed(i64, i64),
+}
+
 impl Int {
+    /// Get the value of a literal without the suffix u: it's signed unless it doesn't fit
+    /// in an intmax_t (e.g. 0xFFFFFFFFFFFFFFFF)
+    #[inline(always)]
+    fn from_literal(x: u64) -> Int {
+        if x <= i64::MAX as u64 {
+            Int::Signed(x as i64)
+        } else {
+            Int::Unsigned(x)
+        }
+    }
+
+    #[inline(always)]
+    fn from_bool(b: bool) -> Int {
+        Int::Signed(b as i64)
+    }
+
+    #[inline(always)]
+    fn as_unsigned(self) -> u64 {
+        match self {
+            Int::Unsigned(n) => n,
+            Int::Signed(n) => n as u64,
+        }
+    }
+
+    /// Apply the usual arithmetic conversions: the operands are converted
+    /// to unsigned when one of them is unsigned (e.g. -1 > 0u is true)
+    #[inline(always)]
+    fn convert(self, right: Int) -> Operands {
+        match (self, right) {
+            (Int::Signed(x), Int::Signed(y)) => Operands::Signed(x, y),
+            (x, y) => Operands::Unsigned(x.as_unsigned(), y.as_unsigned()),
+        }
+    }
+
     #[inline(always)]
     fn minus(&mut self) {
         *self = match self {
-            Int::Unsigned(n) => Int::Signed(-(*n as i64)),
-            Int::Signed(n) => Int::Signed(-*n),
+            Int::Unsigned(n) => Int::Unsigned(n.wrapping_neg()),
+            Int::Signed(n) => Int::Signed(n.wrapping_neg()),
         }
     }
 
     #[inline(always)]
     fn not(&mut self) {
-        *self = match self {
-            Int::Unsigned(n) => Int::Unsigned((!(*n != 0)) as u64),
-            Int::Signed(n) => Int::Unsigned((!(*n != 0)) as u64),
-        }
+        *self = Int::from_bool(self.is_zero());
     }
 
     #[inline(always)]
@@ -165,260 +264,175 @@ impl Int {
 
     #[inline(always)]
     fn mul(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned(*x * y),
-                Int::Signed(y) => Int::Signed((*x as i64) * y),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Signed(*x * (y as i64)),
-                Int::Signed(y) => Int::Signed(*x * y),
-            },
+        *self = match self.convert(right) {
+            Operands::Unsigned(x, y) => Int::Unsigned(x.wrapping_mul(y)),
+            Operands::Signed(x, y) => Int::Signed(x.wrapping_mul(y)),
         }
     }
 
     #[inline(always)]
-    fn div(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned(*x / y),
-                Int::Signed(y) => Int::Signed((*x as i64) / y),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Signed(*x / (y as i64)),
-                Int::Signed(y) => Int::Signed(*x / y),
-            },
+    fn is_zero(&self) -> bool {
+        match self {
+            Int::Unsigned(n) => *n == 0,
+            Int::Signed(n) => *n == 0,
         }
     }
 
     #[inline(always)]
-    fn modulo(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned(*x % y),
-                Int::Signed(y) => Int::Signed((*x as i64) % y),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Signed(*x % (y as i64)),
-                Int::Signed(y) => Int::Signed(*x % y),
-            },
+    fn div(&mut self, right: Int) -> bool {
+        if right.is_zero() {
+            *self = Int::Signed(0);
+            return false;
         }
+        *self = match self.convert(right) {
+            Operands::Unsigned(x, y) => Int::Unsigned(x / y),
+            Operands::Signed(x, y) => Int::Signed(x.wrapping_div(y)),
+        };
+        true
+    }
+
+    #[inline(always)]
+    fn modulo(&mut self, right: Int) -> bool {
+        if right.is_zero() {
+            *self = Int::Signed(0);
+            return false;
+        }
+        *self = match self.convert(right) {
+            Operands::Unsigned(x, y) => Int::Unsigned(x % y),
+            Operands::Signed(x, y) => Int::Signed(x.wrapping_rem(y)),
+        };
+        true
     }
 
     #[inline(always)]
     fn add(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned(*x + y),
-                Int::Signed(y) => Int::Signed((*x as i64) + y),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Signed(*x + (y as i64)),
-                Int::Signed(y) => Int::Signed(*x + y),
-            },
+        *self = match self.convert(right) {
+            Operands::Unsigned(x, y) => Int::Unsigned(x.wrapping_add(y)),
+            Operands::Signed(x, y) => Int::Signed(x.wrapping_add(y)),
         }
     }
 
     #[inline(always)]
     fn sub(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => {
-                    if *x >= y {
-                        Int::Unsigned(*x - y)
-                    } else {
-                        Int::Signed(-((y - *x) as i64))
-                    }
-                }
-                Int::Signed(y) => Int::Signed((*x as i64) - y),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Signed(*x - (y as i64)),
-                Int::Signed(y) => Int::Signed(*x - y),
-            },
+        *self = match self.convert(right) {
+            Operands::Unsigned(x, y) => Int::Unsigned(x.wrapping_sub(y)),
+            Operands::Signed(x, y) => Int::Signed(x.wrapping_sub(y)),
         }
     }
 
+    #[inline(always)]
+    fn shift_count(right: Int) -> u32 {
+        // shifting by a negative number or by more than the width is undefined
+        match right {
+            Int::Unsigned(y) => y.min(64) as u32,
+            Int::Signed(y) => y.clamp(0, 64) as u32,
+        }
+    }
+
+    // the type of a shift is the type of its left operand
+
     #[inline(always)]
     fn lshift(&mut self, right: Int) {
+        let y = Int::shift_count(right);
         *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned(*x << y),
-                Int::Signed(y) => Int::Signed((*x as i64) << y),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Signed(*x << (y as i64)),
-                Int::Signed(y) => Int::Signed(*x << y),
-            },
+            Int::Unsigned(x) => Int::Unsigned(x.checked_shl(y).unwrap_or(0)),
+            Int::Signed(x) => Int::Signed(x.checked_shl(y).unwrap_or(0)),
         }
     }
 
     #[inline(always)]
     fn rshift(&mut self, right: Int) {
+        let y = Int::shift_count(right);
         *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned(*x >> y),
-                Int::Signed(y) => Int::Signed((*x as i64) >> y),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Signed(*x >> (y as i64)),
-                Int::Signed(y) => Int::Signed(*x >> y),
-            },
+            Int::Unsigned(x) => Int::Unsigned(x.checked_shr(y).unwrap_or(0)),
+            Int::Signed(x) => Int::Signed(x.checked_shr(y).unwrap_or(if *x < 0 { -1 } else { 0 })),
         }
     }
 
     #[inline(always)]
     fn lt(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned((*x < y) as u64),
-                Int::Signed(y) => Int::Unsigned(((*x as i64) < y) as u64),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned((*x < (y as i64)) as u64),
-                Int::Signed(y) => Int::Unsigned((*x < y) as u64),
-            },
-        }
+        *self = Int::from_bool(match self.convert(right) {
+            Operands::Unsigned(x, y) => x < y,
+            Operands::Signed(x, y) => x < y,
+        })
     }
 
     #[inline(always)]
     fn gt(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned((*x > y) as u64),
-                Int::Signed(y) => Int::Unsigned(((*x as i64) > y) as u64),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned((*x > (y as i64)) as u64),
-                Int::Signed(y) => Int::Unsigned((*x > y) as u64),
-            },
-        }
+        *self = Int::from_bool(match self.convert(right) {
+            Operands::Unsigned(x, y) => x > y,
+            Operands::Signed(x, y) => x > y,
+        })
     }
 
     #[inline(always)]
     fn leq(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned((*x <= y) as u64),
-                Int::Signed(y) => Int::Unsigned(((*x as i64) <= y) as u64),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned((*x <= (y as i64)) as u64),
-                Int::Signed(y) => Int::Unsigned((*x <= y) as u64),
-            },
-        }
+        *self = Int::from_bool(match self.convert(right) {
+            Operands::Unsigned(x, y) => x <= y,
+            Operands::Signed(x, y) => x <= y,
+        })
     }
 
     #[inline(always)]
     fn geq(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned((*x >= y) as u64),
-                Int::Signed(y) => Int::Unsigned(((*x as i64) >= y) as u64),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned((*x >= (y as i64)) as u64),
-                Int::Signed(y) => Int::Unsigned((*x >= y) as u64),
-            },
-        }
+        *self = Int::from_bool(match self.convert(right) {
+            Operands::Unsigned(x, y) => x >= y,
+            Operands::Signed(x, y) => x >= y,
+        })
     }
 
     #[inline(always)]
     fn eq(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned((*x == y) as u64),
-                Int::Signed(y) => Int::Unsigned(((*x as i64) == y) as u64),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned((*x == (y as i64)) as u64),
-                Int::Signed(y) => Int::Unsigned((*x == y) as u64),
-            },
-        }
+        *self = Int::from_bool(self.as_unsigned() == right.as_unsigned());
     }
 
     #[inline(always)]
     fn neq(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned((*x != y) as u64),
-                Int::Signed(y) => Int::Unsigned(((*x as i64) != y) as u64),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned((*x != (y as i64)) as u64),
-                Int::Signed(y) => Int::Unsigned((*x != y) as u64),
-            },
-        }
+        *self = Int::from_bool(self.as_unsigned() != right.as_unsigned());
     }
 
     #[inline(always)]
     fn bitand(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned(*x & y),
-                Int::Signed(y) => Int::Signed((*x as i64) & y),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Signed(*x & (y as i64)),
-                Int::Signed(y) => Int::Signed(*x & y),
-            },
+        *self = match self.convert(right) {
+            Operands::Unsigned(x, y) => Int::Unsigned(x & y),
+            Operands::Signed(x, y) => Int::Signed(x & y),
         }
     }
 
     #[inline(always)]
     fn bitxor(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned(*x ^ y),
-                Int::Signed(y) => Int::Signed((*x as i64) ^ y),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Signed(*x ^ (y as i64)),
-                Int::Signed(y) => Int::Signed(*x ^ y),
-            },
+        *self = match self.convert(right) {
+            Operands::Unsigned(x, y) => Int::Unsigned(x ^ y),
+            Operands::Signed(x, y) => Int::Signed(x ^ y),
         }
     }
 
     #[inline(always)]
     fn bitor(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned(*x | y),
-                Int::Signed(y) => Int::Signed((*x as i64) | y),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Signed(*x | (y as i64)),
-                Int::Signed(y) => Int::Signed(*x | y),
-            },
+        *self = match self.convert(right) {
+            Operands::Unsigned(x, y) => Int::Unsigned(x | y),
+            Operands::Signed(x, y) => Int::Signed(x | y),
         }
     }
 
     #[inline(always)]
     fn and(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned(((*x != 0) && (y != 0)) as u64),
-                Int::Signed(y) => Int::Unsigned(((*x != 0) && (y != 0)) as u64),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned(((*x != 0) && (y != 0)) as u64),
-                Int::Signed(y) => Int::Unsigned(((*x != 0) && (y != 0)) as u64),
-            },
-        }
+        *self = Int::from_bool(!self.is_zero() && !right.is_zero());
     }
 
     #[inline(always)]
     fn or(&mut self, right: Int) {
-        *self = match self {
-            Int::Unsigned(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned(((*x != 0) || (y != 0)) as u64),
-                Int::Signed(y) => Int::Unsigned(((*x != 0) || (y != 0)) as u64),
-            },
-            Int::Signed(x) => match right {
-                Int::Unsigned(y) => Int::Unsigned(((*x != 0) || (y != 0)) as u64),
-                Int::Signed(y) => Int::Unsigned(((*x != 0) || (y != 0)) as u64),
-            },
-        }
+        *self = Int::from_bool(!self.is_zero() || !right.is_zero());
+    }
+
+    /// self ? b : c where b and c get the usual arithmetic conversions
+    #[inline(always)]
+    fn select(&mut self, b: Int, c: Int) {
+        let (b, c) = match b.convert(c) {
+            Operands::Unsigned(b, c) => (Int::Unsigned(b), Int::Unsigned(c)),
+            Operands::Signed(b, c) => (Int::Signed(b), Int::Signed(c)),
+        };
+        *self = if self.is_zero() { c } else { b };
     }
 }
 
@@ -438,6 +452,7 @@ fn precedence(op: Operator) -> (u32, Associativity) {
         BitOr => (10, Associativity::LR),
         And => (11, Associativity::LR),
         Or => (12, Associativity::LR),
+        Cond | Select => (13, Associativity::RL),
         _ => (0, Associativity::LR),
     }
 }
@@ -457,16 +472,73 @@ pub struct Condition<'a, 'b> {
     operands: Vec<Int>, // TODO: maybe a SmallVec is enough here
     operators: Vec<Operator>,
     last: LastKind,
+    // positions in operators of the && and || whose right operand mustn't be evaluated
+    // (e.g. 0 && 1 / 0 is fine)
+    short_circuits: Vec<usize>,
+    // position of the expression for the diagnostics
+    line: usize,
+    column: usize,
+    // the end of the line met when reading the argument of a query (e.g. __has_builtin)
+    end: Option<Token<'a>>,
 }
 
 impl<'a, 'b> Condition<'a, 'b> {
     pub(crate) fn new(lexer: &'b mut Lexer<'a>) -> Self {
+        let line = lexer.get_line();
+        let column = lexer.get_column();
         Self {
             lexer,
+            line,
+            column,
             operands: Vec::with_capacity(16),
             operators: Vec::with_capacity(16),
             last: LastKind::Operator,
+            short_circuits: Vec::new(),
+            end: None,
+        }
+    }
+
+    #[inline(always)]
+    fn operate(&mut self, op: Operator) {
+        if self.short_circuits.last() == Some(&self.operators.len()) {
+            self.short_circuits.pop();
+        }
+        if !op.operate(&mut self.operands) && self.short_circuits.is_empty() {
+            let message = if op == Operator::Div {
+                "division by zero in #if"
+            } else {
+                "remainder by zero in #if"
+            };
+            self.add_error(message);
+        }
+    }
+
+    fn add_error(&mut self, message: &str) {
+        let diagnostic = Diagnostic::error(message.to_string(), self.line, self.column);
+        self.lexer.context.add_diagnostic(diagnostic);
+    }
+
+    /// Push a binary operator (or ?)
+    #[inline(always)]
+    fn push_binary(&mut self, op: Operator) -> Result<(), String> {
+        if self.last == LastKind::Operator {
+            return Err(format!("operator '{}' has no left operand", op.spelling()));
+        }
+        self.push_operator(op);
+        Ok(())
+    }
+
+    /// Push an unary operator
+    #[inline(always)]
+    fn push_unary(&mut self, op: Operator) -> Result<(), String> {
+        if self.last == LastKind::Operand {
+            return Err(format!(
+                "missing binary operator before token \"{}\"",
+                op.spelling()
+            ));
         }
+        self.push_operator(op);
+        Ok(())
     }
 
     #[inline(always)]
@@ -475,150 +547,308 @@ impl<'a, 'b> Condition<'a, 'b> {
             if let Some(top) = self.operators.last() {
                 if *top != Operator::Parenthesis && check_precedence(*top, op) {
                     let top = self.operators.pop().unwrap();
-                    top.operate(&mut self.operands);
+                    self.operate(top);
                     continue;
                 }
             }
             break;
         }
+
+        if self.last == LastKind::Operand {
+            if let Some(left) = self.operands.last() {
+                let short_circuit = match op {
+                    Operator::And | Operator::Cond => left.is_zero(),
+                    Operator::Or => !left.is_zero(),
+                    _ => false,
+                };
+                if short_circuit {
+                    self.short_circuits.push(self.operators.len());
+                }
+            }
+        }
+
         self.last = LastKind::Operator;
         self.operators.push(op);
     }
 
+    /// Replace the ? of a conditional by the operator selecting one of the operands:
+    /// the third one mustn't be evaluated when the condition is true
+    fn push_else(&mut self) -> Result<(), String> {
+        if self.last == LastKind::Operator {
+            return Err("missing expression before ':'".to_string());
+        }
+        loop {
+            match self.operators.pop() {
+                Some(Operator::Cond) => break,
+                Some(Operator::Parenthesis) | None => {
+                    return Err("':' without preceding '?'".to_string());
+                }
+                Some(op) => self.operate(op),
+            }
+        }
+        let pos = self.operators.len();
+        if self.short_circuits.last() == Some(&pos) {
+            self.short_circuits.pop();
+        }
+        // the condition is under the second operand
+        if !self.operands[self.operands.len() - 2].is_zero() {
+            self.short_circuits.push(pos);
+        }
+        self.operators.push(Operator::Select);
+        self.last = LastKind::Operator;
+        Ok(())
+    }
+
     #[inline(always)]
-    fn flush(&mut self) {
-        while let Some(op) = self.operators.pop() {
-            op.operate(&mut self.operands);
+    fn push_operand(&mut self, x: Int, tok: &Token<'a>) -> Result<(), String> {
+        if self.last == LastKind::Operand {
+            return Err(format!("missing binary operator before token \"{}\"", tok));
+        }
+        self.operands.push(x);
+        self.last = LastKind::Operand;
+        Ok(())
+    }
+
+    /// Check that the last operator has its right operand
+    #[inline(always)]
+    fn check_right_operand(&self) -> Result<(), String> {
+        if self.last == LastKind::Operand {
+            return Ok(());
+        }
+        match self.operators.last() {
+            Some(Operator::Parenthesis) => Err("missing expression in parentheses".to_string()),
+            Some(op) => Err(format!("operator '{}' has no right operand", op.spelling())),
+            None => Err("#if with no expression".to_string()),
         }
     }
 
     #[inline(always)]
-    fn flush_until_paren(&mut self) {
+    fn flush(&mut self) -> Result<(), String> {
+        self.check_right_operand()?;
         while let Some(op) = self.operators.pop() {
             match op {
-                Operator::Parenthesis => {
-                    break;
+                Operator::Parenthesis => return Err("missing ')' in expression".to_string()),
+                Operator::Cond => return Err("'?' without following ':'".to_string()),
+                _ => self.operate(op),
+            }
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn flush_until_paren(&mut self) -> Result<(), String> {
+        self.check_right_operand()?;
+        loop {
+            match self.operators.pop() {
+                Some(Operator::Parenthesis) => break,
+                Some(Operator::Cond) => return Err("'?' without following ':'".to_string()),
+                Some(op) => self.operate(op),
+                None => return Err("missing '(' in expression".to_string()),
+            }
+        }
+        self.last = LastKind::Operand;
+        Ok(())
+    }
+
+    /// Evaluate a query such as __has_cpp_attribute(name) or __has_builtin(name)
+    /// where name (e.g. nodiscard or gnu::unused) comes from the macro-expanded argument
+    fn get_query(&mut self, query: &str) -> u64 {
+        if self.lexer.next_useful() != Token::LeftParen {
+            self.add_error(&format!("missing '(' after {}", query));
+            return 0;
+        }
+        let mut name = String::new();
+        loop {
+            match self.lexer.next_useful() {
+                Token::Identifier(id) => name.push_str(&id),
+                Token::ColonColon => name.push_str("::"),
+                Token::RightParen => break,
+                tok @ (Token::Eol | Token::Eof) => {
+                    self.add_error(&format!("missing ')' after {} operand", query));
+                    self.end = Some(tok);
+                    return 0;
                 }
                 _ => {
-                    op.operate(&mut self.operands);
+                    self.add_error(&format!("invalid operand of {}", query));
+                    name.clear();
                 }
             }
         }
+        match query {
+            "__has_builtin" => self.lexer.context.has_builtin(&name) as u64,
+            "__has_feature" => self.lexer.context.has_feature(&name) as u64,
+            _ => self.lexer.context.has_attribute(&name),
+        }
     }
 
     fn eval(&mut self) -> Int {
+        let res = self.parse();
+        // the rest of the line is skipped after an error
+        loop {
+            let tok = match self.end.take() {
+                Some(tok) => tok,
+                None => self.lexer.next_useful(),
+            };
+            if matches!(tok, Token::Eol | Token::Eof) {
+                break;
+            }
+        }
+        res.unwrap_or_else(|message| {
+            self.add_error(&message);
+            Int::Signed(0)
+        })
+    }
+
+    fn parse(&mut self) -> Result<Int, String> {
         loop {
-            let tok = self.lexer.next_useful();
+            let tok = if let Some(tok) = self.end.take() {
+                tok
+            } else {
+                self.lexer.next_useful()
+            };
             match tok {
                 Token::Plus => {
                     if self.last == LastKind::Operand {
                         self.push_operator(Operator::Add);
                     } else {
-                        self.push_operator(Operator::Plus);
+                        self.push_unary(Operator::Plus)?;
                     }
                 }
                 Token::Minus => {
                     if self.last == LastKind::Operand {
                         self.push_operator(Operator::Sub);
                     } else {
-                        self.push_operator(Operator::Minus);
+                        self.push_unary(Operator::Minus)?;
                     }
                 }
                 Token::Not => {
-                    self.push_operator(Operator::Not);
+                    self.push_unary(Operator::Not)?;
                 }
                 Token::Tilde => {
-                    self.push_operator(Operator::BitNeg);
+                    self.push_unary(Operator::BitNeg)?;
                 }
                 Token::Star => {
-                    self.push_operator(Operator::Mul);
+                    self.push_binary(Operator::Mul)?;
                 }
                 Token::Divide => {
-                    self.push_operator(Operator::Div);
+                    self.push_binary(Operator::Div)?;
                 }
                 Token::Modulo => {
-                    self.push_operator(Operator::Mod);
+                    self.push_binary(Operator::Mod)?;
                 }
                 Token::LeftShift => {
-                    self.push_operator(Operator::LShift);
+                    self.push_binary(Operator::LShift)?;
                 }
                 Token::RightShift => {
-                    self.push_operator(Operator::RShift);
+                    self.push_binary(Operator::RShift)?;
                 }
                 Token::Lower => {
-                    self.push_operator(Operator::Lt);
+                    self.push_binary(Operator::Lt)?;
                 }
                 Token::EqualEqual => {
-                    self.push_operator(Operator::Eq);
+                    self.push_binary(Operator::Eq)?;
                 }
                 Token::Greater => {
-                    self.push_operator(Operator::Gt);
+                    self.push_binary(Operator::Gt)?;
                 }
                 Token::LowerEqual => {
-                    self.push_operator(Operator::Leq);
+                    self.push_binary(Operator::Leq)?;
                 }
                 Token::GreaterEqual => {
-                    self.push_operator(Operator::Geq);
+                    self.push_binary(Operator::Geq)?;
                 }
                 Token::NotEqual => {
-                    self.push_operator(Operator::Neq);
+                    self.push_binary(Operator::Neq)?;
                 }
                 Token::And => {
-                    self.push_operator(Operator::BitAnd);
+                    self.push_binary(Operator::BitAnd)?;
                 }
                 Token::LiteralChar(x)
                 | Token::LiteralLChar(x)
                 | Token::LiteralUUChar(x)
                 | Token::LiteralUChar(x)
                 | Token::LiteralU8Char(x) => {
-                    self.operands.push(Int::Unsigned(x as u64));
-                    self.last = LastKind::Operand;
+                    self.push_operand(Int::Signed(x as i64), &tok)?;
                 }
                 Token::Xor => {
-                    self.push_operator(Operator::BitXor);
+                    self.push_binary(Operator::BitXor)?;
                 }
                 Token::Or => {
-                    self.push_operator(Operator::BitOr);
+                    self.push_binary(Operator::BitOr)?;
                 }
                 Token::AndAnd => {
-                    self.push_operator(Operator::And);
+                    self.push_binary(Operator::And)?;
                 }
                 Token::OrOr => {
-                    self.push_operator(Operator::Or);
+                    self.push_binary(Operator::Or)?;
+                }
+                Token::Question => {
+                    self.push_binary(Operator::Cond)?;
+                }
+                Token::Colon => {
+                    self.push_else()?;
                 }
                 Token::LeftParen => {
+                    if self.last == LastKind::Operand {
+                        return Err("missing binary operator before token \"(\"".to_string());
+                    }
                     self.operators.push(Operator::Parenthesis);
                     self.last = LastKind::Operator;
                 }
                 Token::RightParen => {
-                    self.flush_until_paren();
+                    self.flush_until_paren()?;
                 }
                 Token::LiteralInt(x)
                 | Token::LiteralHex(x)
                 | Token::LiteralBin(x)
-                | Token::LiteralUInt(x)
+                | Token::LiteralOct(x)
                 | Token::LiteralLong(x)
                 | Token::LiteralLongLong(x)
+                | Token::LiteralSignedSize(x) => {
+                    self.push_operand(Int::from_literal(x), &tok)?;
+                }
+                Token::LiteralUInt(x)
                 | Token::LiteralULong(x)
-                | Token::LiteralULongLong(x) => {
-                    self.operands.push(Int::Unsigned(x));
-                    self.last = LastKind::Operand;
-                }
-                Token::Identifier(id) => {
-                    if id == "defined" {
-                        let x = self.lexer.get_defined();
-                        self.operands.push(Int::Unsigned(x));
-                    } else {
-                        self.operands.push(Int::Unsigned(0));
+                | Token::LiteralULongLong(x)
+                | Token::LiteralSize(x) => {
+                    self.push_operand(Int::Unsigned(x), &tok)?;
+                }
+                Token::True => {
+                    self.push_operand(Int::Signed(1), &tok)?;
+                }
+                Token::False => {
+                    self.push_operand(Int::Signed(0), &tok)?;
+                }
+                Token::Identifier(ref id) => {
+                    if self.last == LastKind::Operand {
+                        return Err(format!("missing binary operator before token \"{}\"", id));
                     }
-                    self.last = LastKind::Operand;
+                    let x = if id == "defined" {
+                        self.lexer.get_defined()
+                    } else if id == "__has_include" || id == "__has_include_next" {
+                        self.lexer.get_has_include()
+                    } else if matches!(
+                        id.as_ref(),
+                        "__has_cpp_attribute"
+                            | "__has_attribute"
+                            | "__has_builtin"
+                            | "__has_feature"
+                    ) {
+                        self.get_query(id)
+                    } else {
+                        // an undefined identifier is 0
+                        0
+                    };
+                    self.push_operand(Int::Signed(x as i64), &tok)?;
                 }
                 Token::Eol | Token::Eof => {
-                    self.flush();
-                    return self.operands.pop().unwrap();
+                    self.end = Some(tok);
+                    self.flush()?;
+                    return Ok(self.operands.pop().unwrap_or(Int::Signed(0)));
                 }
-                _ => {
-                    unreachable!();
+                Token::None => {}
+                tok => {
+                    return Err(format!("token \"{}\" is not valid in #if", tok));
                 }
             }
         }
@@ -644,7 +874,7 @@ mod tests {
         let mut cond = Condition::new(&mut lexer);
         let res = cond.eval();
 
-        assert_eq!(res, Int::Unsigned(1));
+        assert_eq!(res, Int::Signed(1));
     }
 
     #[test]
@@ -653,7 +883,7 @@ mod tests {
         let mut cond = Condition::new(&mut lexer);
         let res = cond.eval();
 
-        assert_eq!(res, Int::Unsigned(5));
+        assert_eq!(res, Int::Signed(5));
     }
 
     #[test]
@@ -662,7 +892,7 @@ mod tests {
         let mut cond = Condition::new(&mut lexer);
         let res = cond.eval();
 
-        assert_eq!(res, Int::Unsigned(6));
+        assert_eq!(res, Int::Signed(6));
     }
 
     #[test]
@@ -671,7 +901,7 @@ mod tests {
         let mut cond = Condition::new(&mut lexer);
         let res = cond.eval();
 
-        assert_eq!(res, Int::Unsigned(14));
+        assert_eq!(res, Int::Signed(14));
     }
 
     #[test]
@@ -680,7 +910,7 @@ mod tests {
         let mut cond = Condition::new(&mut lexer);
         let res = cond.eval();
 
-        assert_eq!(res, Int::Unsigned(1));
+        assert_eq!(res, Int::Signed(1));
     }
 
     #[test]
@@ -708,11 +938,278 @@ mod tests {
         let mut cond = Condition::new(&mut lexer);
         let res = cond.eval();
 
-        assert_eq!(res, Int::Unsigned(1));
+        assert_eq!(res, Int::Signed(1));
+
+        let mut cond = Condition::new(&mut lexer);
+        let res = cond.eval();
+
+        assert_eq!(res, Int::Signed(1));
+    }
 
+    fn eval_condition(expr: &str) -> (Int, usize) {
+        let mut lexer = Lexer::new(expr.as_bytes());
         let mut cond = Condition::new(&mut lexer);
         let res = cond.eval();
+        (res, lexer.take_diagnostics().len())
+    }
+
+    #[test]
+    fn test_condition_all_ops() {
+        assert_eq!(eval_condition("(1<<4) - 2 > 0"), (Int::Signed(1), 0));
+        assert_eq!(eval_condition("1 << 4 - 2"), (Int::Signed(4), 0));
+        assert_eq!(eval_condition("(2 + 3) * 4"), (Int::Signed(20), 0));
+        assert_eq!(eval_condition("17 / 5 + 17 % 5"), (Int::Signed(5), 0));
+        assert_eq!(eval_condition("256 >> 2 >> 1"), (Int::Signed(32), 0));
+        assert_eq!(eval_condition("6 & 3 | 8 ^ 12"), (Int::Signed(6), 0));
+        assert_eq!(eval_condition("1 | 2 == 2"), (Int::Signed(1), 0));
+        assert_eq!(eval_condition("~0 == -1"), (Int::Signed(1), 0));
+        assert_eq!(eval_condition("!0 + !5"), (Int::Signed(1), 0));
+        assert_eq!(
+            eval_condition("3 <= 3 && 3 >= 4 || 2 != 2"),
+            (Int::Signed(0), 0)
+        );
+        assert_eq!(eval_condition("1 < 2 == 3 > 2"), (Int::Signed(1), 0));
+        assert_eq!(eval_condition("- - 3"), (Int::Signed(3), 0));
+        assert_eq!(eval_condition("true && !false"), (Int::Signed(1), 0));
+        assert_eq!(eval_condition("UNDEFINED_FOO + 1"), (Int::Signed(1), 0));
+        assert_eq!(
+            eval_condition("0xFFFFFFFFFFFFFFFF * 2 + 2"),
+            (Int::Unsigned(0), 0)
+        );
+        assert_eq!(eval_condition("1 << 70"), (Int::Signed(0), 0));
+    }
+
+    #[test]
+    fn test_condition_short_circuit() {
+        assert_eq!(eval_condition("1 || 1 / 0"), (Int::Signed(1), 0));
+        assert_eq!(eval_condition("0 && (1 % 0)"), (Int::Signed(0), 0));
+        assert_eq!(eval_condition("0 && 1 / 0 || 1"), (Int::Signed(1), 0));
+        assert_eq!(eval_condition("1 && 1 / 0"), (Int::Signed(0), 1));
+        assert_eq!(eval_condition("0 || 2 % 0"), (Int::Signed(0), 1));
+        assert_eq!(eval_condition("(0 && 1) || 1 / 0"), (Int::Signed(0), 1));
+    }
+
+    fn eval_errors(expr: &str) -> (Int, Vec<String>) {
+        let mut lexer = Lexer::new(expr.as_bytes());
+        let mut cond = Condition::new(&mut lexer);
+        let res = cond.eval();
+        let messages = lexer
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| d.message)
+            .collect();
+        (res, messages)
+    }
+
+    #[test]
+    fn test_condition_conditional() {
+        assert_eq!(eval_condition("1 ? 2 : (1/0)"), (Int::Signed(2), 0));
+        assert_eq!(eval_condition("1 ? 2 : 1 / 0"), (Int::Signed(2), 0));
+        assert_eq!(eval_condition("0 ? 1 / 0 : 3"), (Int::Signed(3), 0));
+        assert_eq!(eval_condition("0 ? 2 : 1 / 0"), (Int::Signed(0), 1));
+        assert_eq!(eval_condition("0 ? 1 : 0 ? 2 : 3"), (Int::Signed(3), 0));
+        assert_eq!(eval_condition("1 ? 0 ? 4 : 5 : 6"), (Int::Signed(5), 0));
+        assert_eq!(eval_condition("0 || 1 ? 7 : 8"), (Int::Signed(7), 0));
+        assert_eq!(eval_condition("(1 ? 2 : 3) + 1"), (Int::Signed(3), 0));
+        // the second and third operands get the usual arithmetic conversions
+        assert_eq!(eval_condition("1 ? -1 : 0u"), (Int::Unsigned(u64::MAX), 0));
+    }
+
+    #[test]
+    fn test_condition_conversions() {
+        assert_eq!(eval_condition("-1 > 0u"), (Int::Signed(1), 0));
+        assert_eq!(eval_condition("2u - 3 > 0"), (Int::Signed(1), 0));
+        assert_eq!(eval_condition("-1 < 0"), (Int::Signed(1), 0));
+        assert_eq!(eval_condition("(1 < 2) - 2 < 0"), (Int::Signed(1), 0));
+        assert_eq!(
+            eval_condition("0xFFFFFFFFFFFFFFFF > 0"),
+            (Int::Signed(1), 0)
+        );
+        assert_eq!(eval_condition("-1 / 2u"), (Int::Unsigned(u64::MAX / 2), 0));
+        assert_eq!(eval_condition("(0u - 1) >> 63"), (Int::Unsigned(1), 0));
+        // the type of a shift is the type of its left operand
+        assert_eq!(eval_condition("-1 >> 63u"), (Int::Signed(-1), 0));
+        assert_eq!(eval_condition("'a' == 97"), (Int::Signed(1), 0));
+    }
+
+    #[test]
+    fn test_condition_malformed() {
+        let error = |message: &str| (Int::Signed(0), vec![message.to_string()]);
+        assert_eq!(
+            eval_errors("1 ||"),
+            error("operator '||' has no right operand")
+        );
+        assert_eq!(eval_errors("(1"), error("missing ')' in expression"));
+        assert_eq!(eval_errors("1)"), error("missing '(' in expression"));
+        assert_eq!(
+            eval_errors("()"),
+            error("missing expression in parentheses")
+        );
+        assert_eq!(
+            eval_errors("1 2"),
+            error("missing binary operator before token \"2\"")
+        );
+        assert_eq!(
+            eval_errors("1 (2)"),
+            error("missing binary operator before token \"(\"")
+        );
+        assert_eq!(
+            eval_errors("1 FOO"),
+            error("missing binary operator before token \"FOO\"")
+        );
+        assert_eq!(
+            eval_errors("* 2"),
+            error("operator '*' has no left operand")
+        );
+        assert_eq!(eval_errors("1 ? 2"), error("'?' without following ':'"));
+        assert_eq!(eval_errors("1 : 2"), error("':' without preceding '?'"));
+        // the evaluation stops at the first error
+        assert_eq!(
+            eval_errors("1 = 2 / 0"),
+            error("token \"=\" is not valid in #if")
+        );
+    }
+
+    #[test]
+    fn test_condition_invalid_token() {
+        let mut lexer = Lexer::new(b"#if 1 = 2 / 0\nA\n#endif\nB\n");
+        assert_eq!(get_tokens(&mut lexer), vec![Token::Identifier("B".into())]);
+        let diagnostics: Vec<_> = lexer
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.message, d.line))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![("token \"=\" is not valid in #if".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_condition_errors() {
+        let mut lexer = Lexer::new(b"#if 4 / (2 - 2)\nint a;\n#endif\n#if\nint b;\n#endif\nint c;");
+        let mut tokens = Vec::new();
+        loop {
+            match lexer.next() {
+                Token::Eof => break,
+                Token::Eol => {}
+                tok => tokens.push(tok),
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec![Token::Int, Token::Identifier("c".into()), Token::SemiColon]
+        );
+
+        let diagnostics: Vec<_> = lexer
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.message, d.line))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                ("division by zero in #if".to_string(), 1),
+                ("#if with no expression".to_string(), 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_condition_if() {
+        let mut lexer = Lexer::new(b"#if (1<<4) - 2 > 0\nA\n#else\nB\n#endif\n");
+        assert_eq!(lexer.next(), Token::Identifier("A".into()));
+    }
+
+    fn get_tokens<'a>(lexer: &mut Lexer<'a>) -> Vec<Token<'a>> {
+        let mut tokens = Vec::new();
+        loop {
+            match lexer.next() {
+                Token::Eof => break,
+                Token::Eol => {}
+                tok => tokens.push(tok),
+            }
+        }
+        tokens
+    }
+
+    #[test]
+    fn test_condition_defined() {
+        let mut lexer = Lexer::new(
+            concat!(
+                "#define FOO 1\n",
+                "#define BAR FOO\n",
+                "#if defined(BAR) && defined FOO && !defined( BAZ )\n",
+                "A\n",
+                "#endif\n",
+                "#define F(x) x\n",
+                "#if defined(F) && defined F\n",
+                "B\n",
+                "#endif\n",
+                "#define ZERO 0\n",
+                "#define D defined(ZERO)\n",
+                "#define E defined ZERO && defined ( BAZ )\n",
+                "#if D && !(E)\n",
+                "C\n",
+                "#endif\n",
+                "#if F(defined(ZERO)) && F(defined ZERO)\n",
+                "G\n",
+                "#endif\n",
+                "defined(ZERO)\n",
+            )
+            .as_bytes(),
+        );
+
+        assert_eq!(
+            get_tokens(&mut lexer),
+            vec![
+                Token::Identifier("A".into()),
+                Token::Identifier("B".into()),
+                Token::Identifier("C".into()),
+                Token::Identifier("G".into()),
+                Token::Identifier("defined".into()),
+                Token::LeftParen,
+                Token::LiteralInt(0),
+                Token::RightParen,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_condition_has_attribute() {
+        let mut lexer = Lexer::new(
+            concat!(
+                "#if __has_cpp_attribute(nodiscard) >= 201907L\n",
+                "A\n",
+                "#endif\n",
+                "#if __has_cpp_attribute(foo) || __has_attribute(gnu::unused)\n",
+                "B\n",
+                "#endif\n",
+                "#define ATTR deprecated\n",
+                "#define HAS(x) __has_cpp_attribute(x)\n",
+                "#if HAS(ATTR) == 201309 && __has_attribute(__deprecated__)\n",
+                "C\n",
+                "#endif\n",
+                "#if defined(__has_cpp_attribute) && __has_cpp_attribute(my::attr) == 1\n",
+                "D\n",
+                "#endif\n",
+            )
+            .as_bytes(),
+        );
+        lexer.context.set_attribute("my::attr", 1);
+
+        assert_eq!(
+            get_tokens(&mut lexer),
+            vec![
+                Token::Identifier("A".into()),
+                Token::Identifier("C".into()),
+                Token::Identifier("D".into()),
+            ]
+        );
+        assert!(lexer.take_diagnostics().is_empty());
 
-        assert_eq!(res, Int::Unsigned(1));
+        let mut lexer = Lexer::new(b"#if __has_cpp_attribute(nodiscard\nA\n#endif\nB\n");
+        assert_eq!(get_tokens(&mut lexer), vec![Token::Identifier("B".into())]);
+        assert_eq!(lexer.take_diagnostics().len(), 1);
     }
 }
@@ -0,0 +1,399 @@
+use std::fmt;
+
+use super::lexer::{Token, UdLiteral};
+use super::preprocessor::IncludeType;
+
+// Write a char literal value in a form which gives back the same value once lexed
+fn write_char(f: &mut fmt::Formatter<'_>, prefix: &str, val: u32) -> fmt::Result {
+    write!(f, "{}'", prefix)?;
+    if prefix.is_empty() && val > 0xFF {
+        // multicharacter literal: each char has been shifted in
+        let bytes = val.to_be_bytes();
+        let first = bytes.iter().position(|b| *b != 0).unwrap_or(3);
+        let mut escaped = false;
+        for b in &bytes[first..] {
+            escaped = write_c_char(f, u32::from(*b), escaped)?;
+        }
+    } else {
+        write_c_char(f, val, false)?;
+    }
+    write!(f, "'")
+}
+
+// Write a char in a char literal and get true if a numeric escape has been used
+fn write_c_char(
+    f: &mut fmt::Formatter<'_>,
+    c: u32,
+    after_escape: bool,
+) -> Result<bool, fmt::Error> {
+    match c {
+        0x27 => write!(f, "\\'")?,
+        0x5C => write!(f, "\\\\")?,
+        0x0A => write!(f, "\\n")?,
+        0x09 => write!(f, "\\t")?,
+        0x0D => write!(f, "\\r")?,
+        // a hex digit after a hex escape would be a part of the escape
+        0x20..=0x7E if !(after_escape && (c as u8).is_ascii_hexdigit()) => {
+            write!(f, "{}", c as u8 as char)?
+        }
+        _ => {
+            write!(f, "\\x{:x}", c)?;
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn write_raw_string(f: &mut fmt::Formatter<'_>, prefix: &str, s: &[u8]) -> fmt::Result {
+    // find a delimiter which doesn't close the string too early
+    let mut delimiter = String::new();
+    while contains_closing(s, delimiter.as_bytes()) {
+        delimiter.push('x');
+    }
+    write!(
+        f,
+        "{}R\"{}({}){}\"",
+        prefix,
+        delimiter,
+        String::from_utf8_lossy(s),
+        delimiter
+    )
+}
+
+fn contains_closing(s: &[u8], delimiter: &[u8]) -> bool {
+    s.windows(delimiter.len() + 2)
+        .any(|w| w[0] == b')' && &w[1..=delimiter.len()] == delimiter && w[w.len() - 1] == b'"')
+}
+
+fn write_decimal(f: &mut fmt::Formatter<'_>, x: f64) -> fmt::Result {
+    if x.is_infinite() {
+        // the value has been saturated when lexed
+        write!(f, "1e999")
+    } else {
+        // Debug gives the shortest representation which gives back the same value
+        write!(f, "{:?}", x)
+    }
+}
+
+impl<'a> UdLiteral<'a> {
+    fn into_token(self) -> Token<'a> {
+        match self {
+            UdLiteral::Int(x) => Token::LiteralInt(x),
+            UdLiteral::Decimal(x) => Token::LiteralDecimal(x),
+            UdLiteral::Char(x) => Token::LiteralChar(x),
+            UdLiteral::LChar(x) => Token::LiteralLChar(x),
+            UdLiteral::UChar(x) => Token::LiteralUChar(x),
+            UdLiteral::UUChar(x) => Token::LiteralUUChar(x),
+            UdLiteral::U8Char(x) => Token::LiteralU8Char(x),
+            UdLiteral::String(x) => Token::LiteralString(x),
+            UdLiteral::LString(x) => Token::LiteralLString(x),
+            UdLiteral::UString(x) => Token::LiteralUString(x),
+            UdLiteral::UUString(x) => Token::LiteralUUString(x),
+            UdLiteral::U8String(x) => Token::LiteralU8String(x),
+            UdLiteral::RString(x) => Token::LiteralRString(x),
+            UdLiteral::LRString(x) => Token::LiteralLRString(x),
+            UdLiteral::URString(x) => Token::LiteralURString(x),
+            UdLiteral::UURString(x) => Token::LiteralUURString(x),
+            UdLiteral::U8RString(x) => Token::LiteralU8RString(x),
+        }
+    }
+}
+
+impl<'a> fmt::Display for UdLiteral<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.clone().into_token())
+    }
+}
+
+impl<'a> fmt::Display for IncludeType<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IncludeType::Quote(s) => write!(f, "\"{}\"", String::from_utf8_lossy(s)),
+            IncludeType::Angle(s) => write!(f, "<{}>", String::from_utf8_lossy(s)),
+            IncludeType::Other(s) => write!(f, "{}", String::from_utf8_lossy(s)),
+        }
+    }
+}
+
+/// Write the token as it could be written in a source:
+/// lexing the output gives back the same token
+impl<'a> fmt::Display for Token<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Token::None | Token::Eof => "",
+            Token::Eol => "\n",
+            Token::Comment { block, text } => {
+                let text = String::from_utf8_lossy(text);
+                return if *block {
+                    write!(f, "/*{}*/", text)
+                } else {
+                    write!(f, "//{}", text)
+                };
+            }
+            Token::Not => "!",
+            Token::NotEqual => "!=",
+            Token::Dollar => "$",
+            Token::Modulo => "%",
+            Token::ModuloEqual => "%=",
+            Token::AndAnd => "&&",
+            Token::And => "&",
+            Token::AndEqual => "&=",
+            Token::LiteralChar(x) => return write_char(f, "", *x),
+            Token::LiteralLChar(x) => return write_char(f, "L", *x),
+            Token::LiteralUUChar(x) => return write_char(f, "U", *x),
+            Token::LiteralUChar(x) => return write_char(f, "u", *x),
+            Token::LiteralU8Char(x) => return write_char(f, "u8", *x),
+            Token::LeftParen => "(",
+            Token::RightParen => ")",
+            Token::Star => "*",
+            Token::StarEqual => "*=",
+            Token::PlusPlus => "++",
+            Token::Plus => "+",
+            Token::PlusEqual => "+=",
+            Token::Divide => "/",
+            Token::DivideEqual => "/=",
+            Token::Comma => ",",
+            Token::MinusMinus => "--",
+            Token::Minus => "-",
+            Token::MinusEqual => "-=",
+            Token::Arrow => "->",
+            Token::ArrowStar => "->*",
+            Token::LiteralDecimal(x) => return write_decimal(f, *x),
+            Token::LiteralFloat(x) => {
+                write_decimal(f, *x)?;
+                "f"
+            }
+            Token::LiteralLongDouble(x) => {
+                write_decimal(f, *x)?;
+                "L"
+            }
+            Token::Dot => ".",
+            Token::DotStar => ".*",
+            Token::Ellipsis => "...",
+            Token::LiteralHex(x) => return write!(f, "0x{:x}", x),
+            Token::LiteralBin(x) => return write!(f, "0b{:b}", x),
+            Token::LiteralOct(x) => return write!(f, "0{:o}", x),
+            Token::LiteralInt(x) => return write!(f, "{}", x),
+            Token::LiteralUInt(x) => return write!(f, "{}u", x),
+            Token::LiteralLong(x) => return write!(f, "{}l", x),
+            Token::LiteralLongLong(x) => return write!(f, "{}ll", x),
+            Token::LiteralULong(x) => return write!(f, "{}ul", x),
+            Token::LiteralULongLong(x) => return write!(f, "{}ull", x),
+            Token::LiteralSignedSize(x) => return write!(f, "{}z", x),
+            Token::LiteralSize(x) => return write!(f, "{}uz", x),
+            Token::LiteralString(s) => return write!(f, "\"{}\"", String::from_utf8_lossy(s)),
+            Token::LiteralLString(s) => return write!(f, "L\"{}\"", String::from_utf8_lossy(s)),
+            Token::LiteralUString(s) => return write!(f, "u\"{}\"", String::from_utf8_lossy(s)),
+            Token::LiteralUUString(s) => return write!(f, "U\"{}\"", String::from_utf8_lossy(s)),
+            Token::LiteralU8String(s) => return write!(f, "u8\"{}\"", String::from_utf8_lossy(s)),
+            Token::LiteralRString(s) => return write_raw_string(f, "", s),
+            Token::LiteralLRString(s) => return write_raw_string(f, "L", s),
+            Token::LiteralURString(s) => return write_raw_string(f, "u", s),
+            Token::LiteralUURString(s) => return write_raw_string(f, "U", s),
+            Token::LiteralU8RString(s) => return write_raw_string(f, "u8", s),
+            Token::ColonColon => "::",
+            Token::Colon => ":",
+            Token::SemiColon => ";",
+            Token::Lower => "<",
+            Token::LowerEqual => "<=",
+            Token::LowerEqualGreater => "<=>",
+            Token::LeftShift => "<<",
+            Token::LeftShiftEqual => "<<=",
+            Token::EqualEqual => "==",
+            Token::Equal => "=",
+            Token::Greater => ">",
+            Token::GreaterEqual => ">=",
+            Token::RightShift => ">>",
+            Token::RightShiftEqual => ">>=",
+            Token::Question => "?",
+            Token::At => "@",
+            Token::LiteralUserDefined(lit, suffix) => return write!(f, "{}{}", lit, suffix),
+            Token::Identifier(id) => id,
+            Token::Invalid(s) => return write!(f, "{}", String::from_utf8_lossy(s)),
+            Token::LeftBrack => "[",
+            Token::Backslash => "\\",
+            Token::RightBrack => "]",
+            Token::Xor => "^",
+            Token::XorEqual => "^=",
+            Token::LeftBrace => "{",
+            Token::OrOr => "||",
+            Token::Or => "|",
+            Token::OrEqual => "|=",
+            Token::RightBrace => "}",
+            Token::Tilde => "~",
+            Token::Alignas => "alignas",
+            Token::Alignof => "alignof",
+            Token::AndKw => "and",
+            Token::AndEq => "and_eq",
+            Token::Asm => "asm",
+            Token::Auto => "auto",
+            Token::BitAnd => "bitand",
+            Token::BitOr => "bitor",
+            Token::Bool => "bool",
+            Token::Break => "break",
+            Token::Case => "case",
+            Token::Catch => "catch",
+            Token::Char => "char",
+            Token::Char8 => "char8_t",
+            Token::Char16 => "char16_t",
+            Token::Char32 => "char32_t",
+            Token::Class => "class",
+            Token::CoAwait => "co_await",
+            Token::CoReturn => "co_return",
+            Token::CoYield => "co_yield",
+            Token::Compl => "compl",
+            Token::Concept => "concept",
+            Token::Const => "const",
+            Token::Consteval => "consteval",
+            Token::Constexpr => "constexpr",
+            Token::Constinit => "constinit",
+            Token::ConstCast => "const_cast",
+            Token::Continue => "continue",
+            Token::Decltype => "decltype",
+            Token::Default => "default",
+            Token::Delete => "delete",
+            Token::Do => "do",
+            Token::Double => "double",
+            Token::DynamicCast => "dynamic_cast",
+            Token::Else => "else",
+            Token::Enum => "enum",
+            Token::Explicit => "explicit",
+            Token::Export => "export",
+            Token::Extern => "extern",
+            Token::False => "false",
+            Token::Float => "float",
+            Token::For => "for",
+            Token::Friend => "friend",
+            Token::Goto => "goto",
+            Token::If => "if",
+            Token::Import => "import",
+            Token::Inline => "inline",
+            Token::Int => "int",
+            Token::Long => "long",
+            Token::Module => "module",
+            Token::Mutable => "mutable",
+            Token::Namespace => "namespace",
+            Token::New => "new",
+            Token::Noexcept => "noexcept",
+            Token::NotKw => "not",
+            Token::NotEq => "not_eq",
+            Token::Nullptr => "nullptr",
+            Token::Operator => "operator",
+            Token::OrKw => "or",
+            Token::OrEq => "or_eq",
+            Token::Private => "private",
+            Token::Protected => "protected",
+            Token::Public => "public",
+            Token::Register => "register",
+            Token::ReinterpretCast => "reinterpret_cast",
+            Token::Requires => "requires",
+            Token::Restrict => "restrict",
+            Token::Return => "return",
+            Token::Short => "short",
+            Token::Signed => "signed",
+            Token::Sizeof => "sizeof",
+            Token::Static => "static",
+            Token::StaticAssert => "static_assert",
+            Token::StaticCast => "static_cast",
+            Token::Struct => "struct",
+            Token::Switch => "switch",
+            Token::Template => "template",
+            Token::This => "this",
+            Token::ThreadLocal => "thread_local",
+            Token::Throw => "throw",
+            Token::True => "true",
+            Token::Try => "try",
+            Token::Typedef => "typedef",
+            Token::Typeid => "typeid",
+            Token::TypeName => "typename",
+            Token::Union => "union",
+            Token::Unsigned => "unsigned",
+            Token::Using => "using",
+            Token::Virtual => "virtual",
+            Token::Void => "void",
+            Token::Volatile => "volatile",
+            Token::Wchar => "wchar_t",
+            Token::While => "while",
+            Token::XorKw => "xor",
+            Token::XorEq => "xor_eq",
+            Token::Hash => "#",
+            Token::HashHash => "##",
+            Token::PreprocIf => "#if",
+            Token::PreprocDefine => "#define",
+            Token::PreprocElif => "#elif",
+            Token::PreprocElifdef => "#elifdef",
+            Token::PreprocElifndef => "#elifndef",
+            Token::PreprocElse => "#else",
+            Token::PreprocEndif => "#endif",
+            Token::PreprocError => "#error",
+            Token::PreprocIfdef => "#ifdef",
+            Token::PreprocIfndef => "#ifndef",
+            Token::PreprocInclude(name) => return write!(f, "#include {}", name),
+            Token::PreprocIncludeNext(name) => return write!(f, "#include_next {}", name),
+            Token::PreprocInclude2 => "#include",
+            Token::PreprocIncludeNext2 => "#include_next",
+            Token::PreprocImport(name) => return write!(f, "#import {}", name),
+            Token::PreprocImport2 => "#import",
+            Token::PreprocLine => "#line",
+            Token::PreprocPragma => "#pragma",
+            Token::PreprocUndef => "#undef",
+            Token::PreprocWarning => "#warning",
+            Token::Pragma(s) => return write!(f, "#pragma {}", String::from_utf8_lossy(s)),
+            Token::Directive { text, .. } => return write!(f, "{}", String::from_utf8_lossy(text)),
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::lexer::{PContext, Tokenizer};
+
+    #[test]
+    fn test_round_trip() {
+        let source = concat!(
+            "int main(int argc, char ** argv) {\n",
+            "    /* a comment */ a <<= b->*c <=> d[[x]] ... ::e <% %>\n",
+            "    x = 0x1F + 0b101 + 017 + 42u + 42ul + 42ll + 1.5 + 2.5e-7 + 1e20;\n",
+            "    c = 'a' + '\\'' + '\\n' + '\\x01' + 'ab' + '\\x01a' + L'\\xffff' + u'\\x263A' + U'\\U0001F600';\n",
+            "    s = \"a\\\"b\" L\"c\" u\"d\" U\"e\" u8\"f\" R\"(g)\" u8R\"x(h)\")x\" LR\"(\\n)\";\n",
+            "    t = 12_km + 1.5_s + \"abc\"_s + 'c'_c + R\"(a)\"_r;\n",
+            "    return x and_eq y;\n",
+            "}\n",
+        );
+        let mut ctx = PContext::default();
+        let tokens: Vec<_> = Tokenizer::new(source.as_bytes(), &mut ctx).collect();
+        let output = tokens
+            .iter()
+            .map(|t| t.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut ctx = PContext::default();
+        let round_trip: Vec<_> = Tokenizer::new(output.as_bytes(), &mut ctx).collect();
+        assert_eq!(round_trip, tokens);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Token::LiteralChar(u32::from(b'a')).to_string(), "'a'");
+        assert_eq!(Token::LiteralChar(0x01).to_string(), "'\\x1'");
+        assert_eq!(Token::LiteralChar(0x0161).to_string(), "'\\x1\\x61'");
+        assert_eq!(Token::LiteralUUChar(0x1F600).to_string(), "U'\\x1f600'");
+        assert_eq!(
+            Token::LiteralRString(b")\"".into()).to_string(),
+            "R\"x()\")x\""
+        );
+        assert_eq!(Token::LiteralHex(255).to_string(), "0xff");
+        assert_eq!(Token::LiteralDecimal(1.0).to_string(), "1.0");
+        assert_eq!(
+            Token::LiteralUserDefined(UdLiteral::String(b"a".into()), "_s".into()).to_string(),
+            "\"a\"_s"
+        );
+        assert_eq!(
+            Token::PreprocInclude(IncludeType::Angle(b"a.h".into())).to_string(),
+            "#include <a.h>"
+        );
+    }
+}
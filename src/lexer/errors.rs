@@ -0,0 +1,232 @@
+use std::fmt;
+
+use super::span::{advance_column, Span};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PreprocError {
+    // #error message
+    Error {
+        message: String,
+        line: usize,
+        column: usize,
+    },
+    // #include "name" with a file which can't be found
+    IncludeNotFound {
+        name: String,
+        line: usize,
+        column: usize,
+    },
+    // too many nested #include (probably a file including itself)
+    IncludeDepth {
+        name: String,
+        line: usize,
+        column: usize,
+    },
+}
+
+impl PreprocError {
+    pub fn position(&self) -> (usize, usize) {
+        match self {
+            PreprocError::Error { line, column, .. }
+            | PreprocError::IncludeNotFound { line, column, .. }
+            | PreprocError::IncludeDepth { line, column, .. } => (*line, *column),
+        }
+    }
+}
+
+impl fmt::Display for PreprocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (line, column) = self.position();
+        match self {
+            PreprocError::Error { message, .. } => {
+                write!(f, "{}:{}: error: {}", line, column, message)
+            }
+            PreprocError::IncludeNotFound { name, .. } => write!(
+                f,
+                "{}:{}: error: {}: No such file or directory",
+                line, column, name
+            ),
+            PreprocError::IncludeDepth { name, .. } => write!(
+                f,
+                "{}:{}: error: #include nested too deeply: {}",
+                line, column, name
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    /// The location of the offending text when it's known (e.g. an invalid token)
+    pub span: Option<Span>,
+}
+
+impl Diagnostic {
+    pub fn warning(message: String, line: usize, column: usize) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message,
+            line,
+            column,
+            span: None,
+        }
+    }
+
+    pub fn error(message: String, line: usize, column: usize) -> Self {
+        Self {
+            severity: Severity::Error,
+            message,
+            line,
+            column,
+            span: None,
+        }
+    }
+    /// Set the location of the offending text
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    /// Render the diagnostic with the line of the source where it occurs
+    /// and a caret under the offending text, e.g.:
+    /// ```text
+    /// 1:9: error: invalid digit "8" in octal constant
+    /// int a = 08;
+    ///         ^~
+    /// ```
+    /// Without span, the caret is under the column (computed with a tab width of 8).
+    pub fn render(&self, src: &[u8]) -> String {
+        let mut out = self.to_string();
+        let (line_start, start, end) = if let Some(span) = self.span {
+            let start = (span.start as usize).min(src.len());
+            let line_start = src[..start]
+                .iter()
+                .rposition(|c| *c == b'\n')
+                .map_or(0, |pos| pos + 1);
+            (line_start, start, (span.end as usize).min(src.len()))
+        } else {
+            let line_start = if self.line <= 1 {
+                Some(0)
+            } else {
+                src.iter()
+                    .enumerate()
+                    .filter(|(_, c)| **c == b'\n')
+                    .nth(self.line - 2)
+                    .map(|(pos, _)| pos + 1)
+            };
+            let line_start = if let Some(line_start) = line_start {
+                line_start
+            } else {
+                return out;
+            };
+            let mut start = line_start;
+            let mut col = 1;
+            while col < self.column && start < src.len() && src[start] != b'\n' {
+                col = advance_column(src, start, start + 1, col, 8);
+                start += 1;
+            }
+            // don't stop in the middle of an utf-8 char
+            while start < src.len() && src[start] & 0xC0 == 0x80 {
+                start += 1;
+            }
+            (line_start, start, start + 1)
+        };
+        let line_end = src[line_start..]
+            .iter()
+            .position(|c| *c == b'\n')
+            .map_or(src.len(), |pos| line_start + pos);
+        let line = &src[line_start..line_end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        out.push('\n');
+        out.push_str(&String::from_utf8_lossy(line));
+        out.push('\n');
+        // keep the tabs to be aligned with the line whatever the tab width is
+        for c in &src[line_start..start] {
+            if *c == b'\t' {
+                out.push('\t');
+            } else if *c & 0xC0 != 0x80 {
+                out.push(' ');
+            }
+        }
+        out.push('^');
+        let len = advance_column(src, start, end.min(line_end).max(start), 1, 1) - 1;
+        for _ in 1..len {
+            out.push('~');
+        }
+        out
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}: {}",
+            self.line, self.column, self.severity, self.message
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::lexer::{Lexer, Token};
+
+    #[test]
+    fn test_render() {
+        let source = "int a;\n\tint b = 0x1p; // é\nint c = 08;\r\n".as_bytes();
+        let mut p = Lexer::new(source);
+        while p.next() != Token::Eof {}
+        let rendered = p
+            .take_diagnostics()
+            .iter()
+            .map(|diagnostic| diagnostic.render(source))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rendered,
+            vec![
+                concat!(
+                    "2:17: error: exponent has no digits\n",
+                    "\tint b = 0x1p; // é\n",
+                    "\t        ^~~~",
+                ),
+                concat!(
+                    "3:9: error: invalid digit \"8\" in octal constant\n",
+                    "int c = 08;\n",
+                    "        ^~",
+                ),
+            ]
+        );
+
+        let diagnostic = Diagnostic::warning("unknown".to_string(), 2, 27);
+        assert_eq!(
+            diagnostic.render(source),
+            "2:27: warning: unknown\n\tint b = 0x1p; // é\n\t                  ^"
+        );
+        let diagnostic = Diagnostic::error("end".to_string(), 4, 1);
+        assert_eq!(diagnostic.render(source), "4:1: error: end\n\n^");
+        let diagnostic = Diagnostic::error("far".to_string(), 7, 1);
+        assert_eq!(diagnostic.render(source), "7:1: error: far");
+    }
+}
@@ -0,0 +1,887 @@
+use hashbrown::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use super::errors::{Diagnostic, PreprocError};
+use super::lexer::{Lexer, LexerOptions, Token};
+use super::pmacros::MacroOutput;
+use super::preprocessor::IncludeType;
+use super::translation::{translate, SourceMap};
+
+// Same limit as in gcc
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+/// Find and read the files included with #include
+pub trait IncludeResolver {
+    /// Get the path of the file included with `#include "name"` (or `#include <name>`
+    /// when is_system is true) from the file current
+    fn resolve(&self, name: &str, is_system: bool, current: &Path) -> Option<PathBuf>;
+
+    /// Get the content of a resolved file
+    fn read(&self, path: &Path) -> Option<Vec<u8>>;
+
+    /// Get a unique path for a resolved file (used by #pragma once)
+    fn canonicalize(&self, path: &Path) -> Option<PathBuf> {
+        fs::canonicalize(path).ok()
+    }
+}
+
+fn search_path<F: Fn(&Path) -> bool>(
+    name: &str,
+    is_system: bool,
+    current: &Path,
+    include_paths: &[PathBuf],
+    exists: F,
+) -> Option<PathBuf> {
+    if !is_system {
+        // "foo.h" are searched first in the directory of the current file
+        let dir = current.parent().unwrap_or_else(|| Path::new(""));
+        let path = dir.join(name);
+        if exists(&path) {
+            return Some(path);
+        }
+    }
+    include_paths
+        .iter()
+        .map(|dir| dir.join(name))
+        .find(|path| exists(path))
+}
+
+/// Resolve the includes on the file system using a list of directories (as with -I)
+#[derive(Clone, Debug, Default)]
+pub struct FileSystemResolver {
+    include_paths: Vec<PathBuf>,
+}
+
+impl FileSystemResolver {
+    pub fn new(include_paths: Vec<PathBuf>) -> Self {
+        Self { include_paths }
+    }
+
+    pub fn add_include_path<P: Into<PathBuf>>(&mut self, path: P) {
+        self.include_paths.push(path.into());
+    }
+}
+
+impl IncludeResolver for FileSystemResolver {
+    fn resolve(&self, name: &str, is_system: bool, current: &Path) -> Option<PathBuf> {
+        search_path(name, is_system, current, &self.include_paths, |path| {
+            path.is_file()
+        })
+    }
+
+    fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        fs::read(path).ok()
+    }
+}
+
+/// Resolve the includes in a set of files kept in memory
+#[derive(Clone, Debug, Default)]
+pub struct MemoryResolver {
+    include_paths: Vec<PathBuf>,
+    files: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl MemoryResolver {
+    pub fn new(include_paths: Vec<PathBuf>) -> Self {
+        Self {
+            include_paths,
+            files: HashMap::default(),
+        }
+    }
+
+    pub fn add_file<P: AsRef<Path>>(&mut self, path: P, content: &[u8]) {
+        self.files
+            .insert(normalize_path(path.as_ref()), content.to_vec());
+    }
+}
+
+/// Remove the . and .. from a path without touching the file system
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push("..");
+                }
+            }
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+impl IncludeResolver for MemoryResolver {
+    fn resolve(&self, name: &str, is_system: bool, current: &Path) -> Option<PathBuf> {
+        search_path(name, is_system, current, &self.include_paths, |path| {
+            self.files.contains_key(&normalize_path(path))
+        })
+    }
+
+    fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        self.files.get(&normalize_path(path)).cloned()
+    }
+
+    fn canonicalize(&self, path: &Path) -> Option<PathBuf> {
+        Some(normalize_path(path))
+    }
+}
+
+/// Get the macro FOO when the whole file is in a #ifndef FOO / #define FOO ... #endif
+/// (only the spaces and the comments can be outside): the file can be skipped
+/// when it's included again and FOO is still defined
+fn get_include_guard(content: &[u8], options: LexerOptions) -> Option<String> {
+    let mut lexer = Lexer::new_translated(
+        content,
+        LexerOptions {
+            preprocessor: false,
+            comments: false,
+            ..options
+        },
+    );
+    // get the next token and if it starts a line: without the preprocessor,
+    // a directive is a # at the beginning of a line followed by an identifier or a keyword
+    let mut new_line = true;
+    let mut next = move || loop {
+        match lexer.next() {
+            Token::Eol => new_line = true,
+            tok => {
+                let first = new_line;
+                new_line = false;
+                return (tok, first);
+            }
+        }
+    };
+
+    let is_directive = |(tok, first): (Token, bool), name: &str| {
+        !first && matches!(tok, Token::Identifier(id) if id == name)
+    };
+
+    if next() != (Token::Hash, true) || !is_directive(next(), "ifndef") {
+        return None;
+    }
+    let guard = match next() {
+        (Token::Identifier(id), false) => id,
+        _ => return None,
+    };
+    if next() != (Token::Hash, true)
+        || !is_directive(next(), "define")
+        || !is_directive(next(), &guard)
+    {
+        return None;
+    }
+
+    let mut depth = 1;
+    loop {
+        match next() {
+            (Token::Eof, _) => return None,
+            (Token::Hash, true) => match next() {
+                (Token::If, false) => depth += 1,
+                (Token::Else, false) if depth == 1 => {
+                    // the file content depends on the guard
+                    return None;
+                }
+                (Token::Identifier(id), false) => match id.as_ref() {
+                    "ifdef" | "ifndef" => depth += 1,
+                    "endif" => {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    "elif" | "elifdef" | "elifndef" if depth == 1 => return None,
+                    _ => {}
+                },
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    // only the end of the line of the #endif can follow
+    loop {
+        match next() {
+            (Token::Eof, _) => return Some(guard.to_string()),
+            (_, true) => return None,
+            _ => {}
+        }
+    }
+}
+
+/// The state of the including file, restored at the end of the included one
+pub(crate) struct IncludeFrame<'a> {
+    buf: &'a [u8],
+    len: usize,
+    pos: usize,
+    line: usize,
+    lpos: usize,
+    file: String,
+    source_map: SourceMap,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn set_resolver(&mut self, resolver: Box<dyn IncludeResolver>) {
+        self.resolver = Some(resolver);
+    }
+
+    fn get_resolver(&self) -> &dyn IncludeResolver {
+        static DEFAULT: FileSystemResolver = FileSystemResolver {
+            include_paths: Vec::new(),
+        };
+        if let Some(resolver) = self.resolver.as_ref() {
+            resolver.as_ref()
+        } else {
+            &DEFAULT
+        }
+    }
+
+    pub(crate) fn include(&mut self, include: &IncludeType, line: usize, column: usize) {
+        self.include_file(include, line, column, false);
+    }
+
+    /// #import includes a file as if it began with #pragma once
+    pub(crate) fn import(&mut self, include: &IncludeType, line: usize, column: usize) {
+        self.include_file(include, line, column, true);
+    }
+
+    fn include_file(&mut self, include: &IncludeType, line: usize, column: usize, once: bool) {
+        let (name, is_system) = match include {
+            IncludeType::Quote(name) => (String::from_utf8_lossy(name).to_string(), false),
+            IncludeType::Angle(name) => (String::from_utf8_lossy(name).to_string(), true),
+            IncludeType::Other(text) => {
+                if let Some(header) = self.get_computed_header(text) {
+                    header
+                } else {
+                    self.context.add_diagnostic(Diagnostic::error(
+                        "#include expects \"FILENAME\" or <FILENAME>".to_string(),
+                        line,
+                        column,
+                    ));
+                    return;
+                }
+            }
+        };
+        if self.include_stack.len() >= MAX_INCLUDE_DEPTH {
+            self.add_error(
+                PreprocError::IncludeDepth {
+                    name: name.to_string(),
+                    line,
+                    column,
+                },
+                format!(
+                    "#include nested depth {} exceeds maximum",
+                    MAX_INCLUDE_DEPTH
+                ),
+            );
+            return;
+        }
+
+        let resolver = self.get_resolver();
+        let current = Path::new(self.context.get_file());
+        let path = resolver.resolve(&name, is_system, current);
+        let canonical = path.as_ref().and_then(|path| resolver.canonicalize(path));
+        if let Some(canonical) = canonical.as_ref() {
+            if self.context.is_once(canonical) || self.context.is_guarded(canonical) {
+                // the file isn't read again
+                return;
+            }
+        }
+
+        let content = path
+            .as_ref()
+            .and_then(|path| resolver.read(path).map(|content| (path, content)));
+        let (path, content) = if let Some((path, content)) = content {
+            (path, content)
+        } else {
+            let message = format!("{}: No such file or directory", name);
+            self.add_error(
+                PreprocError::IncludeNotFound {
+                    name: name.to_string(),
+                    line,
+                    column,
+                },
+                message,
+            );
+            return;
+        };
+
+        let (content, source_map) = translate(&content, self.options.trigraphs)
+            .unwrap_or_else(|| (content, SourceMap::default()));
+        if let Some(canonical) = canonical {
+            if once {
+                self.context.add_once(canonical.clone());
+            }
+            if !self.context.has_guard_info(&canonical) {
+                let guard = get_include_guard(&content, self.options);
+                self.context.set_guard(canonical, guard);
+            }
+        }
+
+        self.include_stack.push(IncludeFrame {
+            buf: self.buf,
+            len: self.len,
+            pos: self.pos,
+            line: self.line,
+            lpos: self.lpos,
+            file: self.context.get_file().to_string(),
+            source_map: std::mem::replace(&mut self.source_map, source_map),
+        });
+        self.buf = self.add_buffer(content);
+        self.len = self.buf.len();
+        self.pos = 0;
+        self.line = 1;
+        self.lpos = 0;
+        self.context.set_file(&path.to_string_lossy());
+    }
+
+    /// Get the header name (and if it's a <name>) from the expansion of the macros
+    /// in #include MACRO: the name in <name> is the spelling of the tokens
+    fn get_computed_header(&mut self, text: &[u8]) -> Option<(String, bool)> {
+        let mut out = MacroOutput::default();
+        let mut lexer = Lexer::new_translated(text, LexerOptions::default());
+        lexer.macro_final_eval(&mut out, &self.context);
+
+        let out = String::from_utf8_lossy(&out);
+        let out = out.trim();
+        let (term, is_system) = if out.starts_with('"') {
+            ('"', false)
+        } else if out.starts_with('<') {
+            ('>', true)
+        } else {
+            return None;
+        };
+        // nothing can follow the header name
+        let name = out[1..].strip_suffix(term)?;
+        if name.is_empty() || name.contains(term) {
+            return None;
+        }
+        Some((name.to_string(), is_system))
+    }
+
+    /// Evaluate __has_include("name") or __has_include(<name>) in a #if
+    /// (__has_include_next is handled in the same way since #include_next isn't)
+    pub(crate) fn get_has_include(&mut self) -> u64 {
+        skip_whites!(self);
+        if self.pos >= self.len || self.next_char(0) != b'(' {
+            return 0;
+        }
+        self.pos += 1;
+        skip_whites!(self);
+        if self.pos >= self.len {
+            return 0;
+        }
+
+        // the header name mustn't be lexed as usual tokens: <foo/bar.h> isn't an expression
+        let (name, is_system) = match self.next_char(0) {
+            b'\"' => {
+                self.pos += 1;
+                (self.get_include_content(b'\"'), false)
+            }
+            b'<' => {
+                self.pos += 1;
+                (self.get_include_content(b'>'), true)
+            }
+            _ => {
+                return 0;
+            }
+        };
+        skip_whites!(self);
+        if self.pos < self.len && self.next_char(0) == b')' {
+            self.pos += 1;
+        }
+
+        let name = String::from_utf8_lossy(name);
+        let current = Path::new(self.context.get_file());
+        self.get_resolver()
+            .resolve(&name, is_system, current)
+            .is_some() as u64
+    }
+
+    pub(crate) fn end_include(&mut self) -> bool {
+        if let Some(frame) = self.include_stack.pop() {
+            self.buf = frame.buf;
+            self.len = frame.len;
+            self.pos = frame.pos;
+            self.line = frame.line;
+            self.lpos = frame.lpos;
+            self.source_map = frame.source_map;
+            self.context.set_file(&frame.file);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn pragma_once(&mut self) {
+        if let Some(canonical) = self
+            .get_resolver()
+            .canonicalize(Path::new(self.context.get_file()))
+        {
+            self.context.add_once(canonical);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::lexer::lexer::{LexerBuilder, Token};
+    use crate::lexer::Sources;
+    use std::borrow::Cow;
+
+    fn get_tokens(p: &mut Lexer) -> Vec<String> {
+        let mut tokens = Vec::new();
+        loop {
+            match p.next() {
+                Token::Eof => break,
+                Token::Eol => {}
+                tok => tokens.push(format!("{:?}", tok)),
+            }
+        }
+        tokens
+    }
+
+    #[test]
+    fn test_include_quote() {
+        let mut resolver = MemoryResolver::new(vec![]);
+        resolver.add_file("/src/a.h", b"#define A 1\nint a = A;\n");
+        resolver.add_file(
+            "/src/sub/b.h",
+            b"#include \"../a.h\"\nint b = A + __LINE__;\n",
+        );
+
+        let mut p = Lexer::new(b"#include \"sub/b.h\"\nint c = __LINE__;\n");
+        p.set_file("/src/main.c");
+        p.set_resolver(Box::new(resolver));
+
+        assert_eq!(
+            get_tokens(&mut p),
+            vec![
+                "Int",
+                "Identifier(\"a\")",
+                "Equal",
+                "LiteralInt(1)",
+                "SemiColon",
+                "Int",
+                "Identifier(\"b\")",
+                "Equal",
+                "LiteralInt(1)",
+                "Plus",
+                "LiteralInt(2)",
+                "SemiColon",
+                "Int",
+                "Identifier(\"c\")",
+                "Equal",
+                "LiteralInt(2)",
+                "SemiColon",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pragma_once() {
+        let mut resolver = MemoryResolver::new(vec![]);
+        resolver.add_file("/src/a.h", b"#pragma once\nstruct A {};\n");
+        resolver.add_file("/src/sub/b.h", b"#include \"../a.h\"\n");
+        resolver.add_file("/src/c.h", b"struct C {};\n");
+
+        let mut p = Lexer::new(
+            concat!(
+                "#include \"a.h\"\n",
+                "#include \"./a.h\"\n",
+                "#include \"sub/b.h\"\n",
+                "#include \"sub/../a.h\"\n",
+                "#include \"c.h\"\n",
+                "#include \"c.h\"\n",
+            )
+            .as_bytes(),
+        );
+        p.set_file("/src/main.c");
+        p.set_resolver(Box::new(resolver));
+
+        assert_eq!(
+            get_tokens(&mut p),
+            vec![
+                "Struct",
+                "Identifier(\"A\")",
+                "LeftBrace",
+                "RightBrace",
+                "SemiColon",
+                "Struct",
+                "Identifier(\"C\")",
+                "LeftBrace",
+                "RightBrace",
+                "SemiColon",
+                "Struct",
+                "Identifier(\"C\")",
+                "LeftBrace",
+                "RightBrace",
+                "SemiColon",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_include_sources() {
+        let mut resolver = MemoryResolver::new(vec![]);
+        resolver.add_file("a.h", b"int a;\n");
+
+        // the included file is owned by the lexer: the tokens own their payloads
+        let mut p = Lexer::new(b"#include \"a.h\"\n");
+        p.set_file("main.c");
+        p.set_resolver(Box::new(resolver.clone()));
+        let tokens = [p.next(), p.next()];
+        drop(p);
+        assert_eq!(tokens[1], Token::Identifier(Cow::Owned("a".to_string())));
+
+        // the included file is in sources: the tokens borrow it
+        let sources = Sources::new();
+        let mut p = LexerBuilder::new()
+            .file("main.c")
+            .resolver(Box::new(resolver))
+            .sources(&sources)
+            .build(b"#include \"a.h\"\n");
+        let tokens = [p.next(), p.next()];
+        drop(p);
+        assert_eq!(sources.len(), 1);
+        assert_eq!(tokens[0], Token::Int);
+        assert!(matches!(tokens[1], Token::Identifier(Cow::Borrowed("a"))));
+    }
+
+    // Count the reads of the files
+    #[derive(Debug)]
+    struct CountingResolver {
+        resolver: MemoryResolver,
+        reads: std::rc::Rc<std::cell::RefCell<Vec<PathBuf>>>,
+    }
+
+    impl IncludeResolver for CountingResolver {
+        fn resolve(&self, name: &str, is_system: bool, current: &Path) -> Option<PathBuf> {
+            self.resolver.resolve(name, is_system, current)
+        }
+
+        fn read(&self, path: &Path) -> Option<Vec<u8>> {
+            self.reads.borrow_mut().push(path.to_path_buf());
+            self.resolver.read(path)
+        }
+
+        fn canonicalize(&self, path: &Path) -> Option<PathBuf> {
+            self.resolver.canonicalize(path)
+        }
+    }
+
+    #[test]
+    fn test_include_guard() {
+        let mut resolver = MemoryResolver::new(vec![]);
+        resolver.add_file(
+            "a.h",
+            b"// A\n#ifndef A_H\n#define A_H\n#ifdef X\n#else\n#endif\nint a;\n#endif // A_H\n",
+        );
+        resolver.add_file("b.h", b"#ifndef B_H\n#define B_H\nint b;\n#endif\nint c;\n");
+        resolver.add_file(
+            "c.h",
+            b"#ifndef C_H\n#define C_H\nint c;\n#else\nint d;\n#endif\n",
+        );
+        let reads = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+
+        let mut p = Lexer::new(
+            concat!(
+                "#include \"a.h\"\n",
+                "#include \"a.h\"\n",
+                "#undef A_H\n",
+                "#include \"a.h\"\n",
+                "#include \"b.h\"\n",
+                "#include \"b.h\"\n",
+                "#include \"c.h\"\n",
+                "#include \"c.h\"\n",
+            )
+            .as_bytes(),
+        );
+        p.set_file("main.c");
+        p.set_resolver(Box::new(CountingResolver {
+            resolver,
+            reads: reads.clone(),
+        }));
+
+        assert_eq!(
+            get_tokens(&mut p),
+            vec![
+                "Int",
+                "Identifier(\"a\")",
+                "SemiColon",
+                "Int",
+                "Identifier(\"a\")",
+                "SemiColon",
+                "Int",
+                "Identifier(\"b\")",
+                "SemiColon",
+                "Int",
+                "Identifier(\"c\")",
+                "SemiColon",
+                "Int",
+                "Identifier(\"c\")",
+                "SemiColon",
+                "Int",
+                "Identifier(\"c\")",
+                "SemiColon",
+                "Int",
+                "Identifier(\"d\")",
+                "SemiColon",
+            ]
+        );
+        // a.h is read again after the #undef and b.h and c.h haven't a guard
+        let reads: Vec<_> = reads
+            .borrow()
+            .iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(reads, vec!["a.h", "a.h", "b.h", "b.h", "c.h", "c.h"]);
+    }
+
+    #[test]
+    fn test_import() {
+        let mut resolver = MemoryResolver::new(vec![]);
+        resolver.add_file("a.h", b"int a;\n");
+        resolver.add_file("b.h", b"#import \"a.h\"\nint b;\n");
+
+        let mut p = Lexer::new(
+            concat!(
+                "#import \"a.h\"\n",
+                "#import \"a.h\"\n",
+                "#import \"b.h\"\n",
+                "#include \"a.h\"\n",
+                "#import \"./b.h\"\n",
+            )
+            .as_bytes(),
+        );
+        p.set_file("main.c");
+        p.set_resolver(Box::new(resolver));
+
+        assert_eq!(
+            get_tokens(&mut p),
+            vec![
+                "Int",
+                "Identifier(\"a\")",
+                "SemiColon",
+                "Int",
+                "Identifier(\"b\")",
+                "SemiColon",
+            ]
+        );
+        assert!(p.take_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_computed_include() {
+        let mut resolver = MemoryResolver::new(vec![PathBuf::from("/usr/include")]);
+        resolver.add_file("/usr/include/vector", b"int vector;\n");
+        resolver.add_file("a.h", b"int a;\n");
+        resolver.add_file("b.h", b"int b;\n");
+
+        let mut p = Lexer::new(
+            concat!(
+                "#define HDR <vector>\n",
+                "#define QHDR \"a.h\"\n",
+                "#define STR(x) #x\n",
+                "#define XSTR(x) STR(x)\n",
+                "#define NAME b.h\n",
+                "#include HDR\n",
+                "#include QHDR\n",
+                "#include XSTR(NAME)\n",
+                "#include NAME\n",
+            )
+            .as_bytes(),
+        );
+        p.set_file("main.c");
+        p.set_resolver(Box::new(resolver));
+
+        assert_eq!(
+            get_tokens(&mut p),
+            vec![
+                "Int",
+                "Identifier(\"vector\")",
+                "SemiColon",
+                "Int",
+                "Identifier(\"a\")",
+                "SemiColon",
+                "Int",
+                "Identifier(\"b\")",
+                "SemiColon",
+            ]
+        );
+        let diagnostics = p.take_diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].message,
+            "#include expects \"FILENAME\" or <FILENAME>"
+        );
+        assert_eq!(diagnostics[0].line, 9);
+    }
+
+    #[test]
+    fn test_pragma_operator_once() {
+        let mut resolver = MemoryResolver::new(vec![]);
+        resolver.add_file("a.h", b"_Pragma(\"once\") int a;\n");
+        resolver.add_file(
+            "b.h",
+            b"#define DO_PRAGMA(x) _Pragma(#x)\nDO_PRAGMA(once) int b;\n",
+        );
+
+        let mut p = Lexer::new(
+            concat!(
+                "#include \"a.h\"\n",
+                "#include \"a.h\"\n",
+                "#include \"b.h\"\n",
+                "#include \"b.h\"\n",
+            )
+            .as_bytes(),
+        );
+        p.set_file("main.c");
+        p.set_resolver(Box::new(resolver));
+
+        assert_eq!(
+            get_tokens(&mut p),
+            vec![
+                "Int",
+                "Identifier(\"a\")",
+                "SemiColon",
+                "Int",
+                "Identifier(\"b\")",
+                "SemiColon",
+            ]
+        );
+        assert!(p.take_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_include_precedence() {
+        let mut resolver = MemoryResolver::new(vec![PathBuf::from("/usr/include")]);
+        resolver.add_file("/src/foo.h", b"int local;");
+        resolver.add_file("/usr/include/foo.h", b"int system;");
+        resolver.add_file(
+            "/usr/include/bar.h",
+            b"int bar = __LINE__;\n#include \"foo.h\"\n",
+        );
+
+        let mut p = Lexer::new(b"#include \"foo.h\"\n#include <foo.h>\n#include \"bar.h\"\n");
+        p.set_file("/src/main.c");
+        p.set_resolver(Box::new(resolver));
+
+        assert_eq!(
+            get_tokens(&mut p),
+            vec![
+                "Int",
+                "Identifier(\"local\")",
+                "SemiColon",
+                "Int",
+                "Identifier(\"system\")",
+                "SemiColon",
+                "Int",
+                "Identifier(\"bar\")",
+                "Equal",
+                "LiteralInt(1)",
+                "SemiColon",
+                "Int",
+                "Identifier(\"system\")",
+                "SemiColon",
+            ]
+        );
+        assert!(p.errors().is_empty());
+    }
+
+    #[test]
+    fn test_include_file_macro() {
+        let mut resolver = MemoryResolver::new(vec![]);
+        resolver.add_file("dir/foo.h", b"\n__FILE__ __LINE__\n");
+
+        let mut p = Lexer::new(b"#include \"foo.h\"\n__FILE__ __LINE__");
+        p.set_file("dir/main.c");
+        p.set_resolver(Box::new(resolver));
+
+        assert_eq!(
+            get_tokens(&mut p),
+            vec![
+                "LiteralString([100, 105, 114, 47, 102, 111, 111, 46, 104])",
+                "LiteralInt(2)",
+                "LiteralString([100, 105, 114, 47, 109, 97, 105, 110, 46, 99])",
+                "LiteralInt(2)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let mut resolver = MemoryResolver::new(vec![PathBuf::from("inc")]);
+        resolver.add_file("inc/local.h", b"int x;");
+        resolver.add_file("self.h", b"#include \"self.h\"\n");
+
+        let mut p = Lexer::new(b"#include <missing.h>\n  #include \"local.h\"\n#include <self.h>\n#include \"self.h\"\n");
+        p.set_file("main.c");
+        p.set_resolver(Box::new(resolver));
+
+        assert_eq!(
+            get_tokens(&mut p),
+            vec!["Int", "Identifier(\"x\")", "SemiColon"]
+        );
+        assert_eq!(
+            p.errors()[0],
+            PreprocError::IncludeNotFound {
+                name: "missing.h".to_string(),
+                line: 1,
+                column: 2,
+            }
+        );
+        if let PreprocError::IncludeNotFound { name, line, .. } = &p.errors()[1] {
+            assert_eq!((name.as_str(), *line), ("self.h", 3));
+        } else {
+            panic!("self.h isn't in the include paths");
+        }
+        assert_eq!(p.errors().len(), 3);
+        if let PreprocError::IncludeDepth { name, .. } = &p.errors()[2] {
+            assert_eq!(name, "self.h");
+        } else {
+            panic!("self.h must include itself too many times");
+        }
+    }
+
+    #[test]
+    fn test_has_include() {
+        let mut resolver = MemoryResolver::new(vec![PathBuf::from("/usr/include")]);
+        resolver.add_file("/usr/include/optional", b"");
+        resolver.add_file("/src/config.h", b"");
+
+        let mut p = Lexer::new(concat!(
+            "#if __has_include(<optional>)\n",
+            "int a;\n",
+            "#endif\n",
+            "#if __has_include( <variant> )\n",
+            "int b;\n",
+            "#elif defined(__has_include) && __has_include(\"config.h\") && !__has_include(<config.h>)\n",
+            "int c;\n",
+            "#endif\n",
+            "#if __has_include_next(<optional>) + __has_include_next(<foo/bar.h>) == 1\n",
+            "int d;\n",
+            "#endif\n",
+        ).as_bytes());
+        p.set_file("/src/main.c");
+        p.set_resolver(Box::new(resolver));
+
+        assert_eq!(
+            get_tokens(&mut p),
+            vec![
+                "Int",
+                "Identifier(\"a\")",
+                "SemiColon",
+                "Int",
+                "Identifier(\"c\")",
+                "SemiColon",
+                "Int",
+                "Identifier(\"d\")",
+                "SemiColon",
+            ]
+        );
+    }
+}
@@ -0,0 +1,291 @@
+use std::ops::Range;
+
+use super::lexer::{Lexer, LexerOptions, Token};
+use super::span::{advance_column, Span};
+
+/// A modification of a source: `deleted` bytes at `offset` are replaced by `inserted`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edit<'e> {
+    pub offset: usize,
+    pub deleted: usize,
+    pub inserted: &'e [u8],
+}
+
+/// The tokens lexed again after an edit
+pub struct Relexed<'a> {
+    /// The indices of the new tokens in the updated spans
+    pub range: Range<usize>,
+    tokens: Vec<Token<'a>>,
+}
+
+impl<'a> Relexed<'a> {
+    /// Get the new tokens: they replace the ones in the range given by `range`
+    pub fn tokens(&self) -> &[Token<'a>] {
+        &self.tokens
+    }
+}
+
+/// Lex again the part of the source modified by an edit (the preprocessor isn't used)
+///
+/// `source` is the source after the edit and `spans` the spans of the tokens
+/// of the source before the edit: the spans are updated and the returned tokens
+/// are the ones which have changed.
+/// The lexing starts on the first token of the line where the last token before the edit
+/// starts and stops on the first token after the edit which starts where an old token started: at this point the lexer
+/// is in the same state as before (e.g. not in a string or a comment opened by the edit).
+pub fn relex<'a>(
+    source: &'a [u8],
+    spans: &mut Vec<Span>,
+    edit: &Edit,
+    options: LexerOptions,
+) -> Relexed<'a> {
+    let tab_width = (options.tab_width as usize).max(1);
+    let delta = edit.inserted.len() as i64 - edit.deleted as i64;
+    let old_edit_end = edit.offset + edit.deleted;
+
+    // a token ending at the offset is lexed again since the edit can extend it
+    let first = spans
+        .iter()
+        .position(|span| span.end as usize >= edit.offset)
+        .unwrap_or(spans.len());
+    // the tokens before the edit can depend on the characters after them (e.g. "<::"
+    // isn't lexed as "<:" ":"), so the lexing starts on the first token of the line
+    // of the last token before the edit
+    let first = match first.checked_sub(1) {
+        Some(prev) => {
+            let line = spans[prev].line;
+            spans[..prev]
+                .iter()
+                .rposition(|span| span.line != line)
+                .map_or(0, |pos| pos + 1)
+        }
+        None => 0,
+    };
+    let (window_start, line) = spans
+        .get(first)
+        .map_or((0, 1), |span| (span.start as usize, span.line as usize));
+    let line_start = source[..window_start]
+        .iter()
+        .rposition(|c| *c == b'\n')
+        .map_or(0, |pos| pos + 1);
+
+    let mut lexer = Lexer::with_options(
+        &source[window_start..],
+        LexerOptions {
+            preprocessor: false,
+            ..options
+        },
+    );
+    let mut tokens = Vec::new();
+    let mut new_spans = Vec::new();
+    let mut old = first;
+    let mut resync = None;
+
+    loop {
+        let (tok, span) = lexer.next_with_span();
+        if tok == Token::Eof {
+            break;
+        }
+        let start = span.start as usize + window_start;
+        let span = Span {
+            start: start as u32,
+            end: span.end + window_start as u32,
+            line: span.line + line as u32 - 1,
+            col: if span.line == 1 {
+                advance_column(source, line_start, start, 1, tab_width) as u32
+            } else {
+                span.col
+            },
+        };
+
+        while old < spans.len() && (spans[old].start as i64 + delta) < start as i64 {
+            old += 1;
+        }
+        if old < spans.len()
+            && spans[old].start as usize >= old_edit_end
+            && spans[old].start as i64 + delta == start as i64
+        {
+            resync = Some(span);
+            break;
+        }
+        tokens.push(tok);
+        new_spans.push(span);
+    }
+
+    // the tokens lexed again before the offset with the same spans are unchanged
+    let same = new_spans
+        .iter()
+        .zip(&spans[first..])
+        .take_while(|(new, old)| new == old && (new.end as usize) < edit.offset)
+        .count();
+    tokens.drain(..same);
+    new_spans.drain(..same);
+    let first = first + same;
+
+    let count = new_spans.len();
+    let end = if resync.is_some() { old } else { spans.len() };
+    spans.splice(first..end, new_spans);
+
+    if let Some(resync) = resync {
+        // the tokens after the resync point are only moved
+        let first_old = first + count;
+        let old_line = spans[first_old].line;
+        let line_delta = resync.line as i64 - old_line as i64;
+        let mut prev = resync;
+        spans[first_old] = resync;
+        for span in spans[first_old + 1..].iter_mut() {
+            let on_line = span.line == old_line;
+            span.start = (span.start as i64 + delta) as u32;
+            span.end = (span.end as i64 + delta) as u32;
+            span.line = (span.line as i64 + line_delta) as u32;
+            if on_line {
+                // the columns have changed on the line where the edit ends
+                span.col = advance_column(
+                    source,
+                    prev.start as usize,
+                    span.start as usize,
+                    prev.col as usize,
+                    tab_width,
+                ) as u32;
+                prev = *span;
+            }
+        }
+    }
+
+    Relexed {
+        range: first..first + count,
+        tokens,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn get_tokens(source: &[u8]) -> (Vec<Token<'_>>, Vec<Span>) {
+        let options = LexerOptions {
+            preprocessor: false,
+            ..Default::default()
+        };
+        let mut lexer = Lexer::with_options(source, options);
+        std::iter::from_fn(|| match lexer.next_with_span() {
+            (Token::Eof, _) => None,
+            next => Some(next),
+        })
+        .unzip()
+    }
+
+    fn check_relex(old: &str, edit: Edit) -> Range<usize> {
+        let (_, mut spans) = get_tokens(old.as_bytes());
+        let mut new = old.as_bytes().to_vec();
+        new.splice(
+            edit.offset..edit.offset + edit.deleted,
+            edit.inserted.iter().cloned(),
+        );
+        let (expected_tokens, expected_spans) = get_tokens(&new);
+
+        let relexed = relex(&new, &mut spans, &edit, LexerOptions::default());
+        assert_eq!(spans, expected_spans);
+        assert_eq!(relexed.tokens(), &expected_tokens[relexed.range.clone()]);
+        relexed.range
+    }
+
+    #[test]
+    fn test_relex_between_tokens() {
+        let source = "int a = 1;\nint b\t= 2;\n";
+        let edit = Edit {
+            offset: 5,
+            deleted: 0,
+            inserted: b"bc",
+        };
+        assert_eq!(check_relex(source, edit), 1..2);
+
+        let edit = Edit {
+            offset: 16,
+            deleted: 0,
+            inserted: b" + 3\n\n",
+        };
+        assert_eq!(check_relex(source, edit), 7..12);
+
+        let edit = Edit {
+            offset: 4,
+            deleted: 6,
+            inserted: b"",
+        };
+        assert_eq!(check_relex(source, edit), 1..1);
+    }
+
+    #[test]
+    fn test_relex_in_string() {
+        let source = "s = \"abc\"; t = 'u';\nint x;\n";
+
+        // the literal is still terminated
+        let edit = Edit {
+            offset: 6,
+            deleted: 1,
+            inserted: b"\\\"",
+        };
+        assert_eq!(check_relex(source, edit), 2..3);
+
+        // the literal is split
+        let edit = Edit {
+            offset: 6,
+            deleted: 0,
+            inserted: b"\"; \"",
+        };
+        assert_eq!(check_relex(source, edit), 2..5);
+
+        // an unterminated literal swallows the end of the line
+        let edit = Edit {
+            offset: 11,
+            deleted: 0,
+            inserted: b"\"",
+        };
+        assert_eq!(check_relex(source, edit), 4..5);
+    }
+
+    #[test]
+    fn test_relex_lookahead() {
+        // the tokens before the edit can depend on the characters after them
+        let source = "<::1'<=+";
+        let edit = Edit {
+            offset: 2,
+            deleted: 6,
+            inserted: b")",
+        };
+        check_relex(source, edit);
+
+        let source = "u8'";
+        let edit = Edit {
+            offset: 3,
+            deleted: 0,
+            inserted: b"/*:",
+        };
+        check_relex(source, edit);
+    }
+
+    #[test]
+    fn test_relex_as_full_lex() {
+        let sources = [
+            "a<::b...c%:%:d",
+            "x = u8'a' + L\"s\"; // c\ny /* z */ <%",
+            "#if A\n.5e+1 ..\n",
+        ];
+        let inserts: [&[u8]; 9] = [b"", b":", b".", b"'", b"\"", b"/*", b"*/", b"\n", b"%"];
+        for source in sources.iter() {
+            for offset in 0..=source.len() {
+                for deleted in 0..=(source.len() - offset).min(3) {
+                    for inserted in inserts.iter() {
+                        let edit = Edit {
+                            offset,
+                            deleted,
+                            inserted,
+                        };
+                        check_relex(source, edit);
+                    }
+                }
+            }
+        }
+    }
+}
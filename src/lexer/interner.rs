@@ -0,0 +1,131 @@
+use hashbrown::hash_map::{DefaultHashBuilder, HashMap, RawEntryMut};
+use std::hash::BuildHasher;
+
+use super::lexer::{Lexer, Token};
+
+/// An interned identifier: two symbols from the same [`Interner`] are equal
+/// if and only if their identifiers are equal
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Symbol(pub u32);
+
+/// A set of identifiers where each one is stored once and gets a [`Symbol`]
+#[derive(Clone, Debug, Default)]
+pub struct Interner {
+    // the symbols hashed with their identifiers: the identifiers are only in names
+    symbols: HashMap<Symbol, ()>,
+    names: Vec<String>,
+}
+
+fn hash_name(builder: &DefaultHashBuilder, name: &str) -> u64 {
+    builder.hash_one(name)
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the symbol of an identifier (a new one if the identifier isn't interned yet)
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        let builder = self.symbols.hasher().clone();
+        let hash = hash_name(&builder, name);
+        let names = &mut self.names;
+        match self
+            .symbols
+            .raw_entry_mut()
+            .from_hash(hash, |sym| names[sym.0 as usize] == name)
+        {
+            RawEntryMut::Occupied(entry) => *entry.key(),
+            RawEntryMut::Vacant(entry) => {
+                let sym = Symbol(names.len() as u32);
+                names.push(name.to_string());
+                entry.insert_with_hasher(hash, sym, (), |sym| {
+                    hash_name(&builder, &names[sym.0 as usize])
+                });
+                sym
+            }
+        }
+    }
+
+    /// Get the symbol of an identifier if it has been interned
+    pub fn get(&self, name: &str) -> Option<Symbol> {
+        let hash = hash_name(self.symbols.hasher(), name);
+        self.symbols
+            .raw_entry()
+            .from_hash(hash, |sym| self.names[sym.0 as usize] == name)
+            .map(|(sym, _)| *sym)
+    }
+
+    /// Get the identifier of a symbol
+    ///
+    /// # Panics
+    ///
+    /// If the symbol comes from another interner.
+    pub fn resolve(&self, sym: Symbol) -> &str {
+        &self.names[sym.0 as usize]
+    }
+
+    /// Get the number of interned identifiers
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+impl<'a> Lexer<'a> {
+    /// Get the next token and, for an identifier, its symbol in the interner
+    pub fn next_interned(&mut self, interner: &mut Interner) -> (Token<'a>, Option<Symbol>) {
+        let tok = self.next();
+        let symbol = match &tok {
+            Token::Identifier(id) => Some(interner.intern(id)),
+            _ => None,
+        };
+        (tok, symbol)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_interner() {
+        let mut interner = Interner::new();
+        let mut lexer = Lexer::new(b"#define F(x) x + foo\nfoo = bar * F(foo) + bar;\nint i;");
+        let mut symbols = Vec::new();
+        loop {
+            match lexer.next_interned(&mut interner) {
+                (Token::Eof, _) => break,
+                (_, Some(sym)) => symbols.push(sym),
+                (_, None) => {}
+            }
+        }
+
+        // foo, bar, foo (argument), foo (replacement), bar, i
+        assert_eq!(symbols.len(), 6);
+        assert_eq!(interner.len(), 3);
+        assert_eq!(symbols[0], symbols[2]);
+        assert_eq!(symbols[0], symbols[3]);
+        assert_eq!(symbols[1], symbols[4]);
+        assert_ne!(symbols[0], symbols[1]);
+        assert_eq!(interner.resolve(symbols[0]), "foo");
+        assert_eq!(interner.resolve(symbols[1]), "bar");
+        assert_eq!(interner.resolve(symbols[5]), "i");
+        assert_eq!(interner.get("bar"), Some(symbols[1]));
+        assert_eq!(interner.get("F"), None);
+
+        // the table is grown with the hashes of the stored identifiers
+        let names: Vec<_> = (0..1000).map(|i| format!("x{}", i)).collect();
+        let symbols: Vec<_> = names.iter().map(|name| interner.intern(name)).collect();
+        assert_eq!(interner.len(), 1003);
+        for (name, sym) in names.iter().zip(symbols) {
+            assert_eq!(interner.get(name), Some(sym));
+            assert_eq!(interner.intern(name), sym);
+            assert_eq!(interner.resolve(sym), name);
+        }
+    }
+}
@@ -0,0 +1,351 @@
+use serde_json::{json, Map, Value};
+
+use super::errors::PreprocError;
+use super::lexer::{Token, UdLiteral};
+use super::pmacros::PContext;
+use super::preprocessor::preprocess;
+use super::span::Span;
+
+// The name of the variant (e.g. LiteralString for Token::LiteralString(b"foo"))
+fn get_kind(tok: &Token) -> &'static str {
+    match tok {
+        Token::None => "None",
+        Token::Eof => "Eof",
+        Token::Eol => "Eol",
+        Token::Comment { .. } => "Comment",
+        Token::Not => "Not",
+        Token::NotEqual => "NotEqual",
+        Token::Dollar => "Dollar",
+        Token::Modulo => "Modulo",
+        Token::ModuloEqual => "ModuloEqual",
+        Token::AndAnd => "AndAnd",
+        Token::And => "And",
+        Token::AndEqual => "AndEqual",
+        Token::LiteralChar(..) => "LiteralChar",
+        Token::LiteralLChar(..) => "LiteralLChar",
+        Token::LiteralUUChar(..) => "LiteralUUChar",
+        Token::LiteralUChar(..) => "LiteralUChar",
+        Token::LiteralU8Char(..) => "LiteralU8Char",
+        Token::LeftParen => "LeftParen",
+        Token::RightParen => "RightParen",
+        Token::Star => "Star",
+        Token::StarEqual => "StarEqual",
+        Token::PlusPlus => "PlusPlus",
+        Token::Plus => "Plus",
+        Token::PlusEqual => "PlusEqual",
+        Token::Divide => "Divide",
+        Token::DivideEqual => "DivideEqual",
+        Token::Comma => "Comma",
+        Token::MinusMinus => "MinusMinus",
+        Token::Minus => "Minus",
+        Token::MinusEqual => "MinusEqual",
+        Token::Arrow => "Arrow",
+        Token::ArrowStar => "ArrowStar",
+        Token::LiteralDecimal(..) => "LiteralDecimal",
+        Token::LiteralFloat(..) => "LiteralFloat",
+        Token::LiteralLongDouble(..) => "LiteralLongDouble",
+        Token::Dot => "Dot",
+        Token::DotStar => "DotStar",
+        Token::Ellipsis => "Ellipsis",
+        Token::LiteralHex(..) => "LiteralHex",
+        Token::LiteralBin(..) => "LiteralBin",
+        Token::LiteralOct(..) => "LiteralOct",
+        Token::LiteralInt(..) => "LiteralInt",
+        Token::LiteralUInt(..) => "LiteralUInt",
+        Token::LiteralLong(..) => "LiteralLong",
+        Token::LiteralLongLong(..) => "LiteralLongLong",
+        Token::LiteralULong(..) => "LiteralULong",
+        Token::LiteralULongLong(..) => "LiteralULongLong",
+        Token::LiteralSignedSize(..) => "LiteralSignedSize",
+        Token::LiteralSize(..) => "LiteralSize",
+        Token::LiteralString(..) => "LiteralString",
+        Token::LiteralLString(..) => "LiteralLString",
+        Token::LiteralUString(..) => "LiteralUString",
+        Token::LiteralUUString(..) => "LiteralUUString",
+        Token::LiteralU8String(..) => "LiteralU8String",
+        Token::LiteralRString(..) => "LiteralRString",
+        Token::LiteralLRString(..) => "LiteralLRString",
+        Token::LiteralURString(..) => "LiteralURString",
+        Token::LiteralUURString(..) => "LiteralUURString",
+        Token::LiteralU8RString(..) => "LiteralU8RString",
+        Token::ColonColon => "ColonColon",
+        Token::Colon => "Colon",
+        Token::SemiColon => "SemiColon",
+        Token::Lower => "Lower",
+        Token::LowerEqual => "LowerEqual",
+        Token::LowerEqualGreater => "LowerEqualGreater",
+        Token::LeftShift => "LeftShift",
+        Token::LeftShiftEqual => "LeftShiftEqual",
+        Token::EqualEqual => "EqualEqual",
+        Token::Equal => "Equal",
+        Token::Greater => "Greater",
+        Token::GreaterEqual => "GreaterEqual",
+        Token::RightShift => "RightShift",
+        Token::RightShiftEqual => "RightShiftEqual",
+        Token::Question => "Question",
+        Token::At => "At",
+        Token::LiteralUserDefined(..) => "LiteralUserDefined",
+        Token::Identifier(..) => "Identifier",
+        Token::Invalid(..) => "Invalid",
+        Token::LeftBrack => "LeftBrack",
+        Token::Backslash => "Backslash",
+        Token::RightBrack => "RightBrack",
+        Token::Xor => "Xor",
+        Token::XorEqual => "XorEqual",
+        Token::LeftBrace => "LeftBrace",
+        Token::OrOr => "OrOr",
+        Token::Or => "Or",
+        Token::OrEqual => "OrEqual",
+        Token::RightBrace => "RightBrace",
+        Token::Tilde => "Tilde",
+        Token::Alignas => "Alignas",
+        Token::Alignof => "Alignof",
+        Token::AndKw => "AndKw",
+        Token::AndEq => "AndEq",
+        Token::Asm => "Asm",
+        Token::Auto => "Auto",
+        Token::BitAnd => "BitAnd",
+        Token::BitOr => "BitOr",
+        Token::Bool => "Bool",
+        Token::Break => "Break",
+        Token::Case => "Case",
+        Token::Catch => "Catch",
+        Token::Char => "Char",
+        Token::Char8 => "Char8",
+        Token::Char16 => "Char16",
+        Token::Char32 => "Char32",
+        Token::Class => "Class",
+        Token::CoAwait => "CoAwait",
+        Token::CoReturn => "CoReturn",
+        Token::CoYield => "CoYield",
+        Token::Compl => "Compl",
+        Token::Concept => "Concept",
+        Token::Const => "Const",
+        Token::Consteval => "Consteval",
+        Token::Constexpr => "Constexpr",
+        Token::Constinit => "Constinit",
+        Token::ConstCast => "ConstCast",
+        Token::Continue => "Continue",
+        Token::Decltype => "Decltype",
+        Token::Default => "Default",
+        Token::Delete => "Delete",
+        Token::Do => "Do",
+        Token::Double => "Double",
+        Token::DynamicCast => "DynamicCast",
+        Token::Else => "Else",
+        Token::Enum => "Enum",
+        Token::Explicit => "Explicit",
+        Token::Export => "Export",
+        Token::Extern => "Extern",
+        Token::False => "False",
+        Token::Float => "Float",
+        Token::For => "For",
+        Token::Friend => "Friend",
+        Token::Goto => "Goto",
+        Token::If => "If",
+        Token::Import => "Import",
+        Token::Inline => "Inline",
+        Token::Int => "Int",
+        Token::Long => "Long",
+        Token::Module => "Module",
+        Token::Mutable => "Mutable",
+        Token::Namespace => "Namespace",
+        Token::New => "New",
+        Token::Noexcept => "Noexcept",
+        Token::NotKw => "NotKw",
+        Token::NotEq => "NotEq",
+        Token::Nullptr => "Nullptr",
+        Token::Operator => "Operator",
+        Token::OrKw => "OrKw",
+        Token::OrEq => "OrEq",
+        Token::Private => "Private",
+        Token::Protected => "Protected",
+        Token::Public => "Public",
+        Token::Register => "Register",
+        Token::ReinterpretCast => "ReinterpretCast",
+        Token::Requires => "Requires",
+        Token::Restrict => "Restrict",
+        Token::Return => "Return",
+        Token::Short => "Short",
+        Token::Signed => "Signed",
+        Token::Sizeof => "Sizeof",
+        Token::Static => "Static",
+        Token::StaticAssert => "StaticAssert",
+        Token::StaticCast => "StaticCast",
+        Token::Struct => "Struct",
+        Token::Switch => "Switch",
+        Token::Template => "Template",
+        Token::This => "This",
+        Token::ThreadLocal => "ThreadLocal",
+        Token::Throw => "Throw",
+        Token::True => "True",
+        Token::Try => "Try",
+        Token::Typedef => "Typedef",
+        Token::Typeid => "Typeid",
+        Token::TypeName => "TypeName",
+        Token::Union => "Union",
+        Token::Unsigned => "Unsigned",
+        Token::Using => "Using",
+        Token::Virtual => "Virtual",
+        Token::Void => "Void",
+        Token::Volatile => "Volatile",
+        Token::Wchar => "Wchar",
+        Token::While => "While",
+        Token::XorKw => "XorKw",
+        Token::XorEq => "XorEq",
+        Token::Hash => "Hash",
+        Token::HashHash => "HashHash",
+        Token::PreprocIf => "PreprocIf",
+        Token::PreprocDefine => "PreprocDefine",
+        Token::PreprocElif => "PreprocElif",
+        Token::PreprocElifdef => "PreprocElifdef",
+        Token::PreprocElifndef => "PreprocElifndef",
+        Token::PreprocElse => "PreprocElse",
+        Token::PreprocEndif => "PreprocEndif",
+        Token::PreprocError => "PreprocError",
+        Token::PreprocIfdef => "PreprocIfdef",
+        Token::PreprocIfndef => "PreprocIfndef",
+        Token::PreprocInclude(..) => "PreprocInclude",
+        Token::PreprocIncludeNext(..) => "PreprocIncludeNext",
+        Token::PreprocImport(..) => "PreprocImport",
+        Token::PreprocInclude2 => "PreprocInclude2",
+        Token::PreprocIncludeNext2 => "PreprocIncludeNext2",
+        Token::PreprocImport2 => "PreprocImport2",
+        Token::PreprocLine => "PreprocLine",
+        Token::PreprocPragma => "PreprocPragma",
+        Token::PreprocUndef => "PreprocUndef",
+        Token::PreprocWarning => "PreprocWarning",
+        Token::Directive { .. } => "Directive",
+        Token::Pragma(..) => "Pragma",
+    }
+}
+
+fn get_char_value(tok: &Token) -> Option<u32> {
+    match tok {
+        Token::LiteralChar(c)
+        | Token::LiteralLChar(c)
+        | Token::LiteralUChar(c)
+        | Token::LiteralUUChar(c)
+        | Token::LiteralU8Char(c)
+        | Token::LiteralUserDefined(UdLiteral::Char(c), _)
+        | Token::LiteralUserDefined(UdLiteral::LChar(c), _)
+        | Token::LiteralUserDefined(UdLiteral::UChar(c), _)
+        | Token::LiteralUserDefined(UdLiteral::UUChar(c), _)
+        | Token::LiteralUserDefined(UdLiteral::U8Char(c), _) => Some(*c),
+        _ => None,
+    }
+}
+
+/// Get the JSON value of a token: its kind, its spelling and its span
+///
+/// The char literals have also their value and the decoded char
+/// (null when the value isn't a valid char, e.g. a multicharacter literal)
+pub fn token_to_json(tok: &Token, span: &Span) -> Value {
+    let mut entry = Map::new();
+    entry.insert("kind".to_string(), Value::String(get_kind(tok).to_string()));
+    entry.insert("spelling".to_string(), Value::String(tok.to_string()));
+    entry.insert("span".to_string(), json!(span));
+    if let Some(c) = get_char_value(tok) {
+        entry.insert("value".to_string(), json!(c));
+        entry.insert(
+            "char".to_string(),
+            json!(std::char::from_u32(c).map(|c| c.to_string())),
+        );
+    }
+    Value::Object(entry)
+}
+
+/// Get the JSON array of the tokens
+pub fn tokens_to_json(tokens: &[(Token, Span)]) -> Value {
+    Value::Array(
+        tokens
+            .iter()
+            .map(|(tok, span)| token_to_json(tok, span))
+            .collect(),
+    )
+}
+
+/// Preprocess a source and get the resulting tokens as a JSON string
+pub fn preprocess_to_json(src: &[u8], ctx: &mut PContext) -> Result<String, PreprocError> {
+    let tokens = preprocess(src, ctx)?;
+    Ok(tokens_to_json(&tokens).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_preprocess_to_json() {
+        let mut ctx = PContext::default();
+        let json = preprocess_to_json(b"#define C 'a'\nx = C;\nu'\\u00e9'", &mut ctx).unwrap();
+        let value: Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(
+            value,
+            json!([
+                {
+                    "kind": "Identifier",
+                    "spelling": "x",
+                    "span": { "start": 14, "end": 15, "line": 2, "col": 1 },
+                },
+                {
+                    "kind": "Equal",
+                    "spelling": "=",
+                    "span": { "start": 16, "end": 17, "line": 2, "col": 3 },
+                },
+                {
+                    "kind": "LiteralChar",
+                    "spelling": "'a'",
+                    "span": { "start": 18, "end": 19, "line": 2, "col": 5 },
+                    "value": 97,
+                    "char": "a",
+                },
+                {
+                    "kind": "SemiColon",
+                    "spelling": ";",
+                    "span": { "start": 19, "end": 20, "line": 2, "col": 6 },
+                },
+                {
+                    "kind": "LiteralUChar",
+                    "spelling": "u'\\xe9'",
+                    "span": { "start": 21, "end": 30, "line": 3, "col": 1 },
+                    "value": 233,
+                    "char": "é",
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_multichar_to_json() {
+        let span = Span::default();
+        assert_eq!(
+            token_to_json(&Token::LiteralChar(0xD800), &span),
+            json!({
+                "kind": "LiteralChar",
+                "spelling": "'\\xd8\\x0'",
+                "span": { "start": 0, "end": 0, "line": 0, "col": 0 },
+                "value": 0xD800,
+                "char": null,
+            })
+        );
+    }
+
+    #[test]
+    fn test_kind() {
+        let comment = Token::Comment {
+            block: true,
+            text: b"/* a */".into(),
+        };
+        let directive = Token::Directive {
+            kind: "ident".into(),
+            text: b"#ident \"a\"".into(),
+        };
+        assert_eq!(get_kind(&comment), "Comment");
+        assert_eq!(get_kind(&directive), "Directive");
+        assert_eq!(get_kind(&Token::Identifier("a".into())), "Identifier");
+        assert_eq!(get_kind(&Token::Eol), "Eol");
+    }
+}
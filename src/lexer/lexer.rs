@@ -1,10 +1,16 @@
 use bitflags::bitflags;
 use phf::phf_map;
+use std::borrow::Cow;
 
+use super::errors::{Diagnostic, PreprocError};
+use super::include::{IncludeFrame, IncludeResolver};
 use super::number::get_decimal;
-use super::pmacros::PContext;
-use super::preprocessor::IncludeType;
+use super::pmacros::{MacroOutput, PContext};
+use super::preprocessor::{DirectiveHandler, IncludeType};
+use super::sources::Sources;
+use super::span::{advance_column, ColumnCache, Position, Span};
 use super::string::StringType;
+use super::translation::SourceMap;
 
 bitflags! {
     struct Kind: u8 {
@@ -71,17 +77,21 @@ const CHARS: [Kind; 256] = [
 static PREPROC_KEYWORDS: phf::Map<&'static str, Token<'_>> = phf_map! {
     "define" => Token::PreprocDefine,
     "elif" => Token::PreprocElif,
+    "elifdef" => Token::PreprocElifdef,
+    "elifndef" => Token::PreprocElifndef,
     "else" => Token::PreprocElse,
     "endif" => Token::PreprocEndif,
     "error" => Token::PreprocError,
     "if" => Token::PreprocIf,
     "ifdef" => Token::PreprocIfdef,
     "ifndef" => Token::PreprocIfndef,
+    "import" => Token::PreprocImport2,
     "include" => Token::PreprocInclude2,
     "include_next" => Token::PreprocIncludeNext2,
     "line" => Token::PreprocLine,
     "pragma" => Token::PreprocPragma,
     "undef" => Token::PreprocUndef,
+    "warning" => Token::PreprocWarning,
 };
 
 static CPP_KEYWORDS: phf::Map<&'static str, Token<'_>> = phf_map! {
@@ -98,12 +108,19 @@ static CPP_KEYWORDS: phf::Map<&'static str, Token<'_>> = phf_map! {
     "case" => Token::Case,
     "catch" => Token::Catch,
     "char" => Token::Char,
+    "char8_t" => Token::Char8,
     "char16_t" => Token::Char16,
     "char32_t" => Token::Char32,
     "class" => Token::Class,
+    "co_await" => Token::CoAwait,
+    "co_return" => Token::CoReturn,
+    "co_yield" => Token::CoYield,
     "compl" => Token::Compl,
+    "concept" => Token::Concept,
     "const" => Token::Const,
+    "consteval" => Token::Consteval,
     "constexpr" => Token::Constexpr,
+    "constinit" => Token::Constinit,
     "const_cast" => Token::ConstCast,
     "continue" => Token::Continue,
     "decltype" => Token::Decltype,
@@ -113,13 +130,11 @@ static CPP_KEYWORDS: phf::Map<&'static str, Token<'_>> = phf_map! {
     "double" => Token::Double,
     "dynamic_cast" => Token::DynamicCast,
     "else" => Token::Else,
-    "endif" => Token::Endif,
     "enum" => Token::Enum,
     "explicit" => Token::Explicit,
     "export" => Token::Export,
     "extern" => Token::Extern,
     "false" => Token::False,
-    "final" => Token::Final,
     "float" => Token::Float,
     "for" => Token::For,
     "friend" => Token::Friend,
@@ -138,12 +153,12 @@ static CPP_KEYWORDS: phf::Map<&'static str, Token<'_>> = phf_map! {
     "operator" => Token::Operator,
     "or" => Token::OrKw,
     "or_eq" => Token::OrEq,
-    "override" => Token::Override,
     "private" => Token::Private,
     "protected" => Token::Protected,
     "public" => Token::Public,
     "register" => Token::Register,
     "reinterpret_cast" => Token::ReinterpretCast,
+    "requires" => Token::Requires,
     "restrict" => Token::Restrict,
     "return" => Token::Return,
     "short" => Token::Short,
@@ -175,12 +190,35 @@ static CPP_KEYWORDS: phf::Map<&'static str, Token<'_>> = phf_map! {
     "xor_eq" => Token::XorEq,
 };
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+/// A token produced by the [`Lexer`]
+///
+/// Punctuators and keywords have their own variant (e.g. `PlusPlus` for `++`
+/// or `Constexpr` for `constexpr`), alternative tokens such as `and` or `bitor`
+/// are the variants ending with `Kw` or named after them.
+/// The identifiers with a special meaning in some contexts (`final`, `override`,
+/// `import` and `module`) are identifiers, except `import` and `module` starting
+/// a module declaration or an import when [`LexerOptions::modules`] is set.
+///
+/// The payloads of the identifiers and the literals aren't copied when the token is read
+/// in the source: they borrow it. The tokens from a macro expansion or an included file
+/// own their payloads so a token never borrows the lexer.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Token<'a> {
+    /// Nothing (e.g. a consumed preprocessor directive)
     None,
+    /// End of the input
     Eof,
+    /// End of a line
     Eol,
-    Comment(&'a [u8]),
+    /// A comment, only returned when LexerOptions::comments is set
+    Comment {
+        /// true for a /* ... */ comment and false for a // one
+        block: bool,
+        /// The content of the comment (without the delimiters)
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        text: Cow<'a, [u8]>,
+    },
     Not,
     NotEqual,
     Dollar,
@@ -189,6 +227,7 @@ pub enum Token<'a> {
     AndAnd,
     And,
     AndEqual,
+    /// 'c', L'c', U'c', u'c' and u8'c' with the value of the character
     LiteralChar(u32),
     LiteralLChar(u32),
     LiteralUUChar(u32),
@@ -209,10 +248,14 @@ pub enum Token<'a> {
     MinusEqual,
     Arrow,
     ArrowStar,
+    /// Floating point numbers: the suffix (f or l) is given by the variant
     LiteralDecimal(f64),
+    LiteralFloat(f64),
+    LiteralLongDouble(f64),
     Dot,
     DotStar,
     Ellipsis,
+    /// Integers: the suffix (u, l, ul, ...) is given by the variant
     LiteralHex(u64),
     LiteralBin(u64),
     LiteralOct(u64),
@@ -222,16 +265,52 @@ pub enum Token<'a> {
     LiteralLongLong(u64),
     LiteralULong(u64),
     LiteralULongLong(u64),
-    LiteralString(&'a [u8]),
-    LiteralLString(&'a [u8]),
-    LiteralUString(&'a [u8]),
-    LiteralUUString(&'a [u8]),
-    LiteralU8String(&'a [u8]),
-    LiteralRString(&'a [u8]),
-    LiteralLRString(&'a [u8]),
-    LiteralURString(&'a [u8]),
-    LiteralUURString(&'a [u8]),
-    LiteralU8RString(&'a [u8]),
+    LiteralSignedSize(u64),
+    LiteralSize(u64),
+    /// The content of a string literal (without the quotes nor the prefix)
+    LiteralString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    LiteralLString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    LiteralUString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    LiteralUUString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    /// The content of a u8 string (an array of char8_t in C++20): it's valid UTF-8
+    /// and get_u8_string gives its code units
+    LiteralU8String(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    /// Raw strings: the content is between the delimiters R"delim( and )delim"
+    LiteralRString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    LiteralLRString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    LiteralURString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    LiteralUURString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    LiteralU8RString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
     ColonColon,
     Colon,
     SemiColon,
@@ -248,12 +327,21 @@ pub enum Token<'a> {
     RightShiftEqual,
     Question,
     At,
-    Identifier(&'a str),
+    /// A literal followed by a ud-suffix (e.g. 42_km or "abc"_s)
+    LiteralUserDefined(
+        #[cfg_attr(feature = "serde", serde(borrow))] UdLiteral<'a>,
+        #[cfg_attr(feature = "serde", serde(borrow))] Cow<'a, str>,
+    ),
+    /// An identifier which isn't a keyword nor a macro
+    Identifier(#[cfg_attr(feature = "serde", serde(borrow))] Cow<'a, str>),
+    /// A malformed token (a diagnostic is emitted)
+    Invalid(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
     LeftBrack,
-    DoubleLeftBrack,
     Backslash,
     RightBrack,
-    DoubleRightBrack,
     Xor,
     XorEqual,
     LeftBrace,
@@ -275,12 +363,19 @@ pub enum Token<'a> {
     Case,
     Catch,
     Char,
+    Char8,
     Char16,
     Char32,
     Class,
+    CoAwait,
+    CoReturn,
+    CoYield,
     Compl,
+    Concept,
     Const,
+    Consteval,
     Constexpr,
+    Constinit,
     ConstCast,
     Continue,
     Decltype,
@@ -290,21 +385,21 @@ pub enum Token<'a> {
     Double,
     DynamicCast,
     Else,
-    Endif,
     Enum,
     Explicit,
     Export,
     Extern,
     False,
-    Final,
     Float,
     For,
     Friend,
     Goto,
     If,
+    Import,
     Inline,
     Int,
     Long,
+    Module,
     Mutable,
     Namespace,
     New,
@@ -315,12 +410,12 @@ pub enum Token<'a> {
     Operator,
     OrKw,
     OrEq,
-    Override,
     Private,
     Protected,
     Public,
     Register,
     ReinterpretCast,
+    Requires,
     Restrict,
     Return,
     Short,
@@ -350,35 +445,553 @@ pub enum Token<'a> {
     While,
     XorKw,
     XorEq,
+    /// # when the preprocessor is disabled
+    Hash,
+    /// ## when the preprocessor is disabled
+    HashHash,
+    /// Directives: most of them are consumed by the preprocessor
     PreprocIf,
     PreprocDefine,
     PreprocElif,
+    PreprocElifdef,
+    PreprocElifndef,
     PreprocElse,
     PreprocEndif,
     PreprocError,
     PreprocIfdef,
     PreprocIfndef,
-    PreprocInclude(IncludeType<'a>),
-    PreprocIncludeNext(IncludeType<'a>),
+    PreprocInclude(#[cfg_attr(feature = "serde", serde(borrow))] IncludeType<'a>),
+    PreprocIncludeNext(#[cfg_attr(feature = "serde", serde(borrow))] IncludeType<'a>),
+    /// #import (Objective-C++ and MSVC): an #include of a file included only once
+    PreprocImport(#[cfg_attr(feature = "serde", serde(borrow))] IncludeType<'a>),
+    /// #include, #include_next and #import before the header name is read
     PreprocInclude2,
     PreprocIncludeNext2,
+    PreprocImport2,
     PreprocLine,
     PreprocPragma,
     PreprocUndef,
+    PreprocWarning,
+    /// A directive as written, only returned when LexerOptions::directives is set:
+    /// the directive has been handled by the preprocessor
+    Directive {
+        /// The name of the directive (e.g. define), empty for the null directive
+        #[cfg_attr(feature = "serde", serde(borrow))]
+        kind: Cow<'a, str>,
+        /// The whole line of the directive from the # (without the newline)
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        text: Cow<'a, [u8]>,
+    },
+    /// A pragma not handled by the preprocessor with its text as written
+    /// (e.g. GCC diagnostic push for #pragma GCC diagnostic push)
+    Pragma(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+}
+
+impl<'a> Token<'a> {
+    /// Get the operator spelled by an alternative token (e.g. && for and)
+    /// or the token itself
+    pub fn to_operator(self) -> Self {
+        match self {
+            Token::AndKw => Token::AndAnd,
+            Token::AndEq => Token::AndEqual,
+            Token::BitAnd => Token::And,
+            Token::BitOr => Token::Or,
+            Token::Compl => Token::Tilde,
+            Token::NotKw => Token::Not,
+            Token::NotEq => Token::NotEqual,
+            Token::OrKw => Token::OrOr,
+            Token::OrEq => Token::OrEqual,
+            Token::XorKw => Token::Xor,
+            Token::XorEq => Token::XorEqual,
+            tok => tok,
+        }
+    }
+
+    /// Copy the payload when it's borrowed from a buffer matching is_temporary
+    pub(crate) fn own_payload(&mut self, is_temporary: impl Fn(&[u8]) -> bool) {
+        match self {
+            Token::Comment { text, .. }
+            | Token::LiteralString(text)
+            | Token::LiteralLString(text)
+            | Token::LiteralUString(text)
+            | Token::LiteralUUString(text)
+            | Token::LiteralU8String(text)
+            | Token::LiteralRString(text)
+            | Token::LiteralLRString(text)
+            | Token::LiteralURString(text)
+            | Token::LiteralUURString(text)
+            | Token::LiteralU8RString(text)
+            | Token::Invalid(text)
+            | Token::Pragma(text) => own_bytes(text, &is_temporary),
+            Token::LiteralUserDefined(literal, suffix) => {
+                literal.own_payload(&is_temporary);
+                own_str(suffix, &is_temporary);
+            }
+            Token::Identifier(id) => own_str(id, &is_temporary),
+            Token::PreprocInclude(include)
+            | Token::PreprocIncludeNext(include)
+            | Token::PreprocImport(include) => include.own_payload(&is_temporary),
+            Token::Directive { kind, text } => {
+                own_str(kind, &is_temporary);
+                own_bytes(text, &is_temporary);
+            }
+            _ => {}
+        }
+    }
+}
+
+pub(crate) fn own_bytes(bytes: &mut Cow<[u8]>, is_temporary: impl Fn(&[u8]) -> bool) {
+    if let Cow::Borrowed(b) = *bytes {
+        if is_temporary(b) {
+            *bytes = Cow::Owned(b.to_vec());
+        }
+    }
+}
+
+fn own_str(s: &mut Cow<str>, is_temporary: impl Fn(&[u8]) -> bool) {
+    if let Cow::Borrowed(b) = *s {
+        if is_temporary(b.as_bytes()) {
+            *s = Cow::Owned(b.to_string());
+        }
+    }
+}
+
+/// The literal part of a user-defined literal
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UdLiteral<'a> {
+    Int(u64),
+    Decimal(f64),
+    Char(u32),
+    LChar(u32),
+    UChar(u32),
+    UUChar(u32),
+    U8Char(u32),
+    String(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    LString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    UString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    UUString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    U8String(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    RString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    LRString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    URString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    UURString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    U8RString(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+}
+
+impl<'a> UdLiteral<'a> {
+    fn from_token(tok: &Token<'a>) -> Option<Self> {
+        Some(match *tok {
+            Token::LiteralInt(x)
+            | Token::LiteralHex(x)
+            | Token::LiteralOct(x)
+            | Token::LiteralBin(x) => UdLiteral::Int(x),
+            Token::LiteralDecimal(x) => UdLiteral::Decimal(x),
+            Token::LiteralChar(x) => UdLiteral::Char(x),
+            Token::LiteralLChar(x) => UdLiteral::LChar(x),
+            Token::LiteralUChar(x) => UdLiteral::UChar(x),
+            Token::LiteralUUChar(x) => UdLiteral::UUChar(x),
+            Token::LiteralU8Char(x) => UdLiteral::U8Char(x),
+            Token::LiteralString(ref x) => UdLiteral::String(x.clone()),
+            Token::LiteralLString(ref x) => UdLiteral::LString(x.clone()),
+            Token::LiteralUString(ref x) => UdLiteral::UString(x.clone()),
+            Token::LiteralUUString(ref x) => UdLiteral::UUString(x.clone()),
+            Token::LiteralU8String(ref x) => UdLiteral::U8String(x.clone()),
+            Token::LiteralRString(ref x) => UdLiteral::RString(x.clone()),
+            Token::LiteralLRString(ref x) => UdLiteral::LRString(x.clone()),
+            Token::LiteralURString(ref x) => UdLiteral::URString(x.clone()),
+            Token::LiteralUURString(ref x) => UdLiteral::UURString(x.clone()),
+            Token::LiteralU8RString(ref x) => UdLiteral::U8RString(x.clone()),
+            _ => return None,
+        })
+    }
+
+    fn is_number(&self) -> bool {
+        matches!(self, UdLiteral::Int(_) | UdLiteral::Decimal(_))
+    }
+
+    fn own_payload(&mut self, is_temporary: impl Fn(&[u8]) -> bool) {
+        match self {
+            UdLiteral::String(text)
+            | UdLiteral::LString(text)
+            | UdLiteral::UString(text)
+            | UdLiteral::UUString(text)
+            | UdLiteral::U8String(text)
+            | UdLiteral::RString(text)
+            | UdLiteral::LRString(text)
+            | UdLiteral::URString(text)
+            | UdLiteral::UURString(text)
+            | UdLiteral::U8RString(text) => own_bytes(text, is_temporary),
+            _ => {}
+        }
+    }
+}
+
+/// The language of the source and the revision of its standard
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Language {
+    C89,
+    C99,
+    C11,
+    C23,
+    Cpp14,
+    Cpp17,
+    Cpp20,
+    Cpp23,
+}
+
+impl Language {
+    pub fn is_cpp(self) -> bool {
+        self >= Language::Cpp14
+    }
+
+    /// // comments came with C99
+    pub(crate) fn has_line_comments(self) -> bool {
+        self != Language::C89
+    }
+
+    /// u"...", U"..." and u8"..." came with C11
+    pub(crate) fn has_unicode_strings(self) -> bool {
+        self >= Language::C11
+    }
+
+    /// u8'c' came with C++17 and C23
+    pub(crate) fn has_u8_chars(self) -> bool {
+        self >= Language::Cpp17 || self == Language::C23
+    }
+
+    /// The modules came with C++20
+    pub(crate) fn has_modules(self) -> bool {
+        self >= Language::Cpp20
+    }
+
+    /// #elifdef and #elifndef came with C++23 and C23
+    pub(crate) fn has_elifdef(self) -> bool {
+        self == Language::Cpp23 || self == Language::C23
+    }
+
+    /// The value of __cplusplus (None for C)
+    pub fn cplusplus(self) -> Option<&'static str> {
+        match self {
+            Language::Cpp14 => Some("201402L"),
+            Language::Cpp17 => Some("201703L"),
+            Language::Cpp20 => Some("202002L"),
+            Language::Cpp23 => Some("202302L"),
+            _ => None,
+        }
+    }
+
+    /// Check that a keyword of CPP_KEYWORDS is a keyword in this language:
+    /// the C++ ones (e.g. class or the alternative tokens) are identifiers in C
+    fn has_keyword(self, keyword: &Token) -> bool {
+        if self.is_cpp() {
+            return true;
+        }
+        let since = match keyword {
+            Token::Auto
+            | Token::Break
+            | Token::Case
+            | Token::Char
+            | Token::Const
+            | Token::Continue
+            | Token::Default
+            | Token::Do
+            | Token::Double
+            | Token::Else
+            | Token::Enum
+            | Token::Extern
+            | Token::Float
+            | Token::For
+            | Token::Goto
+            | Token::If
+            | Token::Int
+            | Token::Long
+            | Token::Register
+            | Token::Return
+            | Token::Short
+            | Token::Signed
+            | Token::Sizeof
+            | Token::Static
+            | Token::Struct
+            | Token::Switch
+            | Token::Typedef
+            | Token::Union
+            | Token::Unsigned
+            | Token::Void
+            | Token::Volatile
+            | Token::While => Language::C89,
+            Token::Inline | Token::Restrict => Language::C99,
+            Token::Alignas
+            | Token::Alignof
+            | Token::Bool
+            | Token::Constexpr
+            | Token::False
+            | Token::Nullptr
+            | Token::StaticAssert
+            | Token::ThreadLocal
+            | Token::True => Language::C23,
+            _ => return false,
+        };
+        self >= since
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LexerOptions {
+    /// When false, the directives and the macros aren't handled
+    /// and the # and ## are returned as Token::Hash and Token::HashHash
+    pub preprocessor: bool,
+    /// The width used to compute the column after a tab
+    pub tab_width: u32,
+    /// Replace the trigraphs (e.g. ??= by #) before lexing
+    pub trigraphs: bool,
+    /// Lex the alternative tokens (e.g. and, bitor) as the operators they spell
+    /// (e.g. && and |) instead of Token::AndKw, Token::BitOr, ...
+    pub alternative_operators: bool,
+    /// Return the comments as Token::Comment instead of skipping them
+    pub comments: bool,
+    /// The tokens and the directives depend on the language (e.g. <=> is a token in C++20)
+    pub language: Language,
+    /// Return the directives as Token::Directive once they've been handled
+    /// (the directives in the groups skipped by the conditionals aren't returned)
+    pub directives: bool,
+    /// Lex `import` and `module` as Token::Import and Token::Module when they start
+    /// a line (possibly after `export`) as a module declaration or an import (from C++20)
+    pub modules: bool,
+    /// Return a NUL byte outside of the literals and the comments as Token::Invalid
+    /// with an error (by default, it's a white space)
+    pub nul_error: bool,
+}
+
+impl Default for LexerOptions {
+    fn default() -> Self {
+        Self {
+            preprocessor: true,
+            tab_width: 8,
+            trigraphs: false,
+            alternative_operators: false,
+            comments: false,
+            language: Language::Cpp23,
+            directives: false,
+            modules: false,
+            nul_error: false,
+        }
+    }
+}
+
+/// Build a lexer with some options, a context, a file name and an include resolver
+///
+/// ```
+/// use cpp_parser::lexer::{Language, LexerBuilder};
+///
+/// let mut lexer = LexerBuilder::new()
+///     .tab_width(4)
+///     .comments(true)
+///     .language(Language::C11)
+///     .file("main.c")
+///     .build(b"int a; // comment");
+/// ```
+#[derive(Default)]
+pub struct LexerBuilder<'a> {
+    options: LexerOptions,
+    sources: Option<&'a Sources>,
+    context: Option<PContext>,
+    file: Option<String>,
+    resolver: Option<Box<dyn IncludeResolver>>,
+    directive_handler: Option<Box<dyn DirectiveHandler>>,
+}
+
+impl<'a> LexerBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set all the options at once
+    pub fn options(mut self, options: LexerOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// See [`LexerOptions::preprocessor`]
+    pub fn preprocessor(mut self, preprocessor: bool) -> Self {
+        self.options.preprocessor = preprocessor;
+        self
+    }
+
+    /// See [`LexerOptions::tab_width`]
+    pub fn tab_width(mut self, tab_width: u32) -> Self {
+        self.options.tab_width = tab_width;
+        self
+    }
+
+    /// See [`LexerOptions::trigraphs`]
+    pub fn trigraphs(mut self, trigraphs: bool) -> Self {
+        self.options.trigraphs = trigraphs;
+        self
+    }
+
+    /// See [`LexerOptions::alternative_operators`]
+    pub fn alternative_operators(mut self, alternative_operators: bool) -> Self {
+        self.options.alternative_operators = alternative_operators;
+        self
+    }
+
+    /// See [`LexerOptions::comments`]
+    pub fn comments(mut self, comments: bool) -> Self {
+        self.options.comments = comments;
+        self
+    }
+
+    /// See [`LexerOptions::modules`]
+    pub fn modules(mut self, modules: bool) -> Self {
+        self.options.modules = modules;
+        self
+    }
+
+    /// See [`LexerOptions::nul_error`]
+    pub fn nul_error(mut self, nul_error: bool) -> Self {
+        self.options.nul_error = nul_error;
+        self
+    }
+
+    /// See [`LexerOptions::directives`]
+    pub fn directives(mut self, directives: bool) -> Self {
+        self.options.directives = directives;
+        self
+    }
+
+    /// See [`LexerOptions::language`]
+    pub fn language(mut self, language: Language) -> Self {
+        self.options.language = language;
+        self
+    }
+
+    /// Start with the macros and the state of a context (e.g. with some predefined macros)
+    pub fn context(mut self, context: PContext) -> Self {
+        self.context = Some(context);
+        self
+    }
+
+    /// Set the name of the main file (used by __FILE__ and in the diagnostics)
+    pub fn file(mut self, file: &str) -> Self {
+        self.file = Some(file.to_string());
+        self
+    }
+
+    /// Set the resolver used to find the included files
+    pub fn resolver(mut self, resolver: Box<dyn IncludeResolver>) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// Set the handler of the unknown directives
+    pub fn directive_handler(mut self, handler: Box<dyn DirectiveHandler>) -> Self {
+        self.directive_handler = Some(handler);
+        self
+    }
+
+    /// Store the buffers made by the lexer (e.g. the included files) in sources:
+    /// the tokens read there borrow them instead of owning their payloads
+    pub fn sources(mut self, sources: &'a Sources) -> Self {
+        self.sources = Some(sources);
+        self
+    }
+
+    /// Create the lexer on a source
+    pub fn build(self, buf: &'a [u8]) -> Lexer<'a> {
+        let mut lexer = Lexer::with_sources(buf, self.options, self.sources);
+        if let Some(context) = self.context {
+            lexer.context = context;
+            lexer.define_language_macros();
+        }
+        if let Some(file) = self.file {
+            lexer.set_file(&file);
+        }
+        if let Some(resolver) = self.resolver {
+            lexer.set_resolver(resolver);
+        }
+        if let Some(handler) = self.directive_handler {
+            lexer.set_directive_handler(handler);
+        }
+        lexer
+    }
 }
 
 pub struct Lexer<'a> {
-    pub preproc_buf: Vec<u8>,
-    pub preproc_use: bool,
-    pub sbuf: &'a [u8],
-    pub slen: usize,
-    pub spos: usize,
-    pub buf: &'a [u8],
-    pub len: usize,
+    pub(crate) preproc_buf: Vec<u8>,
+    pub(crate) preproc_use: bool,
+    pub(crate) sbuf: &'a [u8],
+    pub(crate) slen: usize,
+    pub(crate) spos: usize,
+    pub(crate) buf: &'a [u8],
+    pub(crate) len: usize,
     pub pos: usize,
     pub line: usize,
     pub lpos: usize,
     pub(crate) context: PContext,
+    pub(crate) errors: Vec<PreprocError>,
+    pub(crate) include_stack: Vec<IncludeFrame<'a>>,
+    // the buffers owned by the lexer (included files, translated sources)
+    // when they can't be stored in sources
+    pub(crate) include_bufs: Vec<Vec<u8>>,
+    pub(crate) sources: Option<&'a Sources>,
+    pub(crate) resolver: Option<Box<dyn IncludeResolver>>,
+    pub(crate) directive_handler: Option<Box<dyn DirectiveHandler>>,
+    pub(crate) options: LexerOptions,
+    pub(crate) start: Position,
+    pub(crate) expansion_start: Position,
+    // the offsets in buf of the painted identifiers when buf is the output
+    // of an expansion being rescanned (see MacroOutput)
+    pub(crate) painted: Vec<usize>,
+    // the offsets in buf of the separating spaces of an expansion being rescanned
+    pub(crate) separators: Vec<usize>,
+    // the column of the name of the directive being parsed
+    pub(crate) directive_column: usize,
+    // true while a directive is parsed: the unterminated conditionals are checked
+    // once the directive is done
+    pub(crate) in_directive: bool,
+    pub(crate) col_cache: ColumnCache,
+    pub(crate) source_map: SourceMap,
+    // the lines skipped by the conditionals in the main source
+    pub(crate) inactive_ranges: Vec<Span>,
+    // the span of the last Token::Directive (its end isn't the current position)
+    pub(crate) directive_span: Span,
+    // the directive ending a skipped group (with its end in the buffer):
+    // returned after the one starting the group
+    pub(crate) pending_directive: Option<(Token<'a>, Span, usize)>,
 }
 
 macro_rules! get_operator {
@@ -431,7 +1044,40 @@ macro_rules! get_basic_operator {
 }
 
 impl<'a> Lexer<'a> {
+    /// Create a lexer with the default options (see [`LexerBuilder`] to change them)
     pub fn new(buf: &'a [u8]) -> Self {
+        Self::with_options(buf, LexerOptions::default())
+    }
+
+    pub fn with_options(buf: &'a [u8], options: LexerOptions) -> Self {
+        Self::with_sources(buf, options, None)
+    }
+
+    fn with_sources(buf: &'a [u8], options: LexerOptions, sources: Option<&'a Sources>) -> Self {
+        let mut lexer = Self::new_translated(buf, options);
+        lexer.sources = sources;
+        lexer.translate_buffer();
+        lexer.define_language_macros();
+        lexer
+    }
+
+    /// Define the macros given by the language (e.g. __cplusplus)
+    /// unless the context already has them
+    fn define_language_macros(&mut self) {
+        if !self.options.preprocessor {
+            return;
+        }
+        if let Some(version) = self.options.language.cplusplus() {
+            if self.context.get("__cplusplus").is_none() {
+                self.context
+                    .define_from_str(&format!("__cplusplus={}", version));
+            }
+        }
+    }
+
+    /// Create a lexer on a buffer which has already been through the translation phases
+    /// (e.g. the result of a macro expansion)
+    pub(crate) fn new_translated(buf: &'a [u8], options: LexerOptions) -> Self {
         Self {
             preproc_buf: Vec::new(),
             preproc_use: false,
@@ -444,7 +1090,76 @@ impl<'a> Lexer<'a> {
             line: 1,
             lpos: 0,
             context: PContext::default(),
+            errors: Vec::new(),
+            include_stack: Vec::new(),
+            include_bufs: Vec::new(),
+            sources: None,
+            resolver: None,
+            directive_handler: None,
+            options,
+            start: Position::default(),
+            expansion_start: Position::default(),
+            painted: Vec::new(),
+            separators: Vec::new(),
+            directive_column: 0,
+            in_directive: false,
+            col_cache: ColumnCache::default(),
+            source_map: SourceMap::default(),
+            inactive_ranges: Vec::new(),
+            directive_span: Span::default(),
+            pending_directive: None,
+        }
+    }
+
+    /// Create a lexer to rescan the output of an expansion
+    pub(crate) fn new_rescan(output: &'a MacroOutput) -> Self {
+        let mut lexer = Self::new_translated(output, LexerOptions::default());
+        lexer.painted = output.painted().to_vec();
+        lexer.separators = output.separators().to_vec();
+        lexer
+    }
+
+    /// Check if the white at pos is only a space separating two tokens
+    /// in the expansion being rescanned (see MacroOutput)
+    pub(crate) fn is_separator(&self, pos: usize) -> bool {
+        !self.separators.is_empty() && self.separators.binary_search(&pos).is_ok()
+    }
+
+    /// Check if the identifier id read in the buffer is painted
+    pub(crate) fn is_painted(&self, id: &str) -> bool {
+        if self.painted.is_empty() {
+            return false;
         }
+        let pos = id.as_ptr() as usize - self.buf.as_ptr() as usize;
+        self.painted.binary_search(&pos).is_ok()
+    }
+
+    pub fn with_context(buf: &'a [u8], context: PContext) -> Self {
+        let mut lexer = Self::new(buf);
+        lexer.context = context;
+        lexer.define_language_macros();
+        lexer
+    }
+
+    pub fn set_file(&mut self, file: &str) {
+        self.context.set_file(file);
+    }
+
+    /// The errors found while preprocessing (e.g. #error directives)
+    pub fn errors(&self) -> &[PreprocError] {
+        &self.errors
+    }
+
+    /// Get the diagnostics collected so far and clear them
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        self.context.take_diagnostics()
+    }
+
+    /// Get the ranges of the main source skipped by the conditionals so far and clear them:
+    /// a range starts at the beginning of the first skipped line and ends at the beginning
+    /// of the line of the directive ending the skipped group (e.g. the #else or the #endif)
+    pub fn take_inactive_ranges(&mut self) -> Vec<Span> {
+        std::mem::take(&mut self.inactive_ranges)
     }
 
     pub fn reset(&mut self) {
@@ -461,21 +1176,38 @@ impl<'a> Lexer<'a> {
 
     pub fn consume_tokens(&mut self, n: usize) {
         for _ in 0..n {
-            self.next();
+            self.get_token();
         }
     }
 
+    /// Must be called when pos is just after the '\n'
     pub(crate) fn add_new_line(&mut self) {
         self.line += 1;
-        self.lpos = self.pos + 1;
+        if !self.source_map.is_empty() && !self.preproc_use {
+            // the spliced lines are lines too
+            self.line += self.source_map.splices_between(self.lpos, self.pos);
+        }
+        self.lpos = self.pos;
+    }
+
+    /// Get the physical line and its start for the current position
+    #[inline(always)]
+    pub(crate) fn get_line_start(&self) -> (usize, usize) {
+        if self.source_map.is_empty() || self.preproc_use {
+            (self.line, self.lpos)
+        } else {
+            self.source_map
+                .get_line_start(self.line, self.lpos, self.pos)
+        }
     }
 
     pub(crate) fn get_line(&self) -> usize {
-        self.line
+        self.get_line_start().0
     }
 
     pub(crate) fn get_column(&self) -> usize {
-        self.pos - self.lpos + 1
+        let lpos = self.get_line_start().1;
+        advance_column(self.buf, lpos, self.pos, 1, self.get_tab_width())
     }
 
     #[inline(always)]
@@ -499,6 +1231,7 @@ impl<'a> Lexer<'a> {
         self.pos = 0;
         self.sbuf = self.buf;
         self.preproc_use = true;
+        self.expansion_start = self.start;
 
         // TODO:
         // Probably not nice... but the preproc_buf will die with the Lexer and won't change when parsed
@@ -523,12 +1256,53 @@ impl<'a> Lexer<'a> {
 
     pub(crate) fn get_identifier(&mut self) -> Option<Token<'a>> {
         let id = self.get_identifier_str();
-        if !self.preproc_use && self.macro_eval(id) {
+        if self.options.preprocessor && id == "_Pragma" {
+            if let Some(tok) = self.get_pragma_operator() {
+                // a handled pragma gives nothing
+                return if tok == Token::None { None } else { Some(tok) };
+            }
+        }
+        if self.options.preprocessor && !self.preproc_use && self.macro_eval(id) {
             self.swap_buffers();
             None
         } else {
-            Some(Token::Identifier(id))
+            Some(Token::Identifier(id.into()))
+        }
+    }
+
+    /// Get the ud-suffix following a literal if any
+    /// (the suffixes which don't start with _ are reserved for the standard library)
+    pub(crate) fn get_user_defined(&mut self, tok: Token<'a>) -> Token<'a> {
+        if self.pos >= self.len {
+            return tok;
+        }
+
+        let c = self.next_char(0);
+        if c != b'_' && !c.is_ascii_alphabetic() {
+            return tok;
+        }
+
+        let literal = if let Some(literal) = UdLiteral::from_token(&tok) {
+            literal
+        } else {
+            return tok;
+        };
+
+        let spos = self.pos;
+        self.pos += 1;
+        let suffix = self.get_identifier_str();
+
+        if c != b'_'
+            && !literal.is_number()
+            && self.options.preprocessor
+            && self.context.defined(suffix)
+        {
+            // "%"PRId64: as gcc does, a macro after a string isn't a suffix
+            self.pos = spos;
+            return tok;
         }
+
+        Token::LiteralUserDefined(literal, suffix.into())
     }
 
     pub(crate) fn get_identifier_str(&mut self) -> &'a str {
@@ -551,6 +1325,10 @@ impl<'a> Lexer<'a> {
 
     pub(crate) fn get_preproc_keyword(&mut self, eval: bool) -> Token<'a> {
         let spos = self.pos;
+        if eval {
+            // the name can be split by a line continuation
+            self.directive_column = self.get_column();
+        }
         loop {
             if self.pos < self.len {
                 let c = self.next_char(0);
@@ -565,14 +1343,21 @@ impl<'a> Lexer<'a> {
         }
 
         let id = unsafe { std::str::from_utf8_unchecked(&self.buf.get_unchecked(spos..self.pos)) };
-        if let Some(keyword) = PREPROC_KEYWORDS.get(id) {
+        let keyword = PREPROC_KEYWORDS.get(id).filter(|keyword| {
+            self.options.language.has_elifdef()
+                || !matches!(keyword, Token::PreprocElifdef | Token::PreprocElifndef)
+        });
+        if let Some(keyword) = keyword {
             if eval {
-                self.preproc_parse(*keyword)
+                self.preproc_parse(keyword.clone())
             } else {
-                *keyword
+                keyword.clone()
             }
+        } else if eval && !id.is_empty() {
+            self.unknown_directive(id);
+            Token::None
         } else {
-            Token::Identifier(id)
+            Token::Identifier(id.into())
         }
     }
 
@@ -587,7 +1372,8 @@ impl<'a> Lexer<'a> {
                     break;
                 }
 
-                keyword = keyword && *kind == Kind::KEY;
+                // the keywords are made of lowercase letters, _ and digits (e.g. char16_t)
+                keyword = keyword && kind.intersects(Kind::KEY | Kind::NUM);
                 self.pos += 1;
             } else {
                 break;
@@ -595,20 +1381,73 @@ impl<'a> Lexer<'a> {
         }
 
         let id = unsafe { std::str::from_utf8_unchecked(&self.buf.get_unchecked(spos..self.pos)) };
-        if !self.preproc_use && self.macro_eval(id) {
+        if self.options.preprocessor && !self.preproc_use && self.macro_eval(id) {
             self.swap_buffers();
             None
         } else if keyword {
-            if let Some(keyword) = CPP_KEYWORDS.get(id) {
-                Some(*keyword)
+            let keyword = CPP_KEYWORDS
+                .get(id)
+                .filter(|keyword| self.options.language.has_keyword(keyword));
+            if let Some(keyword) = keyword {
+                if self.options.alternative_operators {
+                    Some(keyword.clone().to_operator())
+                } else {
+                    Some(keyword.clone())
+                }
+            } else if (id == "module" || id == "import") && self.is_module_start(spos) {
+                Some(if id == "module" {
+                    Token::Module
+                } else {
+                    Token::Import
+                })
             } else {
-                Some(Token::Identifier(id))
+                Some(Token::Identifier(id.into()))
             }
         } else {
-            Some(Token::Identifier(id))
+            Some(Token::Identifier(id.into()))
         }
     }
 
+    /// Check if the module or import between start and pos begins a module declaration
+    /// or an import: it must start the line (possibly after export) and be followed
+    /// by a name, a partition (e.g. `module :private;`), a header name or a ;
+    fn is_module_start(&self, start: usize) -> bool {
+        if !self.options.modules || !self.options.language.has_modules() || self.preproc_use {
+            return false;
+        }
+        let is_module = &self.buf[start..self.pos] == b"module";
+        let after = self.buf[self.pos..]
+            .iter()
+            .find(|c| **c != b' ' && **c != b'\t')
+            .copied();
+        let followed = match after {
+            Some(b';') => is_module,
+            Some(b'<') | Some(b'\"') => !is_module,
+            Some(c) => c == b':' || c == b'_' || c.is_ascii_alphabetic(),
+            None => false,
+        };
+        if !followed {
+            return false;
+        }
+
+        let line_start = |end: usize| {
+            self.buf[..end]
+                .iter()
+                .rposition(|c| *c != b' ' && *c != b'\t')
+                .map_or(0, |pos| pos + 1)
+        };
+        let begin = line_start(start);
+        if begin == 0 || self.buf[begin - 1] == b'\n' {
+            return true;
+        }
+        // export module m; or export import m;
+        if begin < 6 || &self.buf[begin - 6..begin] != b"export" {
+            return false;
+        }
+        let begin = line_start(begin - 6);
+        begin == 0 || self.buf[begin - 1] == b'\n'
+    }
+
     pub(crate) fn get_exclamation(&mut self) -> Token<'a> {
         if self.pos < self.len {
             let c = self.next_char(0);
@@ -666,6 +1505,10 @@ impl<'a> Lexer<'a> {
     }
 
     pub(crate) fn get_lower(&mut self) -> Token<'a> {
+        if let Some(tok) = self.get_lower_digraph() {
+            return tok;
+        }
+
         let rem = self.len - self.pos;
         match rem {
             #[cold]
@@ -697,7 +1540,7 @@ impl<'a> Lexer<'a> {
                 } else if c == b'=' {
                     self.pos += 1;
                     let c = self.next_char(0);
-                    if c == b'>' {
+                    if c == b'>' && self.options.language >= Language::Cpp20 {
                         self.pos += 1;
                         Token::LowerEqualGreater
                     } else {
@@ -710,11 +1553,84 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    #[inline(always)]
+    fn get_lower_digraph(&mut self) -> Option<Token<'a>> {
+        if self.pos >= self.len {
+            return None;
+        }
+        match self.next_char(0) {
+            b'%' => {
+                self.pos += 1;
+                Some(Token::LeftBrace)
+            }
+            b':' => {
+                // <:: is < followed by :: unless the next char is : or >
+                // (e.g. std::vector<::std::string>)
+                if self.pos + 1 < self.len && self.next_char(1) == b':' {
+                    let c = if self.pos + 2 < self.len {
+                        self.next_char(2)
+                    } else {
+                        0
+                    };
+                    if c != b':' && c != b'>' {
+                        return None;
+                    }
+                }
+                self.pos += 1;
+                Some(Token::LeftBrack)
+            }
+            _ => None,
+        }
+    }
+
+    /// Get the token starting with a '%' (the digraphs %> and %: included)
+    /// or None if a preprocessor directive has been consumed
+    pub(crate) fn get_percent(&mut self) -> Option<Token<'a>> {
+        if self.pos < self.len {
+            let c = self.next_char(0);
+            if c == b'>' {
+                self.pos += 1;
+                return Some(Token::RightBrace);
+            } else if c == b':' {
+                self.pos += 1;
+                if !self.options.preprocessor {
+                    if self.get_hash_len() == 2 {
+                        self.pos += 2;
+                        return Some(Token::HashHash);
+                    }
+                    return Some(Token::Hash);
+                }
+                if self.options.directives {
+                    return Some(self.get_directive());
+                }
+                return match self.get_preproc() {
+                    tok @ Token::Pragma(_) => Some(tok),
+                    _ => None,
+                };
+            }
+        }
+        Some(get_basic_operator!(self, b'%', Modulo, ModuloEqual))
+    }
+
+    /// Get the length of the # (or of its digraph %:) at the current position
+    #[inline(always)]
+    pub(crate) fn get_hash_len(&self) -> usize {
+        if self.pos < self.len {
+            match self.next_char(0) {
+                b'#' => 1,
+                b'%' if self.pos + 1 < self.len && self.next_char(1) == b':' => 2,
+                _ => 0,
+            }
+        } else {
+            0
+        }
+    }
+
     pub(crate) fn get_greater(&mut self) -> Token<'a> {
         let rem = self.len - self.pos;
         match rem {
             #[cold]
-            0 => Token::Lower,
+            0 => Token::Greater,
             #[cold]
             1 => {
                 let c = self.next_char(0);
@@ -757,15 +1673,15 @@ impl<'a> Lexer<'a> {
                 let c = self.next_char(0);
                 if c == b'/' {
                     let c = self.prev_char(1);
-                    if c == b'*' {
-                        let comment = unsafe { &self.buf.get_unchecked(spos..self.pos - 1) };
+                    if c == b'*' && self.pos - 1 > spos {
+                        let text = unsafe { self.buf.get_unchecked(spos..self.pos - 1) }.into();
                         self.pos += 1;
-                        return Token::Comment(comment);
+                        return Token::Comment { block: true, text };
                     }
                     self.pos += 1;
                 } else if c == b'\n' {
-                    self.add_new_line();
                     self.pos += 1;
+                    self.add_new_line();
                 } else {
                     self.pos += 1;
                 }
@@ -774,37 +1690,35 @@ impl<'a> Lexer<'a> {
             }
         }
 
-        let comment = unsafe { &self.buf.get_unchecked(spos..) };
-        return Token::Comment(comment);
+        let start = self.start;
+        let col = self.get_span_column(self.buf, start);
+        self.context.add_diagnostic(Diagnostic::error(
+            "unterminated comment".to_string(),
+            start.line,
+            col,
+        ));
+        let text = unsafe { self.buf.get_unchecked(spos..) }.into();
+        Token::Comment { block: true, text }
     }
 
+    /// Get a // comment: the newline isn't a part of it
+    /// (the line splices have already been removed so a \ followed by a newline
+    /// continues the comment on the next line)
     pub(crate) fn get_single_comment(&mut self) -> Token<'a> {
-        let spos = self.pos + 1;
         self.pos += 1;
-        loop {
-            if self.pos < self.len {
-                let c = self.next_char(0);
-                self.pos += 1;
-                if c == b'\\' {
-                    self.pos += 1;
-                } else if c == b'\n' {
-                    self.add_new_line();
-                    let comment = unsafe { &self.buf.get_unchecked(spos..self.pos - 1) };
-                    return Token::Comment(comment);
-                }
-            } else {
-                break;
-            }
+        let spos = self.pos;
+        while self.pos < self.len && self.next_char(0) != b'\n' {
+            self.pos += 1;
         }
 
-        let comment = unsafe { &self.buf.get_unchecked(spos..) };
-        return Token::Comment(comment);
+        let text = unsafe { self.buf.get_unchecked(spos..self.pos) }.into();
+        Token::Comment { block: false, text }
     }
 
     pub(crate) fn get_slash(&mut self) -> Token<'a> {
         if self.pos < self.len {
             let c = self.next_char(0);
-            if c == b'/' {
+            if c == b'/' && self.options.language.has_line_comments() {
                 // Single line comment
                 return self.get_single_comment();
             } else if c == b'*' {
@@ -823,8 +1737,8 @@ impl<'a> Lexer<'a> {
             let c = self.next_char(0);
             if c == b'\n' {
                 // continuation line
-                self.add_new_line();
                 self.pos += 1;
+                self.add_new_line();
                 return None;
             }
         }
@@ -836,10 +1750,45 @@ impl<'a> Lexer<'a> {
         self.get_preproc_keyword(true)
     }
 
+    /// Check if the next token is a [ (or the digraph <:): after a [, it means that
+    /// an attribute starts (e.g. [[nodiscard]]) since two consecutive left brackets
+    /// can only introduce an attribute (in a[b[c]] the second [ follows b)
+    ///
+    /// The whites and the comments are skipped but not the end of a macro expansion.
+    pub fn is_attribute_start(&self) -> bool {
+        let mut pos = self.pos;
+        while pos < self.len {
+            let next = |n: usize| self.buf.get(pos + n).copied().unwrap_or(0);
+            match self.buf[pos] {
+                b' ' | b'\t' | b'\n' | b'\r' => pos += 1,
+                b'/' if next(1) == b'*' => {
+                    pos = self.buf[pos + 2..]
+                        .windows(2)
+                        .position(|w| w == b"*/")
+                        .map_or(self.len, |end| pos + 2 + end + 2);
+                }
+                b'/' if next(1) == b'/' => {
+                    pos = self.buf[pos..]
+                        .iter()
+                        .position(|c| *c == b'\n')
+                        .map_or(self.len, |end| pos + end);
+                }
+                b'[' => return true,
+                // <:: is < followed by :: unless the next char is : or >
+                b'<' => {
+                    return next(1) == b':'
+                        && (next(2) != b':' || next(3) == b':' || next(3) == b'>')
+                }
+                _ => return false,
+            }
+        }
+        false
+    }
+
     pub(crate) fn next_useful(&mut self) -> Token<'a> {
         loop {
-            match self.next() {
-                Token::Comment(_) => {}
+            match self.get_token() {
+                Token::Comment { .. } => {}
                 tok => {
                     return tok;
                 }
@@ -847,12 +1796,53 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    /// Get the next token
+    ///
+    /// The payload of a token (e.g. the name of an identifier) borrows the source
+    /// when the token is read there: a token from a buffer of the lexer
+    /// (e.g. a macro expansion or an included file) owns a copy of its payload.
     pub fn next(&mut self) -> Token<'a> {
+        let mut tok = self.get_token();
+        tok.own_payload(|s| self.is_lexer_buffer(s));
+        tok
+    }
+
+    /// Keep a buffer made by the lexer (e.g. an included file) to lex it
+    pub(crate) fn add_buffer(&mut self, buf: Vec<u8>) -> &'a [u8] {
+        if let Some(sources) = self.sources {
+            return sources.add(buf);
+        }
+        self.include_bufs.push(buf);
+        let buf = self.include_bufs.last().unwrap();
+        // The buffers are never modified nor dropped before the lexer (and moving a Vec
+        // doesn't move its data): the tokens read there own their payloads in next()
+        unsafe { &*(buf.as_slice() as *const [u8]) }
+    }
+
+    /// Check if s is in a buffer owned by the lexer
+    pub(crate) fn is_lexer_buffer(&self, s: &[u8]) -> bool {
+        let contains = |buf: &[u8]| {
+            let range = buf.as_ptr_range();
+            range.start <= s.as_ptr() && s.as_ptr() <= range.end
+        };
+        contains(&self.preproc_buf) || self.include_bufs.iter().any(|buf| contains(buf))
+    }
+
+    pub(crate) fn get_token(&mut self) -> Token<'a> {
+        if let Some((tok, span, end)) = self.pending_directive.take() {
+            self.directive_span = span;
+            self.skip_directive_line(end);
+            return tok;
+        }
         loop {
             if self.pos < self.len {
+                self.mark_start();
                 let c = self.next_char(0);
                 self.pos += 1;
                 match c {
+                    b'\0' if self.options.nul_error => {
+                        return self.get_invalid("null character in the source".to_string());
+                    }
                     b'\t' => skip_whites!(self),
                     b'\n' => {
                         self.add_new_line();
@@ -865,22 +1855,35 @@ impl<'a> Lexer<'a> {
                         return self.get_exclamation();
                     }
                     b'\"' => {
-                        return self.get_string();
+                        let tok = self.get_string();
+                        return self.get_user_defined(tok);
                     }
                     b'#' => {
-                        self.get_preproc();
+                        if !self.options.preprocessor {
+                            return get_operator!(self, b'#', Hash, HashHash);
+                        }
+                        if self.options.directives {
+                            return self.get_directive();
+                        }
+                        if let tok @ Token::Pragma(_) = self.get_preproc() {
+                            // the unhandled pragmas are kept
+                            return tok;
+                        }
                     }
                     b'$' => {
                         return Token::Dollar;
                     }
                     b'%' => {
-                        return get_basic_operator!(self, b'%', Modulo, ModuloEqual);
+                        if let Some(tok) = self.get_percent() {
+                            return tok;
+                        }
                     }
                     b'&' => {
                         return get_operator!(self, b'&', And, AndAnd, AndEqual);
                     }
                     b'\'' => {
-                        return self.get_char();
+                        let tok = self.get_char();
+                        return self.get_user_defined(tok);
                     }
                     b'(' => {
                         return Token::LeftParen;
@@ -901,15 +1904,24 @@ impl<'a> Lexer<'a> {
                         return self.get_minus();
                     }
                     b'.' => {
-                        return self.get_dot_or_number();
+                        let tok = self.get_dot_or_number();
+                        return self.get_user_defined(tok);
                     }
                     b'/' => {
-                        return self.get_slash();
+                        let tok = self.get_slash();
+                        if self.options.comments || !matches!(tok, Token::Comment { .. }) {
+                            return tok;
+                        }
                     }
                     b'0'..=b'9' => {
-                        return self.get_number(u64::from(c - b'0'));
+                        let tok = self.get_number();
+                        return self.get_user_defined(tok);
                     }
                     b':' => {
+                        if self.pos < self.len && self.next_char(0) == b'>' {
+                            self.pos += 1;
+                            return Token::RightBrack;
+                        }
                         return get_operator!(self, b':', Colon, ColonColon);
                     }
                     b';' => {
@@ -937,7 +1949,7 @@ impl<'a> Lexer<'a> {
                     }
                     b'L' => {
                         if let Some(tok) = self.get_special_string_char(StringType::L) {
-                            return tok;
+                            return self.get_user_defined(tok);
                         } else if let Some(tok) = self.get_identifier() {
                             return tok;
                         }
@@ -949,7 +1961,7 @@ impl<'a> Lexer<'a> {
                     }
                     b'R' => {
                         if let Some(tok) = self.get_special_string_char(StringType::R) {
-                            return tok;
+                            return self.get_user_defined(tok);
                         } else if let Some(tok) = self.get_identifier() {
                             return tok;
                         }
@@ -961,7 +1973,7 @@ impl<'a> Lexer<'a> {
                     }
                     b'U' => {
                         if let Some(tok) = self.get_special_string_char(StringType::UU) {
-                            return tok;
+                            return self.get_user_defined(tok);
                         } else if let Some(tok) = self.get_identifier() {
                             return tok;
                         }
@@ -972,7 +1984,8 @@ impl<'a> Lexer<'a> {
                         }
                     }
                     b'[' => {
-                        return get_operator!(self, b'[', LeftBrack, DoubleLeftBrack);
+                        // [[ is two tokens: see is_attribute_start
+                        return Token::LeftBrack;
                     }
                     b'\\' => {
                         if let Some(tok) = self.get_backslash() {
@@ -980,7 +1993,7 @@ impl<'a> Lexer<'a> {
                         }
                     }
                     b']' => {
-                        return get_operator!(self, b'[', RightBrack, DoubleRightBrack);
+                        return Token::RightBrack;
                     }
                     b'^' => {
                         return get_basic_operator!(self, b'^', Xor, XorEqual);
@@ -997,7 +2010,7 @@ impl<'a> Lexer<'a> {
                     }
                     b'u' => {
                         if let Some(tok) = self.get_special_string_char(StringType::U) {
-                            return tok;
+                            return self.get_user_defined(tok);
                         } else if let Some(tok) = self.get_identifier_or_keyword() {
                             return tok;
                         }
@@ -1023,7 +2036,10 @@ impl<'a> Lexer<'a> {
                 }
             } else if self.preproc_use {
                 self.restore_buffers();
-            } else {
+            } else if !self.end_include() {
+                if !self.in_directive {
+                    self.context.check_unterminated_ifs();
+                }
                 return Token::Eof;
             }
         }
@@ -1034,6 +2050,9 @@ impl<'a> Lexer<'a> {
 mod tests {
 
     use super::*;
+    use crate::lexer::number::{FloatLiteral, FloatSuffix, IntBase, IntLiteral, IntSuffix};
+    use crate::lexer::span::Span;
+    use crate::lexer::string::{get_u16_string, get_u32_string, get_u8_string};
     use std::fs;
 
     #[test]
@@ -1051,10 +2070,220 @@ mod tests {
         assert_eq!(p.next(), Token::LiteralOct(0o1357));
     }
 
+    #[test]
+    fn test_user_defined() {
+        let mut p = Lexer::new(
+            b"42_km 0x2A_km 10us 2.75_deg 1e3_x 2.5f 'a'_c L'b'_c \"abc\"_s u8\"abc\"s R\"(a)\"_r 1.5;",
+        );
+        assert_eq!(
+            p.next(),
+            Token::LiteralUserDefined(UdLiteral::Int(42), "_km".into())
+        );
+        assert_eq!(
+            p.next(),
+            Token::LiteralUserDefined(UdLiteral::Int(42), "_km".into())
+        );
+        assert_eq!(
+            p.next(),
+            Token::LiteralUserDefined(UdLiteral::Int(10), "us".into())
+        );
+        assert_eq!(
+            p.next(),
+            Token::LiteralUserDefined(UdLiteral::Decimal(2.75), "_deg".into())
+        );
+        assert_eq!(
+            p.next(),
+            Token::LiteralUserDefined(UdLiteral::Decimal(1e3), "_x".into())
+        );
+        assert_eq!(p.next(), Token::LiteralFloat(2.5));
+        assert_eq!(
+            p.next(),
+            Token::LiteralUserDefined(UdLiteral::Char(u32::from('a')), "_c".into())
+        );
+        assert_eq!(
+            p.next(),
+            Token::LiteralUserDefined(UdLiteral::LChar(u32::from('b')), "_c".into())
+        );
+        assert_eq!(
+            p.next(),
+            Token::LiteralUserDefined(UdLiteral::String(b"abc".into()), "_s".into())
+        );
+        assert_eq!(
+            p.next(),
+            Token::LiteralUserDefined(UdLiteral::U8String(b"abc".into()), "s".into())
+        );
+        assert_eq!(
+            p.next(),
+            Token::LiteralUserDefined(UdLiteral::RString(b"a".into()), "_r".into())
+        );
+        assert_eq!(p.next(), Token::LiteralDecimal(1.5));
+        assert_eq!(p.next(), Token::SemiColon);
+
+        let mut p = Lexer::new(b"#define PRId64 \"ld\"\n\"%\"PRId64 \"%\"_PRId64");
+        assert_eq!(p.next(), Token::LiteralString(b"%".into()));
+        assert_eq!(p.next(), Token::LiteralString(b"ld".into()));
+        assert_eq!(
+            p.next(),
+            Token::LiteralUserDefined(UdLiteral::String(b"%".into()), "_PRId64".into())
+        );
+    }
+
     #[test]
     fn test_bin() {
         let mut p = Lexer::new(b"0b110001110010010110011101");
         assert_eq!(p.next(), Token::LiteralBin(0b110001110010010110011101));
+
+        let mut p = Lexer::new(b"0b0 0B1111'0000 0b2 0b 0b1012 1");
+        assert_eq!(p.next(), Token::LiteralBin(0));
+        assert_eq!(p.next(), Token::LiteralBin(0b1111_0000));
+        assert_eq!(p.next(), Token::Invalid(b"0b2".into()));
+        assert_eq!(p.next(), Token::Invalid(b"0b".into()));
+        assert_eq!(p.next(), Token::Invalid(b"0b1012".into()));
+        assert_eq!(p.next(), Token::LiteralInt(1));
+
+        let messages: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "1:17: error: invalid digit \"2\" in binary constant",
+                "1:21: error: invalid suffix \"b\" on integer constant",
+                "1:24: error: invalid digit \"2\" in binary constant",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_float() {
+        let mut p = Lexer::new(
+            b"1.5 2.5f 1e10 1e10L 0x1.8p3 0x1p-2f 0XAP+1l 017.5 1.5_km 1.0fl 0x1.8 1e+;",
+        );
+        assert_eq!(p.next(), Token::LiteralDecimal(1.5));
+        assert_eq!(p.next(), Token::LiteralFloat(2.5));
+        assert_eq!(p.next(), Token::LiteralDecimal(1e10));
+        assert_eq!(p.next(), Token::LiteralLongDouble(1e10));
+        assert_eq!(p.next(), Token::LiteralDecimal(12.));
+        assert_eq!(p.next(), Token::LiteralFloat(0.25));
+        assert_eq!(p.next(), Token::LiteralLongDouble(20.));
+        assert_eq!(p.next(), Token::LiteralDecimal(17.5));
+        assert_eq!(
+            p.next(),
+            Token::LiteralUserDefined(UdLiteral::Decimal(1.5), "_km".into())
+        );
+        assert_eq!(p.next(), Token::Invalid(b"1.0fl".into()));
+        assert_eq!(p.next(), Token::Invalid(b"0x1.8".into()));
+        assert_eq!(p.next(), Token::Invalid(b"1e+".into()));
+        assert_eq!(p.next(), Token::SemiColon);
+
+        let messages: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| d.message)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "invalid suffix \"fl\" on floating constant",
+                "hexadecimal floating constants require an exponent",
+                "exponent has no digits",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_float_literal() {
+        assert_eq!(
+            FloatLiteral::parse(b"12.75e-3f"),
+            Ok(FloatLiteral {
+                mantissa: 1275,
+                exponent: -5,
+                hex: false,
+                suffix: FloatSuffix::F,
+            })
+        );
+        assert_eq!(
+            FloatLiteral::parse(b"1e10"),
+            Ok(FloatLiteral {
+                mantissa: 1,
+                exponent: 10,
+                hex: false,
+                suffix: FloatSuffix::None,
+            })
+        );
+        let literal = FloatLiteral::parse(b"0x1.8p3L").unwrap();
+        assert_eq!(
+            literal,
+            FloatLiteral {
+                mantissa: 0x18,
+                exponent: -1,
+                hex: true,
+                suffix: FloatSuffix::L,
+            }
+        );
+        assert_eq!(literal.value(), 12.);
+        assert_eq!(
+            FloatLiteral::parse(b"1.0ff"),
+            Err("invalid suffix \"ff\" on floating constant".to_string())
+        );
+        assert!(FloatLiteral::parse(b"1.5_km").is_err());
+    }
+
+    #[test]
+    fn test_int_literal() {
+        let literals = [
+            (&b"0xFFull"[..], 0xFF, IntBase::Hex, IntSuffix::ULL),
+            (b"123", 123, IntBase::Decimal, IntSuffix::None),
+            (b"0777", 0o777, IntBase::Octal, IntSuffix::None),
+            (b"0b101u", 0b101, IntBase::Binary, IntSuffix::U),
+            (b"1'000zu", 1000, IntBase::Decimal, IntSuffix::UZ),
+            (
+                b"18446744073709551615",
+                u64::MAX,
+                IntBase::Decimal,
+                IntSuffix::None,
+            ),
+        ];
+        for (spelling, value, base, suffix) in literals.iter() {
+            assert_eq!(
+                IntLiteral::parse(spelling),
+                Ok(IntLiteral {
+                    value: *value,
+                    base: *base,
+                    suffix: *suffix,
+                })
+            );
+        }
+        assert_eq!(
+            IntLiteral::parse(b"18446744073709551616"),
+            Err("integer constant is too large for its type".to_string())
+        );
+        assert!(IntLiteral::parse(b"1.5").is_err());
+
+        let mut p = Lexer::new(b"0xFFull 0777 0b101u 12z 12Uz 0x1'0000'0000'0000'0000 089 09.5");
+        assert_eq!(p.next(), Token::LiteralULongLong(0xFF));
+        assert_eq!(p.next(), Token::LiteralOct(0o777));
+        assert_eq!(p.next(), Token::LiteralUInt(0b101));
+        assert_eq!(p.next(), Token::LiteralSignedSize(12));
+        assert_eq!(p.next(), Token::LiteralSize(12));
+        assert_eq!(p.next(), Token::Invalid(b"0x1'0000'0000'0000'0000".into()));
+        assert_eq!(p.next(), Token::Invalid(b"089".into()));
+        assert_eq!(p.next(), Token::LiteralDecimal(9.5));
+
+        let messages: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "1:30: error: integer constant is too large for its type",
+                "1:54: error: invalid digit \"8\" in octal constant",
+            ]
+        );
     }
 
     #[test]
@@ -1116,79 +2345,535 @@ mod tests {
     fn test_keywords() {
         let mut p = Lexer::new(b"while foa whila for While For static_cast");
         assert_eq!(p.next(), Token::While);
-        assert_eq!(p.next(), Token::Identifier("foa"));
-        assert_eq!(p.next(), Token::Identifier("whila"));
+        assert_eq!(p.next(), Token::Identifier("foa".into()));
+        assert_eq!(p.next(), Token::Identifier("whila".into()));
         assert_eq!(p.next(), Token::For);
-        assert_eq!(p.next(), Token::Identifier("While"));
-        assert_eq!(p.next(), Token::Identifier("For"));
+        assert_eq!(p.next(), Token::Identifier("While".into()));
+        assert_eq!(p.next(), Token::Identifier("For".into()));
         assert_eq!(p.next(), Token::StaticCast);
     }
 
+    #[test]
+    fn test_cpp20_keywords() {
+        let mut p = Lexer::new(
+            concat!(
+                "if class constexpr classy char8_t char16_t char32_t char16 co_await co_return ",
+                "co_yield concept consteval constinit requires final override import module ",
+                "and or not bitand bitor xor compl and_eq or_eq xor_eq not_eq endif"
+            )
+            .as_bytes(),
+        );
+        let tokens: Vec<_> = std::iter::from_fn(|| match p.next() {
+            Token::Eof => None,
+            tok => Some(tok),
+        })
+        .collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                Token::If,
+                Token::Class,
+                Token::Constexpr,
+                Token::Identifier("classy".into()),
+                Token::Char8,
+                Token::Char16,
+                Token::Char32,
+                Token::Identifier("char16".into()),
+                Token::CoAwait,
+                Token::CoReturn,
+                Token::CoYield,
+                Token::Concept,
+                Token::Consteval,
+                Token::Constinit,
+                Token::Requires,
+                Token::Identifier("final".into()),
+                Token::Identifier("override".into()),
+                Token::Identifier("import".into()),
+                Token::Identifier("module".into()),
+                Token::AndKw,
+                Token::OrKw,
+                Token::NotKw,
+                Token::BitAnd,
+                Token::BitOr,
+                Token::XorKw,
+                Token::Compl,
+                Token::AndEq,
+                Token::OrEq,
+                Token::XorEq,
+                Token::NotEq,
+                Token::Identifier("endif".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_alternative_operators() {
+        let source = b"a and b && c or_eq not d compl e not_eq f bitand g xor h andy";
+        let lex = |options| {
+            let mut p = Lexer::with_options(source, options);
+            std::iter::from_fn(move || match p.next() {
+                Token::Eof => None,
+                tok => Some(tok.to_string()),
+            })
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            lex(LexerOptions {
+                alternative_operators: true,
+                ..Default::default()
+            }),
+            vec![
+                "a", "&&", "b", "&&", "c", "|=", "!", "d", "~", "e", "!=", "f", "&", "g", "^", "h",
+                "andy"
+            ]
+        );
+        assert_eq!(
+            lex(LexerOptions::default()),
+            vec![
+                "a", "and", "b", "&&", "c", "or_eq", "not", "d", "compl", "e", "not_eq", "f",
+                "bitand", "g", "xor", "h", "andy"
+            ]
+        );
+
+        let options = LexerOptions {
+            alternative_operators: true,
+            ..Default::default()
+        };
+        let mut p = Lexer::with_options(b"x and y", options);
+        assert_eq!(p.next(), Token::Identifier("x".into()));
+        assert_eq!(p.next(), Token::AndAnd);
+        assert_eq!(p.next(), Token::Identifier("y".into()));
+    }
+
     #[test]
     fn test_identifiers() {
         let mut p =
             Lexer::new(b"hello world whilee Roo Lar uoo Uar u851 hello_world_WORLD_HELLO123");
-        assert_eq!(p.next(), Token::Identifier("hello"));
-        assert_eq!(p.next(), Token::Identifier("world"));
-        assert_eq!(p.next(), Token::Identifier("whilee"));
-        assert_eq!(p.next(), Token::Identifier("Roo"));
-        assert_eq!(p.next(), Token::Identifier("Lar"));
-        assert_eq!(p.next(), Token::Identifier("uoo"));
-        assert_eq!(p.next(), Token::Identifier("Uar"));
-        assert_eq!(p.next(), Token::Identifier("u851"));
-        assert_eq!(p.next(), Token::Identifier("hello_world_WORLD_HELLO123"));
+        assert_eq!(p.next(), Token::Identifier("hello".into()));
+        assert_eq!(p.next(), Token::Identifier("world".into()));
+        assert_eq!(p.next(), Token::Identifier("whilee".into()));
+        assert_eq!(p.next(), Token::Identifier("Roo".into()));
+        assert_eq!(p.next(), Token::Identifier("Lar".into()));
+        assert_eq!(p.next(), Token::Identifier("uoo".into()));
+        assert_eq!(p.next(), Token::Identifier("Uar".into()));
+        assert_eq!(p.next(), Token::Identifier("u851".into()));
+        assert_eq!(
+            p.next(),
+            Token::Identifier("hello_world_WORLD_HELLO123".into())
+        );
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut p = LexerBuilder::new()
+            .tab_width(4)
+            .comments(true)
+            .build(b"\ta // b\n#define A 1\n\tA");
+        assert_eq!(p.next_with_span().1.col, 5);
+        assert_eq!(
+            p.next(),
+            Token::Comment {
+                block: false,
+                text: b" b".into()
+            }
+        );
+        assert_eq!(p.next(), Token::Eol);
+        let (tok, span) = p.next_with_span();
+        assert_eq!(tok, Token::LiteralInt(1));
+        assert_eq!((span.line, span.col), (3, 5));
+        assert_eq!(p.next(), Token::Eof);
+
+        let mut context = PContext::default();
+        context.define_from_str("B=2");
+        let mut p = LexerBuilder::new()
+            .preprocessor(false)
+            .context(context)
+            .file("main.c")
+            .build(b"#define A B\nB");
+        assert_eq!(p.next(), Token::Hash);
+        assert_eq!(p.next(), Token::Identifier("define".into()));
+
+        let mut context = PContext::default();
+        context.define_from_str("B=2");
+        let mut p = LexerBuilder::new()
+            .context(context)
+            .file("main.c")
+            .build(b"B __FILE__");
+        assert_eq!(p.next(), Token::LiteralInt(2));
+        assert_eq!(p.next(), Token::LiteralString(b"main.c".into()));
+    }
+
+    #[test]
+    fn test_cplusplus() {
+        let source = concat!(
+            "__cplusplus\n",
+            "#if __cplusplus >= 202002L\n",
+            "cpp20\n",
+            "#elif __cplusplus >= 201703L\n",
+            "cpp17\n",
+            "#elif defined(__cplusplus)\n",
+            "cpp\n",
+            "#else\n",
+            "c\n",
+            "#endif\n",
+        );
+        let tokens = |language| {
+            let mut p = LexerBuilder::new()
+                .language(language)
+                .build(source.as_bytes());
+            let mut next = || loop {
+                match p.next() {
+                    Token::Eol => {}
+                    tok => break tok,
+                }
+            };
+            (next(), next())
+        };
+
+        assert_eq!(
+            tokens(Language::Cpp14),
+            (Token::LiteralLong(201402), Token::Identifier("cpp".into()))
+        );
+        assert_eq!(
+            tokens(Language::Cpp17),
+            (
+                Token::LiteralLong(201703),
+                Token::Identifier("cpp17".into())
+            )
+        );
+        assert_eq!(
+            tokens(Language::Cpp20),
+            (
+                Token::LiteralLong(202002),
+                Token::Identifier("cpp20".into())
+            )
+        );
+        assert_eq!(
+            tokens(Language::Cpp23),
+            (
+                Token::LiteralLong(202302),
+                Token::Identifier("cpp20".into())
+            )
+        );
+        assert_eq!(
+            tokens(Language::C11),
+            (
+                Token::Identifier("__cplusplus".into()),
+                Token::Identifier("c".into())
+            )
+        );
+
+        // a definition in the context is kept
+        let mut context = PContext::default();
+        context.define_from_str("__cplusplus=199711L");
+        let mut p = Lexer::with_context(b"__cplusplus", context);
+        assert_eq!(p.next(), Token::LiteralLong(199711));
     }
 
     #[test]
     fn test_divide() {
         let mut p = Lexer::new(b"a / b");
-        assert_eq!(p.next(), Token::Identifier("a"));
+        assert_eq!(p.next(), Token::Identifier("a".into()));
         assert_eq!(p.next(), Token::Divide);
-        assert_eq!(p.next(), Token::Identifier("b"));
+        assert_eq!(p.next(), Token::Identifier("b".into()));
     }
 
     #[test]
     fn test_comment() {
-        let mut p = Lexer::new(b"/* test */");
-        assert_eq!(p.next(), Token::Comment(" test ".as_bytes()));
+        let options = LexerOptions {
+            comments: true,
+            ..Default::default()
+        };
+        let mut p = Lexer::with_options(b"/* test */ a // b\n/*/ c */", options);
+        assert_eq!(
+            p.next(),
+            Token::Comment {
+                block: true,
+                text: b" test ".into()
+            }
+        );
+        assert_eq!(p.next(), Token::Identifier("a".into()));
+        assert_eq!(
+            p.next(),
+            Token::Comment {
+                block: false,
+                text: b" b".into()
+            }
+        );
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(
+            p.next(),
+            Token::Comment {
+                block: true,
+                text: b"/ c ".into()
+            }
+        );
+        assert_eq!(p.next(), Token::Eof);
+
+        let mut p = Lexer::new(b"/* test */ a // b\nc");
+        assert_eq!(p.next(), Token::Identifier("a".into()));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Identifier("c".into()));
+    }
+
+    #[test]
+    fn test_spliced_line_comment() {
+        let options = LexerOptions {
+            comments: true,
+            ..Default::default()
+        };
+        let mut p = Lexer::with_options(b"// a \\\n b\nc", options);
+        assert_eq!(
+            p.next_with_span(),
+            (
+                Token::Comment {
+                    block: false,
+                    text: b" a  b".into()
+                },
+                Span {
+                    start: 0,
+                    end: 9,
+                    line: 1,
+                    col: 1
+                }
+            )
+        );
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next_with_span().1.line, 3);
+    }
+
+    #[test]
+    fn test_unterminated_comment() {
+        let options = LexerOptions {
+            comments: true,
+            ..Default::default()
+        };
+        let mut p = Lexer::with_options(b"a\n  /* b\nc", options);
+        assert_eq!(p.next(), Token::Identifier("a".into()));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(
+            p.next(),
+            Token::Comment {
+                block: true,
+                text: b" b\nc".into()
+            }
+        );
+        assert_eq!(p.next(), Token::Eof);
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.message, d.line, d.column))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![("unterminated comment".to_string(), 2, 3)]
+        );
+    }
+
+    #[test]
+    fn test_unterminated_string() {
+        let mut p = Lexer::new(b"x = \"ab\ncd\";\n  u8\"abc");
+        assert_eq!(p.next(), Token::Identifier("x".into()));
+        assert_eq!(p.next(), Token::Equal);
+        assert_eq!(p.next(), Token::Invalid(b"\"ab".into()));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Identifier("cd".into()));
+        assert_eq!(p.next(), Token::Invalid(b"\";".into()));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Invalid(b"u8\"abc".into()));
+        assert_eq!(p.next(), Token::Eof);
+
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.message, d.line, d.column))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                ("missing terminating \" character".to_string(), 1, 5),
+                ("missing terminating \" character".to_string(), 2, 3),
+                ("missing terminating \" character".to_string(), 3, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_u8_string() {
+        let mut p = Lexer::new(
+            "u8\"héllo\" u8\"\\u00e9\\x41\\n\" u8R\"(é)\" u8\"\\uD800\" u8\"\\400\"".as_bytes(),
+        );
+        let tok = p.next();
+        assert_eq!(tok, Token::LiteralU8String("héllo".as_bytes().into()));
+        if let Token::LiteralU8String(s) = tok {
+            assert_eq!(
+                get_u8_string(&s).unwrap(),
+                vec![b'h', 0xC3, 0xA9, b'l', b'l', b'o']
+            );
+        }
+        let tok = p.next();
+        assert_eq!(tok, Token::LiteralU8String(b"\\u00e9\\x41\\n".into()));
+        if let Token::LiteralU8String(s) = tok {
+            assert_eq!(get_u8_string(&s).unwrap(), vec![0xC3, 0xA9, b'A', b'\n']);
+        }
+        assert_eq!(p.next(), Token::LiteralU8RString("é".as_bytes().into()));
+        assert_eq!(p.next(), Token::Invalid(b"u8\"\\uD800\"".into()));
+        assert_eq!(p.next(), Token::Invalid(b"u8\"\\400\"".into()));
+
+        let messages: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| d.message)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "\\ud800 is not a valid universal character",
+                "octal escape sequence out of range",
+            ]
+        );
+
+        let mut p = Lexer::new(b"u8\"\xE9t\xE9\" u8R\"(\xFF)\"");
+        assert_eq!(p.next(), Token::Invalid(b"u8\"\xE9t\xE9\"".into()));
+        assert_eq!(p.next(), Token::Invalid(b"u8R\"(\xFF)\"".into()));
+
+        let messages: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| d.message)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "invalid UTF-8 in u8 string literal",
+                "invalid UTF-8 in u8 string literal",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_universal_in_strings() {
+        let mut p = Lexer::new(
+            b"u8\"\\U0001F600\" u\"\\U0001F600\\u00e9\" U\"\\U0001F600a\" u\"\\uDC00\" U\"\\U00110000\"",
+        );
+        match p.next() {
+            Token::LiteralU8String(s) => {
+                assert_eq!(get_u8_string(&s).unwrap(), vec![0xF0, 0x9F, 0x98, 0x80]);
+            }
+            tok => panic!("unexpected token {:?}", tok),
+        }
+        match p.next() {
+            Token::LiteralUString(s) => {
+                assert_eq!(get_u16_string(&s).unwrap(), vec![0xD83D, 0xDE00, 0xE9]);
+            }
+            tok => panic!("unexpected token {:?}", tok),
+        }
+        match p.next() {
+            Token::LiteralUUString(s) => {
+                assert_eq!(get_u32_string(&s).unwrap(), vec![0x1F600, 0x61]);
+            }
+            tok => panic!("unexpected token {:?}", tok),
+        }
+        assert_eq!(p.next(), Token::Invalid(b"u\"\\uDC00\"".into()));
+        assert_eq!(p.next(), Token::Invalid(b"U\"\\U00110000\"".into()));
+
+        let messages: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| d.message)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "\\udc00 is not a valid universal character",
+                "\\U00110000 is not a valid universal character",
+            ]
+        );
     }
 
     #[test]
     fn test_string() {
         let mut p = Lexer::new(b"\"foo\" \"foo\\\"bar\"");
-        assert_eq!(p.next(), Token::LiteralString(b"foo"));
-        assert_eq!(p.next(), Token::LiteralString(b"foo\\\"bar"));
+        assert_eq!(p.next(), Token::LiteralString(b"foo".into()));
+        assert_eq!(p.next(), Token::LiteralString(b"foo\\\"bar".into()));
 
         let mut p = Lexer::new(b"u\"foo\" u\"foo\\\"bar\"");
-        assert_eq!(p.next(), Token::LiteralUString(b"foo"));
-        assert_eq!(p.next(), Token::LiteralUString(b"foo\\\"bar"));
+        assert_eq!(p.next(), Token::LiteralUString(b"foo".into()));
+        assert_eq!(p.next(), Token::LiteralUString(b"foo\\\"bar".into()));
 
         let mut p = Lexer::new(b"U\"foo\" U\"foo\\\"bar\"");
-        assert_eq!(p.next(), Token::LiteralUUString(b"foo"));
-        assert_eq!(p.next(), Token::LiteralUUString(b"foo\\\"bar"));
+        assert_eq!(p.next(), Token::LiteralUUString(b"foo".into()));
+        assert_eq!(p.next(), Token::LiteralUUString(b"foo\\\"bar".into()));
 
         let mut p = Lexer::new(b"u8\"foo\" u8\"foo\\\"bar\"");
-        assert_eq!(p.next(), Token::LiteralU8String(b"foo"));
-        assert_eq!(p.next(), Token::LiteralU8String(b"foo\\\"bar"));
+        assert_eq!(p.next(), Token::LiteralU8String(b"foo".into()));
+        assert_eq!(p.next(), Token::LiteralU8String(b"foo\\\"bar".into()));
 
         let mut p = Lexer::new(b"L\"foo\" L\"foo\\\"bar\"");
-        assert_eq!(p.next(), Token::LiteralLString(b"foo"));
-        assert_eq!(p.next(), Token::LiteralLString(b"foo\\\"bar"));
+        assert_eq!(p.next(), Token::LiteralLString(b"foo".into()));
+        assert_eq!(p.next(), Token::LiteralLString(b"foo\\\"bar".into()));
 
         let mut p = Lexer::new(
             b"R\"hello(foo)hello\" R\"world(foo\n\\\"bar)world\" R\"world(foo)world  )world\"",
         );
-        assert_eq!(p.next(), Token::LiteralRString(b"foo"));
-        assert_eq!(p.next(), Token::LiteralRString(b"foo\n\\\"bar"));
-        assert_eq!(p.next(), Token::LiteralRString(b"foo)world  "));
+        assert_eq!(p.next(), Token::LiteralRString(b"foo".into()));
+        assert_eq!(p.next(), Token::LiteralRString(b"foo\n\\\"bar".into()));
+        assert_eq!(p.next(), Token::LiteralRString(b"foo)world  ".into()));
 
         let mut p = Lexer::new(b"LR\"hello(foo)hello\" UR\"world(foo\n\\\"bar)world\"");
-        assert_eq!(p.next(), Token::LiteralLRString(b"foo"));
-        assert_eq!(p.next(), Token::LiteralUURString(b"foo\n\\\"bar"));
+        assert_eq!(p.next(), Token::LiteralLRString(b"foo".into()));
+        assert_eq!(p.next(), Token::LiteralUURString(b"foo\n\\\"bar".into()));
 
         let mut p = Lexer::new(b"uR\"hello(foo)hello\" u8R\"world(foo\n\\\"bar)world\"");
-        assert_eq!(p.next(), Token::LiteralURString(b"foo"));
-        assert_eq!(p.next(), Token::LiteralU8RString(b"foo\n\\\"bar"));
+        assert_eq!(p.next(), Token::LiteralURString(b"foo".into()));
+        assert_eq!(p.next(), Token::LiteralU8RString(b"foo\n\\\"bar".into()));
+
+        let mut p = Lexer::new(b"R\"a(foo)a\n)a\"");
+        assert_eq!(p.next(), Token::LiteralRString(b"foo)a\n".into()));
+        assert_eq!(p.next(), Token::Eof);
+    }
+
+    #[test]
+    fn test_raw_string() {
+        let mut p = Lexer::new(
+            b"R\"x(a \"quoted\" (string)\\n)\" )y\")x\" u8R\"(\n  multi\n  line\n)\" x LR\"--()--)--\"\nR\"()\"",
+        );
+        assert_eq!(
+            p.next(),
+            Token::LiteralRString(b"a \"quoted\" (string)\\n)\" )y\"".into())
+        );
+        assert_eq!(
+            p.next(),
+            Token::LiteralU8RString(b"\n  multi\n  line\n".into())
+        );
+        assert_eq!(p.next(), Token::Identifier("x".into()));
+        assert_eq!(p.next(), Token::LiteralLRString(b")--".into()));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.line, 5);
+        assert_eq!(p.next(), Token::LiteralRString(b"".into()));
+        assert_eq!(p.next(), Token::Eof);
+
+        let mut p = Lexer::new(b"R\"0123456789abcdefg(x)0123456789abcdefg\" UR\"a b(x)a b\"");
+        assert_eq!(
+            p.next(),
+            Token::Invalid(b"R\"0123456789abcdefg(x)0123456789abcdefg\"".into())
+        );
+        assert_eq!(p.next(), Token::Invalid(b"UR\"a".into()));
+
+        let messages: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| d.message)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "raw string delimiter longer than 16 characters: 0123456789abcdefg",
+                "invalid character ' ' in raw string delimiter",
+            ]
+        );
     }
 
     #[test]
@@ -1234,6 +2919,461 @@ mod tests {
         assert_eq!(p.next(), Token::LowerEqualGreater);
     }
 
+    #[test]
+    fn test_maximal_munch() {
+        let lex = |source: &[u8]| {
+            let mut p = Lexer::new(source);
+            std::iter::from_fn(move || match p.next() {
+                Token::Eof => None,
+                tok => Some(tok.to_string()),
+            })
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(lex(b"a+++b"), vec!["a", "++", "+", "b"]);
+        assert_eq!(lex(b"a+++++b"), vec!["a", "++", "++", "+", "b"]);
+        assert_eq!(lex(b"a---->b"), vec!["a", "--", "--", ">", "b"]);
+        assert_eq!(lex(b"a>>=b"), vec!["a", ">>=", "b"]);
+        assert_eq!(lex(b"a>>>=b"), vec!["a", ">>", ">=", "b"]);
+        assert_eq!(lex(b"a<<=b<<<c"), vec!["a", "<<=", "b", "<<", "<", "c"]);
+        assert_eq!(
+            lex(b"p->*m p->m p-->m"),
+            vec!["p", "->*", "m", "p", "->", "m", "p", "--", ">", "m"]
+        );
+        assert_eq!(
+            lex(b"a<=>b a<=>=b a<==b"),
+            vec!["a", "<=>", "b", "a", "<=>", "=", "b", "a", "<=", "=", "b"]
+        );
+        assert_eq!(
+            lex(b"f(...) a....b a..b"),
+            vec!["f", "(", "...", ")", "a", "...", ".", "b", "a", ".", ".", "b"]
+        );
+        assert_eq!(
+            lex(b"a&&&b a|||b a===b a!==b"),
+            vec![
+                "a", "&&", "&", "b", "a", "||", "|", "b", "a", "==", "=", "b", "a", "!=", "=", "b"
+            ]
+        );
+        assert_eq!(
+            lex(b"a::::b a:::b"),
+            vec!["a", "::", "::", "b", "a", "::", ":", "b"]
+        );
+
+        // an operator at the end of the input
+        for op in &[
+            "<", ">", "-", "+", "&", "|", "^", "=", "!", ".", ":", "*", "/", "%", "<<", ">>", "->",
+            "<=", ">=", "..",
+        ] {
+            let expected: Vec<_> = if *op == ".." {
+                vec![".", "."]
+            } else {
+                vec![*op]
+            };
+            assert_eq!(lex(op.as_bytes()), expected, "{}", op);
+        }
+    }
+
+    #[test]
+    fn test_spaceship() {
+        let mut p = Lexer::new(b"a<=>b a <= > b a<= >b a<=>>b");
+        assert_eq!(p.next(), Token::Identifier("a".into()));
+        assert_eq!(p.next(), Token::LowerEqualGreater);
+        assert_eq!(p.next(), Token::Identifier("b".into()));
+        assert_eq!(p.next(), Token::Identifier("a".into()));
+        assert_eq!(p.next(), Token::LowerEqual);
+        assert_eq!(p.next(), Token::Greater);
+        assert_eq!(p.next(), Token::Identifier("b".into()));
+        assert_eq!(p.next(), Token::Identifier("a".into()));
+        assert_eq!(p.next(), Token::LowerEqual);
+        assert_eq!(p.next(), Token::Greater);
+        assert_eq!(p.next(), Token::Identifier("b".into()));
+        assert_eq!(p.next(), Token::Identifier("a".into()));
+        assert_eq!(p.next(), Token::LowerEqualGreater);
+        assert_eq!(p.next(), Token::Greater);
+        assert_eq!(p.next(), Token::Identifier("b".into()));
+        assert_eq!(p.next(), Token::Eof);
+    }
+
+    #[test]
+    fn test_language() {
+        let with_language = |source: &'static [u8], language| {
+            let options = LexerOptions {
+                language,
+                ..Default::default()
+            };
+            let mut p = Lexer::with_options(source, options);
+            let mut tokens = Vec::new();
+            loop {
+                match p.next() {
+                    Token::Eof => break,
+                    tok => tokens.push(tok),
+                }
+            }
+            tokens
+        };
+
+        let source = b"a<=>b";
+        for language in &[Language::Cpp20, Language::Cpp23] {
+            assert_eq!(
+                with_language(source, *language),
+                vec![
+                    Token::Identifier("a".into()),
+                    Token::LowerEqualGreater,
+                    Token::Identifier("b".into()),
+                ]
+            );
+        }
+        for language in &[Language::C11, Language::C23, Language::Cpp17] {
+            assert_eq!(
+                with_language(source, *language),
+                vec![
+                    Token::Identifier("a".into()),
+                    Token::LowerEqual,
+                    Token::Greater,
+                    Token::Identifier("b".into()),
+                ]
+            );
+        }
+
+        let source = b"class a and b; bool c = true; inline";
+        assert_eq!(
+            with_language(source, Language::C99),
+            vec![
+                Token::Identifier("class".into()),
+                Token::Identifier("a".into()),
+                Token::Identifier("and".into()),
+                Token::Identifier("b".into()),
+                Token::SemiColon,
+                Token::Identifier("bool".into()),
+                Token::Identifier("c".into()),
+                Token::Equal,
+                Token::Identifier("true".into()),
+                Token::SemiColon,
+                Token::Inline,
+            ]
+        );
+        assert_eq!(
+            with_language(source, Language::C23)[5..10],
+            [
+                Token::Bool,
+                Token::Identifier("c".into()),
+                Token::Equal,
+                Token::True,
+                Token::SemiColon,
+            ]
+        );
+        assert_eq!(
+            with_language(source, Language::C89).last(),
+            Some(&Token::Identifier("inline".into()))
+        );
+        assert_eq!(
+            with_language(source, Language::Cpp14)[..4],
+            [
+                Token::Class,
+                Token::Identifier("a".into()),
+                Token::AndKw,
+                Token::Identifier("b".into()),
+            ]
+        );
+
+        let source = b"a //* c */ b";
+        assert_eq!(
+            with_language(source, Language::C89),
+            vec![
+                Token::Identifier("a".into()),
+                Token::Divide,
+                Token::Identifier("b".into())
+            ]
+        );
+        assert_eq!(
+            with_language(source, Language::C99),
+            vec![Token::Identifier("a".into())]
+        );
+
+        let source = b"u8'c' u8\"s\" R\"(r)\"";
+        assert_eq!(
+            with_language(source, Language::C11),
+            vec![
+                Token::Identifier("u8".into()),
+                Token::LiteralChar(u32::from('c')),
+                Token::LiteralU8String(b"s".into()),
+                Token::Identifier("R".into()),
+                Token::LiteralString(b"(r)".into()),
+            ]
+        );
+        assert_eq!(
+            with_language(source, Language::C99)[2..4],
+            [
+                Token::Identifier("u8".into()),
+                Token::LiteralString(b"s".into())
+            ]
+        );
+        assert_eq!(
+            with_language(source, Language::Cpp17),
+            vec![
+                Token::LiteralU8Char(u32::from('c')),
+                Token::LiteralU8String(b"s".into()),
+                Token::LiteralRString(b"r".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dot() {
+        let mut p = Lexer::new(b"a.b a.*b a..b f(...)");
+        assert_eq!(p.next(), Token::Identifier("a".into()));
+        assert_eq!(p.next(), Token::Dot);
+        assert_eq!(p.next(), Token::Identifier("b".into()));
+        assert_eq!(p.next(), Token::Identifier("a".into()));
+        assert_eq!(p.next(), Token::DotStar);
+        assert_eq!(p.next(), Token::Identifier("b".into()));
+        assert_eq!(p.next(), Token::Identifier("a".into()));
+        assert_eq!(p.next(), Token::Dot);
+        assert_eq!(p.next(), Token::Dot);
+        assert_eq!(p.next(), Token::Identifier("b".into()));
+        assert_eq!(p.next(), Token::Identifier("f".into()));
+        assert_eq!(p.next(), Token::LeftParen);
+        assert_eq!(p.next(), Token::Ellipsis);
+        assert_eq!(p.next(), Token::RightParen);
+    }
+
+    #[test]
+    fn test_attributes() {
+        let mut p = Lexer::new(
+            b"[[nodiscard]] int f(); a[b[c]]; [[gnu::always_inline]] [ /* x */ [y]] d[e<:0:>] <:<:z:>:>",
+        );
+        let mut tokens = Vec::new();
+        let mut attributes = Vec::new();
+        loop {
+            match p.next() {
+                Token::Eof => break,
+                Token::LeftBrack => {
+                    if p.is_attribute_start() {
+                        attributes.push(tokens.len());
+                    }
+                    tokens.push(Token::LeftBrack);
+                }
+                tok => tokens.push(tok),
+            }
+        }
+
+        assert_eq!(
+            &tokens[..5],
+            &[
+                Token::LeftBrack,
+                Token::LeftBrack,
+                Token::Identifier("nodiscard".into()),
+                Token::RightBrack,
+                Token::RightBrack,
+            ]
+        );
+        assert_eq!(
+            &tokens[11..18],
+            &[
+                Token::LeftBrack,
+                Token::Identifier("b".into()),
+                Token::LeftBrack,
+                Token::Identifier("c".into()),
+                Token::RightBrack,
+                Token::RightBrack,
+                Token::SemiColon,
+            ]
+        );
+        // the first [ of [[nodiscard]], [[gnu::always_inline]], [ [y]] and <:<:z:>:>
+        assert_eq!(attributes, vec![0, 18, 25, 37]);
+    }
+
+    #[test]
+    fn test_borrowed_tokens() {
+        let source = b"#define S \"def\"\n#define ID(x) x\nabc \"xyz\" S ID(abc)";
+        let in_source = |s: &[u8]| source.as_ptr_range().contains(&s.as_ptr());
+        let mut p = Lexer::new(source);
+        let tokens: Vec<_> = (0..4).map(|_| p.next()).collect();
+        drop(p);
+
+        // the tokens in the source aren't copied
+        match &tokens[0] {
+            Token::Identifier(Cow::Borrowed(id)) => assert!(in_source(id.as_bytes())),
+            tok => panic!("unexpected token {:?}", tok),
+        }
+        match &tokens[1] {
+            Token::LiteralString(Cow::Borrowed(s)) => assert!(in_source(s)),
+            tok => panic!("unexpected token {:?}", tok),
+        }
+        // the expansions are copied and outlive the lexer
+        match &tokens[2] {
+            Token::LiteralString(Cow::Owned(s)) => assert_eq!(s, b"def"),
+            tok => panic!("unexpected token {:?}", tok),
+        }
+        match &tokens[3] {
+            Token::Identifier(Cow::Owned(id)) => assert_eq!(id, "abc"),
+            tok => panic!("unexpected token {:?}", tok),
+        }
+    }
+
+    #[test]
+    fn test_modules() {
+        let source = concat!(
+            "module;\n",
+            "#define X 1\n",
+            "export module m;\n",
+            "import std;\n",
+            "export import :part;\n",
+            "import <vector>;\n",
+            "int module = import(1);\n",
+            "x export module n;\n",
+            "module :private;\n",
+        );
+        let mut p = LexerBuilder::new().modules(true).build(source.as_bytes());
+        let mut tokens = Vec::new();
+        loop {
+            match p.next() {
+                Token::Eof => break,
+                Token::Eol => {}
+                tok => tokens.push(tok),
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Module,
+                Token::SemiColon,
+                Token::Export,
+                Token::Module,
+                Token::Identifier("m".into()),
+                Token::SemiColon,
+                Token::Import,
+                Token::Identifier("std".into()),
+                Token::SemiColon,
+                Token::Export,
+                Token::Import,
+                Token::Colon,
+                Token::Identifier("part".into()),
+                Token::SemiColon,
+                Token::Import,
+                Token::Lower,
+                Token::Identifier("vector".into()),
+                Token::Greater,
+                Token::SemiColon,
+                Token::Int,
+                Token::Identifier("module".into()),
+                Token::Equal,
+                Token::Identifier("import".into()),
+                Token::LeftParen,
+                Token::LiteralInt(1),
+                Token::RightParen,
+                Token::SemiColon,
+                Token::Identifier("x".into()),
+                Token::Export,
+                Token::Identifier("module".into()),
+                Token::Identifier("n".into()),
+                Token::SemiColon,
+                Token::Module,
+                Token::Colon,
+                Token::Private,
+                Token::SemiColon,
+            ]
+        );
+
+        // without the flag, they're identifiers
+        let mut p = Lexer::new(b"export module m;");
+        assert_eq!(p.next(), Token::Export);
+        assert_eq!(p.next(), Token::Identifier("module".into()));
+    }
+
+    #[test]
+    fn test_nul() {
+        let mut p = Lexer::new(b"abc\0def \"x\0y\" \0");
+        assert_eq!(p.next(), Token::Identifier("abc".into()));
+        assert_eq!(p.next(), Token::Identifier("def".into()));
+        assert_eq!(p.next(), Token::LiteralString(b"x\0y".into()));
+        assert_eq!(p.next(), Token::Eof);
+        assert!(p.take_diagnostics().is_empty());
+
+        let mut p = LexerBuilder::new()
+            .nul_error(true)
+            .build(b"abc\0def \"x\0y\" \0");
+        assert_eq!(p.next(), Token::Identifier("abc".into()));
+        assert_eq!(p.next(), Token::Invalid(b"\0".into()));
+        assert_eq!(p.next(), Token::Identifier("def".into()));
+        assert_eq!(p.next(), Token::LiteralString(b"x\0y".into()));
+        assert_eq!(p.next(), Token::Invalid(b"\0".into()));
+        assert_eq!(p.next(), Token::Eof);
+        let diagnostics = p.take_diagnostics();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].message, "null character in the source");
+        assert_eq!(diagnostics[0].column, 4);
+    }
+
+    #[test]
+    fn test_digraphs() {
+        let mut p = Lexer::new(b"<% %> <: :> a<::b> c<:::d:> e<::> f<:: ::> %%> <<%");
+        assert_eq!(p.next(), Token::LeftBrace);
+        assert_eq!(p.next(), Token::RightBrace);
+        assert_eq!(p.next(), Token::LeftBrack);
+        assert_eq!(p.next(), Token::RightBrack);
+        assert_eq!(p.next(), Token::Identifier("a".into()));
+        assert_eq!(p.next(), Token::Lower);
+        assert_eq!(p.next(), Token::ColonColon);
+        assert_eq!(p.next(), Token::Identifier("b".into()));
+        assert_eq!(p.next(), Token::Greater);
+        assert_eq!(p.next(), Token::Identifier("c".into()));
+        assert_eq!(p.next(), Token::LeftBrack);
+        assert_eq!(p.next(), Token::ColonColon);
+        assert_eq!(p.next(), Token::Identifier("d".into()));
+        assert_eq!(p.next(), Token::RightBrack);
+        assert_eq!(p.next(), Token::Identifier("e".into()));
+        assert_eq!(p.next(), Token::LeftBrack);
+        assert_eq!(p.next(), Token::RightBrack);
+        assert_eq!(p.next(), Token::Identifier("f".into()));
+        assert_eq!(p.next(), Token::Lower);
+        assert_eq!(p.next(), Token::ColonColon);
+        assert_eq!(p.next(), Token::ColonColon);
+        assert_eq!(p.next(), Token::Greater);
+        assert_eq!(p.next(), Token::Modulo);
+        assert_eq!(p.next(), Token::RightBrace);
+        assert_eq!(p.next(), Token::LeftShift);
+        assert_eq!(p.next(), Token::Modulo);
+        assert_eq!(p.next(), Token::Eof);
+
+        let mut p = Lexer::new(
+            concat!(
+                "%:define S(x) %:x\n",
+                "%:define C(a, b) a %:%: b\n",
+                "%:define D(a, b) a%:%:b\n",
+                "%:if 0\n",
+                "x\n",
+                "%:else\n",
+                "S(y) C(1, 2) D(3, 4)\n",
+                "%:endif\n",
+            )
+            .as_bytes(),
+        );
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralString(b"y".into()));
+        assert_eq!(p.next(), Token::LiteralInt(12));
+        assert_eq!(p.next(), Token::LiteralInt(34));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Eof);
+
+        let options = LexerOptions {
+            preprocessor: false,
+            ..Default::default()
+        };
+        let mut p = Lexer::with_options(b"%:define C(a) a %:%: b", options);
+        assert_eq!(p.next(), Token::Hash);
+        assert_eq!(p.next(), Token::Identifier("define".into()));
+        assert_eq!(p.next(), Token::Identifier("C".into()));
+        assert_eq!(p.next(), Token::LeftParen);
+        assert_eq!(p.next(), Token::Identifier("a".into()));
+        assert_eq!(p.next(), Token::RightParen);
+        assert_eq!(p.next(), Token::Identifier("a".into()));
+        assert_eq!(p.next(), Token::HashHash);
+        assert_eq!(p.next(), Token::Identifier("b".into()));
+        assert_eq!(p.next(), Token::Eof);
+    }
+
     #[test]
     fn test_basic() {
         let mut p = Lexer::new(
@@ -1250,9 +3390,9 @@ mod tests {
 
         assert_eq!(p.next(), Token::While);
         assert_eq!(p.next(), Token::Eol);
-        assert_eq!(p.next(), Token::Identifier("lewhi"));
+        assert_eq!(p.next(), Token::Identifier("lewhi".into()));
         assert_eq!(p.next(), Token::Eol);
-        assert_eq!(p.next(), Token::Identifier("bar"));
-        assert_eq!(p.next(), Token::Identifier("foo"));
+        assert_eq!(p.next(), Token::Identifier("bar".into()));
+        assert_eq!(p.next(), Token::Identifier("foo".into()));
     }
 }
@@ -1,5 +1,5 @@
-use super::lexer::{Lexer, Token};
-use super::pmacros::{Macro, PContext};
+use super::lexer::Lexer;
+use super::pmacros::{separate_tokens, Macro, MacroFunction, MacroOutput, PContext};
 use super::preprocessor;
 use super::string::StringType;
 
@@ -87,18 +87,43 @@ pub(crate) enum MacroArgToken<'a> {
     String(&'a [u8]),
     Id(&'a str),
     Space,
+    // a space separating two tokens in an expansion (see MacroOutput)
+    Separator,
     OpenPar,
     ClosePar,
     Comma,
     Eom,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ArgsError {
+    // the macro name isn't followed by a parenthesis: it isn't an invocation
+    NoParen,
+    // the number of given arguments when it doesn't match the definition
+    WrongCount(usize),
+    // the closing parenthesis is missing
+    Unterminated,
+}
+
+/// The arguments of an invocation being read
+#[derive(Default)]
+struct PartialArgs<'a> {
+    args: Vec<Vec<MacroNode<'a>>>,
+    arg: Vec<MacroNode<'a>>,
+    // the enclosing arguments of the parenthesized arguments
+    stack: Vec<(Vec<Vec<MacroNode<'a>>>, Vec<MacroNode<'a>>)>,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) enum MacroNode<'a> {
     Nothing(&'a [u8]),
     String(&'a [u8]),
     Id(&'a str),
+    // an identifier which mustn't be expanded (see MacroOutput)
+    PaintedId(&'a str),
     Space,
+    // a space only separating two tokens (see MacroOutput): it isn't stringified
+    Separator,
     Args(Vec<Vec<MacroNode<'a>>>),
     VaArgs(Vec<Vec<MacroNode<'a>>>),
 }
@@ -217,8 +242,12 @@ impl<'a> Lexer<'a> {
                         return MacroArgToken::None(s);
                     }
                     Kind::SPA => {
+                        let p = self.pos;
                         self.pos += 1;
                         skip_whites!(self);
+                        if self.pos == p + 1 && self.is_separator(p) {
+                            return MacroArgToken::Separator;
+                        }
                         return MacroArgToken::Space;
                     }
                     Kind::QUO => {
@@ -239,6 +268,9 @@ impl<'a> Lexer<'a> {
                             let s = unsafe { self.buf.get_unchecked(p..p + 1) };
                             return MacroArgToken::None(s);
                         }
+                        // a comment is a space
+                        skip_whites!(self);
+                        return MacroArgToken::Space;
                     }
                     Kind::COM => {
                         self.pos += 1;
@@ -267,12 +299,14 @@ impl<'a> Lexer<'a> {
         MacroArgToken::Eom
     }
 
+    /// Continue to read the arguments of an invocation: true is returned
+    /// when the closing parenthesis is found
     #[inline(always)]
-    pub(crate) fn get_macro_tokens(&mut self, n_args: usize) -> Vec<Vec<MacroNode<'a>>> {
-        let mut args = Vec::with_capacity(n_args);
-        let mut stack = Vec::new();
-        let mut arg = Vec::new();
-
+    fn read_macro_tokens<'s>(&mut self, partial: &mut PartialArgs<'s>) -> bool
+    where
+        'a: 's,
+    {
+        let PartialArgs { args, arg, stack } = partial;
         loop {
             let tok = self.next_arg_token();
             match tok {
@@ -283,7 +317,11 @@ impl<'a> Lexer<'a> {
                     arg.push(MacroNode::String(s));
                 }
                 MacroArgToken::Id(id) => {
-                    arg.push(MacroNode::Id(id));
+                    if self.is_painted(id) {
+                        arg.push(MacroNode::PaintedId(id));
+                    } else {
+                        arg.push(MacroNode::Id(id));
+                    }
                 }
                 MacroArgToken::Space => {
                     if let Some(last) = arg.last() {
@@ -294,32 +332,29 @@ impl<'a> Lexer<'a> {
                         arg.push(MacroNode::Space);
                     }
                 }
+                MacroArgToken::Separator => {
+                    arg.push(MacroNode::Separator);
+                }
                 MacroArgToken::OpenPar => {
-                    stack.push((args, arg));
-                    args = Vec::new();
-                    arg = Vec::new();
+                    stack.push((std::mem::take(args), std::mem::take(arg)));
                 }
                 MacroArgToken::ClosePar => {
-                    args.push(arg);
-                    let (nargs, narg) = if let Some((nargs, mut narg)) = stack.pop() {
-                        narg.push(MacroNode::Args(args));
-                        (nargs, narg)
+                    args.push(std::mem::take(arg));
+                    if let Some((nargs, mut narg)) = stack.pop() {
+                        narg.push(MacroNode::Args(std::mem::replace(args, nargs)));
+                        *arg = narg;
                     } else {
-                        break;
-                    };
-                    args = nargs;
-                    arg = narg;
+                        return true;
+                    }
                 }
                 MacroArgToken::Comma => {
-                    args.push(arg);
-                    arg = Vec::new();
+                    args.push(std::mem::take(arg));
                 }
                 MacroArgToken::Eom => {
-                    break;
+                    return false;
                 }
             }
         }
-        args
     }
 
     #[inline(always)]
@@ -327,42 +362,176 @@ impl<'a> Lexer<'a> {
         &mut self,
         n_args: usize,
         va_args: Option<&usize>,
-    ) -> Option<Vec<Vec<MacroNode<'a>>>> {
-        let spos = self.pos;
-        skip_whites!(self);
-        if self.pos < self.len {
-            let c = self.next_char(0);
-            if c != b'(' {
+    ) -> Result<Vec<Vec<MacroNode<'a>>>, ArgsError> {
+        static NO_PARTIAL: MacroOutput = MacroOutput::new();
+        self.get_arguments_after(&NO_PARTIAL, n_args, va_args)
+    }
+
+    /// Get the arguments of an invocation whose beginning is in partial
+    /// (e.g. the end of a macro expansion): the ones missing there are read in the lexer
+    pub(crate) fn get_arguments_after<'s>(
+        &mut self,
+        partial: &'s MacroOutput,
+        n_args: usize,
+        va_args: Option<&usize>,
+    ) -> Result<Vec<Vec<MacroNode<'s>>>, ArgsError>
+    where
+        'a: 's,
+    {
+        let (spos, line, lpos) = (self.pos, self.line, self.lpos);
+        let mut args = PartialArgs::default();
+        let closed = if partial.iter().all(|c| *c == b' ') {
+            self.skip_to_arguments();
+            if self.pos >= self.len || self.next_char(0) != b'(' {
                 self.pos = spos;
-                return None;
+                self.line = line;
+                self.lpos = lpos;
+                return Err(ArgsError::NoParen);
             }
             self.pos += 1;
+            self.read_macro_tokens(&mut args)
+        } else {
+            let mut lexer = Lexer::new_rescan(partial);
+            skip_whites!(lexer);
+            if lexer.next_char(0) != b'(' {
+                return Err(ArgsError::NoParen);
+            }
+            lexer.pos += 1;
+            lexer.read_macro_tokens(&mut args) || self.read_macro_tokens(&mut args)
+        };
+
+        let args = if closed {
+            MacroNode::check_arguments(args.args, n_args, va_args)
+        } else {
+            Err(ArgsError::Unterminated)
+        };
+        if args.is_err() {
+            self.pos = spos;
+            self.line = line;
+            self.lpos = lpos;
         }
+        args
+    }
 
-        let mut args = self.get_macro_tokens(n_args);
-        if va_args.is_none() {
-            if args.len() != n_args {
-                self.pos = spos;
-                None
+    /// Skip the whites before the arguments of an invocation: they can be
+    /// on the next lines except in a directive
+    fn skip_to_arguments(&mut self) {
+        loop {
+            skip_whites!(self);
+            if self.pos < self.len && self.next_char(0) == b'\n' && !self.context.in_if() {
+                self.pos += 1;
+                self.add_new_line();
             } else {
-                Some(args)
+                break;
             }
-        } else if args.len() < n_args {
-            self.pos = spos;
-            None
-        } else {
-            let va_pos = va_args.unwrap();
-            let va = args.split_off(*va_pos);
-            args.push(vec![MacroNode::VaArgs(va)]);
-            Some(args)
         }
     }
+
+    /// Get the remaining bytes of the buffer with their painted identifiers and separators
+    pub(crate) fn take_rest(&mut self) -> MacroOutput {
+        let mut rest = MacroOutput::default();
+        let (pos, len) = (self.pos, self.len);
+        let shift = |marks: &[usize]| -> Vec<usize> {
+            let first = marks.partition_point(|mark| *mark < pos);
+            marks[first..].iter().map(|mark| mark - pos).collect()
+        };
+        rest.extend_with_marks(
+            &self.buf[pos..],
+            &shift(&self.painted),
+            &shift(&self.separators),
+        );
+        self.pos = len;
+        rest
+    }
 }
 
 impl<'a> MacroNode<'a> {
-    pub(crate) fn eval_nodes(nodes: &[MacroNode<'a>], context: &PContext, out: &mut Vec<u8>) {
+    /// Check the number of arguments and put the variadic ones together
+    pub(crate) fn check_arguments(
+        mut args: Vec<Vec<MacroNode<'a>>>,
+        n_args: usize,
+        va_args: Option<&usize>,
+    ) -> Result<Vec<Vec<MacroNode<'a>>>, ArgsError> {
+        if n_args == 0 && args.len() == 1 && args[0].iter().all(|n| *n == MacroNode::Space) {
+            // F() for a macro without parameters
+            args.clear();
+        }
+        if let Some(va_pos) = va_args {
+            if args.len() + 1 < n_args {
+                // the variadic part can be omitted: F(a) is valid with F(a, ...)
+                Err(ArgsError::WrongCount(args.len()))
+            } else {
+                let va = args.split_off((*va_pos).min(args.len()));
+                args.push(vec![MacroNode::VaArgs(va)]);
+                Ok(args)
+            }
+        } else if args.len() != n_args {
+            Err(ArgsError::WrongCount(args.len()))
+        } else {
+            Ok(args)
+        }
+    }
+
+    /// Get the arguments of the invocation of the macro at pos in the nodes
+    /// and the position of the arguments
+    fn get_invocation(
+        nodes: &[MacroNode<'a>],
+        pos: usize,
+        mac: &MacroFunction,
+        name: &str,
+        context: &PContext,
+    ) -> Option<(Vec<Vec<MacroNode<'a>>>, usize)> {
+        // we can have a white before arguments
+        let mut next = pos + 1;
+        if let Some(MacroNode::Space | MacroNode::Separator) = nodes.get(next) {
+            next += 1;
+        }
+        if let Some(MacroNode::Args(args)) = nodes.get(next) {
+            mac.check_arguments(name, args.clone(), context)
+                .map(|args| (args, next))
+        } else {
+            None
+        }
+    }
+
+    /// Expand the function-like macro ending the last expansion when the nodes
+    /// after pos are its arguments and get the position of the last used node
+    fn eval_pending_function(
+        nodes: &[MacroNode<'a>],
+        mut pos: usize,
+        context: &PContext,
+        out: &mut MacroOutput,
+    ) -> usize {
+        while let Some(start) = context.take_pending_function() {
+            let name = String::from_utf8_lossy(&out[start..]).trim().to_string();
+            if let Some(Macro::Function(mac)) = context.get(&name) {
+                if let Some((args, next)) = Self::get_invocation(nodes, pos, mac, &name, context) {
+                    out.truncate(start);
+                    mac.eval_parsed_args(&name, &args, context, out);
+                    pos = next;
+                    continue;
+                }
+            }
+            break;
+        }
+        pos
+    }
+
+    pub(crate) fn eval_nodes(nodes: &[MacroNode<'a>], context: &PContext, out: &mut MacroOutput) {
+        // the leading and trailing spaces are removed
+        Self::eval_nodes_with_spaces(nodes, true, true, context, out);
+    }
+
+    fn eval_nodes_with_spaces(
+        nodes: &[MacroNode<'a>],
+        trim_start: bool,
+        trim_end: bool,
+        context: &PContext,
+        out: &mut MacroOutput,
+    ) {
         let mut pos = 0;
         let len = nodes.len();
+        let mut junctions = Vec::new();
         while pos < len {
             let node = unsafe { nodes.get_unchecked(pos) };
             match node {
@@ -370,69 +539,124 @@ impl<'a> MacroNode<'a> {
                     out.extend_from_slice(s);
                 }
                 MacroNode::Id(id) => {
-                    if let Some(mac) = context.get(id) {
-                        match mac {
+                    if *id == "defined" && context.in_if() {
+                        // the operand of defined mustn't be expanded
+                        out.extend_from_slice(b"defined");
+                        while pos + 1 < len {
+                            let node = unsafe { nodes.get_unchecked(pos + 1) };
+                            match node {
+                                MacroNode::Space => {
+                                    out.push(b' ');
+                                }
+                                MacroNode::Separator => {
+                                    out.push_separator();
+                                }
+                                MacroNode::Id(_) | MacroNode::PaintedId(_) | MacroNode::Args(_) => {
+                                    Self::make_expr(std::slice::from_ref(node), out);
+                                    pos += 1;
+                                    break;
+                                }
+                                _ => {
+                                    break;
+                                }
+                            }
+                            pos += 1;
+                        }
+                    } else if let Some(mac) = context.get(id) {
+                        junctions.push(out.len());
+                        context.set_pending_function(None);
+                        let expanded = match mac {
                             Macro::Object(mac) => {
-                                mac.eval(out, context);
+                                mac.eval(id, out, context);
+                                true
                             }
                             Macro::Function(mac) => {
-                                // we can have a white before arguments
-                                pos += 1;
-                                let node = unsafe { nodes.get_unchecked(pos) };
-                                let node = match *node {
-                                    MacroNode::Space => {
-                                        pos += 1;
-                                        unsafe { nodes.get_unchecked(pos) }
-                                    }
-                                    _ => node,
-                                };
-                                if let MacroNode::Args(args) = node {
-                                    mac.eval_parsed_args(&args, context, out);
+                                if let Some((args, next)) =
+                                    Self::get_invocation(nodes, pos, mac, id, context)
+                                {
+                                    mac.eval_parsed_args(id, &args, context, out);
+                                    pos = next;
+                                    true
+                                } else {
+                                    // not an invocation: the arguments are kept as is
+                                    out.extend_from_slice(id.as_bytes());
+                                    false
                                 }
                             }
+                        };
+                        if expanded {
+                            pos = Self::eval_pending_function(nodes, pos, context, out);
                         }
-                    } else {
+                        junctions.push(out.len());
+                    } else if context.in_use(id) {
+                        out.push_painted(id);
+                    } else if !context.eval_builtin(id, out) {
                         out.extend_from_slice(id.as_bytes());
                     }
                 }
+                MacroNode::PaintedId(id) => {
+                    // a painted identifier is never expanded
+                    out.push_painted(id);
+                }
                 MacroNode::Space => {
-                    if pos != 0 && pos != len - 1 {
+                    if (pos != 0 || !trim_start) && (pos != len - 1 || !trim_end) {
                         out.push(b' ');
                     }
                 }
+                MacroNode::Separator => {
+                    out.push_separator();
+                }
                 MacroNode::Args(nodes) => {
+                    // the spaces in the parentheses are kept
                     out.push(b'(');
                     if let Some((last, nodes)) = nodes.split_last() {
                         for arg in nodes {
-                            Self::eval_nodes(arg, context, out);
+                            Self::eval_nodes_with_spaces(arg, false, false, context, out);
                             out.push(b',');
                         }
-                        Self::eval_nodes(last, context, out);
+                        Self::eval_nodes_with_spaces(last, false, false, context, out);
                     }
                     out.push(b')');
                 }
                 MacroNode::VaArgs(nodes) => {
+                    // the spaces around the commas separating the variadic arguments are kept
                     if let Some((last, nodes)) = nodes.split_last() {
+                        let mut first = true;
                         for arg in nodes {
-                            Self::eval_nodes(arg, context, out);
+                            Self::eval_nodes_with_spaces(
+                                arg,
+                                first && trim_start,
+                                false,
+                                context,
+                                out,
+                            );
                             out.push(b',');
+                            first = false;
                         }
-                        Self::eval_nodes(last, context, out);
+                        Self::eval_nodes_with_spaces(
+                            last,
+                            first && trim_start,
+                            trim_end,
+                            context,
+                            out,
+                        );
                     }
                 }
             }
             pos += 1;
         }
+        separate_tokens(out, &junctions);
     }
 
-    pub(crate) fn make_expr(nodes: &[MacroNode<'a>], out: &mut Vec<u8>) {
+    pub(crate) fn make_expr(nodes: &[MacroNode<'a>], out: &mut MacroOutput) {
         let len = nodes.len();
         for (pos, node) in nodes.iter().enumerate() {
             match node {
                 MacroNode::Nothing(s) | MacroNode::String(s) => {
                     out.extend_from_slice(s);
                 }
-                MacroNode::Id(id) => {
+                MacroNode::Id(id) | MacroNode::PaintedId(id) => {
+                    // a pasted token is a new token so it isn't painted
                     out.extend_from_slice(id.as_bytes());
                 }
                 MacroNode::Space => {
@@ -440,6 +664,9 @@ impl<'a> MacroNode<'a> {
                         out.push(b' ');
                     }
                 }
+                MacroNode::Separator => {
+                    out.push_separator();
+                }
                 MacroNode::Args(nodes) => {
                     out.push(b'(');
                     if let Some((last, nodes)) = nodes.split_last() {
@@ -464,7 +691,31 @@ impl<'a> MacroNode<'a> {
         }
     }
 
+    /// Stringify a text made of tokens (e.g. the substituted content of #__VA_OPT__(...))
+    pub(crate) fn stringify_text(text: &MacroOutput, out: &mut Vec<u8>) {
+        // the text is read as the variadic arguments of an invocation
+        let mut invocation = MacroOutput::from(b"(".to_vec());
+        invocation.extend_from_output(text);
+        invocation.push(b')');
+        let mut lexer = Lexer::new_rescan(&invocation);
+        out.push(b'"');
+        if let Ok(args) = lexer.get_arguments(1, Some(&0)) {
+            MacroNode::make_string(&args[0], out);
+        }
+        out.push(b'"');
+    }
+
     pub(crate) fn make_string(nodes: &[MacroNode<'a>], out: &mut Vec<u8>) {
+        // the leading and trailing spaces are removed
+        Self::make_string_with_spaces(nodes, true, true, out);
+    }
+
+    fn make_string_with_spaces(
+        nodes: &[MacroNode<'a>],
+        trim_start: bool,
+        trim_end: bool,
+        out: &mut Vec<u8>,
+    ) {
         let len = nodes.len();
         for (pos, node) in nodes.iter().enumerate() {
             match node {
@@ -472,41 +723,50 @@ impl<'a> MacroNode<'a> {
                     out.extend_from_slice(s);
                 }
                 MacroNode::String(s) => {
-                    // Need to escape chars
+                    // the " and the \ of the string and char literals are escaped
+                    // (and the newlines of the raw strings to keep the string on one line)
                     for c in s.iter() {
-                        let c = *c;
-                        if c == b'\'' || c == b'\"' || c == b'\n' {
-                            out.push(b'\\');
+                        match *c {
+                            b'\n' => out.extend_from_slice(b"\\n"),
+                            c @ b'\"' | c @ b'\\' => {
+                                out.push(b'\\');
+                                out.push(c);
+                            }
+                            c => out.push(c),
                         }
-                        out.push(c);
                     }
                 }
-                MacroNode::Id(id) => {
+                MacroNode::Id(id) | MacroNode::PaintedId(id) => {
                     out.extend_from_slice(id.as_bytes());
                 }
                 MacroNode::Space => {
-                    if pos != 0 && pos != len - 1 {
+                    if (pos != 0 || !trim_start) && (pos != len - 1 || !trim_end) {
                         out.push(b' ');
                     }
                 }
+                // the tokens are only kept apart when the output is rescanned
+                MacroNode::Separator => {}
                 MacroNode::Args(nodes) => {
+                    // the spaces inside the parenthesis are kept
                     out.push(b'(');
                     if let Some((last, nodes)) = nodes.split_last() {
                         for arg in nodes {
-                            Self::make_string(arg, out);
+                            Self::make_string_with_spaces(arg, false, false, out);
                             out.push(b',');
                         }
-                        Self::make_string(last, out);
+                        Self::make_string_with_spaces(last, false, false, out);
                     }
                     out.push(b')');
                 }
                 MacroNode::VaArgs(nodes) => {
                     if let Some((last, nodes)) = nodes.split_last() {
+                        let mut first = true;
                         for arg in nodes {
-                            Self::make_string(arg, out);
+                            Self::make_string_with_spaces(arg, first && trim_start, false, out);
                             out.push(b',');
+                            first = false;
                         }
-                        Self::make_string(last, out);
+                        Self::make_string_with_spaces(last, first && trim_start, trim_end, out);
                     }
                 }
             }
@@ -562,11 +822,55 @@ mod tests {
         assert_eq!(res, exp);
     }
 
+    #[test]
+    fn test_arg_nested() {
+        // only the parentheses are balanced: the commas in the other brackets separate
+        let mut p = Lexer::new(b"(a, (b,c), (), \"d,e\", ',', {f, g}, h<i, j>k)");
+        let exp = vec![
+            vec![Id("a")],
+            vec![Space, Args(vec![vec![Id("b")], vec![Id("c")]])],
+            vec![Space, Args(vec![vec![]])],
+            vec![Space, String(b"\"d,e\"")],
+            vec![Space, String(b"','")],
+            vec![Space, Nothing(b"{"), Id("f")],
+            vec![Space, Id("g"), Nothing(b"}")],
+            vec![Space, Id("h"), Nothing(b"<"), Id("i")],
+            vec![Space, Id("j"), Nothing(b">"), Id("k")],
+        ];
+        let res = p.get_arguments(9, None).unwrap();
+
+        assert_eq!(res, exp);
+
+        let mut p = Lexer::new(b"(a, (b,c))");
+        assert_eq!(p.get_arguments(3, None), Err(ArgsError::WrongCount(2)));
+    }
+
+    #[test]
+    fn test_arg_empty() {
+        let mut p = Lexer::new(b"()");
+        assert_eq!(
+            p.get_arguments(0, None).unwrap(),
+            Vec::<Vec<MacroNode>>::new()
+        );
+
+        let mut p = Lexer::new(b"( )");
+        assert_eq!(p.get_arguments(1, None).unwrap(), vec![vec![Space]]);
+
+        let mut p = Lexer::new(b"()");
+        assert_eq!(p.get_arguments(1, None).unwrap(), vec![vec![]]);
+
+        let mut p = Lexer::new(b"(,)");
+        assert_eq!(p.get_arguments(2, None).unwrap(), vec![vec![], vec![]]);
+
+        let mut p = Lexer::new(b"(,)");
+        assert_eq!(p.get_arguments(1, None), Err(ArgsError::WrongCount(2)));
+    }
+
     #[test]
     fn test_make_expr1() {
         let mut p = Lexer::new(b"(   a /* comment */  , b + 1)");
         let args = Args(p.get_arguments(2, None).unwrap());
-        let mut out = Vec::new();
+        let mut out = MacroOutput::default();
         MacroNode::make_expr(&vec![args], &mut out);
         let res = std::str::from_utf8(&out).unwrap();
         let exp = "(a,b + 1)";
@@ -578,7 +882,7 @@ mod tests {
     fn test_make_expr2() {
         let mut p = Lexer::new(b"(a, b, foo(x+1, y * 2, bar (z,t)))");
         let args = Args(p.get_arguments(3, None).unwrap());
-        let mut out = Vec::new();
+        let mut out = MacroOutput::default();
         MacroNode::make_expr(&vec![args], &mut out);
         let res = std::str::from_utf8(&out).unwrap();
         let exp = "(a,b,foo(x+1,y * 2,bar (z,t)))";
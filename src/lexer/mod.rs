@@ -21,3 +21,45 @@ pub use self::macro_args::*;
 
 pub mod condition;
 pub use self::condition::*;
+
+pub mod errors;
+pub use self::errors::*;
+
+pub mod include;
+pub use self::include::*;
+
+pub mod sources;
+pub use self::sources::*;
+
+pub mod span;
+pub use self::span::*;
+
+pub mod incremental;
+pub use self::incremental::*;
+
+pub mod trace;
+pub use self::trace::*;
+
+pub mod profile;
+pub use self::profile::*;
+
+pub mod interner;
+pub use self::interner::*;
+
+pub mod stats;
+pub use self::stats::*;
+
+mod translation;
+
+#[cfg(feature = "serde")]
+mod serialization;
+
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "json")]
+pub use self::json::*;
+
+mod display;
+
+pub mod tokenizer;
+pub use self::tokenizer::*;
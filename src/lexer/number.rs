@@ -1,7 +1,7 @@
 use bitflags::bitflags;
 use phf::phf_map;
 
-use super::lexer::{Lexer, Token};
+use super::lexer::{Lexer, LexerOptions, Token};
 
 const POW_P_10: [f64; 309] = [
     1., 1e1, 1e2, 1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10, 1e11, 1e12, 1e13, 1e14, 1e15, 1e16,
@@ -150,37 +150,61 @@ const NUMCHARS: [Nums; 256] = [
     Nums::NON, Nums::NON, Nums::NON, Nums::NON, Nums::NON, Nums::NON, Nums::NON, Nums::NON, //
 ];
 
-enum IntType {
+/// The suffix of an integer literal: z is the signed size_t suffix (C++23)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntSuffix {
+    None,
     U,
     L,
     UL,
     LL,
     ULL,
+    Z,
+    UZ,
 }
 
-static INT_SUFFIXES: phf::Map<&'static str, IntType> = phf_map! {
-    "u" => IntType::U,
-    "U" => IntType::U,
-    "l" => IntType::L,
-    "L" => IntType::L,
-    "ul" => IntType::UL,
-    "Ul" => IntType::UL,
-    "uL" => IntType::UL,
-    "UL" => IntType::UL,
-    "lu" => IntType::UL,
-    "lU" => IntType::UL,
-    "Lu" => IntType::UL,
-    "LU" => IntType::UL,
-    "ll" => IntType::LL,
-    "LL" => IntType::LL,
-    "llu" => IntType::ULL,
-    "llU" => IntType::ULL,
-    "LLu" => IntType::ULL,
-    "LLU" => IntType::ULL,
-    "ull" => IntType::ULL,
-    "Ull" => IntType::ULL,
-    "uLL" => IntType::ULL,
-    "ULL" => IntType::ULL,
+/// The base of an integer literal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntBase {
+    Decimal,
+    Hex,
+    Octal,
+    Binary,
+}
+
+static INT_SUFFIXES: phf::Map<&'static str, IntSuffix> = phf_map! {
+    "u" => IntSuffix::U,
+    "U" => IntSuffix::U,
+    "l" => IntSuffix::L,
+    "L" => IntSuffix::L,
+    "ul" => IntSuffix::UL,
+    "Ul" => IntSuffix::UL,
+    "uL" => IntSuffix::UL,
+    "UL" => IntSuffix::UL,
+    "lu" => IntSuffix::UL,
+    "lU" => IntSuffix::UL,
+    "Lu" => IntSuffix::UL,
+    "LU" => IntSuffix::UL,
+    "ll" => IntSuffix::LL,
+    "LL" => IntSuffix::LL,
+    "llu" => IntSuffix::ULL,
+    "llU" => IntSuffix::ULL,
+    "LLu" => IntSuffix::ULL,
+    "LLU" => IntSuffix::ULL,
+    "ull" => IntSuffix::ULL,
+    "Ull" => IntSuffix::ULL,
+    "uLL" => IntSuffix::ULL,
+    "ULL" => IntSuffix::ULL,
+    "z" => IntSuffix::Z,
+    "Z" => IntSuffix::Z,
+    "uz" => IntSuffix::UZ,
+    "uZ" => IntSuffix::UZ,
+    "Uz" => IntSuffix::UZ,
+    "UZ" => IntSuffix::UZ,
+    "zu" => IntSuffix::UZ,
+    "zU" => IntSuffix::UZ,
+    "Zu" => IntSuffix::UZ,
+    "ZU" => IntSuffix::UZ,
 };
 
 #[inline(always)]
@@ -202,72 +226,248 @@ pub(crate) fn get_decimal(dec: u64, exp: i64) -> f64 {
     }
 }
 
+/// The suffix of a floating literal: f for a float and l for a long double
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FloatSuffix {
+    None,
+    F,
+    L,
+}
+
+/// A floating literal: its value is mantissa * 10^exponent
+/// or mantissa * 2^exponent for an hexadecimal one (e.g. 0x1.8p3 is 0x18 * 2^-1)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FloatLiteral {
+    pub mantissa: u64,
+    pub exponent: i64,
+    pub hex: bool,
+    pub suffix: FloatSuffix,
+}
+
+impl FloatLiteral {
+    /// Decompose the spelling of a floating literal (e.g. 1.5e3f or 0x1.8p3)
+    pub fn parse(spelling: &[u8]) -> Result<Self, String> {
+        let mut lexer = Lexer::new_translated(spelling, LexerOptions::default());
+        let literal = lexer.get_float_literal()?;
+        if lexer.pos == lexer.len {
+            Ok(literal)
+        } else {
+            Err(format!(
+                "invalid floating constant \"{}\"",
+                String::from_utf8_lossy(spelling)
+            ))
+        }
+    }
+
+    pub fn value(&self) -> f64 {
+        if !self.hex {
+            get_decimal(self.mantissa, self.exponent)
+        } else if self.mantissa == 0 {
+            0.
+        } else {
+            let exp = self
+                .exponent
+                .max(i64::from(i32::MIN))
+                .min(i64::from(i32::MAX));
+            (self.mantissa as f64) * 2f64.powi(exp as i32)
+        }
+    }
+
+    fn to_token<'a>(self) -> Token<'a> {
+        match self.suffix {
+            FloatSuffix::None => Token::LiteralDecimal(self.value()),
+            FloatSuffix::F => Token::LiteralFloat(self.value()),
+            FloatSuffix::L => Token::LiteralLongDouble(self.value()),
+        }
+    }
+}
+
+/// An integer literal (e.g. 0xFFull is 255 in base 16 with the suffix ull)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IntLiteral {
+    pub value: u64,
+    pub base: IntBase,
+    pub suffix: IntSuffix,
+}
+
+impl IntLiteral {
+    /// Decompose the spelling of an integer literal (e.g. 0777 or 0b101u)
+    pub fn parse(spelling: &[u8]) -> Result<Self, String> {
+        let mut lexer = Lexer::new_translated(spelling, LexerOptions::default());
+        match lexer.get_int_literal()? {
+            Some(literal) if lexer.pos == lexer.len => Ok(literal),
+            _ => Err(format!(
+                "invalid integer constant \"{}\"",
+                String::from_utf8_lossy(spelling)
+            )),
+        }
+    }
+
+    /// Get the token: the base is only kept for the literals without suffix
+    pub fn to_token<'a>(self) -> Token<'a> {
+        let x = self.value;
+        match self.suffix {
+            IntSuffix::None => match self.base {
+                IntBase::Decimal => Token::LiteralInt(x),
+                IntBase::Hex => Token::LiteralHex(x),
+                IntBase::Octal => Token::LiteralOct(x),
+                IntBase::Binary => Token::LiteralBin(x),
+            },
+            IntSuffix::U => Token::LiteralUInt(x),
+            IntSuffix::L => Token::LiteralLong(x),
+            IntSuffix::UL => Token::LiteralULong(x),
+            IntSuffix::LL => Token::LiteralLongLong(x),
+            IntSuffix::ULL => Token::LiteralULongLong(x),
+            IntSuffix::Z => Token::LiteralSignedSize(x),
+            IntSuffix::UZ => Token::LiteralSize(x),
+        }
+    }
+}
+
 impl<'a> Lexer<'a> {
+    /// Get the floating literal starting at spos
     #[inline(always)]
-    pub(crate) fn get_exponent(&mut self) -> i64 {
-        if self.pos < self.len {
+    pub(crate) fn get_float(&mut self, spos: usize) -> Token<'a> {
+        self.pos = spos;
+        match self.get_float_literal() {
+            Ok(literal) => literal.to_token(),
+            Err(message) => self.get_invalid(message),
+        }
+    }
+
+    pub(crate) fn get_float_literal(&mut self) -> Result<FloatLiteral, String> {
+        let hex = self.pos + 1 < self.len
+            && self.next_char(0) == b'0'
+            && self.next_char(1) | 0x20 == b'x';
+        if hex {
+            self.pos += 2;
+        }
+        // a digit is 4 bits in an hexadecimal literal
+        let (radix, shift) = if hex { (16, 4) } else { (10, 1) };
+        let mut mantissa: u64 = 0;
+        let mut exponent: i64 = 0;
+        let mut dot = false;
+        let mut digits = 0;
+        while self.pos < self.len {
             let c = self.next_char(0);
-            let sign = c == b'-';
-            let c = if sign || c == b'+' {
-                self.pos += 1;
-                self.next_char(0)
+            let d = if hex {
+                Self::get_hex_num(c)
+            } else if c.is_ascii_digit() {
+                u64::from(c - b'0')
             } else {
-                c
+                radix
             };
+            if d < radix {
+                digits += 1;
+                if mantissa <= (u64::MAX - d) / radix {
+                    mantissa = mantissa * radix + d;
+                    if dot {
+                        exponent -= shift;
+                    }
+                } else if !dot {
+                    // the digits which don't fit are just ignored
+                    exponent += shift;
+                }
+            } else if !(c == b'.' && !dot || c == b'\'') {
+                break;
+            }
+            dot = dot || c == b'.';
+            self.pos += 1;
+        }
+        if digits == 0 {
+            self.skip_pp_number();
+            return Err("no digits in hexadecimal floating constant".to_string());
+        }
 
+        let exp_char = if hex { b'p' } else { b'e' };
+        if self.pos < self.len && self.next_char(0) | 0x20 == exp_char {
             self.pos += 1;
-            let first = u64::from(c - b'0');
-            let num = self.get_int(first) as i64;
-            if sign {
-                -num
-            } else {
-                num
+            let sign = self.pos < self.len && matches!(self.next_char(0), b'+' | b'-');
+            let first = self.pos + sign as usize;
+            if first >= self.len || !self.buf[first].is_ascii_digit() {
+                self.pos = first;
+                self.skip_pp_number();
+                return Err("exponent has no digits".to_string());
             }
-        } else {
-            0
+            exponent = exponent.saturating_add(self.get_exponent());
+        } else if hex {
+            self.skip_pp_number();
+            return Err("hexadecimal floating constants require an exponent".to_string());
         }
+
+        Ok(FloatLiteral {
+            mantissa,
+            exponent,
+            hex,
+            suffix: self.get_float_suffix()?,
+        })
     }
 
-    #[inline(always)]
-    pub(crate) fn get_number_after_dot(&mut self, start: u64) -> (u64, i64) {
+    fn get_float_suffix(&mut self) -> Result<FloatSuffix, String> {
         let spos = self.pos;
-        let mut num = start;
-        loop {
-            if self.pos < self.len {
-                let c = self.next_char(0);
+        while self.pos < self.len && matches!(self.next_char(0), b'f' | b'F' | b'l' | b'L') {
+            self.pos += 1;
+        }
+        if self.pos < self.len {
+            let c = self.next_char(0);
+            if c.is_ascii_alphanumeric() || c == b'_' {
+                // an user-defined suffix (e.g. 1.5_km or 2.0if)
+                self.pos = spos;
+                return Ok(FloatSuffix::None);
+            }
+        }
+        match &self.buf[spos..self.pos] {
+            b"" => Ok(FloatSuffix::None),
+            b"f" | b"F" => Ok(FloatSuffix::F),
+            b"l" | b"L" => Ok(FloatSuffix::L),
+            suffix => Err(format!(
+                "invalid suffix \"{}\" on floating constant",
+                String::from_utf8_lossy(suffix)
+            )),
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn get_exponent(&mut self) -> i64 {
+        let mut sign = false;
+        if self.pos < self.len {
+            let c = self.next_char(0);
+            if c == b'-' || c == b'+' {
+                sign = c == b'-';
+                self.pos += 1;
+            }
+        }
+
+        let mut num: i64 = 0;
+        while self.pos < self.len {
+            let c = self.next_char(0);
+            if c.is_ascii_digit() {
+                self.pos += 1;
+                num = num.saturating_mul(10).saturating_add(i64::from(c - b'0'));
+            } else if c == b'\'' {
                 self.pos += 1;
-                if num <= (std::u64::MAX / 10) && b'0' <= c && c <= b'9' {
-                    num = 10 * num + u64::from(c - b'0');
-                } else if c == b'e' {
-                    let shift = (self.pos - spos) as i64;
-                    let exp = self.get_exponent();
-                    return (num, exp - shift);
-                } else {
-                    let shift = (self.pos - spos) as i64;
-                    return (num, -shift);
-                }
             } else {
-                let shift = (self.pos - spos) as i64;
-                return (num, -shift);
+                break;
             }
         }
+
+        if sign {
+            -num
+        } else {
+            num
+        }
     }
 
     #[inline(always)]
     pub(crate) fn get_dot_or_number(&mut self) -> Token<'a> {
         if self.pos < self.len {
             let c = self.next_char(0);
-            self.pos += 1;
-            if b'0' <= c && c <= b'9' {
-                let (dec, exp) = self.get_number_after_dot(u64::from(c - b'0'));
-                return Token::LiteralDecimal(get_decimal(dec, exp));
+            if c.is_ascii_digit() {
+                return self.get_float(self.pos - 1);
             } else if c == b'.' {
-                if self.pos < self.len {
-                    let c = self.next_char(0);
-                    if c == b'.' {
-                        self.pos += 1;
-                        return Token::Ellipsis;
-                    }
+                if self.pos + 1 < self.len && self.next_char(1) == b'.' {
+                    self.pos += 2;
+                    return Token::Ellipsis;
                 }
             } else if c == b'*' {
                 self.pos += 1;
@@ -282,190 +482,146 @@ impl<'a> Lexer<'a> {
         unsafe { *HEX.get_unchecked(c as usize) }
     }
 
-    #[inline(always)]
-    pub(crate) fn get_hex(&mut self) -> Token<'a> {
-        let mut num = 0;
-        loop {
-            if self.pos < self.len {
-                let c = self.next_char(0);
-                let n = Self::get_hex_num(c);
-                if n < 16 {
-                    self.pos += 1;
-                    num = 16 * num + n;
-                } else if c == b'\'' {
-                    self.pos += 1;
-                } else {
-                    break;
-                }
+    // Skip the remaining chars of a malformed number
+    fn skip_pp_number(&mut self) {
+        while self.pos < self.len {
+            let c = self.next_char(0);
+            if c.is_ascii_alphanumeric() || c == b'_' || c == b'\'' {
+                self.pos += 1;
             } else {
                 break;
             }
         }
-        Token::LiteralHex(num)
     }
 
     #[inline(always)]
-    pub(crate) fn get_oct(&mut self, start: u64) -> Token<'a> {
-        let mut num = start;
-        loop {
-            if self.pos < self.len {
-                let c = self.next_char(0);
-                if b'0' <= c && c <= b'7' {
-                    self.pos += 1;
-                    num = 8 * num + u64::from(c - b'0');
-                } else if c == b'\'' {
-                    self.pos += 1;
-                } else {
-                    break;
-                }
-            } else {
-                break;
-            }
+    fn is_float_part(&self, hex: bool) -> bool {
+        if self.pos >= self.len {
+            return false;
+        }
+        match self.next_char(0) {
+            b'.' => true,
+            b'e' | b'E' => !hex,
+            b'p' | b'P' => hex,
+            _ => false,
         }
-        Token::LiteralOct(num)
     }
 
-    #[inline(always)]
-    fn get_bin(&mut self) -> Token<'a> {
-        let mut num = 0;
-        loop {
-            if self.pos < self.len {
-                let c = self.next_char(0);
-                if b'0' <= c && c <= b'1' {
-                    self.pos += 1;
-                    num = 2 * num + u64::from(c - b'0');
-                } else if c == b'\'' {
-                    self.pos += 1;
-                } else {
-                    break;
-                }
-            } else {
-                break;
+    /// Get the integer literal at the current position or None if it's a floating one
+    pub(crate) fn get_int_literal(&mut self) -> Result<Option<IntLiteral>, String> {
+        let spos = self.pos;
+        let mut base = IntBase::Decimal;
+        if self.pos + 1 < self.len && self.next_char(0) == b'0' {
+            match self.next_char(1) | 0x20 {
+                b'x' => base = IntBase::Hex,
+                b'b' => base = IntBase::Binary,
+                c if c.is_ascii_digit() => base = IntBase::Octal,
+                _ => {}
+            }
+            if matches!(base, IntBase::Hex | IntBase::Binary) {
+                self.pos += 2;
             }
         }
-        return Token::LiteralBin(num);
-    }
+        let radix = match base {
+            IntBase::Decimal => 10,
+            IntBase::Hex => 16,
+            IntBase::Octal => 8,
+            IntBase::Binary => 2,
+        };
 
-    #[inline(always)]
-    pub(crate) fn get_int(&mut self, start: u64) -> u64 {
-        let mut num = start;
-        loop {
-            if self.pos < self.len {
-                let c = self.next_char(0);
-                if b'0' <= c && c <= b'9' {
-                    self.pos += 1;
-                    num = 10 * num + u64::from(c - b'0');
-                } else if c == b'\'' {
-                    self.pos += 1;
-                } else {
-                    break;
-                }
+        let dpos = self.pos;
+        let mut value: u64 = 0;
+        let mut overflow = false;
+        let mut bad_digit = None;
+        while self.pos < self.len {
+            let c = self.next_char(0);
+            if c == b'\'' {
+                self.pos += 1;
+                continue;
+            }
+            let d = if base == IntBase::Hex {
+                Self::get_hex_num(c)
+            } else if c.is_ascii_digit() {
+                u64::from(c - b'0')
             } else {
+                16
+            };
+            if d >= 16 {
                 break;
+            } else if d >= radix {
+                // 09 can still be the beginning of a floating literal as 09.5
+                bad_digit = bad_digit.or(Some(c));
+            } else if let Some(v) = value.checked_mul(radix).and_then(|v| v.checked_add(d)) {
+                value = v;
+            } else {
+                overflow = true;
             }
+            self.pos += 1;
+        }
+
+        if base != IntBase::Binary && self.is_float_part(base == IntBase::Hex) {
+            self.pos = spos;
+            return Ok(None);
+        }
+        if self.pos == dpos && base != IntBase::Decimal {
+            let prefix = self.buf[spos + 1];
+            self.skip_pp_number();
+            return Err(format!(
+                "invalid suffix \"{}\" on integer constant",
+                char::from(prefix)
+            ));
         }
-        return num;
+        if let Some(c) = bad_digit {
+            self.skip_pp_number();
+            let kind = if base == IntBase::Octal {
+                "octal"
+            } else {
+                "binary"
+            };
+            return Err(format!(
+                "invalid digit \"{}\" in {} constant",
+                char::from(c),
+                kind
+            ));
+        }
+        if overflow {
+            self.skip_pp_number();
+            return Err("integer constant is too large for its type".to_string());
+        }
+
+        Ok(Some(IntLiteral {
+            value,
+            base,
+            suffix: self.get_int_suffix(),
+        }))
     }
 
-    #[inline(always)]
-    pub(crate) fn get_typed_int(&mut self, num: u64) -> Token<'a> {
+    fn get_int_suffix(&mut self) -> IntSuffix {
         if self.pos < self.len {
             let c = self.next_char(0);
-            if c == b'u' || c == b'l' || c == b'L' || c == b'U' {
+            if matches!(c, b'u' | b'U' | b'l' | b'L' | b'z' | b'Z') {
+                let spos = self.pos;
                 self.pos += 1;
                 let id = self.get_identifier_str();
-                if let Some(suf) = INT_SUFFIXES.get(id) {
-                    return match suf {
-                        IntType::U => Token::LiteralUInt(num),
-                        IntType::L => Token::LiteralLong(num),
-                        IntType::UL => Token::LiteralULong(num),
-                        IntType::LL => Token::LiteralLongLong(num),
-                        IntType::ULL => Token::LiteralULongLong(num),
-                    };
+                if let Some(suffix) = INT_SUFFIXES.get(id) {
+                    return *suffix;
                 }
+                // not an integer suffix (e.g. 10us)
+                self.pos = spos;
             }
         }
-        Token::LiteralInt(num)
+        IntSuffix::None
     }
 
     #[inline(always)]
-    pub(crate) fn get_number(&mut self, start: u64) -> Token<'a> {
-        let num = start;
-        if self.pos < self.len {
-            if num == 0 {
-                let c = self.next_char(0);
-                if c == b'x' || c == b'X' {
-                    // hex
-                    self.pos += 1;
-                    return self.get_hex();
-                } else if c == b'b' {
-                    // binary
-                    self.pos += 1;
-                    return self.get_bin();
-                } else if b'0' <= c && c <= b'9' {
-                    // octal
-                    self.pos += 1;
-                    return self.get_oct(u64::from(c - b'0'));
-                } else if c == b'e' {
-                    // We've 0e....: useless so just consume exponent and return 0.
-                    self.pos += 1;
-                    let _ = self.get_exponent();
-                    return Token::LiteralDecimal(0.);
-                } else if c == b'.' {
-                    self.pos += 1;
-                    if self.pos < self.len {
-                        let c = self.next_char(0);
-                        if b'0' <= c && c <= b'9' {
-                            self.pos += 1;
-                            let (dec, exp) = self.get_number_after_dot(u64::from(c - b'0'));
-                            return Token::LiteralDecimal(get_decimal(dec, exp));
-                        } else if c == b'e' {
-                            self.pos += 1;
-                            let _ = self.get_exponent();
-                            return Token::LiteralDecimal(0.);
-                        } else {
-                            return Token::LiteralDecimal(0.);
-                        }
-                    } else {
-                        return Token::LiteralDecimal(0.);
-                    }
-                }
-            } else {
-                let num = self.get_int(num);
-                if self.pos < self.len {
-                    let c = self.next_char(0);
-                    if c == b'.' {
-                        self.pos += 1;
-                        let c = self.next_char(0);
-                        self.pos += 1;
-                        if b'0' <= c && c <= b'9' {
-                            if num > (std::u64::MAX / 10) {
-                                let _ = self.get_number_after_dot(0);
-                                return Token::LiteralDecimal(num as f64);
-                            }
-                            let num = 10 * num + u64::from(c - b'0');
-                            let (dec, exp) = self.get_number_after_dot(num);
-                            return Token::LiteralDecimal(get_decimal(dec, exp));
-                        } else if c == b'e' {
-                            let exp = self.get_exponent();
-                            return Token::LiteralDecimal(get_decimal(num, exp));
-                        } else {
-                            return Token::LiteralDecimal(num as f64);
-                        }
-                    } else if c == b'e' {
-                        self.pos += 1;
-                        let exp = self.get_exponent();
-                        return Token::LiteralDecimal(get_decimal(num, exp));
-                    } else {
-                        return self.get_typed_int(num);
-                    }
-                } else {
-                    return Token::LiteralInt(num);
-                }
-            }
+    pub(crate) fn get_number(&mut self) -> Token<'a> {
+        let spos = self.pos - 1;
+        self.pos = spos;
+        match self.get_int_literal() {
+            Ok(Some(literal)) => literal.to_token(),
+            Ok(None) => self.get_float(spos),
+            Err(message) => self.get_invalid(message),
         }
-
-        return Token::LiteralInt(num);
     }
 
     #[inline(always)]
@@ -1,11 +1,19 @@
 use bitflags::bitflags;
-use hashbrown::HashMap;
-use std::cell::Cell;
+use hashbrown::{HashMap, HashSet};
+use phf::phf_map;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
 use std::fmt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use super::lexer::Lexer;
-use super::macro_args::{MacroDefArg, MacroNode};
-use super::preprocessor::MacroToken;
+use super::errors::Diagnostic;
+use super::interner::{Interner, Symbol};
+use super::lexer::{Lexer, LexerOptions, Token};
+use super::macro_args::{ArgsError, MacroNode};
+use super::preprocessor::StdcPragma;
+use super::span::Span;
 
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) enum IfState {
@@ -21,17 +29,246 @@ pub(crate) enum IfKind {
     Ifndef,
 }
 
+impl IfKind {
+    fn directive(&self) -> &'static str {
+        match self {
+            IfKind::If => "#if",
+            IfKind::Ifdef => "#ifdef",
+            IfKind::Ifndef => "#ifndef",
+        }
+    }
+}
+
+/// A conditional in the if stack with the span of the directive opening it
+#[derive(Clone, Debug)]
+pub(crate) struct IfEntry {
+    state: IfState,
+    kind: IfKind,
+    span: Span,
+}
+
+/// A builtin macro which can be used in a replacement list (e.g. __LINE__)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Builtin {
+    Counter,
+    Date,
+    File,
+    Line,
+    Time,
+}
+
+static BUILTINS: phf::Map<&'static str, Builtin> = phf_map! {
+    "__COUNTER__" => Builtin::Counter,
+    "__DATE__" => Builtin::Date,
+    "__FILE__" => Builtin::File,
+    "__LINE__" => Builtin::Line,
+    "__TIME__" => Builtin::Time,
+};
+
+// The standard attributes with the value of __has_cpp_attribute ([cpp.cond])
+const STD_ATTRIBUTES: [(&str, u64); 10] = [
+    ("assume", 202207),
+    ("carries_dependency", 200809),
+    ("deprecated", 201309),
+    ("fallthrough", 201603),
+    ("likely", 201803),
+    ("maybe_unused", 201603),
+    ("no_unique_address", 201803),
+    ("nodiscard", 201907),
+    ("noreturn", 200809),
+    ("unlikely", 201803),
+];
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
 #[derive(Clone, Debug)]
-pub(crate) struct PContext {
-    macros: HashMap<String, Macro>,
-    if_stack: Vec<IfState>,
+pub struct PContext {
+    // the macros are keyed by the symbols of their names
+    names: Interner,
+    macros: HashMap<Symbol, Macro>,
+    if_stack: Vec<IfEntry>,
+    line: usize,
+    file: String,
+    counter: Cell<u64>,
+    timestamp: Option<u64>,
+    diagnostics: RefCell<Vec<Diagnostic>>,
+    once_files: HashSet<PathBuf>,
+    // the include guards of the included files (#ifndef FOO / #define FOO ... #endif)
+    // or None for the files without guard
+    guarded_files: HashMap<PathBuf, Option<String>>,
+    in_if: bool,
+    // the macros saved by #pragma push_macro (None when the macro wasn't defined)
+    pushed_macros: HashMap<String, Vec<Option<Macro>>>,
+    // the number of nested macro expansions and its limit
+    expansion_depth: Cell<usize>,
+    max_expansion_depth: usize,
+    // the first error in the current expansion
+    expansion_error: RefCell<Option<ExpansionError>>,
+    observer: Option<Rc<dyn ExpansionObserver>>,
+    lookup: Rc<dyn MacroLookup>,
+    // the span of the outermost invocation in the current expansion
+    expansion_span: Cell<Span>,
+    // the offset in the output of the name of a function-like macro ending an expansion:
+    // the arguments of the invocation can follow the expansion
+    pending_function: Cell<Option<usize>>,
+    // the previous definitions of the macros changed since the oldest checkpoint
+    journal: Vec<(Symbol, Option<Macro>)>,
+    // the indices of the checkpoints which can be given back (oldest first)
+    checkpoints: Vec<usize>,
+    // the index of the next checkpoint
+    next_checkpoint: usize,
+    // the attributes known by __has_cpp_attribute and __has_attribute with their value
+    attributes: HashMap<String, u64>,
+    // the names for which __has_builtin and __has_feature are 1
+    builtins: HashSet<String>,
+    features: HashSet<String>,
+    // the last #pragma STDC for each name
+    stdc_pragmas: HashMap<String, StdcPragma>,
+}
+
+/// A state of the macros and of the conditionals to go back to with [`PContext::rollback`]
+#[derive(Debug)]
+pub struct Checkpoint {
+    index: usize,
+    journal_len: usize,
+    if_stack: Vec<IfEntry>,
+}
+
+/// The result of one step of a macro expansion (see [`PContext::expand_once`])
+#[derive(Clone, Debug, PartialEq)]
+pub struct MacroExpansion {
+    output: Vec<u8>,
+}
+
+impl MacroExpansion {
+    /// Get the replacement list with the substituted arguments
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+
+    /// Get the tokens of the expansion
+    pub fn tokens(&self) -> Vec<Token<'_>> {
+        get_tokens(&self.output)
+            .into_iter()
+            .map(|(tok, _, _)| tok)
+            .collect()
+    }
+}
+
+/// A token in the replacement list of a macro (see [`PContext::replacement_tokens`])
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplacementToken<'a> {
+    pub token: Token<'a>,
+    /// The offset of the token in the definition text (see [`PContext::definition_text`])
+    pub offset: usize,
+    /// The index of the parameter when the token is a parameter of a function-like macro
+    /// (__VA_ARGS__ or the named variadic parameter has the index of the variadic one)
+    pub param: Option<usize>,
+}
+
+/// Observe the macro expansions (e.g. to build a tree of the expansions in a tool)
+///
+/// The nested expansions are notified between the enter and the leave of
+/// the expansion containing them, in the order they happen.
+pub trait ExpansionObserver: fmt::Debug {
+    /// The expansion of the macro name starts: args are the unexpanded arguments
+    /// of a function-like macro (the variadic ones are together in the last one)
+    /// and span is the one of the outermost invocation in the source
+    fn enter(&self, name: &str, args: Option<&[String]>, span: Span);
+
+    /// The expansion of the macro name is finished and gives tokens
+    fn leave(&self, name: &str, tokens: &[Token]);
+}
+
+/// The defined macros as seen by a [`MacroLookup`]
+#[derive(Clone, Copy)]
+pub struct MacroTable<'m> {
+    names: &'m Interner,
+    macros: &'m HashMap<Symbol, Macro>,
+}
+
+impl<'m> MacroTable<'m> {
+    /// Get the name of the macro defined as name (exact match)
+    pub fn get_name(&self, name: &str) -> Option<&'m str> {
+        self.names
+            .get(name)
+            .filter(|sym| self.macros.contains_key(sym))
+            .map(|sym| self.names.resolve(sym))
+    }
+
+    /// Get the names of the defined macros (in no particular order)
+    pub fn names(&self) -> impl Iterator<Item = &'m str> {
+        let names = self.names;
+        self.macros.keys().map(move |sym| names.resolve(*sym))
+    }
+}
+
+/// Find the macro used for an identifier (e.g. to support the dialects of a preprocessor)
+///
+/// The lookup is used by the expansions and by defined, #ifdef and #ifndef
+/// but not by the definitions: #define and #undef use the name as is.
+pub trait MacroLookup: fmt::Debug {
+    /// Get the name of the defined macro to use for the identifier name
+    /// or None when it isn't a macro
+    fn lookup<'m>(&self, name: &str, macros: MacroTable<'m>) -> Option<&'m str>;
+}
+
+/// The default lookup: an identifier is a macro only when a macro has the same name
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExactLookup;
+
+impl MacroLookup for ExactLookup {
+    fn lookup<'m>(&self, name: &str, macros: MacroTable<'m>) -> Option<&'m str> {
+        macros.get_name(name)
+    }
+}
+
+// gnu::__always_inline__ is gnu::always_inline
+fn normalize_attribute(name: &str) -> String {
+    name.split("::")
+        .map(|part| {
+            part.strip_prefix("__")
+                .and_then(|part| part.strip_suffix("__"))
+                .unwrap_or(part)
+        })
+        .collect::<Vec<_>>()
+        .join("::")
 }
 
 impl Default for PContext {
     fn default() -> Self {
         Self {
+            names: Interner::default(),
             macros: HashMap::default(),
             if_stack: Vec::new(),
+            line: 1,
+            file: String::new(),
+            counter: Cell::new(0),
+            timestamp: None,
+            diagnostics: RefCell::new(Vec::new()),
+            once_files: HashSet::default(),
+            guarded_files: HashMap::default(),
+            in_if: false,
+            pushed_macros: HashMap::default(),
+            expansion_depth: Cell::new(0),
+            max_expansion_depth: 256,
+            expansion_error: RefCell::new(None),
+            observer: None,
+            lookup: Rc::new(ExactLookup),
+            expansion_span: Cell::new(Span::default()),
+            pending_function: Cell::new(None),
+            journal: Vec::new(),
+            checkpoints: Vec::new(),
+            next_checkpoint: 0,
+            attributes: STD_ATTRIBUTES
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value))
+                .collect(),
+            builtins: HashSet::default(),
+            features: HashSet::default(),
+            stdc_pragmas: HashMap::default(),
         }
     }
 }
@@ -39,17 +276,27 @@ impl Default for PContext {
 #[derive(Clone)]
 pub(crate) struct MacroObject {
     out: Vec<u8>,
-    has_id: bool,
+    // the identifiers in the replacement list: when none of them is a macro,
+    // the list is copied without being rescanned
+    ids: Vec<String>,
+    // some tokens are pasted with ## (so the identifiers aren't known)
+    has_paste: bool,
     in_use: Cell<bool>,
+    // the replacement list as written (with the spaces normalized)
+    text: String,
 }
 
+/// A function-like macro
 #[derive(Clone)]
-pub(crate) struct MacroFunction {
+pub struct MacroFunction {
     out: Vec<u8>,
     actions: Vec<Action>,
     n_args: usize,
     in_use: Cell<bool>,
     va_args: Option<usize>,
+    // the parameters without the spaces
+    params: String,
+    text: String,
 }
 
 impl fmt::Debug for MacroFunction {
@@ -76,6 +323,63 @@ pub(crate) enum Macro {
     Function(MacroFunction),
 }
 
+/// The kind of a macro as returned by PContext::iter_macros and PContext::macro_kind
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MacroKind {
+    Object,
+    /// The number of parameters includes the variadic one
+    Function {
+        n_args: usize,
+        variadic: bool,
+    },
+}
+
+impl Macro {
+    fn kind(&self) -> MacroKind {
+        match self {
+            Macro::Object(_) => MacroKind::Object,
+            Macro::Function(mac) => MacroKind::Function {
+                n_args: mac.n_args,
+                variadic: mac.va_args.is_some(),
+            },
+        }
+    }
+
+    /// Check that a redefinition is valid: the parameters and the replacement
+    /// list must be the same (the whitespaces are normalized)
+    fn is_same(&self, other: &Macro) -> bool {
+        match (self, other) {
+            (Macro::Object(a), Macro::Object(b)) => a.text == b.text,
+            (Macro::Function(a), Macro::Function(b)) => a.params == b.params && a.text == b.text,
+            _ => false,
+        }
+    }
+
+    fn text(&self) -> &str {
+        match self {
+            Macro::Object(mac) => &mac.text,
+            Macro::Function(mac) => &mac.text,
+        }
+    }
+
+    // Get the names of the parameters in their order
+    fn param_names(&self) -> Vec<Cow<'_, str>> {
+        let mut names = Vec::new();
+        if let Macro::Function(mac) = self {
+            let mut name = None;
+            for (tok, _, _) in get_tokens(mac.params.as_bytes()) {
+                match tok {
+                    Token::Identifier(id) => name = Some(id),
+                    Token::Ellipsis => name = Some(name.unwrap_or(Cow::Borrowed("__VA_ARGS__"))),
+                    Token::Comma | Token::RightParen => names.extend(name.take()),
+                    _ => {}
+                }
+            }
+        }
+        names
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) enum MacroType<'a> {
     None,
@@ -83,12 +387,319 @@ pub(crate) enum MacroType<'a> {
     Function((usize, Option<usize>)),
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub(crate) enum Action {
+// The output of a function-like macro while its actions are evaluated
+#[derive(Default)]
+struct Expansion {
+    // the position in the replacement list
+    out_pos: usize,
+    output: MacroOutput,
+    // the positions of the ## in the output
+    pastes: Vec<usize>,
+    // the bounds of the substituted arguments in the output
+    junctions: Vec<usize>,
+    // the bounds in the output of the expanded arguments: an argument used
+    // several times in the replacement list is expanded only once
+    expanded: Vec<Option<(usize, usize)>>,
+    // the variadic arguments don't expand to nothing (checked by the first __VA_OPT__)
+    has_va_args: Option<bool>,
+    // the expansion of the variadic arguments made to check them
+    va_args_expansion: Option<MacroOutput>,
+}
+
+/// The output of an expansion with the positions of its painted identifiers:
+/// an identifier is "painted blue" when it's the name of a macro being expanded
+/// and it mustn't be expanded anymore, even when it's rescanned after the end of this expansion
+///
+/// The spaces inserted between two tokens which would be merged else (see separate_tokens)
+/// are marked too: they keep the tokens apart when the output is rescanned but they aren't
+/// whitespaces for the stringification (e.g. `ID(a)ID(b)` is stringified into "ab").
+///
+/// The text is reached through Deref: only the methods of MacroOutput keep the marked
+/// positions right when the text is changed elsewhere than at its end.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MacroOutput {
+    text: Vec<u8>,
+    // the offsets in text of the painted identifiers (sorted)
+    painted: Vec<usize>,
+    // the offsets in text of the separating spaces (sorted)
+    separators: Vec<usize>,
+}
+
+// Get the marks in start..end
+fn marks_in(marks: &[usize], start: usize, end: usize) -> &[usize] {
+    let first = marks.partition_point(|pos| *pos < start);
+    let last = marks.partition_point(|pos| *pos < end);
+    &marks[first..last]
+}
+
+impl MacroOutput {
+    pub(crate) const fn new() -> Self {
+        Self {
+            text: Vec::new(),
+            painted: Vec::new(),
+            separators: Vec::new(),
+        }
+    }
+
+    /// Push a painted identifier
+    pub(crate) fn push_painted(&mut self, id: &str) {
+        self.painted.push(self.text.len());
+        self.text.extend_from_slice(id.as_bytes());
+    }
+
+    /// Push a space separating two tokens
+    pub(crate) fn push_separator(&mut self) {
+        self.separators.push(self.text.len());
+        self.text.push(b' ');
+    }
+
+    /// Push a text whose painted identifiers and separators are at the offsets
+    /// painted and separators
+    pub(crate) fn extend_with_marks(
+        &mut self,
+        text: &[u8],
+        painted: &[usize],
+        separators: &[usize],
+    ) {
+        let len = self.text.len();
+        self.painted.extend(painted.iter().map(|pos| pos + len));
+        self.separators
+            .extend(separators.iter().map(|pos| pos + len));
+        self.text.extend_from_slice(text);
+    }
+
+    /// Push another output
+    pub(crate) fn extend_from_output(&mut self, other: &MacroOutput) {
+        self.extend_with_marks(&other.text, &other.painted, &other.separators);
+    }
+
+    /// Push again the text in range with its painted identifiers and its separators
+    pub(crate) fn extend_from_within(&mut self, range: std::ops::Range<usize>) {
+        let len = self.text.len();
+        let shift = |pos: &usize| pos - range.start + len;
+        let painted: Vec<_> = marks_in(&self.painted, range.start, range.end)
+            .iter()
+            .map(shift)
+            .collect();
+        let separators: Vec<_> = marks_in(&self.separators, range.start, range.end)
+            .iter()
+            .map(shift)
+            .collect();
+        self.painted.extend(painted);
+        self.separators.extend(separators);
+        self.text.extend_from_within(range);
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.text.truncate(len);
+        let n = self.painted.partition_point(|pos| *pos < len);
+        self.painted.truncate(n);
+        let n = self.separators.partition_point(|pos| *pos < len);
+        self.separators.truncate(n);
+    }
+
+    /// Get the output after start
+    pub(crate) fn tail(&self, start: usize) -> MacroOutput {
+        let end = self.text.len();
+        let shift = |pos: &usize| pos - start;
+        MacroOutput {
+            text: self.text[start..].to_vec(),
+            painted: marks_in(&self.painted, start, end)
+                .iter()
+                .map(shift)
+                .collect(),
+            separators: marks_in(&self.separators, start, end)
+                .iter()
+                .map(shift)
+                .collect(),
+        }
+    }
+
+    pub(crate) fn painted(&self) -> &[usize] {
+        &self.painted
+    }
+
+    pub(crate) fn separators(&self) -> &[usize] {
+        &self.separators
+    }
+
+    pub(crate) fn into_text(self) -> Vec<u8> {
+        self.text
+    }
+}
+
+impl From<Vec<u8>> for MacroOutput {
+    fn from(text: Vec<u8>) -> Self {
+        Self {
+            text,
+            ..Self::new()
+        }
+    }
+}
+
+impl std::ops::Deref for MacroOutput {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.text
+    }
+}
+
+impl std::ops::DerefMut for MacroOutput {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.text
+    }
+}
+
+// Check if the texts on the both sides of a junction would be lexed with a token across it
+// (e.g. - and - would give --)
+#[inline(always)]
+pub(crate) fn could_merge(left: &[u8], right: &[u8]) -> bool {
+    let is_id = |c: u8| c.is_ascii_alphanumeric() || c == b'_' || c >= 0x80;
+    let is_quote = |c: u8| c == b'\'' || c == b'"';
+    let (left_c, right_c) = match (left.last(), right.first()) {
+        (Some(&left), Some(&right)) => (left, right),
+        _ => return false,
+    };
+
+    if is_punct(left_c) && is_punct(right_c) {
+        return relex_punctuators(left, right);
+    }
+    (is_id(left_c) && (is_id(right_c) || is_quote(right_c)))
+        || (is_quote(left_c) && is_id(right_c))
+        // pp-numbers: .5, 1.e, 1e+5
+        || (left_c == b'.' && right_c.is_ascii_digit())
+        || (left_c.is_ascii_digit() && right_c == b'.')
+        || (b"eEpP".contains(&left_c) && (right_c == b'+' || right_c == b'-'))
+}
+
+#[inline(always)]
+fn is_punct(c: u8) -> bool {
+    c.is_ascii_punctuation() && !b"\'\"()[]{},;?~".contains(&c)
+}
+
+// Lex the punctuators around a junction to check if a token (or a comment) crosses it
+fn relex_punctuators(left: &[u8], right: &[u8]) -> bool {
+    // a punctuator has at most 4 chars (e.g. %:%:)
+    let start = left.len()
+        - left
+            .iter()
+            .rev()
+            .take(4)
+            .take_while(|c| is_punct(**c))
+            .count();
+    let end = right.iter().take(4).take_while(|c| is_punct(**c)).count();
+    let mut text = left[start..].to_vec();
+    text.extend_from_slice(&right[..end]);
+    let junction = left.len() - start;
+
+    let mut lexer = Lexer::new_translated(
+        &text,
+        LexerOptions {
+            preprocessor: false,
+            ..Default::default()
+        },
+    );
+    while lexer.pos < junction {
+        if lexer.next() == Token::Eof {
+            break;
+        }
+    }
+    lexer.pos != junction
+}
+
+/// Insert a space at the junctions (the bounds of the expansions in the output)
+/// where two tokens would be merged (e.g. - -1 and not --1 for `#define F(x) -x` and F(-1)):
+/// these spaces are marked as separators
+pub(crate) fn separate_tokens(out: &mut MacroOutput, junctions: &[usize]) {
+    // the junctions are sorted: the output is rebuilt in one pass
+    let mut text = Vec::with_capacity(out.text.len() + junctions.len());
+    let mut spaces = Vec::new();
+    let mut last = 0;
+    for &pos in junctions {
+        if pos < last || pos >= out.text.len() {
+            continue;
+        }
+        text.extend_from_slice(&out.text[last..pos]);
+        last = pos;
+        if could_merge(&text, &out.text[pos..]) {
+            text.push(b' ');
+            spaces.push(pos);
+        }
+    }
+    if spaces.is_empty() {
+        return;
+    }
+    text.extend_from_slice(&out.text[last..]);
+    out.text = text;
+
+    // the marks are moved by the spaces inserted before them
+    let shift_marks = |marks: &mut Vec<usize>| {
+        let mut shift = 0;
+        for mark in marks.iter_mut() {
+            while shift < spaces.len() && spaces[shift] <= *mark {
+                shift += 1;
+            }
+            *mark += shift;
+        }
+    };
+    shift_marks(&mut out.painted);
+    shift_marks(&mut out.separators);
+    out.separators
+        .extend(spaces.iter().enumerate().map(|(n, pos)| pos + n));
+    out.separators.sort_unstable();
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ExpansionError {
+    TooDeep,
+    WrongCount {
+        name: String,
+        n_args: usize,
+        variadic: bool,
+        given: usize,
+    },
+    InvalidPaste {
+        left: String,
+        right: String,
+    },
+    Unterminated(String),
+}
+
+/// The error given by [`MacroFunction::try_new`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum MacroBuildError {
+    /// A chunk ending before the end of the previous one or after the replacement list
+    Chunk { start: usize, end: usize },
+    /// An argument which isn't a parameter of the macro
+    Arg(usize),
+    /// The position of the variadic parameter isn't the last one
+    VaArgs(usize),
+}
+
+/// A step of the substitution of the arguments in the replacement list of a function-like
+/// macro: the chunks are the parts of the replacement list copied as is
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// The argument n fully expanded
     Arg(usize),
+    /// The argument n as written (an operand of ##)
     Concat(usize),
+    /// The argument n stringified (an operand of #)
     Stringify(usize),
+    /// The replacement list from the end of the previous chunk to this position
     Chunk(usize),
+    /// A builtin macro (e.g. __LINE__)
+    Builtin(Builtin),
+    /// __VA_OPT__(...): the nested actions and the end of its chunk in out
+    VaOpt(Vec<Action>, usize),
+    /// #__VA_OPT__(...): as VaOpt but the result is stringified
+    StringifyVaOpt(Vec<Action>, usize),
+    /// The comma of `, ## __VA_ARGS__` (GNU): the chunk until the position is dropped
+    /// when the variadic arguments are empty
+    VaComma(usize),
+    /// ##: the tokens on each side of the current position in the output are pasted
+    Paste,
 }
 
 impl Action {
@@ -114,6 +725,8 @@ impl MacroFunction {
         actions: Vec<Action>,
         n_args: usize,
         va_args: Option<usize>,
+        params: String,
+        text: String,
     ) -> Self {
         Self {
             out,
@@ -121,42 +734,295 @@ impl MacroFunction {
             n_args,
             in_use: Cell::new(false),
             va_args,
+            params,
+            text,
+        }
+    }
+
+    /// Create a function-like macro from untrusted data: the actions are checked
+    /// against the replacement list and the parameters
+    ///
+    /// out is the replacement list, n_args the number of parameters (the variadic
+    /// one included) and va_args the position of the variadic parameter if any.
+    /// params and text are the parameters and the replacement list as written.
+    ///
+    /// # Errors
+    ///
+    /// A [`MacroBuildError`] is returned when a chunk is out of the replacement list
+    /// or doesn't follow the previous one, when an argument isn't a parameter or
+    /// when the variadic parameter isn't the last one.
+    pub fn try_new(
+        out: Vec<u8>,
+        actions: Vec<Action>,
+        n_args: usize,
+        va_args: Option<usize>,
+        params: String,
+        text: String,
+    ) -> Result<Self, MacroBuildError> {
+        if let Some(va_args) = va_args {
+            if va_args + 1 != n_args {
+                return Err(MacroBuildError::VaArgs(va_args));
+            }
         }
+        Self::check_actions(&actions, out.len(), n_args, &mut 0)?;
+        Ok(Self::new(out, actions, n_args, va_args, params, text))
+    }
+
+    fn check_actions(
+        actions: &[Action],
+        len: usize,
+        n_args: usize,
+        out_pos: &mut usize,
+    ) -> Result<(), MacroBuildError> {
+        let check_chunk = |start: usize, end: usize| {
+            if start <= end && end <= len {
+                Ok(())
+            } else {
+                Err(MacroBuildError::Chunk { start, end })
+            }
+        };
+        for action in actions.iter() {
+            match action {
+                Action::Arg(n) | Action::Concat(n) | Action::Stringify(n) => {
+                    if *n >= n_args {
+                        return Err(MacroBuildError::Arg(*n));
+                    }
+                }
+                Action::Chunk(pos) => {
+                    check_chunk(*out_pos, *pos)?;
+                    *out_pos = *pos;
+                }
+                Action::VaOpt(actions, end) | Action::StringifyVaOpt(actions, end) => {
+                    let start = *out_pos;
+                    Self::check_actions(actions, len, n_args, out_pos)?;
+                    check_chunk(*out_pos, *end)?;
+                    // the chunk is skipped when there are no variadic arguments
+                    check_chunk(start, *end)?;
+                    *out_pos = *end;
+                }
+                Action::VaComma(end) => {
+                    check_chunk(*out_pos, *end)?;
+                    *out_pos = *end;
+                }
+                Action::Builtin(_) | Action::Paste => {}
+            }
+        }
+        Ok(())
     }
 
     #[inline(always)]
     pub(crate) fn eval_parsed_args<'a>(
         &self,
+        name: &str,
         args: &[Vec<MacroNode<'a>>],
         context: &PContext,
-        out: &mut Vec<u8>,
+        out: &mut MacroOutput,
     ) {
-        let mut out_pos = 0;
-        let mut output = Vec::new();
+        if !context.enter_expansion() {
+            return;
+        }
+        let start = out.len();
+        context.notify_enter(name, Some(args));
+        let output = self.substitute(args, context);
+
+        let mut lexer = Lexer::new_rescan(&output);
+        self.in_use.set(true);
+        lexer.macro_final_eval(out, context);
+        self.in_use.set(false);
+        context.notify_leave(name, &out[start..]);
+        context.leave_expansion();
+    }
+
+    /// Replace the parameters by the arguments (expanded or not according to the operators)
+    /// in the replacement list: the result isn't rescanned
+    fn substitute(&self, args: &[Vec<MacroNode>], context: &PContext) -> MacroOutput {
+        let mut exp = Expansion::default();
+
+        self.eval_actions(&self.actions, args, context, &mut exp);
+        let Expansion {
+            out_pos,
+            mut output,
+            pastes,
+            junctions,
+            ..
+        } = exp;
+        output.extend_from_slice(self.get_chunk(out_pos, self.out.len()));
+        if !pastes.is_empty() {
+            check_pastes(&output, &pastes, context);
+        }
+        separate_tokens(&mut output, &junctions);
+        output
+    }
+
+    /// Get a chunk of the replacement list
+    /// (the bounds are only checked with the feature checked-bounds)
+    #[inline(always)]
+    fn get_chunk(&self, start: usize, end: usize) -> &[u8] {
+        #[cfg(feature = "checked-bounds")]
+        {
+            self.out.get(start..end).unwrap_or_else(|| {
+                panic!(
+                    "Invalid chunk {}..{} in a replacement list of length {}",
+                    start,
+                    end,
+                    self.out.len()
+                )
+            })
+        }
+        #[cfg(not(feature = "checked-bounds"))]
+        unsafe {
+            self.out.get_unchecked(start..end)
+        }
+    }
+
+    /// Check if the variadic arguments are present and aren't only made of spaces
+    #[inline(always)]
+    fn has_va_args(&self, args: &[Vec<MacroNode>]) -> bool {
+        if let Some(n) = self.va_args {
+            if let Some(MacroNode::VaArgs(va)) = args.get(n).and_then(|arg| arg.first()) {
+                return va.len() > 1
+                    || va
+                        .iter()
+                        .any(|arg| arg.iter().any(|node| *node != MacroNode::Space));
+            }
+        }
+        false
+    }
+
+    /// Check if the variadic arguments don't expand to nothing: the content of
+    /// __VA_OPT__ is dropped else (e.g. F(EMPTY) with `#define EMPTY`)
+    fn has_expanded_va_args<'a>(
+        &self,
+        args: &[Vec<MacroNode<'a>>],
+        context: &PContext,
+        exp: &mut Expansion,
+    ) -> bool {
+        if let Some(has) = exp.has_va_args {
+            return has;
+        }
+        let has = self.has_va_args(args) && {
+            // the expansion is kept for the substitution of __VA_ARGS__
+            let n = self.va_args.unwrap_or_default();
+            let mut expanded = MacroOutput::default();
+            MacroNode::eval_nodes(&args[n], context, &mut expanded);
+            let has = expanded.iter().any(|c| !c.is_ascii_whitespace());
+            exp.va_args_expansion = Some(expanded);
+            has
+        };
+        exp.has_va_args = Some(has);
+        has
+    }
 
-        for action in self.actions.iter() {
+    fn eval_actions<'a>(
+        &self,
+        actions: &[Action],
+        args: &[Vec<MacroNode<'a>>],
+        context: &PContext,
+        exp: &mut Expansion,
+    ) {
+        for action in actions.iter() {
             match action {
                 Action::Arg(pos) => {
-                    MacroNode::eval_nodes(&args[*pos], context, &mut output);
+                    let start = exp.output.len();
+                    exp.junctions.push(start);
+                    if exp.expanded.len() <= *pos {
+                        exp.expanded.resize(*pos + 1, None);
+                    }
+                    if let Some((first, end)) = exp.expanded[*pos] {
+                        exp.output.extend_from_within(first..end);
+                    } else {
+                        match exp.va_args_expansion.as_ref() {
+                            Some(expanded) if self.va_args == Some(*pos) => {
+                                exp.output.extend_from_output(expanded);
+                            }
+                            _ => {
+                                MacroNode::eval_nodes(&args[*pos], context, &mut exp.output);
+                            }
+                        }
+                        exp.expanded[*pos] = Some((start, exp.output.len()));
+                    }
+                    exp.junctions.push(exp.output.len());
                 }
                 Action::Concat(pos) => {
-                    MacroNode::make_expr(&args[*pos], &mut output);
+                    MacroNode::make_expr(&args[*pos], &mut exp.output);
                 }
                 Action::Stringify(pos) => {
-                    MacroNode::make_string(&args[*pos], &mut output);
+                    MacroNode::make_string(&args[*pos], &mut exp.output);
                 }
                 Action::Chunk(pos) => {
-                    output.extend_from_slice(unsafe { &self.out.get_unchecked(out_pos..*pos) });
-                    out_pos = *pos;
+                    exp.output
+                        .extend_from_slice(self.get_chunk(exp.out_pos, *pos));
+                    exp.out_pos = *pos;
+                }
+                Action::Builtin(builtin) => {
+                    context.expand_builtin(*builtin, &mut exp.output);
+                }
+                Action::VaOpt(actions, end) => {
+                    if self.has_expanded_va_args(args, context, exp) {
+                        self.eval_actions(actions, args, context, exp);
+                        exp.output
+                            .extend_from_slice(self.get_chunk(exp.out_pos, *end));
+                    }
+                    exp.out_pos = *end;
+                }
+                Action::StringifyVaOpt(actions, end) => {
+                    let start = exp.output.len();
+                    if self.has_expanded_va_args(args, context, exp) {
+                        self.eval_actions(actions, args, context, exp);
+                        exp.output
+                            .extend_from_slice(self.get_chunk(exp.out_pos, *end));
+                    }
+                    exp.out_pos = *end;
+                    let text = exp.output.tail(start);
+                    exp.output.truncate(start);
+                    // the substituted text isn't in the output anymore
+                    exp.pastes.retain(|pos| *pos < start);
+                    exp.junctions.retain(|pos| *pos < start);
+                    for expanded in exp.expanded.iter_mut() {
+                        if expanded.is_some_and(|(_, end)| end > start) {
+                            *expanded = None;
+                        }
+                    }
+                    MacroNode::stringify_text(&text, &mut exp.output);
+                }
+                Action::VaComma(end) => {
+                    if self.has_va_args(args) {
+                        exp.output
+                            .extend_from_slice(self.get_chunk(exp.out_pos, *end));
+                    }
+                    exp.out_pos = *end;
+                }
+                Action::Paste => {
+                    exp.pastes.push(exp.output.len());
                 }
             }
         }
-        output.extend_from_slice(unsafe { &self.out.get_unchecked(out_pos..) });
+    }
 
-        let mut lexer = Lexer::new(&output);
-        self.in_use.set(true);
-        lexer.macro_final_eval(out, context);
-        self.in_use.set(false);
+    /// Check the arguments of an invocation which have been already parsed
+    pub(crate) fn check_arguments<'a>(
+        &self,
+        name: &str,
+        args: Vec<Vec<MacroNode<'a>>>,
+        context: &PContext,
+    ) -> Option<Vec<Vec<MacroNode<'a>>>> {
+        match MacroNode::check_arguments(args, self.n_args, self.va_args.as_ref()) {
+            Ok(args) => Some(args),
+            Err(ArgsError::WrongCount(given)) => {
+                self.wrong_count(name, given, context);
+                None
+            }
+            Err(_) => None,
+        }
+    }
+
+    pub(crate) fn wrong_count(&self, name: &str, given: usize, context: &PContext) {
+        context.set_expansion_error(ExpansionError::WrongCount {
+            name: name.to_string(),
+            n_args: self.n_args,
+            variadic: self.va_args.is_some(),
+            given,
+        });
     }
 
     #[inline(always)]
@@ -172,86 +1038,777 @@ impl MacroFunction {
 
 impl MacroObject {
     #[inline(always)]
-    pub(crate) fn new(out: Vec<u8>, has_id: bool) -> Self {
+    pub(crate) fn new(out: Vec<u8>, ids: Vec<String>, has_paste: bool, text: String) -> Self {
         Self {
             out,
-            has_id,
+            ids,
+            has_paste,
             in_use: Cell::new(false),
+            text,
         }
     }
 
     #[inline(always)]
-    pub(crate) fn eval(&self, out: &mut Vec<u8>, context: &PContext) {
-        if self.has_id {
-            let mut lexer = Lexer::new(&self.out);
+    fn need_rescan(&self, context: &PContext) -> bool {
+        !self.ids.is_empty() && (self.has_paste || self.ids.iter().any(|id| context.defined(id)))
+    }
+
+    #[inline(always)]
+    pub(crate) fn eval(&self, name: &str, out: &mut MacroOutput, context: &PContext) {
+        let start = out.len();
+        if self.need_rescan(context) {
+            if !context.enter_expansion() {
+                return;
+            }
+            context.notify_enter(name, None);
+            let mut lexer = Lexer::new_translated(&self.out, LexerOptions::default());
             self.in_use.set(true);
             lexer.macro_final_eval(out, context);
             self.in_use.set(false);
+            context.notify_leave(name, &out[start..]);
+            context.leave_expansion();
         } else {
+            context.notify_enter(name, None);
             out.extend_from_slice(&self.out);
+            context.notify_leave(name, &out[start..]);
         }
     }
 }
 
-impl PContext {
-    pub(crate) fn show_if_stack(&self) {
-        eprintln!("IF_STACK: {:?}", self.if_stack);
+/// Lex the output of a macro (without preprocessing it) to get the tokens and their offsets
+fn get_tokens(output: &[u8]) -> Vec<(Token<'_>, usize, usize)> {
+    let options = LexerOptions {
+        preprocessor: false,
+        ..Default::default()
+    };
+    let mut lexer = Lexer::new_translated(output, options);
+    let mut tokens = Vec::new();
+    loop {
+        match lexer.next() {
+            Token::Eof => break,
+            // a comment isn't a token
+            Token::Comment { .. } => {}
+            tok => tokens.push((tok, lexer.start.pos, lexer.pos)),
+        }
     }
+    tokens
+}
 
-    pub(crate) fn add_if(&mut self, state: IfState) {
-        self.if_stack.push(state);
+/// Check that the tokens around the ## in the output of a macro have been pasted into one token
+fn check_pastes(output: &[u8], pastes: &[usize], context: &PContext) {
+    let spans: Vec<_> = get_tokens(output)
+        .into_iter()
+        .map(|(_, start, end)| (start, end))
+        .collect();
+
+    let is_space = |c: Option<&u8>| matches!(c, None | Some(b' ') | Some(b'\t'));
+    for &pos in pastes {
+        // an empty operand is a placemarker
+        if pos == 0 || is_space(output.get(pos - 1)) || is_space(output.get(pos)) {
+            continue;
+        }
+        if spans.iter().any(|(start, end)| *start < pos && pos < *end) {
+            continue;
+        }
+        let left = spans
+            .iter()
+            .rev()
+            .find(|(_, end)| *end == pos)
+            .map_or(pos - 1, |(start, _)| *start);
+        let right = spans
+            .iter()
+            .find(|(start, _)| *start == pos)
+            .map_or(pos + 1, |(_, end)| *end);
+        context.set_expansion_error(ExpansionError::InvalidPaste {
+            left: String::from_utf8_lossy(&output[left..pos]).into_owned(),
+            right: String::from_utf8_lossy(&output[pos..right]).into_owned(),
+        });
     }
+}
 
-    pub(crate) fn rm_if(&mut self) {
-        self.if_stack.pop();
+/// Convert a number of days since 1970-01-01 into (year, month, day)
+/// (see http://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+fn civil_from_days(days: u64) -> (u64, usize, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+    (year, month as usize, day)
+}
+
+impl fmt::Display for MacroBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MacroBuildError::Chunk { start, end } => {
+                write!(
+                    f,
+                    "invalid chunk {}..{} in the replacement list",
+                    start, end
+                )
+            }
+            MacroBuildError::Arg(n) => write!(f, "invalid argument {}", n),
+            MacroBuildError::VaArgs(n) => {
+                write!(f, "invalid position {} for the variadic arguments", n)
+            }
+        }
     }
+}
 
-    pub(crate) fn if_state(&self) -> Option<&IfState> {
-        self.if_stack.last()
+impl fmt::Display for ExpansionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpansionError::TooDeep => write!(f, "macro expansion nested too deeply"),
+            ExpansionError::Unterminated(name) => {
+                write!(f, "unterminated argument list invoking macro \"{}\"", name)
+            }
+            ExpansionError::InvalidPaste { left, right } => write!(
+                f,
+                "pasting \"{}\" and \"{}\" does not give a valid preprocessing token",
+                left, right
+            ),
+            ExpansionError::WrongCount {
+                name,
+                n_args,
+                variadic: true,
+                given,
+            } => write!(
+                f,
+                "macro \"{}\" requires at least {} arguments, but only {} given",
+                name,
+                n_args - 1,
+                given
+            ),
+            ExpansionError::WrongCount {
+                name,
+                n_args,
+                given,
+                ..
+            } => {
+                if given < n_args {
+                    write!(
+                        f,
+                        "macro \"{}\" requires {} arguments, but only {} given",
+                        name, n_args, given
+                    )
+                } else {
+                    write!(
+                        f,
+                        "macro \"{}\" passed {} arguments, but takes just {}",
+                        name, given, n_args
+                    )
+                }
+            }
+        }
     }
+}
 
-    pub(crate) fn if_change(&mut self, state: IfState) {
-        *self.if_stack.last_mut().unwrap() = state;
+impl PContext {
+    /// Create a context where __DATE__ and __TIME__ are computed from the given
+    /// number of seconds since the epoch (UTC) in order to have reproducible outputs.
+    pub fn with_timestamp(timestamp: u64) -> Self {
+        Self {
+            timestamp: Some(timestamp),
+            ..Default::default()
+        }
     }
 
-    pub(crate) fn add_function(&mut self, name: String, mac: MacroFunction) {
-        self.macros.insert(name, Macro::Function(mac));
+    fn get_timestamp(&self) -> u64 {
+        if let Some(timestamp) = self.timestamp {
+            timestamp
+        } else {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        }
     }
 
-    pub(crate) fn add_object(&mut self, name: String, mac: MacroObject) {
-        self.macros.insert(name, Macro::Object(mac));
+    /// Set the maximum number of nested macro expansions (256 by default):
+    /// an expansion going deeper is aborted with a diagnostic
+    pub fn set_max_expansion_depth(&mut self, depth: usize) {
+        self.max_expansion_depth = depth;
     }
 
-    pub(crate) fn undef(&mut self, name: &str) {
-        self.macros.remove(name);
+    pub(crate) fn get_max_expansion_depth(&self) -> usize {
+        self.max_expansion_depth
     }
 
-    pub(crate) fn defined(&self, name: &str) -> bool {
-        self.macros.contains_key(name)
+    /// Enter in a nested expansion: false when it's too deep
+    #[inline(always)]
+    pub(crate) fn enter_expansion(&self) -> bool {
+        let depth = self.expansion_depth.get();
+        if depth >= self.max_expansion_depth {
+            self.set_expansion_error(ExpansionError::TooDeep);
+            false
+        } else {
+            self.expansion_depth.set(depth + 1);
+            true
+        }
     }
 
-    pub(crate) fn eval(&self, name: &str, lexer: &mut Lexer, out: &mut Vec<u8>) -> bool {
-        if let Some(mac) = self.get(name) {
-            match mac {
-                Macro::Object(mac) => {
-                    mac.eval(out, &self);
-                }
-                Macro::Function(mac) => {
-                    if let Some(args) = lexer.get_arguments(mac.n_args, mac.va_args.as_ref()) {
-                        mac.eval_parsed_args(&args, &self, out);
-                    } else {
-                        return false;
+    #[inline(always)]
+    pub(crate) fn leave_expansion(&self) {
+        self.expansion_depth.set(self.expansion_depth.get() - 1);
+    }
+
+    pub(crate) fn set_expansion_error(&self, error: ExpansionError) {
+        let mut expansion_error = self.expansion_error.borrow_mut();
+        if expansion_error.is_none() {
+            *expansion_error = Some(error);
+        }
+    }
+
+    /// Get the first error in the expansions since the last call
+    pub(crate) fn take_expansion_error(&self) -> Option<ExpansionError> {
+        self.expansion_error.replace(None)
+    }
+
+    /// Set an observer notified of each macro expansion
+    pub fn set_observer(&mut self, observer: Rc<dyn ExpansionObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Set the strategy used to find the macro of an identifier
+    pub fn set_macro_lookup(&mut self, lookup: Rc<dyn MacroLookup>) {
+        self.lookup = lookup;
+    }
+
+    // Find the macro used for the identifier name
+    fn find_macro(&self, name: &str) -> Option<&Macro> {
+        let table = MacroTable {
+            names: &self.names,
+            macros: &self.macros,
+        };
+        self.lookup
+            .lookup(name, table)
+            .and_then(|name| self.get_macro(name))
+    }
+
+    // Get the macro defined as name (exact match)
+    fn get_macro(&self, name: &str) -> Option<&Macro> {
+        self.names.get(name).and_then(|sym| self.macros.get(&sym))
+    }
+
+    pub(crate) fn take_observer(&mut self) -> Option<Rc<dyn ExpansionObserver>> {
+        self.observer.take()
+    }
+
+    pub(crate) fn has_observer(&self) -> bool {
+        self.observer.is_some()
+    }
+
+    pub(crate) fn set_pending_function(&self, start: Option<usize>) {
+        self.pending_function.set(start);
+    }
+
+    /// Get the offset of the name of a function-like macro ending the last expansion
+    /// (when it isn't followed by arguments in the expansion)
+    pub(crate) fn take_pending_function(&self) -> Option<usize> {
+        self.pending_function.take()
+    }
+
+    pub(crate) fn set_expansion_span(&self, span: Span) {
+        self.expansion_span.set(span);
+    }
+
+    pub(crate) fn notify_enter(&self, name: &str, args: Option<&[Vec<MacroNode>]>) {
+        if let Some(observer) = self.observer.as_ref() {
+            let args = args.map(|args| {
+                args.iter()
+                    .map(|arg| {
+                        let mut out = MacroOutput::default();
+                        MacroNode::make_expr(arg, &mut out);
+                        String::from_utf8_lossy(&out).into_owned()
+                    })
+                    .collect::<Vec<_>>()
+            });
+            observer.enter(name, args.as_deref(), self.expansion_span.get());
+        }
+    }
+
+    pub(crate) fn notify_leave(&self, name: &str, output: &[u8]) {
+        if let Some(observer) = self.observer.as_ref() {
+            let tokens: Vec<_> = get_tokens(output)
+                .into_iter()
+                .map(|(tok, _, _)| tok)
+                .collect();
+            observer.leave(name, &tokens);
+        }
+    }
+
+    pub(crate) fn add_diagnostic(&self, diagnostic: Diagnostic) {
+        self.diagnostics.borrow_mut().push(diagnostic);
+    }
+
+    /// Get the diagnostics (#warning, ...) collected so far and clear them
+    pub fn take_diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics.replace(Vec::new())
+    }
+
+    pub(crate) fn show_if_stack(&self) {
+        eprintln!("IF_STACK: {:?}", self.if_stack);
+    }
+
+    pub(crate) fn add_if(&mut self, state: IfState, kind: IfKind, span: Span) {
+        self.if_stack.push(IfEntry { state, kind, span });
+    }
+
+    pub(crate) fn rm_if(&mut self) {
+        self.if_stack.pop();
+    }
+
+    pub(crate) fn if_state(&self) -> Option<&IfState> {
+        self.if_stack.last().map(|entry| &entry.state)
+    }
+
+    pub(crate) fn if_change(&mut self, state: IfState) {
+        if let Some(entry) = self.if_stack.last_mut() {
+            entry.state = state;
+        }
+    }
+
+    /// Report the conditionals still opened at the end of the input
+    pub(crate) fn check_unterminated_ifs(&mut self) {
+        for entry in self.if_stack.drain(..) {
+            self.diagnostics.borrow_mut().push(Diagnostic::error(
+                format!("unterminated {}", entry.kind.directive()),
+                entry.span.line as usize,
+                entry.span.col as usize,
+            ));
+        }
+    }
+
+    /// Get a checkpoint to go back to the current macros and conditionals
+    ///
+    /// The changes of the macros are recorded until the checkpoint is given back
+    /// to rollback or release: only the changed macros are saved.
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        let index = self.next_checkpoint;
+        self.next_checkpoint += 1;
+        self.checkpoints.push(index);
+        Checkpoint {
+            index,
+            journal_len: self.journal.len(),
+            if_stack: self.if_stack.clone(),
+        }
+    }
+
+    /// Restore the macros and the conditionals as they were at the checkpoint
+    ///
+    /// The checkpoints taken after this one can't be rolled back anymore:
+    /// false is returned (and nothing is done) when the checkpoint is one of them.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) -> bool {
+        let position = self.checkpoints.iter().position(|i| *i == checkpoint.index);
+        let position = if let Some(position) = position {
+            position
+        } else {
+            return false;
+        };
+        for (sym, mac) in self.journal.drain(checkpoint.journal_len..).rev() {
+            if let Some(mac) = mac {
+                self.macros.insert(sym, mac);
+            } else {
+                self.macros.remove(&sym);
+            }
+        }
+        self.if_stack = checkpoint.if_stack;
+        self.checkpoints.truncate(position);
+        self.clear_journal();
+        true
+    }
+
+    /// Keep the changes made since the checkpoint
+    pub fn release(&mut self, checkpoint: Checkpoint) {
+        self.checkpoints.retain(|i| *i != checkpoint.index);
+        self.clear_journal();
+    }
+
+    fn clear_journal(&mut self) {
+        if self.checkpoints.is_empty() {
+            self.journal.clear();
+        }
+    }
+
+    /// Set or remove (when mac is None) a macro
+    fn set_macro(&mut self, name: &str, mac: Option<Macro>) {
+        let sym = match (&mac, self.names.get(name)) {
+            (_, Some(sym)) => sym,
+            (Some(_), None) => self.names.intern(name),
+            // the names which have never been defined aren't interned
+            (None, None) => return,
+        };
+        let old = if let Some(mac) = mac {
+            self.macros.insert(sym, mac)
+        } else {
+            self.macros.remove(&sym)
+        };
+        if !self.checkpoints.is_empty() {
+            self.journal.push((sym, old));
+        }
+    }
+
+    /// Add a macro and return false when it replaces a different one
+    fn add_macro(&mut self, name: String, mac: Macro) -> bool {
+        let same = if let Some(old) = self.get_macro(&name) {
+            old.is_same(&mac)
+        } else {
+            true
+        };
+        self.set_macro(&name, Some(mac));
+        same
+    }
+
+    pub(crate) fn add_function(&mut self, name: String, mac: MacroFunction) -> bool {
+        self.add_macro(name, Macro::Function(mac))
+    }
+
+    pub(crate) fn add_object(&mut self, name: String, mac: MacroObject) -> bool {
+        self.add_macro(name, Macro::Object(mac))
+    }
+
+    /// Define a function-like macro (e.g. one built with [`MacroFunction::try_new`])
+    pub fn define_function(&mut self, name: &str, mac: MacroFunction) {
+        self.add_function(name.to_string(), mac);
+    }
+
+    /// Get the defined macros (in no particular order)
+    pub fn iter_macros(&self) -> impl Iterator<Item = (&str, MacroKind)> {
+        self.macros
+            .iter()
+            .map(move |(sym, mac)| (self.names.resolve(*sym), mac.kind()))
+    }
+
+    /// Get the kind of the macro used for the identifier name (e.g. to know if an identifier
+    /// followed by a parenthesis is an invocation): None is returned when it isn't a macro
+    /// or when the macro is being expanded (so it isn't expanded again)
+    pub fn macro_kind(&self, name: &str) -> Option<MacroKind> {
+        self.get(name).map(Macro::kind)
+    }
+
+    /// Get the replacement list of a macro: the whitespaces and the comments
+    /// are replaced by one space
+    pub fn definition_text(&self, name: &str) -> Option<String> {
+        self.get_macro(name).map(|mac| mac.text().to_string())
+    }
+
+    /// Get the tokens of the replacement list of a macro: # and ## are
+    /// Token::Hash and Token::HashHash and the parameters are marked with their index
+    pub fn replacement_tokens(&self, name: &str) -> Option<Vec<ReplacementToken<'_>>> {
+        let mac = self.get_macro(name)?;
+        let params = mac.param_names();
+        let tokens = get_tokens(mac.text().as_bytes())
+            .into_iter()
+            .map(|(token, offset, _)| {
+                let param = match &token {
+                    Token::Identifier(id) => params.iter().position(|param| param == id),
+                    _ => None,
+                };
+                ReplacementToken {
+                    token,
+                    offset,
+                    param,
+                }
+            })
+            .collect();
+        Some(tokens)
+    }
+
+    /// Define a macro as with the -D option of a compiler:
+    /// NAME defines NAME as 1, NAME=value as value and NAME(a, b)=body a function-like macro
+    /// (the definition stops at the first newline)
+    /// Return false when the spec doesn't start with an identifier
+    pub fn define_from_str(&mut self, spec: &str) -> bool {
+        let spec = spec.split('\n').next().unwrap_or("");
+        let (name, body) = if let Some(eq) = spec.find('=') {
+            (&spec[..eq], &spec[eq + 1..])
+        } else {
+            (spec, "1")
+        };
+
+        let id_len = name
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(name.len());
+        if id_len == 0 || name.as_bytes()[0].is_ascii_digit() {
+            return false;
+        }
+        let params = &name[id_len..];
+        if !params.is_empty() && !params.starts_with('(') {
+            return false;
+        }
+
+        let definition = format!("{} {}", name, body);
+        let mut lexer = Lexer::new_translated(definition.as_bytes(), LexerOptions::default());
+        lexer.context = std::mem::take(self);
+        lexer.get_define();
+        *self = std::mem::take(&mut lexer.context);
+        true
+    }
+
+    /// Remove the definition of a macro as with the -U option of a compiler
+    pub fn undefine(&mut self, name: &str) {
+        self.set_macro(name, None);
+    }
+
+    pub(crate) fn push_macro(&mut self, name: &str) {
+        let mac = self.get_macro(name).cloned();
+        self.pushed_macros
+            .entry(name.to_string())
+            .or_default()
+            .push(mac);
+    }
+
+    /// Restore the macro saved by push_macro
+    /// Return false when there is nothing to restore
+    pub(crate) fn pop_macro(&mut self, name: &str) -> bool {
+        if let Some(mac) = self
+            .pushed_macros
+            .get_mut(name)
+            .and_then(|stack| stack.pop())
+        {
+            self.set_macro(name, mac);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn defined(&self, name: &str) -> bool {
+        self.find_macro(name).is_some()
+            || BUILTINS.contains_key(name)
+            || name == "__has_include"
+            || name == "__has_include_next"
+            || name == "__has_cpp_attribute"
+            || name == "__has_attribute"
+            || name == "__has_builtin"
+            || name == "__has_feature"
+    }
+
+    /// Set the value given by `__has_cpp_attribute(name)` (e.g. 1 for `gnu::always_inline`):
+    /// the standard attributes are known by default
+    pub fn set_attribute(&mut self, name: &str, value: u64) {
+        self.attributes.insert(normalize_attribute(name), value);
+    }
+
+    /// Make an attribute unknown (`__has_cpp_attribute(name)` is then 0)
+    pub fn remove_attribute(&mut self, name: &str) {
+        self.attributes.remove(&normalize_attribute(name));
+    }
+
+    /// Make `__has_builtin(name)` true (e.g. for `__builtin_expect`)
+    pub fn add_builtin(&mut self, name: &str) {
+        self.builtins.insert(name.to_string());
+    }
+
+    /// Make `__has_feature(name)` true (e.g. for `cxx_rvalue_references`)
+    pub fn add_feature(&mut self, name: &str) {
+        self.features.insert(normalize_attribute(name));
+    }
+
+    pub fn has_builtin(&self, name: &str) -> bool {
+        self.builtins.contains(name)
+    }
+
+    /// `__name__` is the same feature as `name`
+    pub fn has_feature(&self, name: &str) -> bool {
+        self.features.contains(&normalize_attribute(name))
+    }
+
+    /// Get the value of `__has_cpp_attribute(name)`: 0 when the attribute is unknown
+    /// (`__name__` is the same attribute as `name`)
+    pub fn has_attribute(&self, name: &str) -> u64 {
+        self.attributes
+            .get(&normalize_attribute(name))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn set_stdc_pragma(&mut self, pragma: StdcPragma) {
+        self.stdc_pragmas.insert(pragma.name.clone(), pragma);
+    }
+
+    /// Get the last `#pragma STDC name` (e.g. with FP_CONTRACT as name)
+    pub fn stdc_pragma(&self, name: &str) -> Option<&StdcPragma> {
+        self.stdc_pragmas.get(name)
+    }
+
+    /// Set when a #if expression is evaluated: the operand of defined mustn't be expanded
+    pub(crate) fn set_in_if(&mut self, in_if: bool) {
+        self.in_if = in_if;
+    }
+
+    pub(crate) fn in_if(&self) -> bool {
+        self.in_if
+    }
+
+    pub(crate) fn set_line(&mut self, line: usize) {
+        self.line = line;
+    }
+
+    pub(crate) fn set_file(&mut self, file: &str) {
+        self.file = file.to_string();
+    }
+
+    pub(crate) fn get_file(&self) -> &str {
+        &self.file
+    }
+
+    pub(crate) fn add_once(&mut self, path: PathBuf) {
+        self.once_files.insert(path);
+    }
+
+    pub(crate) fn is_once(&self, path: &Path) -> bool {
+        self.once_files.contains(path)
+    }
+
+    pub(crate) fn set_guard(&mut self, path: PathBuf, guard: Option<String>) {
+        self.guarded_files.insert(path, guard);
+    }
+
+    /// Check if the file is protected by an include guard which is defined
+    pub(crate) fn is_guarded(&self, path: &Path) -> bool {
+        self.guarded_files
+            .get(path)
+            .and_then(|guard| guard.as_ref())
+            .is_some_and(|guard| self.get_macro(guard).is_some())
+    }
+
+    pub(crate) fn has_guard_info(&self, path: &Path) -> bool {
+        self.guarded_files.contains_key(path)
+    }
+
+    pub(crate) fn get_builtin(name: &str) -> Option<Builtin> {
+        BUILTINS.get(name).copied()
+    }
+
+    pub(crate) fn eval_builtin(&self, name: &str, out: &mut Vec<u8>) -> bool {
+        if let Some(builtin) = BUILTINS.get(name) {
+            self.expand_builtin(*builtin, out);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn expand_builtin(&self, builtin: Builtin, out: &mut Vec<u8>) {
+        match builtin {
+            Builtin::Counter => {
+                let counter = self.counter.get();
+                self.counter.set(counter + 1);
+                out.extend_from_slice(counter.to_string().as_bytes());
+            }
+            Builtin::File => {
+                out.push(b'\"');
+                for c in self.file.bytes() {
+                    if c == b'\\' || c == b'\"' {
+                        out.push(b'\\');
+                    }
+                    out.push(c);
+                }
+                out.push(b'\"');
+            }
+            Builtin::Line => {
+                out.extend_from_slice(self.line.to_string().as_bytes());
+            }
+            Builtin::Date => {
+                let (year, month, day) = civil_from_days(self.get_timestamp() / 86400);
+                let date = format!("\"{} {:>2} {}\"", MONTHS[month - 1], day, year);
+                out.extend_from_slice(date.as_bytes());
+            }
+            Builtin::Time => {
+                let secs = self.get_timestamp() % 86400;
+                let time = format!(
+                    "\"{:02}:{:02}:{:02}\"",
+                    secs / 3600,
+                    (secs % 3600) / 60,
+                    secs % 60
+                );
+                out.extend_from_slice(time.as_bytes());
+            }
+        }
+    }
+
+    pub(crate) fn eval(&self, name: &str, lexer: &mut Lexer, out: &mut MacroOutput) -> bool {
+        if let Some(mac) = self.get(name) {
+            match mac {
+                Macro::Object(mac) => {
+                    mac.eval(name, out, self);
+                }
+                Macro::Function(mac) => {
+                    match lexer.get_arguments(mac.n_args, mac.va_args.as_ref()) {
+                        Ok(args) => mac.eval_parsed_args(name, &args, self, out),
+                        Err(ArgsError::WrongCount(given)) => {
+                            mac.wrong_count(name, given, self);
+                            return false;
+                        }
+                        Err(ArgsError::Unterminated) => {
+                            // the arguments continue after the output being rescanned:
+                            // the invocation is completed by the enclosing expansion
+                            let start = out.len();
+                            out.extend_from_slice(name.as_bytes());
+                            out.extend_from_output(&lexer.take_rest());
+                            self.set_pending_function(Some(start));
+                        }
+                        Err(ArgsError::NoParen) => return false,
                     }
                 }
             }
             true
         } else {
-            false
+            self.eval_builtin(name, out)
+        }
+    }
+
+    /// Expand an invocation of the macro name with one level of substitution:
+    /// the arguments are fully expanded before their substitution (unless they are
+    /// operands of # or ##) but the result isn't rescanned for further macros
+    /// (e.g. to show an expansion step by step).
+    ///
+    /// args are the arguments of a function-like macro and must be empty for
+    /// an object-like one.
+    /// None is returned when name isn't a macro or when the arguments don't match.
+    pub fn expand_once(&self, name: &str, args: &[&str]) -> Option<MacroExpansion> {
+        let mut output = Vec::new();
+        match self.find_macro(name) {
+            Some(Macro::Object(mac)) => {
+                if !args.is_empty() {
+                    return None;
+                }
+                output.extend_from_slice(&mac.out);
+            }
+            Some(Macro::Function(mac)) => {
+                let invocation = format!("({})", args.join(","));
+                let mut lexer =
+                    Lexer::new_translated(invocation.as_bytes(), LexerOptions::default());
+                let args = lexer.get_arguments(mac.n_args, mac.va_args.as_ref()).ok()?;
+                output = mac.substitute(&args, self).into_text();
+            }
+            None => {
+                if !args.is_empty() || !self.eval_builtin(name, &mut output) {
+                    return None;
+                }
+            }
+        }
+        // the errors are only reported for the expansions in a source
+        self.take_expansion_error();
+        Some(MacroExpansion { output })
+    }
+
+    /// Check if name is a macro being expanded
+    pub(crate) fn in_use(&self, name: &str) -> bool {
+        match self.find_macro(name) {
+            Some(Macro::Object(mac)) => mac.in_use.get(),
+            Some(Macro::Function(mac)) => mac.in_use.get(),
+            None => false,
         }
     }
 
     pub(crate) fn get(&self, name: &str) -> Option<&Macro> {
-        if let Some(mac) = self.macros.get(name) {
+        if let Some(mac) = self.find_macro(name) {
             match mac {
                 Macro::Object(m) => {
                     return if m.in_use.get() { None } else { Some(mac) };
@@ -281,14 +1838,14 @@ impl PContext {
 mod tests {
 
     use super::*;
-    use crate::lexer::Token;
+    use crate::lexer::{Token, Tokenizer};
 
     macro_rules! eval {
         ( $name: expr, $lexer: expr ) => {{
             let context = $lexer.context.clone();
-            let mut res = Vec::new();
+            let mut res = MacroOutput::default();
             context.eval($name, &mut $lexer, &mut res);
-            String::from_utf8(res).unwrap()
+            String::from_utf8(res.into_text()).unwrap()
         }};
     }
 
@@ -302,6 +1859,315 @@ mod tests {
         assert!(p.context.get("foobar").is_some());
     }
 
+    #[test]
+    fn test_macro_names() {
+        let mut context = PContext::default();
+        context.define_from_str("FOO=1");
+        context.define_from_str("BAR(x)=x");
+        context.undefine("FOO");
+        context.undefine("BAZ");
+        context.define_from_str("FOO=2");
+
+        // each name is interned once and the undefined ones aren't interned
+        assert_eq!(context.names.len(), 2);
+        assert_eq!(context.macros.len(), 2);
+        assert_eq!(context.definition_text("FOO").as_deref(), Some("2"));
+        assert!(context.defined("BAR"));
+        assert!(!context.defined("BAZ"));
+    }
+
+    #[test]
+    fn test_iter_macros() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define A\n",
+                "#define B   x  +  /* comment */ 1 \n",
+                "#define C(a, b) a##b # a  ## b\n",
+                "#define D(fmt, ...) printf(fmt __VA_OPT__(,) __VA_ARGS__)\n",
+                "#define E(args...) f(#args)\n",
+                "#define F() \"a  b\"\n",
+                "#undef A\n",
+                "F()",
+            )
+            .as_bytes(),
+        );
+        // the newline after #undef
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralString(b"a  b".into()));
+
+        let mut macros: Vec<_> = p.context.iter_macros().collect();
+        macros.sort_by_key(|(name, _)| *name);
+        assert_eq!(
+            macros,
+            vec![
+                ("B", MacroKind::Object),
+                (
+                    "C",
+                    MacroKind::Function {
+                        n_args: 2,
+                        variadic: false
+                    }
+                ),
+                (
+                    "D",
+                    MacroKind::Function {
+                        n_args: 2,
+                        variadic: true
+                    }
+                ),
+                (
+                    "E",
+                    MacroKind::Function {
+                        n_args: 1,
+                        variadic: true
+                    }
+                ),
+                (
+                    "F",
+                    MacroKind::Function {
+                        n_args: 0,
+                        variadic: false
+                    }
+                ),
+                // defined by the language
+                ("__cplusplus", MacroKind::Object),
+            ]
+        );
+
+        let text = |name| p.context.definition_text(name);
+        assert_eq!(text("A"), None);
+        assert_eq!(text("B").unwrap(), "x + 1");
+        assert_eq!(text("C").unwrap(), "a ## b # a ## b");
+        assert_eq!(text("D").unwrap(), "printf(fmt __VA_OPT__(,) __VA_ARGS__)");
+        assert_eq!(text("E").unwrap(), "f(#args)");
+        assert_eq!(text("F").unwrap(), "\"a  b\"");
+    }
+
+    #[test]
+    fn test_replacement_tokens() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define A (x + 1) /* one */\n",
+                "#define F(a, b) a ## b # a + c\n",
+                "#define G(fmt, ...) printf(fmt, __VA_ARGS__)\n",
+                "#define H(x, args...) f(x, args)\n",
+                "#define I() __VA_ARGS__\n",
+            )
+            .as_bytes(),
+        );
+        p.consume_tokens(5);
+
+        let tokens = |name| {
+            p.context
+                .replacement_tokens(name)
+                .unwrap()
+                .iter()
+                .map(|tok| match tok.param {
+                    Some(param) => format!("${}@{}", param, tok.offset),
+                    None => format!("{}@{}", tok.token, tok.offset),
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(tokens("A"), "(@0 x@1 +@3 1@5 )@6");
+        assert_eq!(tokens("F"), "$0@0 ##@2 $1@5 #@7 $0@9 +@11 c@13");
+        assert_eq!(tokens("G"), "printf@0 (@6 $0@7 ,@10 $1@12 )@23");
+        assert_eq!(tokens("H"), "f@0 (@1 $0@2 ,@3 $1@5 )@9");
+        assert_eq!(tokens("I"), "__VA_ARGS__@0");
+        assert!(p.context.replacement_tokens("J").is_none());
+    }
+
+    #[test]
+    fn test_define_from_str() {
+        let mut context = PContext::default();
+        assert!(context.define_from_str("A"));
+        assert!(context.define_from_str("B=x + 2*(y >> 1)"));
+        assert!(context.define_from_str("C="));
+        assert!(context.define_from_str("F(a,b)=a ## b"));
+        assert!(context.define_from_str("G(x)"));
+        assert!(context.define_from_str("H=a==b\nc"));
+        assert!(!context.define_from_str("1A=2"));
+        assert!(!context.define_from_str("=2"));
+        assert!(!context.define_from_str("I+1"));
+
+        let text = |name| context.definition_text(name);
+        assert_eq!(text("A").unwrap(), "1");
+        assert_eq!(text("B").unwrap(), "x + 2*(y >> 1)");
+        assert_eq!(text("C").unwrap(), "");
+        assert_eq!(text("F").unwrap(), "a ## b");
+        assert_eq!(text("G").unwrap(), "1");
+        assert_eq!(text("H").unwrap(), "a==b");
+        assert!(!context.defined("1A"));
+        assert!(!context.defined("I"));
+
+        let mut p = Lexer::with_context(b"A B C F(x, y) G(0)", context);
+        let mut tokens = Vec::new();
+        loop {
+            let tok = p.next();
+            if tok == Token::Eof {
+                break;
+            }
+            tokens.push(tok.to_string());
+        }
+        assert_eq!(
+            tokens,
+            vec!["1", "x", "+", "2", "*", "(", "y", ">>", "1", ")", "xy", "1"]
+        );
+
+        p.context.undefine("B");
+        p.context.undefine("F");
+        assert!(p.context.defined("A"));
+        assert!(!p.context.defined("B"));
+        assert!(!p.context.defined("F"));
+    }
+
+    #[test]
+    fn test_no_merge_in_expansion() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define F(x) x>\n",
+                "#define G(x) <=x\n",
+                "#define NEG(x) -x\n",
+                "#define M -\n",
+                "#define E\n",
+                "#define H() -M\n",
+                "#define ID(x) x\n",
+                "#define CAT(x, y) x ## y\n",
+                "F(a<=) G(>) NEG(-1) NEG(M) H() ID(-)E- ID(1)ID(.5) L ID('a') CAT(-, -)\n",
+                "a <=E> b\n",
+            )
+            .as_bytes(),
+        );
+        let mut tokens = Vec::new();
+        loop {
+            match p.next() {
+                Token::Eof => break,
+                Token::Eol => {}
+                tok => tokens.push(tok.to_string()),
+            }
+        }
+
+        assert_eq!(
+            tokens,
+            vec![
+                "a", "<=", ">", "<=", ">", "-", "-", "1", "-", "-", "-", "-", "-", "-", "1", "0.5",
+                "L", "'a'", "--", "a", "<=", ">", "b"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let mut context = PContext::default();
+        context.define_from_str("A=1");
+        context.define_from_str("B=2");
+        context.define_from_str("F(x)=x");
+
+        let checkpoint = context.checkpoint();
+        context.define_from_str("A=3");
+        context.undefine("B");
+        context.define_from_str("C=4");
+        context.undefine("C");
+        context.define_from_str("D");
+        context.undefine("F");
+        context.define_from_str("F(x, y)=x + y");
+
+        let inner = context.checkpoint();
+        context.undefine("A");
+        context.release(inner);
+        assert!(!context.defined("A"));
+        assert!(context.defined("D"));
+        assert_eq!(context.definition_text("F").unwrap(), "x + y");
+
+        context.rollback(checkpoint);
+        assert_eq!(context.definition_text("A").unwrap(), "1");
+        assert_eq!(context.definition_text("B").unwrap(), "2");
+        assert!(!context.defined("C"));
+        assert!(!context.defined("D"));
+        assert_eq!(context.definition_text("F").unwrap(), "x");
+        assert!(context.journal.is_empty());
+
+        // no more journal without any checkpoint
+        context.undefine("A");
+        assert!(context.journal.is_empty());
+    }
+
+    #[test]
+    fn test_checkpoint_out_of_order() {
+        let mut context = PContext::default();
+        let outer = context.checkpoint();
+        context.define_from_str("A=1");
+        let inner = context.checkpoint();
+        context.define_from_str("B=1");
+        context.release(outer);
+        assert!(context.rollback(inner));
+        assert!(context.defined("A"));
+        assert!(!context.defined("B"));
+        assert!(context.journal.is_empty());
+
+        let outer = context.checkpoint();
+        context.define_from_str("C=1");
+        let inner = context.checkpoint();
+        context.define_from_str("D=1");
+        assert!(context.rollback(outer));
+        assert!(!context.rollback(inner));
+        assert!(!context.defined("C"));
+        assert!(!context.defined("D"));
+        assert!(context.journal.is_empty());
+    }
+
+    #[test]
+    fn test_checkpoint_if_stack() {
+        let mut p = Lexer::new(b"#define A 1\n#if A\nx\n#define B 2\n#endif\ny");
+        while p.next() != Token::Identifier("x".into()) {}
+        let checkpoint = p.context.checkpoint();
+        while p.next() != Token::Eof {}
+        assert!(p.context.defined("B"));
+        assert!(p.context.if_state().is_none());
+
+        p.context.rollback(checkpoint);
+        assert!(p.context.defined("A"));
+        assert!(!p.context.defined("B"));
+        assert_eq!(p.context.if_state(), Some(&IfState::Eval));
+    }
+
+    #[test]
+    fn test_redefinition() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define A x +  1 /* c */\n",
+                "#define A x /* c */ + 1\n",
+                "#define F(a, b) a ## b\n",
+                "#define F( a,b ) a##b\n",
+                "#define B 1\n",
+                "#define B 2\n",
+                "#define G(a) a\n",
+                "#define G(b) b\n",
+                "#define H(a) 1\n",
+                "#define H(b) 1\n",
+                "#define I(a) a\n",
+                "#define I a\n",
+                "#undef B\n",
+                "#define B 3\n",
+                "B",
+            )
+            .as_bytes(),
+        );
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralInt(3));
+
+        let diagnostics = p.context.take_diagnostics();
+        assert_eq!(
+            diagnostics,
+            vec![
+                Diagnostic::error("\"B\" redefined".to_string(), 6, 9),
+                Diagnostic::error("\"G\" redefined".to_string(), 8, 9),
+                Diagnostic::error("\"H\" redefined".to_string(), 10, 9),
+                Diagnostic::error("\"I\" redefined".to_string(), 12, 9),
+            ]
+        );
+    }
+
     #[test]
     fn test_eval_object() {
         let mut p = Lexer::new(
@@ -339,6 +2205,27 @@ mod tests {
         assert_eq!(eval!("test2", p), "12bar(34)");
     }
 
+    #[test]
+    fn test_eval_nested_args() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define F(x, y) [x] [y]\n",
+                "#define g(a, b) a + b\n",
+                "#define test1 F(a, g(b, c))\n",
+                "#define test2 F(a, (b,c))\n",
+                "#define test3 F(\"a,b\", ())\n",
+                "#define test4 F({a, b})",
+            )
+            .as_bytes(),
+        );
+        p.consume_tokens(6);
+
+        assert_eq!(eval!("test1", p), "[a] [b + c]");
+        assert_eq!(eval!("test2", p), "[a] [(b,c)]");
+        assert_eq!(eval!("test3", p), "[\"a,b\"] [()]");
+        assert_eq!(eval!("test4", p), "[{a] [b}]");
+    }
+
     #[test]
     fn test_eval_function() {
         let mut p = Lexer::new(
@@ -370,6 +2257,354 @@ mod tests {
         assert_eq!(eval!("test", p), "\"4\"");
     }
 
+    #[test]
+    fn test_expand_once() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define xstr(s) str(s)\n",
+                "#define str(s) #s\n",
+                "#define foo 4\n",
+                "#define test xstr(foo)",
+            )
+            .as_bytes(),
+        );
+        p.consume_tokens(4);
+        let context = p.context.clone();
+
+        let step = context.expand_once("test", &[]).unwrap();
+        assert_eq!(
+            step.tokens(),
+            vec![
+                Token::Identifier("xstr".into()),
+                Token::LeftParen,
+                Token::Identifier("foo".into()),
+                Token::RightParen,
+            ]
+        );
+        // the argument is expanded before its substitution
+        let step = context.expand_once("xstr", &["foo"]).unwrap();
+        assert_eq!(step.text(), "str(4)");
+        // but not when it's stringified
+        let step = context.expand_once("str", &["foo"]).unwrap();
+        assert_eq!(step.tokens(), vec![Token::LiteralString(b"foo".into())]);
+        assert_eq!(eval!("test", p), "\"4\"");
+
+        assert!(context.expand_once("foo", &["1"]).is_none());
+        assert!(context.expand_once("xstr", &["1", "2"]).is_none());
+        assert!(context.expand_once("bar", &[]).is_none());
+        assert!(context.take_expansion_error().is_none());
+    }
+
+    #[derive(Debug, Default)]
+    struct Recorder {
+        events: RefCell<Vec<String>>,
+    }
+
+    impl ExpansionObserver for Recorder {
+        fn enter(&self, name: &str, args: Option<&[String]>, span: Span) {
+            let args = args.map_or(String::new(), |args| format!("({})", args.join(", ")));
+            self.events.borrow_mut().push(format!(
+                "enter {}{} at {}:{} [{}, {}]",
+                name, args, span.line, span.col, span.start, span.end
+            ));
+        }
+
+        fn leave(&self, name: &str, tokens: &[Token]) {
+            let tokens: Vec<_> = tokens.iter().map(|tok| tok.to_string()).collect();
+            self.events
+                .borrow_mut()
+                .push(format!("leave {} -> {}", name, tokens.join(" ")));
+        }
+    }
+
+    #[test]
+    fn test_expansion_observer() {
+        let recorder = Rc::new(Recorder::default());
+        let mut context = PContext::default();
+        context.set_observer(recorder.clone());
+        let mut p = Lexer::with_context(
+            concat!(
+                "#define xstr(s) str(s)\n",
+                "#define str(s) #s\n",
+                "#define foo 4\n",
+                "xstr(foo) foo",
+            )
+            .as_bytes(),
+            context,
+        );
+
+        assert_eq!(p.next(), Token::LiteralString(b"4".into()));
+        assert_eq!(p.next(), Token::LiteralInt(4));
+        assert_eq!(p.next(), Token::Eof);
+        assert_eq!(
+            *recorder.events.borrow(),
+            vec![
+                "enter xstr(foo) at 4:1 [55, 64]",
+                "enter foo at 4:1 [55, 64]",
+                "leave foo -> 4",
+                "enter str(4) at 4:1 [55, 64]",
+                "leave str -> \"4\"",
+                "leave xstr -> \"4\"",
+                "enter foo at 4:11 [65, 68]",
+                "leave foo -> 4",
+            ]
+        );
+    }
+
+    #[derive(Debug)]
+    struct AliasLookup {
+        aliases: HashMap<String, String>,
+    }
+
+    impl MacroLookup for AliasLookup {
+        fn lookup<'m>(&self, name: &str, macros: MacroTable<'m>) -> Option<&'m str> {
+            let name = self.aliases.get(name).map_or(name, |alias| alias.as_str());
+            macros.get_name(name)
+        }
+    }
+
+    #[test]
+    fn test_macro_kind() {
+        let mut context = PContext::default();
+        context.define_from_str("A=1");
+        context.define_from_str("F(a, b)=a + b");
+        context.define_from_str("G(a, ...)=a __VA_ARGS__");
+        context.define_from_str("H()=0");
+
+        assert_eq!(context.macro_kind("A"), Some(MacroKind::Object));
+        assert_eq!(
+            context.macro_kind("F"),
+            Some(MacroKind::Function {
+                n_args: 2,
+                variadic: false
+            })
+        );
+        assert_eq!(
+            context.macro_kind("G"),
+            Some(MacroKind::Function {
+                n_args: 2,
+                variadic: true
+            })
+        );
+        assert_eq!(
+            context.macro_kind("H"),
+            Some(MacroKind::Function {
+                n_args: 0,
+                variadic: false
+            })
+        );
+        assert_eq!(context.macro_kind("B"), None);
+        // the builtins aren't macros with a definition
+        assert_eq!(context.macro_kind("__LINE__"), None);
+
+        // a macro being expanded is hidden
+        if let Some(Macro::Object(mac)) = context.find_macro("A") {
+            mac.in_use.set(true);
+        }
+        if let Some(Macro::Function(mac)) = context.find_macro("F") {
+            mac.in_use.set(true);
+        }
+        assert_eq!(context.macro_kind("A"), None);
+        assert_eq!(context.macro_kind("F"), None);
+
+        context.undefine("G");
+        assert_eq!(context.macro_kind("G"), None);
+    }
+
+    #[test]
+    fn test_define_paren() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define A() a\n",
+                "#define B () b\n",
+                "#define C\t(x) x\n",
+                "#define D/**/(x) x\n",
+                "#define test1 A()\n",
+                "#define test2 B\n",
+                "#define test3 C(1)\n",
+            )
+            .as_bytes(),
+        );
+
+        p.consume_tokens(7);
+
+        assert_eq!(
+            p.context.macro_kind("A"),
+            Some(MacroKind::Function {
+                n_args: 0,
+                variadic: false
+            })
+        );
+        // a white between the name and the parenthesis gives an object-like macro
+        assert_eq!(p.context.macro_kind("B"), Some(MacroKind::Object));
+        assert_eq!(p.context.macro_kind("C"), Some(MacroKind::Object));
+        assert_eq!(p.context.macro_kind("D"), Some(MacroKind::Object));
+        assert_eq!(p.context.definition_text("B"), Some("() b".to_string()));
+
+        assert_eq!(eval!("test1", p), "a");
+        assert_eq!(eval!("test2", p), "() b");
+        assert_eq!(eval!("test3", p), "(x) x(1)");
+    }
+
+    #[test]
+    fn test_macro_lookup() {
+        let mut aliases = HashMap::default();
+        aliases.insert("VERSION".to_string(), "__version".to_string());
+        aliases.insert("max".to_string(), "MAX".to_string());
+        let mut context = PContext::default();
+        context.set_macro_lookup(Rc::new(AliasLookup { aliases }));
+        let mut p = Lexer::with_context(
+            concat!(
+                "#define __version 3\n",
+                "#define MAX(a, b) ((a) > (b) ? (a) : (b))\n",
+                "#ifdef VERSION\n",
+                "max(VERSION, 2)\n",
+                "#endif\n",
+                "#if defined(max) && !defined(min)\n",
+                "__version\n",
+                "#endif\n",
+            )
+            .as_bytes(),
+            context,
+        );
+
+        let mut tokens = Vec::new();
+        loop {
+            match p.next() {
+                Token::Eof => break,
+                Token::Eol => {}
+                tok => tokens.push(tok.to_string()),
+            }
+        }
+        let text = tokens.join(" ");
+        assert_eq!(text, "( ( 3 ) > ( 2 ) ? ( 3 ) : ( 2 ) ) 3");
+        assert!(p.context.get("max").is_some());
+        assert!(p.context.get("VERSION").is_some());
+        assert!(p.context.get("min").is_none());
+    }
+
+    #[test]
+    fn test_eval_object_without_macros() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define A x + y * 2\n",
+                "#define B ( x )/* comment */ - 1.5e+3\n",
+                "#define C A\n",
+                "#define D x ## y\n",
+                "#define E __COUNTER__\n",
+            )
+            .as_bytes(),
+        );
+        p.consume_tokens(5);
+
+        assert_eq!(eval!("A", p), "x + y * 2");
+        assert_eq!(eval!("B", p), "( x ) - 1.5e+3");
+        assert_eq!(eval!("C", p), "x + y * 2");
+        assert_eq!(eval!("D", p), "xy");
+        assert_eq!(eval!("E", p), "0");
+
+        // the macros defined after A are expanded in A
+        let mut p = Lexer::with_context(
+            concat!("#define x 1\n", "#define xy 3\n").as_bytes(),
+            p.context,
+        );
+        p.consume_tokens(2);
+
+        assert_eq!(eval!("A", p), "1 + y * 2");
+        assert_eq!(eval!("B", p), "( 1 ) - 1.5e+3");
+        assert_eq!(eval!("C", p), "1 + y * 2");
+        assert_eq!(eval!("D", p), "3");
+    }
+
+    #[cfg(feature = "checked-bounds")]
+    #[test]
+    #[should_panic(expected = "Invalid chunk 0..10 in a replacement list of length 3")]
+    fn test_corrupted_actions() {
+        let mac = MacroFunction::new(
+            b"a+b".to_vec(),
+            vec![Action::Chunk(10)],
+            0,
+            None,
+            String::new(),
+            "a+b".to_string(),
+        );
+        let context = PContext::default();
+        let mut out = MacroOutput::default();
+        mac.eval_parsed_args("F", &[], &context, &mut out);
+    }
+
+    #[test]
+    fn test_try_new() {
+        let new = |out: &[u8], actions, n_args, va_args| {
+            MacroFunction::try_new(
+                out.to_vec(),
+                actions,
+                n_args,
+                va_args,
+                String::new(),
+                String::new(),
+            )
+        };
+
+        // #define F(a, ...) (a) + __VA_OPT__(f(__VA_ARGS__))
+        let mac = new(
+            b"() + f()",
+            vec![
+                Action::Chunk(1),
+                Action::Arg(0),
+                Action::Chunk(5),
+                Action::VaOpt(vec![Action::Chunk(7), Action::Arg(1)], 8),
+            ],
+            2,
+            Some(1),
+        )
+        .unwrap();
+        let mut p = Lexer::new(b"(x, y)");
+        let args = p.get_arguments(2, Some(&1)).unwrap();
+        let mut out = MacroOutput::default();
+        mac.eval_parsed_args("F", &args, &PContext::default(), &mut out);
+        assert_eq!(String::from_utf8(out.into_text()).unwrap(), "(x) + f(y)");
+
+        // the macro can be defined in a context as the ones from a #define
+        let mut context = PContext::default();
+        context.define_function("F", mac);
+        assert_eq!(
+            context.expand_once("F", &["x", "y"]).unwrap().text(),
+            "(x) + f(y)"
+        );
+
+        assert_eq!(
+            new(b"abc", vec![Action::Chunk(10)], 0, None).err(),
+            Some(MacroBuildError::Chunk { start: 0, end: 10 })
+        );
+        assert_eq!(
+            new(b"abc", vec![Action::Chunk(2), Action::Chunk(1)], 0, None).err(),
+            Some(MacroBuildError::Chunk { start: 2, end: 1 })
+        );
+        assert_eq!(
+            new(
+                b"abc",
+                vec![Action::Chunk(2), Action::VaOpt(vec![], 1)],
+                1,
+                Some(0)
+            )
+            .err(),
+            Some(MacroBuildError::Chunk { start: 2, end: 1 })
+        );
+        assert_eq!(
+            new(b"abc", vec![Action::Arg(0), Action::Stringify(1)], 1, None).err(),
+            Some(MacroBuildError::Arg(1))
+        );
+        assert_eq!(
+            new(b"abc", vec![Action::Concat(0)], 0, None).err(),
+            Some(MacroBuildError::Arg(0))
+        );
+        assert_eq!(
+            new(b"abc", vec![], 2, Some(0)).err(),
+            Some(MacroBuildError::VaArgs(0))
+        );
+    }
+
     #[test]
     fn test_eval_base() {
         let mut p = Lexer::new(
@@ -471,8 +2706,80 @@ mod tests {
 
         assert_eq!(
             eval!("test", p),
-            "\"R\\\"delimiter( a string with some \\\', \\\" and \\\n.)delimiter\\\"\""
+            "\"R\\\"delimiter( a string with some ', \\\" and \\n.)delimiter\\\"\""
+        );
+    }
+
+    #[test]
+    fn test_eval_stringify_literals() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define str(a) #a\n",
+                "#define test1 str(\"a\\tb\")\n",
+                "#define test2 str('\\'')\n",
+                "#define test3 str('\"')\n",
+                "#define test4 str(f(x, \"it's \\\"y\\\"\") + L\"\\\\\")\n",
+            )
+            .as_bytes(),
+        );
+        p.consume_tokens(4);
+
+        assert_eq!(eval!("test1", p), r#""\"a\\tb\"""#);
+        assert_eq!(eval!("test2", p), r#""'\\''""#);
+        assert_eq!(eval!("test3", p), r#""'\"'""#);
+        assert_eq!(
+            eval!("test4", p),
+            r#""f(x, \"it's \\\"y\\\"\") + L\"\\\\\"""#
+        );
+    }
+
+    #[test]
+    fn test_eval_stringify_spaces() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define str(a) #a\n",
+                "#define vstr(...) #__VA_ARGS__\n",
+                "#define test1 str(a   b)\n",
+                "#define test2 str(  a /* comment */ b  )\n",
+                "#define test3 str(a/**/b)\n",
+                "#define test4 str( f( x ,  y ) )\n",
+                "#define test5 vstr(  a ,  b  ,c  )\n",
+                "#define test6 str(  )\n",
+            )
+            .as_bytes(),
+        );
+        p.consume_tokens(8);
+
+        assert_eq!(eval!("test1", p), "\"a b\"");
+        assert_eq!(eval!("test2", p), "\"a b\"");
+        assert_eq!(eval!("test3", p), "\"a b\"");
+        assert_eq!(eval!("test4", p), "\"f( x , y )\"");
+        assert_eq!(eval!("test5", p), "\"a , b ,c\"");
+        assert_eq!(eval!("test6", p), "\"\"");
+    }
+
+    #[test]
+    fn test_eval_stringify_escape() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define str(a) #a\n",
+                "#define xstr(a) str(a)\n",
+                "#define test1 str(\"a\\\"b\\\\\")\n",
+                "#define test2 str('\\\\')\n",
+                "#define test3 str(\"a\" + \"\\n\")\n",
+                "#define test4 str(str(\"a\\\\b\"))\n",
+                "#define test5 xstr(str(\"a\\\\b\"))\n",
+            )
+            .as_bytes(),
         );
+        p.consume_tokens(7);
+
+        // "a\"b\\" -> "\"a\\\"b\\\\\""
+        assert_eq!(eval!("test1", p), r#""\"a\\\"b\\\\\"""#);
+        assert_eq!(eval!("test2", p), r#""'\\\\'""#);
+        assert_eq!(eval!("test3", p), r#""\"a\" + \"\\n\"""#);
+        assert_eq!(eval!("test4", p), r#""str(\"a\\\\b\")""#);
+        assert_eq!(eval!("test5", p), r#""\"\\\"a\\\\\\\\b\\\"\"""#);
     }
 
     #[test]
@@ -518,6 +2825,440 @@ mod tests {
         assert_eq!(eval!("test", p), "hello hello hello foo(hello)");
     }
 
+    fn get_tokens(source: &str) -> Vec<String> {
+        // the expanded tokens borrow the context
+        let mut ctx = PContext::default();
+        let tokens = Tokenizer::new(source.as_bytes(), &mut ctx)
+            .filter(|tok| *tok != Token::Eol)
+            .map(|tok| format!("{:?}", tok))
+            .collect();
+        assert!(ctx.take_diagnostics().is_empty());
+        tokens
+    }
+
+    #[test]
+    fn test_rescan_painted() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define f(a) a*g\n",
+                "#define g(a) f(a)\n",
+                "#define h(x) x h\n",
+                "#define test1 f(2)(9)\n",
+                "#define test2 g(2)(9)\n",
+                "#define test3 h(1)(2)\n",
+            )
+            .as_bytes(),
+        );
+
+        p.consume_tokens(6);
+
+        // the arguments of g follow the expansion of f
+        assert_eq!(eval!("test1", p), "2*9*g");
+        // g comes from the expansion of g so it's painted
+        assert_eq!(eval!("test2", p), "2*g(9)");
+        assert_eq!(eval!("test3", p), "1 h(2)");
+
+        assert_eq!(
+            get_tokens("#define f(a) a*g\n#define g(a) f(a)\nf(2)(9)"),
+            get_tokens("2*9*g")
+        );
+        assert_eq!(
+            get_tokens("#define h(x) x h\nh(1)(2)"),
+            get_tokens("1 h(2)")
+        );
+    }
+
+    #[test]
+    fn test_separate_tokens() {
+        let mut out = MacroOutput::default();
+        out.extend_from_slice(b"--");
+        out.push_painted("A");
+        out.extend_from_slice(b"--");
+        out.push_painted("B");
+        separate_tokens(&mut out, &[1, 2, 3, 4, 5]);
+        assert_eq!(&out[..], b"- -A- -B");
+        assert_eq!(out.painted(), &[3, 7]);
+        assert_eq!(out.separators(), &[1, 5]);
+    }
+
+    #[test]
+    fn test_rescan_control_char() {
+        // a 0x01 in the source doesn't paint the identifier following it
+        assert_eq!(
+            get_tokens("#define A 1\n#define ID(x) x\nID(\x01A) ID(ID(\x01A)) ID(\x01)A"),
+            get_tokens("1 1 1")
+        );
+    }
+
+    #[test]
+    fn test_rescan_standard_example() {
+        // example 3 in [cpp.rescan]
+        let source = concat!(
+            "#define x 3\n",
+            "#define f(a) f(x * (a))\n",
+            "#undef x\n",
+            "#define x 2\n",
+            "#define g f\n",
+            "#define z z[0]\n",
+            "#define h g(~\n",
+            "#define m(a) a(w)\n",
+            "#define w 0,1\n",
+            "#define t(a) a\n",
+            "#define p() int\n",
+            "#define q(x) x\n",
+            "#define r(x,y) x ## y\n",
+            "#define str(x) # x\n",
+            "f(y+1) + f(f(z)) % t(t(g)(0) + t)(1);\n",
+            "g(x+(3,4)-w) | h 5) & m\n",
+            "      (f)^m(m);\n",
+            "p() i[q()] = { q(1), r(2,3), r(4,), r(,5), r(,) };\n",
+            "char c[2][6] = { str(hello), str() };\n",
+        );
+        let expected = concat!(
+            "f(2 * (y+1)) + f(2 * (f(2 * (z[0])))) % f(2 * (0)) + t(1);\n",
+            "f(2 * (2+(3,4)-0,1)) | f(2 * (~ 5)) & f(2 * (0,1))^m(0,1);\n",
+            "int i[] = { 1, 23, 4, 5, };\n",
+            "char c[2][6] = { \"hello\", \"\" };\n",
+        );
+
+        assert_eq!(get_tokens(source), get_tokens(expected));
+    }
+
+    #[test]
+    fn test_expansion_depth() {
+        // F0(x) -> F1(x) -> ... -> F999(x) -> x
+        let mut buf = String::new();
+        for i in 0..1000 {
+            buf.push_str(&format!("#define F{}(x) F{}(x)\n", i, i + 1));
+        }
+        buf.push_str("#define F1000(x) x\n");
+        buf.push_str("F0(1) F990(2)");
+
+        // the frames are big in debug mode
+        let diagnostics = std::thread::Builder::new()
+            .stack_size(256 << 20)
+            .spawn(move || {
+                let mut p = Lexer::new(buf.as_bytes());
+                assert_eq!(p.next(), Token::LiteralInt(2));
+                assert_eq!(p.next(), Token::Eof);
+                p.context.take_diagnostics()
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic::error(
+                "expansion of macro \"F0\" nested too deeply (limit is 256)".to_string(),
+                1002,
+                1
+            )]
+        );
+
+        // the nested expansions in the arguments are counted too
+        let mut context = PContext::default();
+        context.set_max_expansion_depth(3);
+        let mut p = Lexer::with_context(
+            concat!("#define F(x) x\n", "F(F(F(1))) F(F(F(F(2)))) 3").as_bytes(),
+            context,
+        );
+        assert_eq!(p.next(), Token::LiteralInt(1));
+        assert_eq!(p.next(), Token::LiteralInt(3));
+        assert_eq!(p.next(), Token::Eof);
+        assert_eq!(p.context.take_diagnostics().len(), 1);
+    }
+
+    #[test]
+    fn test_wrong_arguments_count() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define F(a, b) a + b\n",
+                "#define G(a, b, ...) a b __VA_ARGS__\n",
+                "#define H F(1)\n",
+                "F(1) F(1, 2, 3) F(1, 2)\n",
+                "G(1) G(1, 2) H",
+            )
+            .as_bytes(),
+        );
+
+        // the invocations are left unexpanded
+        let mut tokens = Vec::new();
+        loop {
+            let tok = p.next();
+            if tok == Token::Eof {
+                break;
+            }
+            tokens.push(tok);
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Identifier("F".into()),
+                Token::LeftParen,
+                Token::LiteralInt(1),
+                Token::RightParen,
+                Token::Identifier("F".into()),
+                Token::LeftParen,
+                Token::LiteralInt(1),
+                Token::Comma,
+                Token::LiteralInt(2),
+                Token::Comma,
+                Token::LiteralInt(3),
+                Token::RightParen,
+                Token::LiteralInt(1),
+                Token::Plus,
+                Token::LiteralInt(2),
+                Token::Eol,
+                Token::Identifier("G".into()),
+                Token::LeftParen,
+                Token::LiteralInt(1),
+                Token::RightParen,
+                Token::LiteralInt(1),
+                Token::LiteralInt(2),
+                Token::Identifier("F".into()),
+                Token::LeftParen,
+                Token::LiteralInt(1),
+                Token::RightParen,
+            ]
+        );
+
+        let diagnostics = p.context.take_diagnostics();
+        assert_eq!(
+            diagnostics,
+            vec![
+                Diagnostic::error(
+                    "macro \"F\" requires 2 arguments, but only 1 given".to_string(),
+                    4,
+                    1
+                ),
+                Diagnostic::error(
+                    "macro \"F\" passed 3 arguments, but takes just 2".to_string(),
+                    4,
+                    6
+                ),
+                Diagnostic::error(
+                    "macro \"G\" requires at least 2 arguments, but only 1 given".to_string(),
+                    5,
+                    1
+                ),
+                Diagnostic::error(
+                    "macro \"F\" requires 2 arguments, but only 1 given".to_string(),
+                    5,
+                    14
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unterminated_arguments() {
+        let mut p = Lexer::new(concat!("#define F(a) a\n", "F\n  (1) F\n", "F(2").as_bytes());
+
+        // the arguments can be on the next lines
+        assert_eq!(p.next(), Token::LiteralInt(1));
+        assert_eq!(p.next(), Token::Identifier("F".into()));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Identifier("F".into()));
+        assert_eq!(p.next(), Token::LeftParen);
+        assert_eq!(p.next(), Token::LiteralInt(2));
+        assert_eq!(p.next(), Token::Eof);
+
+        assert_eq!(
+            p.context.take_diagnostics(),
+            vec![Diagnostic::error(
+                "unterminated argument list invoking macro \"F\"".to_string(),
+                4,
+                1
+            )]
+        );
+    }
+
+    #[test]
+    fn test_empty_arguments() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define STR(x) #x\n",
+                "#define STR2(x, y) #x #y\n",
+                "#define CAT(x, y) x ## y\n",
+                "#define ID(x) [x]\n",
+                "#define ID2(x, y) [x|y]\n",
+                "#define ID3(x, y, z) [x|y|z]\n",
+                "#define NONE() []\n",
+                "#define test1 STR()\n",
+                "#define test2 STR2(,)\n",
+                "#define test3 STR2(a,)\n",
+                "#define test4 CAT(,)\n",
+                "#define test5 CAT(a,)\n",
+                "#define test6 CAT(,b)\n",
+                "#define test7 ID()\n",
+                "#define test8 ID( )\n",
+                "#define test9 ID2(,)\n",
+                "#define test10 ID3(,,)\n",
+                "#define test11 ID3(a, ,c)\n",
+                "#define test12 NONE()\n",
+                "#define test13 ID(ID())\n",
+            )
+            .as_bytes(),
+        );
+
+        p.consume_tokens(20);
+
+        assert_eq!(eval!("test1", p), "\"\"");
+        assert_eq!(eval!("test2", p), "\"\" \"\"");
+        assert_eq!(eval!("test3", p), "\"a\" \"\"");
+        assert_eq!(eval!("test4", p), "");
+        assert_eq!(eval!("test5", p), "a");
+        assert_eq!(eval!("test6", p), "b");
+        assert_eq!(eval!("test7", p), "[]");
+        assert_eq!(eval!("test8", p), "[]");
+        assert_eq!(eval!("test9", p), "[|]");
+        // the two | mustn't be merged into a ||
+        assert_eq!(eval!("test10", p), "[| |]");
+        assert_eq!(eval!("test11", p), "[a| |c]");
+        assert_eq!(eval!("test12", p), "[]");
+        assert_eq!(eval!("test13", p), "[[]]");
+
+        let mut p = Lexer::new(
+            concat!(
+                "#define STR(x) #x\n",
+                "#define CAT(x, y) x ## y\n",
+                "STR() CAT(,) CAT(,1) STR( )",
+            )
+            .as_bytes(),
+        );
+        assert_eq!(p.next(), Token::LiteralString(b"".into()));
+        assert_eq!(p.next(), Token::LiteralInt(1));
+        assert_eq!(p.next(), Token::LiteralString(b"".into()));
+        assert_eq!(p.next(), Token::Eof);
+        assert!(p.context.take_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_placemarker() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define CAT(a, b) a ## b\n",
+                "#define CAT3(a, b, c) a ## b ## c\n",
+                "#define SEP(a, b, c) a ## b c\n",
+                "#define BRACKET(a, b) [a ## b]\n",
+                "#define ARG(a, b) a ## b ## a\n",
+                "#define STRCAT(a, b) #a ## b\n",
+                "#define EMPTY\n",
+                "#define y 42\n",
+                "#define test1 CAT(,y)\n",
+                "#define test2 CAT(x,)\n",
+                "#define test3 CAT(,)\n",
+                "#define test4 CAT3(x,,z)\n",
+                "#define test5 CAT3(,,z)\n",
+                "#define test6 CAT3(,,)\n",
+                "#define test7 SEP(x,,z)\n",
+                "#define test8 BRACKET(,)\n",
+                "#define test9 ARG(,b)\n",
+                "#define test10 CAT(EMPTY,x)\n",
+                "#define test11 CAT(x, EMPTY)\n",
+                "#define test12 STRCAT(,)\n",
+                "#define test13 CAT(+,)=\n",
+            )
+            .as_bytes(),
+        );
+
+        p.consume_tokens(21);
+
+        assert_eq!(eval!("test1", p), "42");
+        assert_eq!(eval!("test2", p), "x");
+        assert_eq!(eval!("test3", p), "");
+        assert_eq!(eval!("test4", p), "xz");
+        assert_eq!(eval!("test5", p), "z");
+        assert_eq!(eval!("test6", p), "");
+        assert_eq!(eval!("test7", p), "x z");
+        assert_eq!(eval!("test8", p), "[]");
+        assert_eq!(eval!("test9", p), "b");
+        // the operands aren't expanded before the pasting
+        assert_eq!(eval!("test10", p), "EMPTYx");
+        assert_eq!(eval!("test11", p), "xEMPTY");
+        assert_eq!(eval!("test12", p), "\"\"");
+        // the placemarker doesn't make + and = a +=
+        assert_eq!(eval!("test13", p), "+ =");
+        assert!(p.context.take_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_comma_elision() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define LOG(fmt, ...) printf(fmt, ##__VA_ARGS__)\n",
+                "#define LOG2(fmt, args...) printf(fmt , ## args)\n",
+                "#define X 42\n",
+                "#define test1 LOG(\"a\")\n",
+                "#define test2 LOG(\"a\",)\n",
+                "#define test3 LOG(\"a\", x)\n",
+                "#define test4 LOG(\"a\", x, y)\n",
+                "#define test5 LOG(\"a\", X)\n",
+                "#define test6 LOG2(\"a\")\n",
+                "#define test7 LOG2(\"a\", x, y)\n",
+            )
+            .as_bytes(),
+        );
+
+        p.consume_tokens(10);
+
+        assert_eq!(eval!("test1", p), "printf(\"a\")");
+        assert_eq!(eval!("test2", p), "printf(\"a\")");
+        assert_eq!(eval!("test3", p), "printf(\"a\",x)");
+        assert_eq!(eval!("test4", p), "printf(\"a\",x, y)");
+        assert_eq!(eval!("test5", p), "printf(\"a\",42)");
+        assert_eq!(eval!("test6", p), "printf(\"a\" )");
+        assert_eq!(eval!("test7", p), "printf(\"a\" ,x, y)");
+    }
+
+    #[test]
+    fn test_invalid_paste() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define CAT(a, b) a ## b\n",
+                "#define SLASH(a) a ## /\n",
+                "#define ARROW(a) a ## >\n",
+                "CAT(x, _y) CAT(L, \"s\") CAT(, 1) ARROW(-)\n",
+                "CAT(x, !) SLASH(/) CAT(+, -)",
+            )
+            .as_bytes(),
+        );
+
+        assert_eq!(p.next(), Token::Identifier("x_y".into()));
+        assert_eq!(p.next(), Token::LiteralLString(b"s".into()));
+        assert_eq!(p.next(), Token::LiteralInt(1));
+        assert_eq!(p.next(), Token::Arrow);
+        assert_eq!(p.next(), Token::Eol);
+        assert!(p.context.take_diagnostics().is_empty());
+
+        // the tokens are kept unpasted
+        assert_eq!(p.next(), Token::Identifier("x".into()));
+        assert_eq!(p.next(), Token::Not);
+        assert_eq!(p.next(), Token::Plus);
+        assert_eq!(p.next(), Token::Minus);
+        assert_eq!(p.next(), Token::Eof);
+        assert_eq!(
+            p.context.take_diagnostics(),
+            vec![
+                Diagnostic::error(
+                    "pasting \"x\" and \"!\" does not give a valid preprocessing token".to_string(),
+                    5,
+                    1
+                ),
+                Diagnostic::error(
+                    "pasting \"/\" and \"/\" does not give a valid preprocessing token".to_string(),
+                    5,
+                    11
+                ),
+                Diagnostic::error(
+                    "pasting \"+\" and \"-\" does not give a valid preprocessing token".to_string(),
+                    5,
+                    20
+                ),
+            ]
+        );
+    }
+
     #[test]
     fn test_eval_va() {
         let mut p = Lexer::new(
@@ -535,9 +3276,443 @@ mod tests {
 
         p.consume_tokens(7);
 
-        assert_eq!(eval!("test1", p), "a b c,d,e,f");
-        assert_eq!(eval!("test2", p), "a b c,d,e,f");
-        assert_eq!(eval!("test3", p), "printf(a,b)");
+        assert_eq!(eval!("test1", p), "a b c, d, e, f");
+        assert_eq!(eval!("test2", p), "a b c, d, e, f");
+        assert_eq!(eval!("test3", p), "printf(a, b)");
         assert_eq!(eval!("test4", p), "printf()");
     }
+
+    #[test]
+    fn test_eval_va_nested() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define foo(x, y, ...) x y __VA_ARGS__\n",
+                "#define str(x, ...) #__VA_ARGS__\n",
+                "#define g(a, b) a + b\n",
+                "#define count(...) n(__VA_ARGS__)\n",
+                "#define test1 foo(a,b, g(1,2), 3)\n",
+                "#define test2 foo(a, b, c,)\n",
+                "#define test3 foo(a, b, h(1, (2,3)),[4 ,5] )\n",
+                "#define test4 str(a,  b ,c(1 , 2),   d  )\n",
+                "#define test5 str(a, b,)\n",
+                "#define test6 count( g(1,2),x)\n",
+            )
+            .as_bytes(),
+        );
+
+        p.consume_tokens(10);
+
+        assert_eq!(eval!("test1", p), "a b 1 + 2, 3");
+        assert_eq!(eval!("test2", p), "a b c,");
+        assert_eq!(eval!("test3", p), "a b h(1, (2,3)),[4 ,5]");
+        assert_eq!(eval!("test4", p), "\"b ,c(1 , 2), d\"");
+        assert_eq!(eval!("test5", p), "\"b,\"");
+        assert_eq!(eval!("test6", p), "n(1 + 2,x)");
+    }
+
+    #[test]
+    fn test_eval_expanded_once() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define SQ(x) ((x) * (x))\n",
+                "#define CUBE(x) ((x) * SQ(x))\n",
+                "#define TWICE(x) x x\n",
+                "#define A 1 + B\n",
+                "#define B 2\n",
+                "#define test1 CUBE(A)\n",
+                "#define test2 TWICE(__COUNTER__)\n",
+                "#define test3 TWICE(SQ(A))\n",
+            )
+            .as_bytes(),
+        );
+
+        p.consume_tokens(8);
+
+        assert_eq!(eval!("test1", p), "((1 + 2) * ((1 + 2) * (1 + 2)))");
+        // the argument is expanded before being substituted
+        assert_eq!(eval!("test2", p), "0 0");
+        assert_eq!(eval!("test3", p), "((1 + 2) * (1 + 2)) ((1 + 2) * (1 + 2))");
+    }
+
+    #[test]
+    fn test_eval_anti_paste() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define PLUS +\n",
+                "#define SLASH /\n",
+                "#define LT <\n",
+                "#define F(x) x+\n",
+                "#define G(x) -x\n",
+                "#define E\n",
+                "#define test1 PLUS PLUS\n",
+                "#define test2 PLUS/**/PLUS\n",
+                "#define test3 PLUS E PLUS\n",
+                "#define test4 F(+)\n",
+                "#define test5 F(PLUS)\n",
+                "#define test6 G(-1)\n",
+                "#define test7 G(E-)\n",
+                "#define test8 -PLUS\n",
+                "#define test9 SLASH SLASH\n",
+                "#define test10 LT<=>\n",
+                "#define test11 LT:\n",
+                "#define test12 (PLUS)\n",
+            )
+            .as_bytes(),
+        );
+
+        p.consume_tokens(18);
+
+        assert_eq!(eval!("test1", p), "+ +");
+        assert_eq!(eval!("test2", p), "+ +");
+        assert_eq!(eval!("test3", p), "+ +");
+        assert_eq!(eval!("test4", p), "+ +");
+        assert_eq!(eval!("test5", p), "+ +");
+        assert_eq!(eval!("test6", p), "- -1");
+        assert_eq!(eval!("test7", p), "- -");
+        // -+ isn't a token
+        assert_eq!(eval!("test8", p), "-+");
+        // not a comment
+        assert_eq!(eval!("test9", p), "/ /");
+        assert_eq!(eval!("test10", p), "< <=>");
+        // not the digraph <:
+        assert_eq!(eval!("test11", p), "< :");
+        assert_eq!(eval!("test12", p), "(+)");
+
+        let mut ctx = PContext::default();
+        let out =
+            crate::lexer::preprocess_to_string(b"#define PLUS +\nPLUS PLUS +PLUS PLUS-", &mut ctx);
+        assert_eq!(out.unwrap(), "+ + + + +-");
+    }
+
+    #[test]
+    fn test_eval_named_va() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define str(x, args...) x #args\n",
+                "#define cat(x, args...) x ## args\n",
+                "#define cat2(x, args...) args ## x\n",
+                "#define test1 str(a, b,  c, \"d\")\n",
+                "#define test2 str(a)\n",
+                "#define test3 cat(x, y, z)\n",
+                "#define test4 cat2(x, y, z)\n",
+                "#define test5 cat(x)\n",
+            )
+            .as_bytes(),
+        );
+
+        p.consume_tokens(8);
+
+        assert_eq!(eval!("test1", p), "a \"b, c, \\\"d\\\"\"");
+        assert_eq!(eval!("test2", p), "a \"\"");
+        assert_eq!(eval!("test3", p), "xy,z");
+        assert_eq!(eval!("test4", p), "y,zx");
+        assert_eq!(eval!("test5", p), "x");
+    }
+
+    #[test]
+    fn test_eval_va_opt() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define F(a, ...) a __VA_OPT__(,) __VA_ARGS__\n",
+                "#define G(f, ...) f(0 __VA_OPT__(, (1, (2)), #f) __VA_ARGS__)\n",
+                "#define test1 F(x)\n",
+                "#define test2 F(x, y, z)\n",
+                "#define test3 F(x, /* nothing */ )\n",
+                "#define test4 G(foo)\n",
+                "#define test5 G(foo, bar)\n",
+            )
+            .as_bytes(),
+        );
+
+        p.consume_tokens(7);
+
+        assert_eq!(eval!("test1", p), "x ");
+        assert_eq!(eval!("test2", p), "x , y, z");
+        assert_eq!(eval!("test3", p), "x ");
+        assert_eq!(eval!("test4", p), "foo(0 )");
+        assert_eq!(eval!("test5", p), "foo(0 , (1, (2)), \"foo\" bar)");
+    }
+
+    #[test]
+    fn test_va_opt_standard_examples() {
+        // examples in [cpp.subst]
+        assert_eq!(
+            get_tokens(concat!(
+                "#define F(...) f(0 __VA_OPT__(,) __VA_ARGS__)\n",
+                "#define G(X, ...) f(0, X __VA_OPT__(,) __VA_ARGS__)\n",
+                "#define SDEF(sname, ...) S sname __VA_OPT__(= { __VA_ARGS__ })\n",
+                "#define EMP\n",
+                "F(a,b,c)\n",
+                "F()\n",
+                "F(EMP)\n",
+                "G(a,b,c)\n",
+                "G(a,)\n",
+                "G(a)\n",
+                "SDEF(foo);\n",
+                "SDEF(bar, 1, 2);\n",
+            )),
+            get_tokens(concat!(
+                "f(0, a, b, c)\n",
+                "f(0)\n",
+                "f(0)\n",
+                "f(0, a, b, c)\n",
+                "f(0, a)\n",
+                "f(0, a)\n",
+                "S foo;\n",
+                "S bar = { 1, 2 };\n",
+            ))
+        );
+        assert_eq!(
+            get_tokens(concat!(
+                "#define H2(X, Y, ...) __VA_OPT__(X ## Y,) __VA_ARGS__\n",
+                "#define H3(X, ...) #__VA_OPT__(X##X X##X)\n",
+                "#define H4(X, ...) __VA_OPT__(a X ## X) ## b\n",
+                "#define H5A(...) __VA_OPT__()/**/__VA_OPT__()\n",
+                "#define H5B(X) a ## X ## b\n",
+                "#define H5C(X) H5B(X)\n",
+                "H2(a, b, c, d)\n",
+                "H3(, 0)\n",
+                "H4(, 1)\n",
+                "H5C(H5A())\n",
+            )),
+            get_tokens("ab, c, d\n\"\"\na b\nab\n")
+        );
+    }
+
+    #[test]
+    fn test_stringify_separated_tokens() {
+        // the spaces keeping the tokens apart aren't stringified
+        assert_eq!(
+            get_tokens(concat!(
+                "#define str(s) # s\n",
+                "#define xstr(s) str(s)\n",
+                "#define ID(x) x\n",
+                "#define CAT(a, b) a ## b\n",
+                "#define G(x) CAT(x, c)\n",
+                "xstr(ID(a)ID(b))\n",
+                "xstr(CAT(a,b)c)\n",
+                "xstr(ID(-)ID(-))\n",
+                "xstr(G(ID(a)ID(b)))\n",
+                "xstr(ID(ID(a)ID(b)) ID(c))\n",
+                "ID(-)ID(-)\n",
+            )),
+            get_tokens("\"ab\"\n\"abc\"\n\"--\"\n\"abc\"\n\"ab c\"\n- -\n")
+        );
+    }
+
+    #[test]
+    fn test_c11_examples() {
+        // examples in C11 6.10.3.5
+        assert_eq!(
+            get_tokens(concat!(
+                "#define x 3\n",
+                "#define f(a) f(x * (a))\n",
+                "#undef x\n",
+                "#define x 2\n",
+                "#define g f\n",
+                "#define z z[0]\n",
+                "#define h g(~\n",
+                "#define m(a) a(w)\n",
+                "#define w 0,1\n",
+                "#define t(a) a\n",
+                "#define p() int\n",
+                "#define q(x) x\n",
+                "#define r(x,y) x ## y\n",
+                "#define str(x) # x\n",
+                "f(y+1) + f(f(z)) % t(t(g)(0) + t)(1);\n",
+                "g(x+(3,4)-w) | h 5) & m\n",
+                "(f)^m(m);\n",
+                "p() i[q()] = { q(1), r(2,3), r(4,), r(,5), r(,) };\n",
+                "char c[2][6] = { str(hello), str() };\n",
+            )),
+            get_tokens(concat!(
+                "f(2 * (y+1)) + f(2 * (f(2 * (z[0])))) % f(2 * (0)) + t(1);\n",
+                "f(2 * (2+(3,4)-0,1)) | f(2 * (~ 5)) & f(2 * (0,1))^m(0,1);\n",
+                "int i[] = { 1, 23, 4, 5, };\n",
+                "char c[2][6] = { \"hello\", \"\" };\n",
+            ))
+        );
+        assert_eq!(
+            get_tokens(concat!(
+                "#define str(s) # s\n",
+                "#define xstr(s) str(s)\n",
+                "#define debug(s, t) printf(\"x\" # s \"= %d, x\" # t \"= %s\", \\\n",
+                " x ## s, x ## t)\n",
+                "#define INCFILE(n) vers ## n\n",
+                "#define glue(a, b) a ## b\n",
+                "#define xglue(a, b) glue(a, b)\n",
+                "#define HIGHLOW \"hello\"\n",
+                "#define LOW LOW \", world\"\n",
+                "debug(1, 2);\n",
+                "fputs(str(strncmp(\"abc\\0d\", \"abc\", '\\4') // this goes away\n",
+                " == 0) str(: @\\n), s);\n",
+                "xstr(INCFILE(2).h)\n",
+                "glue(HIGH, LOW);\n",
+                "xglue(HIGH, LOW)\n",
+            )),
+            get_tokens(concat!(
+                "printf(\"x\" \"1\" \"= %d, x\" \"2\" \"= %s\", x1, x2);\n",
+                "fputs(\"strncmp(\\\"abc\\\\0d\\\", \\\"abc\\\", '\\\\4') == 0\" \": @\\n\", s);\n",
+                "\"vers2.h\"\n",
+                "\"hello\";\n",
+                "\"hello\" \", world\"\n",
+            ))
+        );
+        assert_eq!(
+            get_tokens(concat!(
+                "#define t(x,y,z) x ## y ## z\n",
+                "int j[] = { t(1,2,3), t(,4,5), t(6,,7), t(8,9,),\n",
+                " t(10,,), t(,11,), t(,,12), t(,,) };\n",
+            )),
+            get_tokens("int j[] = { 123, 45, 67, 89,\n 10, 11, 12, };\n")
+        );
+        assert_eq!(
+            get_tokens(concat!(
+                "#define debug(...) fprintf(stderr, __VA_ARGS__)\n",
+                "#define showlist(...) puts(#__VA_ARGS__)\n",
+                "#define report(test, ...) ((test)?puts(#test):\\\n",
+                " printf(__VA_ARGS__))\n",
+                "debug(\"Flag\");\n",
+                "debug(\"X = %d\\n\", x);\n",
+                "showlist(The first, second, and third items.);\n",
+                "report(x>y, \"x is %d but y is %d\", x, y);\n",
+            )),
+            get_tokens(concat!(
+                "fprintf(stderr, \"Flag\");\n",
+                "fprintf(stderr, \"X = %d\\n\", x);\n",
+                "puts(\"The first, second, and third items.\");\n",
+                "((x>y)?puts(\"x>y\"): printf(\"x is %d but y is %d\", x, y));\n",
+            ))
+        );
+    }
+
+    #[test]
+    fn test_stringify_va_opt() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define EMP\n",
+                "#define S(a, ...) #__VA_OPT__(a  b \"c\\n\" __VA_ARGS__)\n",
+                "#define F(...) __VA_OPT__(x)\n",
+                "#define G(a, ...) f(a, ## __VA_ARGS__)\n",
+                "#define test1 S(1, 2,3)\n",
+                "#define test2 S(1)\n",
+                "#define test3 S(1, EMP)\n",
+                "#define test4 F(EMP)\n",
+                "#define test5 F(EMP 1)\n",
+                "#define test6 G(1, EMP)\n",
+            )
+            .as_bytes(),
+        );
+
+        p.consume_tokens(10);
+
+        assert_eq!(eval!("test1", p), "\"1 b \\\"c\\\\n\\\" 2,3\"");
+        assert_eq!(eval!("test2", p), "\"\"");
+        assert_eq!(eval!("test3", p), "\"\"");
+        assert_eq!(eval!("test4", p), "");
+        assert_eq!(eval!("test5", p), "x");
+        // the comma before ## __VA_ARGS__ is only removed when there are no arguments
+        assert_eq!(eval!("test6", p), "f(1,)");
+    }
+
+    #[test]
+    fn test_line() {
+        let mut p = Lexer::new(
+            concat!(
+                "__LINE__\n",
+                "#define L __LINE__\n",
+                "\n",
+                "L\n",
+                "#define F(x) x + __LINE__\n",
+                "F(__LINE__)\n",
+                "F(\n",
+                "__LINE__)\n",
+                "defined(__LINE__)\n",
+            )
+            .as_bytes(),
+        );
+
+        assert_eq!(p.next(), Token::LiteralInt(1));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralInt(4));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralInt(6));
+        assert_eq!(p.next(), Token::Plus);
+        assert_eq!(p.next(), Token::LiteralInt(6));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralInt(7));
+        assert_eq!(p.next(), Token::Plus);
+        assert_eq!(p.next(), Token::LiteralInt(7));
+        assert!(p.context.defined("__LINE__"));
+    }
+
+    #[test]
+    fn test_file() {
+        let mut p = Lexer::new(concat!("__FILE__\n", "#define F __FILE__\n", "F\n",).as_bytes());
+        p.set_file("dir\\foo.cpp");
+
+        assert_eq!(p.next(), Token::LiteralString(b"dir\\\\foo.cpp".into()));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralString(b"dir\\\\foo.cpp".into()));
+        assert!(p.context.defined("__FILE__"));
+    }
+
+    #[test]
+    fn test_counter() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define A __COUNTER__\n",
+                "A A A\n",
+                "#define U(x) x ## __COUNTER__\n",
+                "U(FOO) U(FOO)\n",
+                "__COUNTER__\n",
+            )
+            .as_bytes(),
+        );
+
+        assert_eq!(p.next(), Token::LiteralInt(0));
+        assert_eq!(p.next(), Token::LiteralInt(1));
+        assert_eq!(p.next(), Token::LiteralInt(2));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Identifier("FOO3".into()));
+        assert_eq!(p.next(), Token::Identifier("FOO4".into()));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralInt(5));
+
+        let mut p = Lexer::new(b"__COUNTER__ __COUNTER__");
+        assert_eq!(p.next(), Token::LiteralInt(0));
+        assert_eq!(p.next(), Token::LiteralInt(1));
+    }
+
+    #[test]
+    fn test_date_time() {
+        let buf = b"__DATE__ __TIME__\n#define D __DATE__\nD";
+
+        let mut p = Lexer::with_context(buf, PContext::with_timestamp(0));
+        assert_eq!(p.next(), Token::LiteralString(b"Jan  1 1970".into()));
+        assert_eq!(p.next(), Token::LiteralString(b"00:00:00".into()));
+
+        let mut p = Lexer::with_context(buf, PContext::with_timestamp(1_234_567_890));
+        assert_eq!(p.next(), Token::LiteralString(b"Feb 13 2009".into()));
+        assert_eq!(p.next(), Token::LiteralString(b"23:31:30".into()));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralString(b"Feb 13 2009".into()));
+
+        let mut p = Lexer::with_context(buf, PContext::with_timestamp(951_782_400));
+        assert_eq!(p.next(), Token::LiteralString(b"Feb 29 2000".into()));
+        assert_eq!(p.next(), Token::LiteralString(b"00:00:00".into()));
+
+        let mut p = Lexer::new(buf);
+        if let Token::LiteralString(date) = p.next() {
+            assert_eq!(date.len(), 11);
+        } else {
+            panic!("__DATE__ must be a string");
+        }
+        if let Token::LiteralString(time) = p.next() {
+            assert_eq!(time.len(), 8);
+        } else {
+            panic!("__TIME__ must be a string");
+        }
+    }
 }
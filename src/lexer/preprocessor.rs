@@ -1,12 +1,16 @@
 use bitflags::bitflags;
 use hashbrown::HashMap;
+use std::borrow::Cow;
 
 use super::condition::Condition;
-use super::lexer::{Lexer, Token};
-use super::macro_args::MacroDefArg;
+use super::errors::{Diagnostic, PreprocError};
+use super::lexer::{own_bytes, Lexer, LexerOptions, Token};
+use super::macro_args::{ArgsError, MacroDefArg};
 use super::pmacros::{
-    Action, IfKind, IfState, Macro, MacroFunction, MacroObject, MacroType, PContext,
+    could_merge, separate_tokens, Action, ExpansionError, IfKind, IfState, Macro, MacroBuildError,
+    MacroFunction, MacroObject, MacroOutput, MacroType, PContext,
 };
+use super::span::{Position, Span};
 use super::string::StringType;
 
 #[derive(Clone, Debug, Copy, PartialEq, PartialOrd)]
@@ -79,11 +83,74 @@ pub(crate) const PPCHARS: [Kind; 256] = [
     Kind::NON, Kind::NON, Kind::NON, Kind::NON, Kind::NON, Kind::NON, Kind::NON, Kind::NON, //
 ];
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+/// Handle the directives unknown to the preprocessor (e.g. `#sccs` or a typo as `#defne`)
+///
+/// Without a handler, a warning is reported for each unknown directive.
+pub trait DirectiveHandler {
+    /// Get the diagnostic to report (if any) for the directive name followed by text:
+    /// line, column and span are the location of the name
+    fn unknown_directive(
+        &self,
+        name: &str,
+        text: &str,
+        line: usize,
+        column: usize,
+        span: Span,
+    ) -> Option<Diagnostic>;
+}
+
+/// A standard pragma (e.g. `#pragma STDC FENV_ACCESS ON`): it's kept in the tokens
+/// as the other pragmas and the last one with a name is in the context
+/// (see [`PContext::stdc_pragma`])
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StdcPragma {
+    /// FP_CONTRACT, FENV_ACCESS, CX_LIMITED_RANGE, FENV_ROUND, ...
+    pub name: String,
+    /// ON, OFF or DEFAULT (or a rounding direction for FENV_ROUND)
+    pub args: Vec<String>,
+}
+
+impl StdcPragma {
+    /// Parse the text following STDC in a pragma (the lines are already spliced)
+    pub fn parse(text: &[u8]) -> Option<Self> {
+        let text = String::from_utf8_lossy(text);
+        let mut words = text.split_whitespace();
+        let name = words.next()?;
+        if !name.bytes().all(|c| c == b'_' || c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        Some(Self {
+            name: name.to_string(),
+            args: words.map(str::to_string).collect(),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum IncludeType<'a> {
-    Quote(&'a [u8]),
-    Angle(&'a [u8]),
-    Other(&'a [u8]),
+    Quote(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    Angle(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+    Other(
+        #[cfg_attr(feature = "serde", serde(borrow, with = "super::serialization::bytes"))]
+        Cow<'a, [u8]>,
+    ),
+}
+
+impl<'a> IncludeType<'a> {
+    pub(crate) fn own_payload(&mut self, is_temporary: impl Fn(&[u8]) -> bool) {
+        match self {
+            IncludeType::Quote(name) | IncludeType::Angle(name) | IncludeType::Other(name) => {
+                own_bytes(name, is_temporary)
+            }
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -106,50 +173,356 @@ pub enum MacroToken<'a> {
     Eom,
 }
 
+// The whitespaces and the comments in a macro definition are replaced by one space
+fn push_space(text: &mut Vec<u8>) {
+    if !matches!(text.last(), None | Some(b' ')) {
+        text.push(b' ');
+    }
+}
+
+// Remove the \ in front of \ and " in the content of a string literal
+fn destringize(s: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut escape = false;
+    for c in s.iter() {
+        if !escape && *c == b'\\' {
+            escape = true;
+            continue;
+        }
+        if escape && *c != b'\\' && *c != b'\"' {
+            out.push(b'\\');
+        }
+        out.push(*c);
+        escape = false;
+    }
+    out
+}
+
+// ## is always surrounded by spaces
+fn push_concat(text: &mut Vec<u8>) {
+    push_space(text);
+    text.extend_from_slice(b"## ");
+}
+
+fn get_text(mut text: Vec<u8>) -> String {
+    if text.last() == Some(&b' ') {
+        text.pop();
+    }
+    String::from_utf8_lossy(&text).into_owned()
+}
+
+/// The tokens with their spans
+pub type SpannedTokens<'a> = Vec<(Token<'a>, Span)>;
+
+/// Preprocess a source and get the resulting tokens with their spans
+///
+/// The directives are consumed, the macros are expanded and the conditionals applied:
+/// ctx contains the initial macros and gets the state at the end of the source.
+/// The first preprocessing error (e.g. a #error) is returned if any.
+pub fn preprocess<'a>(
+    src: &'a [u8],
+    ctx: &mut PContext,
+) -> Result<SpannedTokens<'a>, PreprocError> {
+    preprocess_with_inactive_ranges(src, ctx).map(|(tokens, _)| tokens)
+}
+
+/// Preprocess a source as [`preprocess`] does and get the spelling of the resulting tokens
+/// (e.g. as `gcc -E` does)
+///
+/// The tokens are on the line where they are in the source and separated by a space
+/// when they are separated in the source. A space is also inserted between two tokens
+/// which would be lexed as one token once put together (e.g. `- -1` and not `--1`).
+pub fn preprocess_to_string(src: &[u8], ctx: &mut PContext) -> Result<String, PreprocError> {
+    let tokens = preprocess(src, ctx)?;
+    let mut out = String::new();
+    let mut prev: Option<(Span, bool)> = None;
+    for (tok, span) in tokens {
+        let spelling = tok.to_string();
+        // a pragma is alone on its line
+        let is_pragma = matches!(tok, Token::Pragma(_));
+        if let Some((prev, prev_is_pragma)) = prev {
+            if span.line != prev.line || is_pragma || prev_is_pragma {
+                out.push('\n');
+            } else if prev.end < span.start || could_merge(out.as_bytes(), spelling.as_bytes()) {
+                out.push(' ');
+            }
+        }
+        out.push_str(&spelling);
+        prev = Some((span, is_pragma));
+    }
+    Ok(out)
+}
+
+/// Get the distinct identifiers in some tokens (e.g. the ones given by [`preprocess`])
+/// with the spans of their occurrences
+///
+/// The keywords aren't identifiers (e.g. `int` is Token::Int).
+pub fn collect_identifiers<'a, I: IntoIterator<Item = (Token<'a>, Span)>>(
+    tokens: I,
+) -> std::collections::HashMap<String, Vec<Span>> {
+    let mut ids = std::collections::HashMap::<String, Vec<Span>>::new();
+    for (tok, span) in tokens {
+        if let Token::Identifier(id) = tok {
+            ids.entry(id.to_string()).or_default().push(span);
+        }
+    }
+    ids
+}
+
+/// Preprocess a source as [`preprocess`] does and get also the ranges of the source
+/// skipped by the conditionals (e.g. the body of a #if 0): see [`Lexer::take_inactive_ranges`]
+pub fn preprocess_with_inactive_ranges<'a>(
+    src: &'a [u8],
+    ctx: &mut PContext,
+) -> Result<(SpannedTokens<'a>, Vec<Span>), PreprocError> {
+    let mut lexer = Lexer::with_context(src, std::mem::take(ctx));
+
+    let mut tokens = Vec::new();
+    loop {
+        let (tok, span) = lexer.next_with_span();
+        match tok {
+            Token::Eof => break,
+            _ if is_output(&tok) => tokens.push((tok, span)),
+            _ => {}
+        }
+    }
+
+    *ctx = std::mem::take(&mut lexer.context);
+
+    if let Some(error) = lexer.errors.first() {
+        Err(error.clone())
+    } else {
+        Ok((tokens, lexer.take_inactive_ranges()))
+    }
+}
+
+// Check if a token is a part of the preprocessed source (and not a directive or a newline)
+fn is_output(tok: &Token) -> bool {
+    !matches!(
+        tok,
+        Token::Eol
+            | Token::Comment { .. }
+            | Token::PreprocInclude(_)
+            | Token::PreprocIncludeNext(_)
+            | Token::PreprocImport(_)
+            | Token::PreprocPragma
+            | Token::PreprocUndef
+            | Token::Directive { .. }
+    )
+}
+
+/// Preprocess a source lazily: the tokens are produced one by one as [`preprocess`] would
+/// give them and the errors are given as soon as they're found
+///
+/// ctx contains the initial macros and gets the state once the iterator is dropped.
+/// Only the expansion being read is buffered: its tokens own their payloads.
+pub struct PreprocIterator<'a> {
+    lexer: Lexer<'a>,
+    ctx: &'a mut PContext,
+    // the number of errors already given
+    reported: usize,
+    // the token found with some errors which must be given before it
+    pending: Option<(Token<'a>, Span)>,
+    done: bool,
+}
+
+impl<'a> PreprocIterator<'a> {
+    pub fn new(src: &'a [u8], ctx: &'a mut PContext) -> Self {
+        Self {
+            lexer: Lexer::with_context(src, std::mem::take(ctx)),
+            ctx,
+            reported: 0,
+            pending: None,
+            done: false,
+        }
+    }
+
+    /// Get the lexer (e.g. to set a resolver for the includes)
+    pub fn lexer(&mut self) -> &mut Lexer<'a> {
+        &mut self.lexer
+    }
+}
+
+impl<'a> Drop for PreprocIterator<'a> {
+    fn drop(&mut self) {
+        *self.ctx = std::mem::take(&mut self.lexer.context);
+    }
+}
+
+impl<'a> Iterator for PreprocIterator<'a> {
+    type Item = Result<(Token<'a>, Span), PreprocError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(error) = self.lexer.errors.get(self.reported) {
+                self.reported += 1;
+                return Some(Err(error.clone()));
+            }
+            if let Some(tok) = self.pending.take() {
+                return Some(Ok(tok));
+            }
+            if self.done {
+                return None;
+            }
+            match self.lexer.next_with_span() {
+                (Token::Eof, _) => self.done = true,
+                (tok, span) if is_output(&tok) => self.pending = Some((tok, span)),
+                _ => {}
+            }
+        }
+    }
+}
+
+// Get the name of a directive from its text (e.g. define for #  define A 1)
+fn get_directive_kind(text: &[u8]) -> &str {
+    let text = text
+        .strip_prefix(b"%:")
+        .or_else(|| text.strip_prefix(b"#"))
+        .unwrap_or(text);
+    let start = text
+        .iter()
+        .position(|c| *c != b' ' && *c != b'\t')
+        .unwrap_or(text.len());
+    let len = text[start..]
+        .iter()
+        .position(|c| !c.is_ascii_alphanumeric() && *c != b'_')
+        .unwrap_or(text.len() - start);
+    std::str::from_utf8(&text[start..start + len]).unwrap_or_default()
+}
+
+// Remove the spaces around the text of a pragma
+fn trim_pragma(text: &[u8]) -> &[u8] {
+    let is_space = |c: &u8| matches!(c, b' ' | b'\t' | b'\r' | b'\n');
+    let start = text.iter().position(|c| !is_space(c)).unwrap_or(text.len());
+    let end = text
+        .iter()
+        .rposition(|c| !is_space(c))
+        .map_or(start, |pos| pos + 1);
+    &text[start..end]
+}
+
+/// Split an invocation in the name of the macro and the beginning of its arguments
+fn get_invocation_name(text: &[u8]) -> &str {
+    let end = text
+        .iter()
+        .position(|c| !c.is_ascii_alphanumeric() && *c != b'_')
+        .unwrap_or(text.len());
+    std::str::from_utf8(&text[..end]).unwrap_or("")
+}
+
+fn trim_end(text: &[u8]) -> &[u8] {
+    let end = text
+        .iter()
+        .rposition(|c| *c != b' ')
+        .map_or(0, |pos| pos + 1);
+    &text[..end]
+}
+
 impl<'a> Lexer<'a> {
-    #[inline(always)]
     pub fn preproc_parse(&mut self, instr: Token<'a>) -> Token<'a> {
+        // the end of the input can be met while the directive is parsed (e.g. in a #elif condition)
+        let in_directive = std::mem::replace(&mut self.in_directive, true);
+        let mut tok = self.parse_directive(instr);
+        self.in_directive = in_directive;
+        tok.own_payload(|s| self.is_lexer_buffer(s));
+        tok
+    }
+
+    #[inline(always)]
+    fn parse_directive(&mut self, instr: Token<'a>) -> Token<'a> {
         // https://docs.freebsd.org/info/cpp/cpp.pdf
+        let span = match instr {
+            Token::PreprocIf => self.get_directive_span("if"),
+            Token::PreprocIfdef => self.get_directive_span("ifdef"),
+            Token::PreprocIfndef => self.get_directive_span("ifndef"),
+            _ => Span::default(),
+        };
         skip_whites!(self);
         match instr {
-            Token::PreprocInclude2 => Token::PreprocInclude(self.get_include()),
+            Token::PreprocError => {
+                let line = self.line;
+                let message = self.get_message();
+                self.add_error(
+                    PreprocError::Error {
+                        message: message.clone(),
+                        line,
+                        column: self.directive_column,
+                    },
+                    message,
+                );
+                Token::None
+            }
+            Token::PreprocWarning => {
+                let line = self.line;
+                let message = self.get_message();
+                self.context.add_diagnostic(Diagnostic::warning(
+                    message,
+                    line,
+                    self.directive_column,
+                ));
+                Token::None
+            }
+            Token::PreprocInclude2 => {
+                let line = self.line;
+                let include = self.get_include();
+                self.include(&include, line, self.directive_column);
+                Token::PreprocInclude(include)
+            }
             Token::PreprocIncludeNext2 => Token::PreprocIncludeNext(self.get_include()),
+            Token::PreprocImport2 => {
+                let line = self.line;
+                let include = self.get_include();
+                self.import(&include, line, self.directive_column);
+                Token::PreprocImport(include)
+            }
             Token::PreprocUndef => {
                 self.get_undef();
                 Token::PreprocUndef
             }
             Token::PreprocIf => {
-                if !self.get_if(IfKind::If) {
+                if !self.get_if(IfKind::If, span) {
                     self.skip_until_else_endif();
                 }
                 Token::None
             }
             Token::PreprocIfdef => {
-                if !self.get_if(IfKind::Ifdef) {
+                if !self.get_if(IfKind::Ifdef, span) {
                     self.skip_until_else_endif();
                 }
                 Token::None
             }
             Token::PreprocIfndef => {
-                if !self.get_if(IfKind::Ifndef) {
+                if !self.get_if(IfKind::Ifndef, span) {
                     self.skip_until_else_endif();
                 }
                 Token::None
             }
             Token::PreprocElif => {
-                if !self.get_elif() {
+                if self.check_in_if("elif") && !self.get_elif(IfKind::If) {
+                    self.skip_until_else_endif();
+                }
+                Token::None
+            }
+            Token::PreprocElifdef => {
+                if self.check_in_if("elifdef") && !self.get_elif(IfKind::Ifdef) {
+                    self.skip_until_else_endif();
+                }
+                Token::None
+            }
+            Token::PreprocElifndef => {
+                if self.check_in_if("elifndef") && !self.get_elif(IfKind::Ifndef) {
                     self.skip_until_else_endif();
                 }
                 Token::None
             }
             Token::PreprocElse => {
-                if !self.get_else() {
+                if self.check_in_if("else") && !self.get_else() {
                     self.skip_until_else_endif();
                 }
                 Token::None
             }
             Token::PreprocEndif => {
-                if !self.get_endif() {
+                if self.check_in_if("endif") && !self.get_endif() {
                     self.skip_until_else_endif();
                 }
                 Token::None
@@ -158,10 +531,88 @@ impl<'a> Lexer<'a> {
                 self.get_define();
                 Token::None
             }
+            Token::PreprocPragma => {
+                let line = self.line;
+                if self.get_pragma(line, self.directive_column) {
+                    Token::None
+                } else {
+                    self.get_pragma_text()
+                }
+            }
+            Token::PreprocLine => {
+                let line = self.line;
+                self.get_line_directive(line, self.directive_column);
+                Token::None
+            }
             _ => instr,
         }
     }
 
+    pub(crate) fn add_error(&mut self, error: PreprocError, message: String) {
+        let (line, column) = error.position();
+        self.context
+            .add_diagnostic(Diagnostic::error(message, line, column));
+        self.errors.push(error);
+    }
+
+    #[inline(always)]
+    pub(crate) fn get_message(&mut self) -> String {
+        // get the text until the end of the line (a line can be continued with a backslash)
+        let mut message = Vec::new();
+        let mut spos = self.pos;
+        loop {
+            if self.pos < self.len {
+                let c = self.next_char(0);
+                if c == b'\n' {
+                    message.extend_from_slice(unsafe { self.buf.get_unchecked(spos..self.pos) });
+                    if message.last() == Some(&b'\\') {
+                        message.pop();
+                        self.pos += 1;
+                        self.add_new_line();
+                        spos = self.pos;
+                        continue;
+                    }
+                    break;
+                }
+                self.pos += 1;
+            } else {
+                message.extend_from_slice(unsafe { self.buf.get_unchecked(spos..) });
+                break;
+            }
+        }
+
+        let message = String::from_utf8_lossy(&message);
+        message.trim_end().to_string()
+    }
+
+    /// An unknown directive (the line is skipped): the null directive `#` isn't one
+    pub(crate) fn unknown_directive(&mut self, name: &str) {
+        let line = self.line;
+        let column = self.directive_column;
+        let span = self.get_directive_span(name);
+        skip_whites!(self);
+        let text = self.get_message();
+        let diagnostic = if let Some(handler) = self.directive_handler.as_ref() {
+            handler.unknown_directive(name, &text, line, column, span)
+        } else {
+            Some(
+                Diagnostic::warning(
+                    format!("invalid preprocessing directive #{}", name),
+                    line,
+                    column,
+                )
+                .with_span(span),
+            )
+        };
+        if let Some(diagnostic) = diagnostic {
+            self.context.add_diagnostic(diagnostic);
+        }
+    }
+
+    pub fn set_directive_handler(&mut self, handler: Box<dyn DirectiveHandler>) {
+        self.directive_handler = Some(handler);
+    }
+
     #[inline(always)]
     pub(crate) fn get_preproc_identifier(&mut self) -> &'a str {
         let spos = self.pos;
@@ -207,20 +658,20 @@ impl<'a> Lexer<'a> {
             let c = self.next_char(0);
             if c == b'\"' {
                 self.pos += 1;
-                return IncludeType::Quote(self.get_include_content(b'\"'));
+                return IncludeType::Quote(self.get_include_content(b'\"').into());
             } else if c == b'<' {
                 self.pos += 1;
-                return IncludeType::Angle(self.get_include_content(b'>'));
+                return IncludeType::Angle(self.get_include_content(b'>').into());
             } else {
                 let spos = self.pos;
                 skip_until!(self, b'\n');
-                self.add_new_line();
                 let code = unsafe { self.buf.get_unchecked(spos..self.pos) };
                 self.pos += 1;
-                return IncludeType::Other(code);
+                self.add_new_line();
+                return IncludeType::Other(code.into());
             }
         }
-        IncludeType::Other(&[])
+        IncludeType::Other(Cow::Borrowed(&[]))
     }
 
     #[inline(always)]
@@ -231,6 +682,8 @@ impl<'a> Lexer<'a> {
         skip_whites!(self);
         let c = self.next_char(0);
         if c == b')' {
+            self.pos += 1;
+            skip_whites!(self);
             return (args, va_args);
         }
 
@@ -316,10 +769,10 @@ impl<'a> Lexer<'a> {
                     self.pos += 1;
                     if self.pos < self.len {
                         let c = self.next_char(0);
+                        self.pos += 1;
                         if c == b'\n' {
                             self.add_new_line();
                         }
-                        self.pos += 1;
                     } else {
                         break;
                     }
@@ -367,7 +820,7 @@ impl<'a> Lexer<'a> {
             if self.pos < self.len {
                 let c = self.next_char(0);
                 let kind = unsafe { *PPCHARS.get_unchecked(c as usize) };
-                if kind != Kind::NON {
+                if kind != Kind::NON || (c == b'%' && self.get_hash_len() == 2) {
                     break;
                 }
                 self.pos += 1;
@@ -377,6 +830,19 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    /// Get a ## (or %:%:) or a # (or %:) with the given length
+    #[inline(always)]
+    fn get_hash_or_concat(&mut self, len: usize, stringify: MacroToken<'a>) -> MacroToken<'a> {
+        self.pos += len;
+        if self.get_hash_len() == len {
+            self.pos += len;
+            skip_whites!(self);
+            MacroToken::Concat
+        } else {
+            stringify
+        }
+    }
+
     #[inline(always)]
     pub(crate) fn skip_spaces_or_hash(&mut self) -> MacroToken<'a> {
         loop {
@@ -384,20 +850,11 @@ impl<'a> Lexer<'a> {
                 let c = self.next_char(0);
                 let kind = unsafe { *PPCHARS.get_unchecked(c as usize) };
                 if kind != Kind::SPA {
-                    if c != b'#' {
+                    let len = self.get_hash_len();
+                    if len == 0 {
                         return MacroToken::Space;
                     }
-
-                    self.pos += 1;
-                    if self.pos < self.len {
-                        let c = self.next_char(0);
-                        if c == b'#' {
-                            self.pos += 1;
-                            skip_whites!(self);
-                            return MacroToken::Concat;
-                        }
-                        return MacroToken::WhiteStringify;
-                    }
+                    return self.get_hash_or_concat(len, MacroToken::WhiteStringify);
                 }
                 self.pos += 1;
             } else {
@@ -473,16 +930,7 @@ impl<'a> Lexer<'a> {
                         return self.skip_spaces_or_hash();
                     }
                     Kind::HAS => {
-                        self.pos += 1;
-                        if self.pos < self.len {
-                            let c = self.next_char(0);
-                            if c == b'#' {
-                                self.pos += 1;
-                                skip_whites!(self);
-                                return MacroToken::Concat;
-                            }
-                            return MacroToken::Stringify;
-                        }
+                        return self.get_hash_or_concat(1, MacroToken::Stringify);
                     }
                     Kind::QUO => {
                         // we've a string or char literal
@@ -504,6 +952,8 @@ impl<'a> Lexer<'a> {
                             let s = unsafe { self.buf.get_unchecked(p..p + 1) };
                             return MacroToken::None(s);
                         }
+                        // a comment is a space
+                        return self.skip_spaces_or_hash();
                     }
                     Kind::BAC => {
                         let p = self.pos;
@@ -511,8 +961,8 @@ impl<'a> Lexer<'a> {
                         if self.pos < self.len {
                             let c = self.next_char(0);
                             if c == b'\n' {
-                                self.add_new_line();
                                 self.pos += 1;
+                                self.add_new_line();
                             } else {
                                 let s = unsafe { self.buf.get_unchecked(p..p + 1) };
                                 return MacroToken::None(s);
@@ -523,6 +973,9 @@ impl<'a> Lexer<'a> {
                         }
                     }
                     Kind::NON => {
+                        if c == b'%' && self.get_hash_len() == 2 {
+                            return self.get_hash_or_concat(2, MacroToken::Stringify);
+                        }
                         let p = self.pos;
                         self.skip_none();
                         let s = unsafe { self.buf.get_unchecked(p..self.pos) };
@@ -541,22 +994,138 @@ impl<'a> Lexer<'a> {
         &mut self,
         args: HashMap<&str, usize>,
         va_args: Option<usize>,
-    ) -> MacroFunction {
+        params: String,
+    ) -> Result<MacroFunction, MacroBuildError> {
         let mut out = Vec::with_capacity(1024);
         let mut actions = Vec::with_capacity(args.len());
+        let mut text = Vec::new();
+
+        self.get_function_actions(&args, va_args, &mut out, &mut actions, &mut text, false);
+
+        let text = get_text(text);
+        MacroFunction::try_new(out, actions, args.len(), va_args, params, text)
+    }
+
+    #[inline(always)]
+    fn get_va_opt_end(&mut self, s: &'a [u8], depth: &mut usize) -> Option<&'a [u8]> {
+        if unsafe { *PPCHARS.get_unchecked(s[0] as usize) } != Kind::NON {
+            // a string, a number, ...
+            return None;
+        }
+        for (i, c) in s.iter().enumerate() {
+            if *c == b'(' {
+                *depth += 1;
+            } else if *c == b')' {
+                if *depth == 0 {
+                    // rewind just after the closing parenthesis
+                    self.pos -= s.len() - i - 1;
+                    return Some(&s[..i]);
+                }
+                *depth -= 1;
+            }
+        }
+        None
+    }
+
+    #[inline(always)]
+    fn is_va_opt(&mut self, id: &str) -> bool {
+        if id != "__VA_OPT__" {
+            return false;
+        }
+        let spos = self.pos;
+        skip_whites!(self);
+        if self.pos < self.len && self.next_char(0) == b'(' {
+            self.pos += 1;
+            true
+        } else {
+            self.pos = spos;
+            false
+        }
+    }
+
+    // Get the position of the comma on the left of ## if any
+    #[inline(always)]
+    fn get_elided_comma(out: &[u8], start: usize) -> Option<usize> {
+        let end = out[start..].iter().rposition(|c| *c != b' ')? + start;
+        if out[end] == b',' {
+            Some(end)
+        } else {
+            None
+        }
+    }
+
+    fn get_function_actions(
+        &mut self,
+        args: &HashMap<&str, usize>,
+        va_args: Option<usize>,
+        out: &mut Vec<u8>,
+        actions: &mut Vec<Action>,
+        text: &mut Vec<u8>,
+        in_va_opt: bool,
+    ) {
         let mut last_kind = LastKind::None;
-        let mut last_chunk_end = 0;
+        let mut last_chunk_end = out.len();
+        // the end of the last chunk before the last ##
+        let mut paste_start = out.len();
+        let mut depth = 0;
 
         loop {
             let tok = self.next_macro_token();
             match tok {
                 MacroToken::None(s) => {
+                    if in_va_opt {
+                        if let Some(s) = self.get_va_opt_end(s, &mut depth) {
+                            out.extend_from_slice(s);
+                            text.extend_from_slice(s);
+                            break;
+                        }
+                    }
                     out.extend_from_slice(s);
+                    text.extend_from_slice(s);
                     last_kind = LastKind::None;
                 }
                 MacroToken::Id(id) => {
-                    if let Some(arg_pos) = args.get(id) {
+                    if !in_va_opt && va_args.is_some() && self.is_va_opt(id) {
+                        if last_chunk_end != out.len() {
+                            actions.push(Action::Chunk(out.len()));
+                        }
+                        let mut va_opt_actions = Vec::new();
+                        text.extend_from_slice(b"__VA_OPT__(");
+                        self.get_function_actions(
+                            args,
+                            va_args,
+                            out,
+                            &mut va_opt_actions,
+                            text,
+                            true,
+                        );
+                        text.push(b')');
+                        actions.push(Action::VaOpt(va_opt_actions, out.len()));
+                        last_chunk_end = out.len();
+                        last_kind = LastKind::None;
+                    } else if let Some(arg_pos) = args.get(id) {
+                        text.extend_from_slice(id.as_bytes());
                         let n = *arg_pos;
+                        if last_kind == LastKind::Concat && va_args == Some(n) {
+                            if let Some(comma) = Self::get_elided_comma(out, paste_start) {
+                                // , ## __VA_ARGS__ (GNU): the comma is removed when the variadic
+                                // arguments are empty, else the ## has no effect
+                                actions.pop();
+                                if paste_start != last_chunk_end {
+                                    actions.pop();
+                                }
+                                last_chunk_end = paste_start;
+                                out.truncate(comma + 1);
+                                if last_chunk_end != comma {
+                                    actions.push(Action::Chunk(comma));
+                                }
+                                actions.push(Action::VaComma(out.len()));
+                                actions.push(Action::Arg(n));
+                                last_chunk_end = out.len();
+                                last_kind = LastKind::Arg(n);
+                                continue;
+                            }
+                        }
                         if last_chunk_end != out.len() {
                             actions.push(Action::Chunk(out.len()));
                             last_chunk_end = out.len();
@@ -570,12 +1139,25 @@ impl<'a> Lexer<'a> {
                             }
                         }
                         last_kind = LastKind::Arg(n);
+                    } else if let (LastKind::Concat, Some(builtin)) =
+                        (last_kind, PContext::get_builtin(id))
+                    {
+                        // something ## __COUNTER__: paste the value and not the name
+                        text.extend_from_slice(id.as_bytes());
+                        if last_chunk_end != out.len() {
+                            actions.push(Action::Chunk(out.len()));
+                            last_chunk_end = out.len();
+                        }
+                        actions.push(Action::Builtin(builtin));
+                        last_kind = LastKind::None;
                     } else {
                         out.extend_from_slice(id.as_bytes());
+                        text.extend_from_slice(id.as_bytes());
                         last_kind = LastKind::None;
                     }
                 }
                 MacroToken::Space => {
+                    push_space(text);
                     if last_kind != LastKind::Space {
                         out.push(b' ');
                         last_kind = LastKind::Space;
@@ -583,13 +1165,38 @@ impl<'a> Lexer<'a> {
                 }
                 MacroToken::WhiteStringify | MacroToken::Stringify => {
                     if tok == MacroToken::WhiteStringify {
+                        push_space(text);
                         if last_kind != LastKind::Space {
                             out.push(b' ');
                             last_kind = LastKind::Space;
                         }
                     }
+                    text.push(b'#');
+                    // the operand can follow the # after whites
+                    let spos = self.pos;
+                    skip_whites!(self);
+                    text.extend_from_slice(&self.buf[spos..self.pos]);
                     let id = self.get_preproc_identifier();
-                    if let Some(arg_pos) = args.get(id) {
+                    text.extend_from_slice(id.as_bytes());
+                    if !in_va_opt && va_args.is_some() && self.is_va_opt(id) {
+                        // #__VA_OPT__(...): the result of the substitution is stringified
+                        if last_chunk_end != out.len() {
+                            actions.push(Action::Chunk(out.len()));
+                        }
+                        let mut va_opt_actions = Vec::new();
+                        text.push(b'(');
+                        self.get_function_actions(
+                            args,
+                            va_args,
+                            out,
+                            &mut va_opt_actions,
+                            text,
+                            true,
+                        );
+                        text.push(b')');
+                        actions.push(Action::StringifyVaOpt(va_opt_actions, out.len()));
+                        last_chunk_end = out.len();
+                    } else if let Some(arg_pos) = args.get(id) {
                         out.extend_from_slice(b"\"\"");
                         if last_chunk_end != out.len() - 1 {
                             actions.push(Action::Chunk(out.len() - 1));
@@ -603,6 +1210,7 @@ impl<'a> Lexer<'a> {
                     last_kind = LastKind::None;
                 }
                 MacroToken::Concat => {
+                    push_concat(text);
                     match last_kind {
                         LastKind::Arg(n) => {
                             actions.pop();
@@ -610,6 +1218,12 @@ impl<'a> Lexer<'a> {
                         }
                         _ => {}
                     }
+                    paste_start = last_chunk_end;
+                    if last_chunk_end != out.len() {
+                        actions.push(Action::Chunk(out.len()));
+                        last_chunk_end = out.len();
+                    }
+                    actions.push(Action::Paste);
                     last_kind = LastKind::Concat;
                 }
                 MacroToken::Eom => {
@@ -617,15 +1231,15 @@ impl<'a> Lexer<'a> {
                 }
             }
         }
-
-        MacroFunction::new(out, actions, args.len(), va_args)
     }
 
     #[inline(always)]
     pub(crate) fn get_object_definition(&mut self) -> MacroObject {
         let mut out = Vec::with_capacity(64);
         let mut last_kind = LastKind::None;
-        let mut has_id = false;
+        let mut ids: Vec<String> = Vec::new();
+        let mut has_paste = false;
+        let mut text = Vec::new();
 
         skip_whites!(self);
 
@@ -634,44 +1248,113 @@ impl<'a> Lexer<'a> {
             match tok {
                 MacroToken::None(s) => {
                     out.extend_from_slice(s);
+                    text.extend_from_slice(s);
                     last_kind = LastKind::None;
                 }
                 MacroToken::Id(id) => {
                     out.extend_from_slice(id.as_bytes());
+                    text.extend_from_slice(id.as_bytes());
                     last_kind = LastKind::Id;
-                    has_id = true;
+                    if !ids.iter().any(|x| x == id) {
+                        ids.push(id.to_string());
+                    }
                 }
                 MacroToken::Space => {
+                    push_space(&mut text);
                     if last_kind != LastKind::Space {
                         out.push(b' ');
                         last_kind = LastKind::Space;
                     }
                 }
-                MacroToken::WhiteStringify | MacroToken::Stringify | MacroToken::Concat => {}
+                MacroToken::WhiteStringify => {
+                    push_space(&mut text);
+                    text.push(b'#');
+                }
+                MacroToken::Stringify => {
+                    text.push(b'#');
+                }
+                MacroToken::Concat => {
+                    push_concat(&mut text);
+                    has_paste = true;
+                }
                 MacroToken::Eom => {
                     break;
                 }
             }
         }
 
-        MacroObject::new(out, has_id)
+        MacroObject::new(out, ids, has_paste, get_text(text))
+    }
+
+    #[inline(always)]
+    fn copy_defined_operand(&mut self, out: &mut Vec<u8>) {
+        skip_whites!(self);
+        let paren = self.pos < self.len && self.next_char(0) == b'(';
+        if paren {
+            self.pos += 1;
+            skip_whites!(self);
+        }
+        let id = self.get_preproc_identifier();
+        if paren {
+            skip_whites!(self);
+            if self.pos < self.len && self.next_char(0) == b')' {
+                self.pos += 1;
+            }
+            out.push(b'(');
+            out.extend_from_slice(id.as_bytes());
+            out.push(b')');
+        } else {
+            out.push(b' ');
+            out.extend_from_slice(id.as_bytes());
+        }
     }
 
     #[inline(always)]
-    pub(crate) fn macro_final_eval(&mut self, out: &mut Vec<u8>, context: &PContext) {
+    pub(crate) fn macro_final_eval(&mut self, out: &mut MacroOutput, context: &PContext) {
+        let mut junctions = Vec::new();
+        // the length of the invocation of a function-like macro ending the output
+        // without its arguments (or with only a part of them)
+        let mut pending = None;
         loop {
+            let spos = self.pos;
             let tok = self.next_macro_token();
             match tok {
-                MacroToken::None(s) => {
-                    out.extend_from_slice(s);
-                }
                 MacroToken::Id(id) => {
-                    if !context.eval(&id, self, out) {
-                        out.extend_from_slice(id.as_bytes());
+                    if id == "defined" && context.in_if() {
+                        // the operand of defined mustn't be expanded
+                        out.extend_from_slice(b"defined");
+                        self.copy_defined_operand(out);
+                    } else if self.is_painted(id) || context.in_use(id) {
+                        // a painted identifier is never expanded
+                        out.push_painted(id);
+                        pending = None;
+                    } else {
+                        let start = out.len();
+                        context.set_pending_function(None);
+                        if context.eval(id, self, out) {
+                            junctions.push(start);
+                            pending = self.expand_pending_function(out, context);
+                            junctions.push(out.len());
+                        } else {
+                            pending = None;
+                            if self.at_end_of_macro()
+                                && matches!(context.get(id), Some(Macro::Function(_)))
+                            {
+                                // the arguments can follow the expansion being rescanned
+                                pending = Some(id.len());
+                            }
+                            out.extend_from_slice(id.as_bytes());
+                        }
                     }
                 }
+                MacroToken::None(s) => {
+                    out.extend_from_slice(s);
+                    pending = None;
+                }
                 MacroToken::Space => {
-                    if let Some(last) = out.last() {
+                    if self.pos == spos + 1 && self.is_separator(spos) {
+                        out.push_separator();
+                    } else if let Some(last) = out.last() {
                         if *last != b' ' {
                             out.push(b' ');
                         }
@@ -685,28 +1368,171 @@ impl<'a> Lexer<'a> {
                 }
             }
         }
+        separate_tokens(out, &junctions);
+        let pending = pending.map(|len| {
+            let end = out
+                .iter()
+                .rposition(|c| *c != b' ')
+                .map_or(0, |pos| pos + 1);
+            end - len
+        });
+        context.set_pending_function(pending);
     }
 
-    #[inline(always)]
-    pub(crate) fn macro_eval(&mut self, name: &str) -> bool {
-        match self.context.get_type(name) {
-            MacroType::None => {
-                return false;
-            }
-            MacroType::Object(mac) => {
-                mac.eval(&mut self.preproc_buf, &self.context);
-            }
-            MacroType::Function((n, va_args)) => {
-                if let Some(args) = self.get_arguments(n, va_args.as_ref()) {
-                    if let Macro::Function(mac) = self.context.get(name).unwrap() {
-                        mac.eval_parsed_args(&args, &self.context, &mut self.preproc_buf);
-                    }
-                } else {
-                    return false;
+    /// Check if only whites remain in the macro output being rescanned
+    fn at_end_of_macro(&self) -> bool {
+        self.buf[self.pos..]
+            .iter()
+            .all(|c| *c == b' ' || *c == b'\t')
+    }
+
+    /// Expand the function-like macro ending the expansion which has just been made
+    /// with the arguments following it in the output being rescanned: the length of
+    /// the invocation is returned when they aren't there and it still ends the output
+    fn expand_pending_function(
+        &mut self,
+        out: &mut MacroOutput,
+        context: &PContext,
+    ) -> Option<usize> {
+        while let Some(start) = context.take_pending_function() {
+            let text = out.tail(start);
+            let name = get_invocation_name(&text);
+            let partial = text.tail(name.len());
+            let (mac, n_args, va_args) = match (context.get(name), context.get_type(name)) {
+                (Some(Macro::Function(mac)), MacroType::Function((n_args, va_args))) => {
+                    (mac, n_args, va_args)
+                }
+                _ => return None,
+            };
+            match self.get_arguments_after(&partial, n_args, va_args.as_ref()) {
+                Ok(args) => {
+                    out.truncate(start);
+                    mac.eval_parsed_args(name, &args, context, out);
+                }
+                Err(ArgsError::Unterminated) => {
+                    out.extend_from_output(&self.take_rest());
+                    return Some(trim_end(&out[start..]).len());
+                }
+                Err(ArgsError::WrongCount(given)) => {
+                    mac.wrong_count(name, given, context);
+                    return None;
+                }
+                Err(ArgsError::NoParen) => {
+                    return if self.at_end_of_macro() {
+                        Some(trim_end(&text).len())
+                    } else {
+                        None
+                    };
                 }
             }
         }
-        true
+        None
+    }
+
+    /// Expand the invocation of the function-like macro name: partial contains
+    /// the beginning of its arguments if any and start is where the expansion goes in out
+    /// (the result is None when the name isn't followed by arguments)
+    fn eval_function(
+        &mut self,
+        name: &str,
+        partial: &MacroOutput,
+        n_args: usize,
+        va_args: Option<usize>,
+        start: usize,
+        out: &mut MacroOutput,
+    ) -> Option<bool> {
+        match self.get_arguments_after(partial, n_args, va_args.as_ref()) {
+            Ok(args) => {
+                // the invocation ends after the arguments
+                self.mark_expansion_span();
+                out.truncate(start);
+                if let Some(Macro::Function(mac)) = self.context.get(name) {
+                    mac.eval_parsed_args(name, &args, &self.context, out);
+                }
+                Some(true)
+            }
+            Err(ArgsError::WrongCount(given)) => {
+                self.context
+                    .set_expansion_error(ExpansionError::WrongCount {
+                        name: name.to_string(),
+                        n_args,
+                        variadic: va_args.is_some(),
+                        given,
+                    });
+                Some(false)
+            }
+            Err(ArgsError::Unterminated) => {
+                self.context
+                    .set_expansion_error(ExpansionError::Unterminated(name.to_string()));
+                Some(false)
+            }
+            Err(ArgsError::NoParen) => None,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn macro_eval(&mut self, name: &str) -> bool {
+        // __LINE__ must give the line where the macro is used
+        self.context.set_line(self.get_line());
+        let mut expanded = true;
+        self.mark_expansion_span();
+        self.context.set_pending_function(None);
+        match self.context.get_type(name) {
+            MacroType::None => {
+                return self.context.eval_builtin(name, &mut self.preproc_buf);
+            }
+            MacroType::Object(mac) => {
+                let mut out = MacroOutput::from(std::mem::take(&mut self.preproc_buf));
+                mac.eval(name, &mut out, &self.context);
+                self.preproc_buf = out.into_text();
+            }
+            MacroType::Function((n, va_args)) => {
+                let start = self.preproc_buf.len();
+                let mut out = MacroOutput::from(std::mem::take(&mut self.preproc_buf));
+                let partial = MacroOutput::default();
+                let res = self.eval_function(name, &partial, n, va_args, start, &mut out);
+                self.preproc_buf = out.into_text();
+                match res {
+                    Some(ok) => expanded = ok,
+                    None => return false,
+                }
+            }
+        }
+        // the expansion can end with the invocation of a function-like macro
+        // whose arguments follow (or end) in the source
+        if expanded {
+            while let Some(start) = self.context.take_pending_function() {
+                let mut out = MacroOutput::from(std::mem::take(&mut self.preproc_buf));
+                let text = out.tail(start);
+                let pending = get_invocation_name(&text);
+                let partial = text.tail(pending.len());
+                let res = if let MacroType::Function((n, va_args)) = self.context.get_type(pending)
+                {
+                    self.eval_function(pending, &partial, n, va_args, start, &mut out)
+                } else {
+                    None
+                };
+                self.preproc_buf = out.into_text();
+                if res != Some(true) {
+                    break;
+                }
+            }
+        }
+        if let Some(error) = self.context.take_expansion_error() {
+            let message = match error {
+                ExpansionError::TooDeep => format!(
+                    "expansion of macro \"{}\" nested too deeply (limit is {})",
+                    name,
+                    self.context.get_max_expansion_depth()
+                ),
+                error => error.to_string(),
+            };
+            let start = self.start;
+            let col = self.get_span_column(self.buf, start);
+            self.context
+                .add_diagnostic(Diagnostic::error(message, start.line, col));
+        }
+        expanded
     }
 
     #[inline(always)]
@@ -715,11 +1541,31 @@ impl<'a> Lexer<'a> {
         // need to lex to avoid to catch #else or #endif in a string, comment
         // or something like #define foo(else) #else (who want to do that ???)
 
+        // the skipped lines start after the one of the directive
+        // (the evaluation of a #if condition consumes the end of its line)
+        let (line, lpos) = self.get_line_start();
+        let mut start = if self.pos == lpos {
+            Some(Position {
+                pos: self.pos,
+                line,
+                lpos,
+            })
+        } else {
+            None
+        };
+
         skip_whites!(self);
+        let hash = Position {
+            pos: self.pos,
+            line,
+            lpos,
+        };
         if self.stop_skipping() {
+            self.set_pending_directive(hash);
             return;
         }
 
+        let mut end = self.len;
         loop {
             if self.pos < self.len {
                 let c = self.next_char(0);
@@ -733,9 +1579,22 @@ impl<'a> Lexer<'a> {
                     }
                     Kind::RET => {
                         self.add_new_line();
+                        let (line, lpos) = self.get_line_start();
+                        let line_start = Position {
+                            pos: self.pos,
+                            line,
+                            lpos,
+                        };
+                        start.get_or_insert(line_start);
                         skip_whites!(self);
                         // we've a new line so check if it starts with preproc directive
+                        let hash = Position {
+                            pos: self.pos,
+                            ..line_start
+                        };
                         if self.stop_skipping() {
+                            self.set_pending_directive(hash);
+                            end = line_start.pos;
                             break;
                         }
                     }
@@ -748,6 +1607,86 @@ impl<'a> Lexer<'a> {
                 break;
             }
         }
+
+        if let Some(start) = start {
+            if start.pos < end && self.include_stack.is_empty() && !self.preproc_use {
+                let span = self.get_span(self.buf, start, end);
+                self.inactive_ranges.push(span);
+            }
+        }
+    }
+
+    /// Handle the directive starting at the current token and get it as a Token::Directive
+    pub(crate) fn get_directive(&mut self) -> Token<'a> {
+        let start = self.start;
+        let buf = self.buf;
+        let end = self.get_directive_end(start.pos);
+        let text = &buf[start.pos..end];
+        self.directive_span = self.get_span(buf, start, end);
+
+        let depth = self.include_stack.len();
+        self.get_preproc();
+        if self.include_stack.len() == depth && std::ptr::eq(self.buf, buf) {
+            // the tokens after the directive are a part of its text
+            self.skip_directive_line(end);
+        }
+        Token::Directive {
+            kind: get_directive_kind(text).into(),
+            text: text.into(),
+        }
+    }
+
+    // Keep the directive ending a skipped group to return it on the next call
+    fn set_pending_directive(&mut self, start: Position) {
+        if self.options.directives {
+            let end = self.get_directive_end(start.pos);
+            let text = &self.buf[start.pos..end];
+            let tok = Token::Directive {
+                kind: get_directive_kind(text).into(),
+                text: text.into(),
+            };
+            self.pending_directive = Some((tok, self.get_span(self.buf, start, end), end));
+        }
+    }
+
+    // Get the end of the line of the directive starting at pos
+    // (a block comment can continue on the next lines)
+    fn get_directive_end(&self, mut pos: usize) -> usize {
+        let buf = self.buf;
+        while pos < self.len {
+            match buf[pos] {
+                b'\n' => break,
+                b'/' if buf.get(pos + 1) == Some(&b'*') => {
+                    pos += 2;
+                    while pos < self.len && !buf[pos..].starts_with(b"*/") {
+                        pos += 1;
+                    }
+                    pos += 2;
+                }
+                quote @ b'"' | quote @ b'\'' => {
+                    pos += 1;
+                    while pos < self.len && buf[pos] != quote && buf[pos] != b'\n' {
+                        pos += if buf[pos] == b'\\' { 2 } else { 1 };
+                    }
+                    if pos < self.len && buf[pos] == quote {
+                        pos += 1;
+                    }
+                }
+                _ => pos += 1,
+            }
+        }
+        pos.min(self.len)
+    }
+
+    // Move after the newline ending a directive (if it hasn't been consumed yet)
+    pub(crate) fn skip_directive_line(&mut self, end: usize) {
+        if self.pos <= end {
+            self.pos = end;
+            if self.pos < self.len {
+                self.pos += 1;
+                self.add_new_line();
+            }
+        }
     }
 
     #[inline(always)]
@@ -755,17 +1694,28 @@ impl<'a> Lexer<'a> {
         // we must be after a newline and skipped whites
         // the goal is to avoid to catch #define foo(else) #else
         if self.pos < self.len {
-            let c = self.next_char(0);
-            if c == b'#' {
+            let len = self.get_hash_len();
+            if len != 0 {
                 // we've a hash at the beginning of a line
-                self.pos += 1;
+                self.pos += len;
                 skip_whites!(self);
                 let id = self.get_preproc_keyword(false);
                 match id {
-                    Token::PreprocIf => self.get_if(IfKind::If),
-                    Token::PreprocIfdef => self.get_if(IfKind::Ifdef),
-                    Token::PreprocIfndef => self.get_if(IfKind::Ifndef),
-                    Token::PreprocElif => self.get_elif(),
+                    Token::PreprocIf => {
+                        let span = self.get_directive_span("if");
+                        self.get_if(IfKind::If, span)
+                    }
+                    Token::PreprocIfdef => {
+                        let span = self.get_directive_span("ifdef");
+                        self.get_if(IfKind::Ifdef, span)
+                    }
+                    Token::PreprocIfndef => {
+                        let span = self.get_directive_span("ifndef");
+                        self.get_if(IfKind::Ifndef, span)
+                    }
+                    Token::PreprocElif => self.get_elif(IfKind::If),
+                    Token::PreprocElifdef => self.get_elif(IfKind::Ifdef),
+                    Token::PreprocElifndef => self.get_elif(IfKind::Ifndef),
                     Token::PreprocElse => self.get_else(),
                     Token::PreprocEndif => self.get_endif(),
                     _ => false,
@@ -779,48 +1729,77 @@ impl<'a> Lexer<'a> {
     }
 
     #[inline(always)]
-    pub(crate) fn get_if(&mut self, kind: IfKind) -> bool {
+    pub(crate) fn get_if(&mut self, kind: IfKind, span: Span) -> bool {
         let must_eval = if let Some(state) = self.context.if_state() {
             *state == IfState::Eval
         } else {
             true
         };
 
-        if must_eval {
-            skip_whites!(self);
-            let condition = match kind {
-                IfKind::If => {
-                    let mut condition = Condition::new(self);
-                    condition.eval_as_bool()
-                }
-                IfKind::Ifdef => {
-                    let id = self.get_preproc_identifier();
-                    self.context.defined(id)
-                }
-                IfKind::Ifndef => {
-                    let id = self.get_preproc_identifier();
-                    !self.context.defined(id)
-                }
-            };
+        let state = if must_eval {
+            self.get_if_state(&kind)
+        } else {
+            IfState::Skip
+        };
+        let eval = state == IfState::Eval;
+        self.context.add_if(state, kind, span);
 
-            if condition {
-                self.context.add_if(IfState::Eval);
-                true
-            } else {
-                self.context.add_if(IfState::SkipAndSwitch);
-                false
+        eval
+    }
+
+    /// Evaluate the condition of a #if, #ifdef, ... and get the state of the group
+    fn get_if_state(&mut self, kind: &IfKind) -> IfState {
+        skip_whites!(self);
+        let condition = match kind {
+            IfKind::If => {
+                self.context.set_in_if(true);
+                let mut condition = Condition::new(self);
+                let condition = condition.eval_as_bool();
+                self.context.set_in_if(false);
+                condition
+            }
+            IfKind::Ifdef => {
+                let id = self.get_preproc_identifier();
+                self.context.defined(id)
             }
+            IfKind::Ifndef => {
+                let id = self.get_preproc_identifier();
+                !self.context.defined(id)
+            }
+        };
+
+        if condition {
+            IfState::Eval
+        } else {
+            IfState::SkipAndSwitch
+        }
+    }
+
+    /// Check that a #else, #elif* or #endif is in a #if
+    fn check_in_if(&mut self, directive: &str) -> bool {
+        if self.context.if_state().is_some() {
+            true
         } else {
-            self.context.add_if(IfState::Skip);
+            self.context.add_diagnostic(Diagnostic::error(
+                format!("#{} without #if", directive),
+                self.line,
+                self.directive_column,
+            ));
+            // the rest of the line is discarded
+            self.get_message();
             false
         }
     }
 
     #[inline(always)]
-    pub(crate) fn get_elif(&mut self) -> bool {
-        // elif == else if
+    pub(crate) fn get_elif(&mut self, kind: IfKind) -> bool {
+        // elif == else if, elifdef == else ifdef, elifndef == else ifndef
         if self.get_else() {
-            self.get_if(IfKind::If)
+            // no group has been taken: the state of the group is given by the condition
+            let state = self.get_if_state(&kind);
+            let eval = state == IfState::Eval;
+            self.context.if_change(state);
+            eval
         } else {
             false
         }
@@ -862,18 +1841,44 @@ impl<'a> Lexer<'a> {
     #[inline(always)]
     pub(crate) fn get_define(&mut self) {
         skip_whites!(self);
+        let line = self.get_line();
+        let column = self.get_column();
         let name = self.get_preproc_identifier();
         if self.pos < self.len {
             let c = self.next_char(0);
-            if c == b'(' {
+            let compatible = if c == b'(' {
                 self.pos += 1;
+                let spos = self.pos;
                 let (args, va_args) = self.get_macro_arguments();
-                let mac = self.get_function_definition(args, va_args);
-                self.context.add_function(name.to_string(), mac);
+                // the parameters without the spaces: used to check the redefinitions
+                let params: String = unsafe { self.buf.get_unchecked(spos..self.pos) }
+                    .iter()
+                    .filter(|c| !c.is_ascii_whitespace())
+                    .map(|c| *c as char)
+                    .collect();
+                match self.get_function_definition(args, va_args, params) {
+                    Ok(mac) => self.context.add_function(name.to_string(), mac),
+                    Err(e) => {
+                        // the definition is dropped
+                        self.context.add_diagnostic(Diagnostic::error(
+                            format!("invalid definition of \"{}\": {}", name, e),
+                            line,
+                            column,
+                        ));
+                        true
+                    }
+                }
             } else {
                 skip_whites!(self);
                 let obj = self.get_object_definition();
-                self.context.add_object(name.to_string(), obj);
+                self.context.add_object(name.to_string(), obj)
+            };
+            if !compatible {
+                self.context.add_diagnostic(Diagnostic::error(
+                    format!("\"{}\" redefined", name),
+                    line,
+                    column,
+                ));
             }
         }
     }
@@ -904,11 +1909,244 @@ impl<'a> Lexer<'a> {
         0
     }
 
+    #[inline(always)]
+    pub(crate) fn get_pragma(&mut self, line: usize, column: usize) -> bool {
+        let spos = self.pos;
+        match self.get_preproc_identifier() {
+            "once" => {
+                self.pragma_once();
+            }
+            "push_macro" => {
+                if let Some(name) = self.get_pragma_macro_name() {
+                    self.context.push_macro(name);
+                }
+            }
+            "pop_macro" => {
+                if let Some(name) = self.get_pragma_macro_name() {
+                    if !self.context.pop_macro(name) {
+                        self.context.add_diagnostic(Diagnostic::warning(
+                            format!(
+                                "pragma pop_macro could not pop '{}', no matching push_macro",
+                                name
+                            ),
+                            line,
+                            column,
+                        ));
+                    }
+                }
+            }
+            "STDC" => {
+                // the standard pragmas are kept in the token stream too:
+                // the line is read again to make the pragma token
+                let (sline, slpos) = (self.line, self.lpos);
+                let text = self.get_message();
+                if let Some(pragma) = StdcPragma::parse(text.as_bytes()) {
+                    self.context.set_stdc_pragma(pragma);
+                }
+                self.pos = spos;
+                self.line = sline;
+                self.lpos = slpos;
+                return false;
+            }
+            _ => {
+                // other pragmas are kept in the token stream
+                self.pos = spos;
+                return false;
+            }
+        }
+        true
+    }
+
+    /// _Pragma("...") is handled as #pragma ...
+    pub(crate) fn get_pragma_operator(&mut self) -> Option<Token<'a>> {
+        let spos = self.pos;
+        let start = if self.preproc_use {
+            self.expansion_start
+        } else {
+            self.start
+        };
+        skip_whites!(self);
+        let mut text = None;
+        if self.pos < self.len && self.next_char(0) == b'(' {
+            self.pos += 1;
+            skip_whites!(self);
+            if self.pos < self.len && self.next_char(0) == b'L' {
+                self.pos += 1;
+            }
+            if self.pos < self.len && self.next_char(0) == b'\"' {
+                self.pos += 1;
+                let sstart = self.pos;
+                self.skip_by_delim(b'\"');
+                if self.pos > sstart && self.buf[self.pos - 1] == b'\"' {
+                    let s = &self.buf[sstart..self.pos - 1];
+                    skip_whites!(self);
+                    if self.pos < self.len && self.next_char(0) == b')' {
+                        self.pos += 1;
+                        text = Some(destringize(s));
+                    }
+                }
+            }
+        }
+
+        let buf = if self.preproc_use {
+            self.sbuf
+        } else {
+            self.buf
+        };
+        let line = start.line;
+        let column = self.get_span_column(buf, start);
+        let text = if let Some(text) = text {
+            text
+        } else {
+            self.pos = spos;
+            self.context.add_diagnostic(Diagnostic::error(
+                "_Pragma takes a parenthesized string literal".to_string(),
+                line,
+                column,
+            ));
+            return None;
+        };
+
+        // the pragma is read by a lexer on the text with the context of this one
+        let mut lexer = Lexer::new_translated(&text, self.options);
+        lexer.context = std::mem::take(&mut self.context);
+        lexer.resolver = self.resolver.take();
+        skip_whites!(lexer);
+        let handled = lexer.get_pragma(line, column);
+        self.context = lexer.context;
+        self.resolver = lexer.resolver;
+
+        if handled {
+            return Some(Token::None);
+        }
+        // the other pragmas are kept as for #pragma
+        Some(Token::Pragma(Cow::Owned(trim_pragma(&text).to_vec())))
+    }
+
+    /// Get the text of a pragma until the end of the line
+    fn get_pragma_text(&mut self) -> Token<'a> {
+        let spos = self.pos;
+        while self.pos < self.len {
+            let c = self.next_char(0);
+            if c == b'\n' {
+                if self.buf[spos..self.pos].ends_with(b"\\") {
+                    // a line continued with a backslash
+                    self.pos += 1;
+                    self.add_new_line();
+                    continue;
+                }
+                break;
+            }
+            self.pos += 1;
+        }
+        Token::Pragma(trim_pragma(&self.buf[spos..self.pos]).into())
+    }
+
+    /// #line 123 "file": the next line is the line 123 of file
+    pub(crate) fn get_line_directive(&mut self, line: usize, column: usize) {
+        let text = self.get_message();
+        let mut out = MacroOutput::default();
+        let mut lexer = Lexer::new_translated(text.as_bytes(), LexerOptions::default());
+        lexer.macro_final_eval(&mut out, &self.context);
+
+        let options = LexerOptions {
+            preprocessor: false,
+            ..Default::default()
+        };
+        let mut lexer = Lexer::new_translated(&out, options);
+        let number = lexer.next();
+        let digits = &out[lexer.start.pos..lexer.pos];
+        let new_line = match number {
+            Token::Eof => {
+                self.context.add_diagnostic(Diagnostic::error(
+                    "unexpected end of file after #line".to_string(),
+                    line,
+                    column,
+                ));
+                return;
+            }
+            Token::LiteralInt(n) if digits.iter().all(u8::is_ascii_digit) => {
+                if n == 0 || n > 2_147_483_647 {
+                    self.context.add_diagnostic(Diagnostic::error(
+                        "line number out of range".to_string(),
+                        line,
+                        column,
+                    ));
+                    return;
+                }
+                n as usize
+            }
+            _ => {
+                self.context.add_diagnostic(Diagnostic::error(
+                    format!(
+                        "\"{}\" after #line is not a positive integer",
+                        String::from_utf8_lossy(digits)
+                    ),
+                    line,
+                    column,
+                ));
+                return;
+            }
+        };
+
+        match lexer.next() {
+            Token::Eof => {}
+            Token::LiteralString(file) => {
+                let mut name = Vec::with_capacity(file.len());
+                let mut escape = false;
+                for c in file.iter() {
+                    if *c == b'\\' && !escape {
+                        escape = true;
+                    } else {
+                        name.push(*c);
+                        escape = false;
+                    }
+                }
+                self.context.set_file(&String::from_utf8_lossy(&name));
+            }
+            _ => {
+                self.context.add_diagnostic(Diagnostic::error(
+                    format!(
+                        "invalid filename \"{}\"",
+                        String::from_utf8_lossy(&out[lexer.start.pos..lexer.pos])
+                    ),
+                    line,
+                    column,
+                ));
+                return;
+            }
+        }
+
+        // the end of this line gives the new line
+        self.line = new_line - 1;
+    }
+
+    #[inline(always)]
+    fn get_pragma_macro_name(&mut self) -> Option<&'a str> {
+        // ("NAME")
+        skip_whites!(self);
+        if self.pos >= self.len || self.next_char(0) != b'(' {
+            return None;
+        }
+        self.pos += 1;
+        skip_whites!(self);
+        if self.pos >= self.len || self.next_char(0) != b'\"' {
+            return None;
+        }
+        self.pos += 1;
+        let name = self.get_include_content(b'\"');
+        skip_whites!(self);
+        if self.pos < self.len && self.next_char(0) == b')' {
+            self.pos += 1;
+        }
+        std::str::from_utf8(name).ok()
+    }
+
     #[inline(always)]
     pub(crate) fn get_undef(&mut self) {
         skip_whites!(self);
         let name = self.get_preproc_identifier();
-        self.context.undef(name);
+        self.context.undefine(name);
     }
 }
 
@@ -916,6 +2154,8 @@ impl<'a> Lexer<'a> {
 mod tests {
 
     use super::*;
+    use crate::lexer::errors::Severity;
+    use crate::lexer::lexer::Language;
 
     macro_rules! mk_args {
         ( $( $a: expr ),* ) => {
@@ -930,12 +2170,15 @@ mod tests {
         );
         assert_eq!(
             p.next(),
-            Token::PreprocInclude(IncludeType::Quote(b"foo.h"))
+            Token::PreprocInclude(IncludeType::Quote(b"foo.h".into()))
         );
-        assert_eq!(p.next(), Token::PreprocInclude(IncludeType::Other(b"A(B)")));
         assert_eq!(
             p.next(),
-            Token::PreprocIncludeNext(IncludeType::Angle(b"foo\\barbar.h"))
+            Token::PreprocInclude(IncludeType::Other(b"A(B)".into()))
+        );
+        assert_eq!(
+            p.next(),
+            Token::PreprocIncludeNext(IncludeType::Angle(b"foo\\barbar.h".into()))
         );
     }
 
@@ -1030,90 +2273,407 @@ mod tests {
         assert_eq!(p.next(), Token::LiteralInt(56));
     }
 
+    fn get_values(p: &mut Lexer) -> Vec<u64> {
+        let mut values = Vec::new();
+        loop {
+            match p.next() {
+                Token::Eof => break,
+                Token::LiteralInt(n) => values.push(n),
+                _ => {}
+            }
+        }
+        values
+    }
+
     #[test]
-    fn test_if_else_nested() {
+    fn test_if_nested_innermost() {
         let mut p = Lexer::new(
             concat!(
-                "#define COND1 12\n",
-                "#define COND2 0\n",
-                "#define COND3 34\n",
-                "\n",
-                "#if COND1\n",
-                "    #define foo 56\n",
-                "    #if COND2\n",
-                "        #define bar 78\n",
-                "    #else\n",
-                "       #if COND3\n",
-                "           #define bar 910\n",
-                "       #else\n",
-                "           #define bar 1112\n",
-                "       #endif\n",
-                "    #endif\n",
-                "#endif\n",
-                "foo bar"
+                "#ifdef A
+",
+                "1
+",
+                "#elif 1
+",
+                "    #ifndef B
+",
+                "        #if 0
+",
+                "        2
+",
+                "        #elif 1
+",
+                "        3
+",
+                "        #else
+",
+                "        4
+",
+                "        #endif
+",
+                "    #else
+",
+                "    5
+",
+                "    #endif
+",
+                "#else
+",
+                "6
+",
+                "#endif
+",
+                "7
+",
             )
             .as_bytes(),
         );
 
-        assert_eq!(p.next(), Token::Eol);
-        assert_eq!(p.next(), Token::Eol);
-        assert_eq!(p.next(), Token::Eol);
-        assert_eq!(p.next(), Token::Eol);
-        assert_eq!(p.next(), Token::Eol);
-        assert_eq!(p.next(), Token::LiteralInt(56));
-        assert_eq!(p.next(), Token::LiteralInt(910));
+        assert_eq!(get_values(&mut p), vec![3, 7]);
+        assert!(p.take_diagnostics().is_empty());
+    }
 
+    #[test]
+    fn test_elif_after_taken() {
         let mut p = Lexer::new(
             concat!(
-                "#define COND1 12\n",
-                "#define COND2\n",
-                "#define COND3 34\n",
-                "\n",
-                "#if COND1\n",
-                "    #define foo 56\n",
-                "    #if defined(COND2)\n",
-                "        #define bar 78\n",
-                "    #else\n",
-                "       #if COND3\n",
-                "           #define bar 910\n",
-                "       #else\n",
-                "           #define bar 1112\n",
-                "       #endif\n",
-                "    #endif\n",
-                "#endif\n",
-                "foo bar"
+                "#if 1
+",
+                "1
+",
+                "#elif 1
+",
+                "2
+",
+                "#elif 0
+",
+                "3
+",
+                "#else
+",
+                "4
+",
+                "#endif
+",
+                "#if 0
+",
+                "5
+",
+                "#elif 1
+",
+                "6
+",
+                "#elif 1
+",
+                "7
+",
+                "#else
+",
+                "8
+",
+                "#endif
+",
+                "#if 1
+",
+                "    #if 0
+",
+                "    9
+",
+                "    #elif 0
+",
+                "    10
+",
+                "    #endif
+",
+                "#else
+",
+                "11
+",
+                "#endif
+",
+                "12
+",
             )
             .as_bytes(),
         );
 
-        assert_eq!(p.next(), Token::Eol);
-        assert_eq!(p.next(), Token::Eol);
-        assert_eq!(p.next(), Token::Eol);
-        assert_eq!(p.next(), Token::LiteralInt(56));
-        assert_eq!(p.next(), Token::LiteralInt(78));
+        assert_eq!(get_values(&mut p), vec![1, 6, 12]);
+    }
+
+    #[test]
+    fn test_elifdef_language() {
+        let source = b"#define B\n#ifdef A\n1\n#elifdef B\n2\n#else\n3\n#endif\n";
+        for (language, value) in &[(Language::C23, 2), (Language::C11, 3), (Language::Cpp20, 3)] {
+            let options = LexerOptions {
+                language: *language,
+                ..Default::default()
+            };
+            let mut p = Lexer::with_options(source, options);
+            assert_eq!(get_values(&mut p), vec![*value]);
+        }
+    }
 
+    #[test]
+    fn test_elifdef() {
         let mut p = Lexer::new(
             concat!(
-                "#define COND1 12\n",
-                "\n",
-                "#if COND1\n",
-                "    #define foo 56\n",
-                "    #if defined(COND2)\n",
-                "        #define bar 78\n",
+                "#define B\n",
+                "#ifdef A\n",
+                "1\n",
+                "#elifdef B\n",
+                "2\n",
+                "#elifdef B\n",
+                "3\n",
+                "#else\n",
+                "4\n",
+                "#endif\n",
+                "#if 1\n",
+                "5\n",
+                "#elifndef A\n",
+                "6\n",
+                "#elifdef B\n",
+                "7\n",
+                "#endif\n",
+                "#if 0\n",
+                "    #ifdef B\n",
+                "    8\n",
+                "    #elifndef A\n",
+                "    9\n",
+                "    #endif\n",
+                "#elifndef B\n",
+                "10\n",
+                "#elifndef A\n",
+                "    #ifndef B\n",
+                "    11\n",
+                "    #elifndef C\n",
+                "    12\n",
                 "    #else\n",
-                "       #if COND3\n",
-                "           #define bar 910\n",
-                "       #else\n",
-                "           #define bar 1112\n",
-                "       #endif\n",
+                "    13\n",
                 "    #endif\n",
+                "#else\n",
+                "14\n",
                 "#endif\n",
-                "foo bar"
+                "15\n",
             )
             .as_bytes(),
         );
 
-        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(get_values(&mut p), vec![2, 5, 12, 15]);
+        assert!(p.take_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_unterminated_if() {
+        let mut p = Lexer::new(
+            concat!(
+                "#if 1\n",
+                "1\n",
+                "  #  ifdef A\n",
+                "2\n",
+                "#endif\n",
+                "#ifndef A\n",
+                "3\n",
+                "    #if 0\n",
+                "    #elif 1\n",
+                "4\n",
+            )
+            .as_bytes(),
+        );
+
+        assert_eq!(get_values(&mut p), vec![1, 3, 4]);
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.message, d.line, d.column))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                ("unterminated #if".to_string(), 1, 2),
+                ("unterminated #ifndef".to_string(), 6, 2),
+                ("unterminated #if".to_string(), 8, 6),
+            ]
+        );
+        assert_eq!(p.next(), Token::Eof);
+        assert!(p.take_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_unterminated_if_at_eof() {
+        for source in &[
+            "#if 0\n#elif",
+            "#ifdef#elif",
+            "#if 1\n#elif",
+            "#if 0\n#elif 1",
+        ] {
+            let mut p = Lexer::new(source.as_bytes());
+            while p.next() != Token::Eof {}
+            let diagnostics: Vec<_> = p
+                .take_diagnostics()
+                .into_iter()
+                .map(|d| d.message)
+                .filter(|message| message.starts_with("unterminated"))
+                .collect();
+            assert_eq!(diagnostics.len(), 1, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_unterminated_if_position() {
+        let mut p = Lexer::new(
+            concat!(
+                "#ifdef A\n",
+                "#elif 1\n",
+                "1\n",
+                "    #if 0\n",
+                "\t#  ifndef B\n",
+                "    #elif 1\n",
+                "2\n",
+            )
+            .as_bytes(),
+        );
+
+        assert_eq!(get_values(&mut p), vec![1]);
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.message, d.line, d.column))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                ("unterminated #ifdef".to_string(), 1, 2),
+                ("unterminated #if".to_string(), 4, 6),
+                ("unterminated #ifndef".to_string(), 5, 12),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stray_endif() {
+        let mut p = Lexer::new(b"#if 1\n1\n#endif\n#endif\n2\n");
+
+        assert_eq!(get_values(&mut p), vec![1, 2]);
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.message, d.line, d.column))
+            .collect();
+        assert_eq!(diagnostics, vec![("#endif without #if".to_string(), 4, 2)]);
+    }
+
+    #[test]
+    fn test_if_without_if() {
+        let mut p = Lexer::new(
+            b"#else
+1
+#elif 0
+2
+#endif
+3
+",
+        );
+
+        assert_eq!(get_values(&mut p), vec![1, 2, 3]);
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.message, d.line, d.column))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                ("#else without #if".to_string(), 1, 2),
+                ("#elif without #if".to_string(), 3, 2),
+                ("#endif without #if".to_string(), 5, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_if_else_nested() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define COND1 12\n",
+                "#define COND2 0\n",
+                "#define COND3 34\n",
+                "\n",
+                "#if COND1\n",
+                "    #define foo 56\n",
+                "    #if COND2\n",
+                "        #define bar 78\n",
+                "    #else\n",
+                "       #if COND3\n",
+                "           #define bar 910\n",
+                "       #else\n",
+                "           #define bar 1112\n",
+                "       #endif\n",
+                "    #endif\n",
+                "#endif\n",
+                "foo bar"
+            )
+            .as_bytes(),
+        );
+
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralInt(56));
+        assert_eq!(p.next(), Token::LiteralInt(910));
+
+        let mut p = Lexer::new(
+            concat!(
+                "#define COND1 12\n",
+                "#define COND2\n",
+                "#define COND3 34\n",
+                "\n",
+                "#if COND1\n",
+                "    #define foo 56\n",
+                "    #if defined(COND2)\n",
+                "        #define bar 78\n",
+                "    #else\n",
+                "       #if COND3\n",
+                "           #define bar 910\n",
+                "       #else\n",
+                "           #define bar 1112\n",
+                "       #endif\n",
+                "    #endif\n",
+                "#endif\n",
+                "foo bar"
+            )
+            .as_bytes(),
+        );
+
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralInt(56));
+        assert_eq!(p.next(), Token::LiteralInt(78));
+
+        let mut p = Lexer::new(
+            concat!(
+                "#define COND1 12\n",
+                "\n",
+                "#if COND1\n",
+                "    #define foo 56\n",
+                "    #if defined(COND2)\n",
+                "        #define bar 78\n",
+                "    #else\n",
+                "       #if COND3\n",
+                "           #define bar 910\n",
+                "       #else\n",
+                "           #define bar 1112\n",
+                "       #endif\n",
+                "    #endif\n",
+                "#endif\n",
+                "foo bar"
+            )
+            .as_bytes(),
+        );
+
+        assert_eq!(p.next(), Token::Eol);
         assert_eq!(p.next(), Token::Eol);
         assert_eq!(p.next(), Token::Eol);
         assert_eq!(p.next(), Token::Eol);
@@ -1164,4 +2724,934 @@ mod tests {
         assert_eq!(p.next(), Token::Eol);
         assert_eq!(p.next(), Token::LiteralInt(56));
     }
+
+    #[test]
+    fn test_error() {
+        let mut p = Lexer::new(b"#if 0\n#error nope\n#endif\n#error boom\nint x;");
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Int);
+        assert_eq!(p.next(), Token::Identifier("x".into()));
+        assert_eq!(p.next(), Token::SemiColon);
+        assert_eq!(
+            p.errors(),
+            &[PreprocError::Error {
+                message: "boom".to_string(),
+                line: 4,
+                column: 2,
+            }]
+        );
+
+        let mut p = Lexer::new(b"  #  error  a \\\n  message \nA");
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Identifier("A".into()));
+        assert_eq!(
+            p.errors(),
+            &[PreprocError::Error {
+                message: "a   message".to_string(),
+                line: 1,
+                column: 6,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_invalid_function_definition() {
+        let mut p = Lexer::new(b"#define F(#)#\nF(1)\n");
+        let mut tokens = Vec::new();
+        loop {
+            match p.next() {
+                Token::Eof => break,
+                Token::Eol => {}
+                tok => tokens.push(tok),
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Identifier("F".into()),
+                Token::LeftParen,
+                Token::LiteralInt(1),
+                Token::RightParen
+            ]
+        );
+        let diagnostics = p.take_diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(
+            diagnostics[0].message,
+            "invalid definition of \"F\": invalid argument 1"
+        );
+        assert_eq!(diagnostics[0].line, 1);
+    }
+
+    #[test]
+    fn test_spliced_directive() {
+        let mut p = Lexer::new(b"#err\\\nor x\n");
+        while p.next() != Token::Eof {}
+        assert_eq!(
+            p.errors(),
+            &[PreprocError::Error {
+                message: "x".to_string(),
+                line: 1,
+                column: 2,
+            }]
+        );
+
+        let mut p = Lexer::new(b"  #warn\\\ning x\n");
+        while p.next() != Token::Eof {}
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.message, d.line, d.column))
+            .collect();
+        assert_eq!(diagnostics, vec![("x".to_string(), 1, 4)]);
+
+        let mut p = Lexer::new(b"#incl\\\nude \"nothere.h\"\n");
+        while p.next() != Token::Eof {}
+        assert_eq!(
+            p.errors(),
+            &[PreprocError::IncludeNotFound {
+                name: "nothere.h".to_string(),
+                line: 1,
+                column: 2,
+            }]
+        );
+
+        let mut p = Lexer::new(b"#imp\\\nort \"nothere.h\"\n");
+        while p.next() != Token::Eof {}
+        assert_eq!(
+            p.errors(),
+            &[PreprocError::IncludeNotFound {
+                name: "nothere.h".to_string(),
+                line: 1,
+                column: 2,
+            }]
+        );
+
+        let mut p = Lexer::new(b"#prag\\\nma pop_macro(\"X\")\n");
+        while p.next() != Token::Eof {}
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.line, d.column))
+            .collect();
+        assert_eq!(diagnostics, vec![(1, 2)]);
+
+        let mut p = Lexer::new(b"#li\\\nne x\n");
+        while p.next() != Token::Eof {}
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.line, d.column))
+            .collect();
+        assert_eq!(diagnostics, vec![(1, 2)]);
+
+        let mut p = Lexer::new(b"#e\\\ny\n");
+        while p.next() != Token::Eof {}
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.message, d.line, d.column))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![("invalid preprocessing directive #ey".to_string(), 1, 2)]
+        );
+
+        let mut p = Lexer::new(b"#el\\\nse\n");
+        while p.next() != Token::Eof {}
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.message, d.line, d.column))
+            .collect();
+        assert_eq!(diagnostics, vec![("#else without #if".to_string(), 1, 2)]);
+    }
+
+    #[test]
+    fn test_warning() {
+        let mut p = Lexer::new(
+            concat!(
+                "#warning deprecated header\n",
+                "#ifdef FOO\n",
+                "#warning not here\n",
+                "#endif\n",
+                "#define FOO\n",
+                "#ifdef FOO\n",
+                "#  warning   with  some   spaces  \n",
+                "#endif\n",
+                "int x;\n",
+                "#error stop\n",
+            )
+            .as_bytes(),
+        );
+        let mut tokens = Vec::new();
+        loop {
+            match p.next() {
+                Token::Eof => break,
+                Token::Eol => {}
+                tok => tokens.push(tok),
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec![Token::Int, Token::Identifier("x".into()), Token::SemiColon]
+        );
+
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.severity, d.message, d.line))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                (Severity::Warning, "deprecated header".to_string(), 1),
+                (Severity::Warning, "with  some   spaces".to_string(), 7),
+                (Severity::Error, "stop".to_string(), 10),
+            ]
+        );
+        assert!(p.take_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_unknown_directive() {
+        let source = concat!(
+            "#unknown_directive foo\n",
+            "#\n",
+            "  #  \n",
+            "#if 0\n",
+            "#sccs not here\n",
+            "#endif\n",
+            "int x;\n",
+        );
+        let mut p = Lexer::new(source.as_bytes());
+        let mut tokens = Vec::new();
+        loop {
+            match p.next() {
+                Token::Eof => break,
+                Token::Eol => {}
+                tok => tokens.push(tok),
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec![Token::Int, Token::Identifier("x".into()), Token::SemiColon]
+        );
+
+        let diagnostics = p.take_diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        let diagnostic = &diagnostics[0];
+        assert_eq!(diagnostic.severity, Severity::Warning);
+        assert_eq!(
+            diagnostic.message,
+            "invalid preprocessing directive #unknown_directive"
+        );
+        assert_eq!((diagnostic.line, diagnostic.column), (1, 2));
+        let span = diagnostic.span.unwrap();
+        assert_eq!(
+            &source[span.start as usize..span.end as usize],
+            "unknown_directive"
+        );
+
+        // a bare # gives nothing
+        let mut p = Lexer::new(b"#\n#\nint x;");
+        while p.next() != Token::Eof {}
+        assert!(p.take_diagnostics().is_empty());
+    }
+
+    #[derive(Debug)]
+    struct Ident;
+
+    impl DirectiveHandler for Ident {
+        fn unknown_directive(
+            &self,
+            name: &str,
+            text: &str,
+            line: usize,
+            column: usize,
+            _span: Span,
+        ) -> Option<Diagnostic> {
+            if name == "ident" {
+                None
+            } else {
+                Some(Diagnostic::error(
+                    format!("#{} {}", name, text),
+                    line,
+                    column,
+                ))
+            }
+        }
+    }
+
+    #[test]
+    fn test_directive_handler() {
+        let mut p = Lexer::new(b"#ident \"1.0\"\n#sccs  \"2.0\"\nint x;");
+        p.set_directive_handler(Box::new(Ident));
+        while p.next() != Token::Eof {}
+
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.severity, d.message, d.line))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![(Severity::Error, "#sccs \"2.0\"".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn test_push_pop_macro() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define FOO 1\n",
+                "#define BAR(x) x + 1\n",
+                "#pragma push_macro(\"FOO\")\n",
+                "#pragma push_macro( \"BAR\" )\n",
+                "#pragma push_macro(\"BAZ\")\n",
+                "#undef FOO\n",
+                "#define FOO(x) x\n",
+                "#define BAR 2\n",
+                "#define BAZ 3\n",
+                "FOO(4) BAR BAZ\n",
+                "#pragma pop_macro(\"FOO\")\n",
+                "#pragma pop_macro(\"BAR\")\n",
+                "#pragma pop_macro(\"BAZ\")\n",
+                "FOO BAR(5) BAZ\n",
+                "#pragma pop_macro(\"FOO\")\n",
+                "FOO\n",
+            )
+            .as_bytes(),
+        );
+
+        let mut tokens = Vec::new();
+        loop {
+            match p.next() {
+                Token::Eof => break,
+                Token::Eol => {}
+                tok => tokens.push(tok),
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                Token::LiteralInt(4),
+                Token::LiteralInt(2),
+                Token::LiteralInt(3),
+                Token::LiteralInt(1),
+                Token::LiteralInt(5),
+                Token::Plus,
+                Token::LiteralInt(1),
+                Token::Identifier("BAZ".into()),
+                Token::LiteralInt(1),
+            ]
+        );
+
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.severity, d.line))
+            .collect();
+        // BAR is redefined without #undef
+        assert_eq!(
+            diagnostics,
+            vec![(Severity::Error, 8), (Severity::Warning, 15)]
+        );
+    }
+
+    #[test]
+    fn test_line_directive() {
+        let mut p = Lexer::new(
+            concat!(
+                "__LINE__\n",
+                "#line 100\n",
+                "__LINE__\n",
+                "__LINE__ __FILE__\n",
+                "#define N 200\n",
+                "#define F \"foo.c\"\n",
+                "#line N F\n",
+                "__LINE__ __FILE__\n",
+                "#line 300 \"dir\\\\bar.c\"\n",
+                "__FILE__\n",
+                "x\n",
+            )
+            .as_bytes(),
+        );
+        p.set_file("test.c");
+
+        assert_eq!(p.next(), Token::LiteralInt(1));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralInt(100));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralInt(101));
+        assert_eq!(p.next(), Token::LiteralString(b"test.c".into()));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralInt(200));
+        assert_eq!(p.next(), Token::LiteralString(b"foo.c".into()));
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralString(b"dir\\\\bar.c".into()));
+        assert_eq!(p.next(), Token::Eol);
+        let (tok, span) = p.next_with_span();
+        assert_eq!(tok, Token::Identifier("x".into()));
+        assert_eq!((span.line, span.col), (301, 1));
+        assert!(p.take_diagnostics().is_empty());
+
+        let mut p = Lexer::new(
+            concat!(
+                "#line\n",
+                "#line x\n",
+                "#line 0x10\n",
+                "#line 0\n",
+                "#line 10 foo\n",
+                "__LINE__\n",
+            )
+            .as_bytes(),
+        );
+        loop {
+            match p.next() {
+                Token::Eof => break,
+                Token::Eol => {}
+                tok => assert_eq!(tok, Token::LiteralInt(6)),
+            }
+        }
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.message, d.line, d.column))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                ("unexpected end of file after #line".to_string(), 1, 2),
+                (
+                    "\"x\" after #line is not a positive integer".to_string(),
+                    2,
+                    2
+                ),
+                (
+                    "\"0x10\" after #line is not a positive integer".to_string(),
+                    3,
+                    2
+                ),
+                ("line number out of range".to_string(), 4, 2),
+                ("invalid filename \"foo\"".to_string(), 5, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pragma() {
+        let mut p = Lexer::new(
+            concat!(
+                "#pragma once\n",
+                "#pragma GCC diagnostic push\n",
+                "#  pragma  GCC diagnostic ignored \"-Wfoo\"  \n",
+                "int x;\n",
+                "#pragma omp parallel \\\n",
+                "    for\n",
+                "#pragma GCC diagnostic pop",
+            )
+            .as_bytes(),
+        );
+        let mut tokens = Vec::new();
+        loop {
+            match p.next() {
+                Token::Eof => break,
+                Token::Eol => {}
+                tok => tokens.push(tok),
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Pragma(b"GCC diagnostic push".into()),
+                Token::Pragma(b"GCC diagnostic ignored \"-Wfoo\"".into()),
+                Token::Int,
+                Token::Identifier("x".into()),
+                Token::SemiColon,
+                // the line splicing is done before
+                Token::Pragma(b"omp parallel     for".into()),
+                Token::Pragma(b"GCC diagnostic pop".into()),
+            ]
+        );
+        assert_eq!(
+            tokens[1].to_string(),
+            "#pragma GCC diagnostic ignored \"-Wfoo\""
+        );
+
+        let mut ctx = PContext::default();
+        let tokens = preprocess(
+            b"#pragma once\n#pragma GCC diagnostic ignored \"-Wfoo\"\nint x;",
+            &mut ctx,
+        )
+        .unwrap();
+        assert_eq!(
+            tokens[0].0,
+            Token::Pragma(b"GCC diagnostic ignored \"-Wfoo\"".into())
+        );
+        assert_eq!(tokens[0].1.line, 2);
+        assert_eq!(tokens.len(), 4);
+
+        // a token following a pragma isn't on the line of the pragma
+        let mut ctx = PContext::default();
+        let out = preprocess_to_string(
+            b"#pragma GCC diagnostic push\n_Pragma(\"GCC diagnostic pop\") int x;",
+            &mut ctx,
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            "#pragma GCC diagnostic push\n#pragma GCC diagnostic pop\nint x;"
+        );
+
+        // the handled directives aren't a part of the output
+        assert!(!is_output(&Token::Directive {
+            kind: "pragma".into(),
+            text: b"#pragma once".into(),
+        }));
+        assert!(is_output(&Token::Pragma(b"GCC diagnostic pop".into())));
+    }
+
+    #[test]
+    fn test_stdc_pragma() {
+        let mut p = Lexer::new(
+            concat!(
+                "#pragma STDC FENV_ACCESS ON\n",
+                "#pragma STDC FP_CONTRACT OFF\n",
+                "#pragma STDC FP_CONTRACT \\\n",
+                "  DEFAULT\n",
+                "_Pragma(\"STDC FENV_ROUND FE_UPWARD\")\n",
+            )
+            .as_bytes(),
+        );
+        let mut tokens = Vec::new();
+        loop {
+            match p.next() {
+                Token::Eof => break,
+                Token::Eol => {}
+                tok => tokens.push(tok),
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Pragma(b"STDC FENV_ACCESS ON".into()),
+                Token::Pragma(b"STDC FP_CONTRACT OFF".into()),
+                Token::Pragma(b"STDC FP_CONTRACT   DEFAULT".into()),
+                Token::Pragma(b"STDC FENV_ROUND FE_UPWARD".into()),
+            ]
+        );
+
+        let pragma = |name: &str| p.context.stdc_pragma(name).cloned();
+        assert_eq!(
+            pragma("FENV_ACCESS"),
+            Some(StdcPragma {
+                name: "FENV_ACCESS".to_string(),
+                args: vec!["ON".to_string()],
+            })
+        );
+        assert_eq!(pragma("FP_CONTRACT").unwrap().args, vec!["DEFAULT"]);
+        assert_eq!(pragma("FENV_ROUND").unwrap().args, vec!["FE_UPWARD"]);
+        assert_eq!(pragma("CX_LIMITED_RANGE"), None);
+
+        // the continued line of the pragma is counted once
+        let mut p = Lexer::new(b"#pragma STDC FP_CONTRACT \\\n  DEFAULT\nx");
+        loop {
+            match p.next_with_span() {
+                (Token::Identifier(_), span) => {
+                    assert_eq!(span.line, 3);
+                    break;
+                }
+                (Token::Eof, _) => unreachable!(),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_pragma_operator() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define DO_PRAGMA(x) _Pragma(#x)\n",
+                "#define FOO 1\n",
+                "DO_PRAGMA(push_macro(\"FOO\"))\n",
+                "#undef FOO\n",
+                "FOO\n",
+                "_Pragma(\"pop_macro(\\\"FOO\\\")\")\n",
+                "FOO\n",
+                "_Pragma ( \"GCC poison\" ) x\n",
+                "DO_PRAGMA(omp parallel) y\n",
+                "_Pragma(foo) z\n",
+                "_Pragma(\"pop_macro(\\\"BAR\\\")\")\n",
+            )
+            .as_bytes(),
+        );
+
+        let mut tokens = Vec::new();
+        loop {
+            match p.next() {
+                Token::Eof => break,
+                Token::Eol => {}
+                tok => tokens.push(tok),
+            }
+        }
+        let diagnostics: Vec<_> = p
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.message, d.line, d.column))
+            .collect();
+        // the pragmas own their texts
+        drop(p);
+        assert_eq!(
+            tokens.iter().map(|tok| tok.to_string()).collect::<Vec<_>>(),
+            vec![
+                "FOO",
+                "1",
+                "#pragma GCC poison",
+                "x",
+                "#pragma omp parallel",
+                "y",
+                "_Pragma",
+                "(",
+                "foo",
+                ")",
+                "z",
+            ]
+        );
+        assert_eq!(
+            diagnostics,
+            vec![
+                (
+                    "_Pragma takes a parenthesized string literal".to_string(),
+                    10,
+                    1
+                ),
+                (
+                    "pragma pop_macro could not pop 'BAR', no matching push_macro".to_string(),
+                    11,
+                    1
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn test_preprocess() {
+        let mut ctx = PContext::default();
+        assert!(preprocess(b"#define N 4", &mut ctx).unwrap().is_empty());
+        let tokens = preprocess(
+            concat!(
+                "#define MAX(a, b) ((a) > (b) ? (a) : (b))\n",
+                "#ifdef N\n",
+                "int x = MAX(y, N); // max\n",
+                "#else\n",
+                "int x;\n",
+                "#endif\n",
+                "#undef N\n",
+                "int N;\n",
+            )
+            .as_bytes(),
+            &mut ctx,
+        )
+        .unwrap();
+        let tokens: Vec<_> = tokens.into_iter().map(|(tok, _)| tok).collect();
+        // the tokens don't borrow the context
+        assert!(ctx.defined("MAX"));
+        assert!(!ctx.defined("N"));
+
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Int,
+                Token::Identifier("x".into()),
+                Token::Equal,
+                Token::LeftParen,
+                Token::LeftParen,
+                Token::Identifier("y".into()),
+                Token::RightParen,
+                Token::Greater,
+                Token::LeftParen,
+                Token::LiteralInt(4),
+                Token::RightParen,
+                Token::Question,
+                Token::LeftParen,
+                Token::Identifier("y".into()),
+                Token::RightParen,
+                Token::Colon,
+                Token::LeftParen,
+                Token::LiteralInt(4),
+                Token::RightParen,
+                Token::RightParen,
+                Token::SemiColon,
+                Token::Int,
+                Token::Identifier("N".into()),
+                Token::SemiColon,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_preproc_iterator() {
+        let mut source = String::from("#define MAX(a, b) ((a) > (b) ? (a) : (b))\n#define N 7\n");
+        for i in 0..20_000 {
+            source.push_str(&format!("int x{} = MAX({}, N);\n", i, i));
+        }
+        source.push_str("#error end\nlast");
+
+        // 21 tokens by line: int x = ((i) > (7) ? (i) : (7));
+        let mut ctx = PContext::default();
+        let mut iter = PreprocIterator::new(source.as_bytes(), &mut ctx);
+        let (tok, span) = iter.nth(21 * 1000 + 1).unwrap().unwrap();
+        assert_eq!(tok, Token::Identifier("x1000".into()));
+        assert_eq!(span.line, 1003);
+        let (tok, _) = iter.nth(3).unwrap().unwrap();
+        assert_eq!(tok, Token::LiteralInt(1000));
+        let (tok, _) = iter.nth(21 * 500 + 3).unwrap().unwrap();
+        assert_eq!(tok, Token::LiteralInt(7));
+        // the previous expansions aren't kept
+        assert!(iter.lexer().include_bufs.is_empty());
+
+        let mut count = 21 * 1500 + 10;
+        let mut errors = Vec::new();
+        let mut last = None;
+        for item in iter {
+            match item {
+                Ok((tok, _)) => {
+                    count += 1;
+                    last = Some(tok.to_string());
+                }
+                Err(error) => errors.push(error),
+            }
+        }
+        assert_eq!(count, 21 * 20_000 + 1);
+        assert_eq!(last.unwrap(), "last");
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_preproc_iterator_collect() {
+        let mut source = String::from("#define A(x) alpha_##x beta_##x\n");
+        for i in 0..200 {
+            source.push_str(&format!("A(v{})\n", i));
+        }
+
+        let mut ctx = PContext::default();
+        let tokens: Vec<_> = PreprocIterator::new(source.as_bytes(), &mut ctx)
+            .map(|item| item.unwrap().0)
+            .collect();
+
+        assert_eq!(tokens.len(), 400);
+        for (i, pair) in tokens.chunks(2).enumerate() {
+            assert_eq!(pair[0].to_string(), format!("alpha_v{}", i));
+            assert_eq!(pair[1].to_string(), format!("beta_v{}", i));
+        }
+    }
+
+    #[test]
+    fn test_directives() {
+        let options = LexerOptions {
+            directives: true,
+            comments: true,
+            ..Default::default()
+        };
+        let source = concat!(
+            "#define A(x) x + 1 /* one\n",
+            "two */\n",
+            "#if A(0)\n",
+            "a\n",
+            "#else\n",
+            "b\n",
+            "# endif FOO\n",
+            "#ifdef B\n",
+            "c\n",
+            "#elif 1 // \"d\n",
+            "A(2)\n",
+            "#endif\n",
+            "%:undef A\n",
+            "#\n",
+            "#error don't\n",
+            "A",
+        );
+        let mut p = Lexer::with_options(source.as_bytes(), options);
+        let mut tokens = Vec::new();
+        loop {
+            match p.next_with_span() {
+                (Token::Eof, _) => break,
+                (Token::Directive { kind, text }, span) => tokens.push(format!(
+                    "{}: {} at {}:{} [{}, {}]",
+                    kind,
+                    String::from_utf8_lossy(&text),
+                    span.line,
+                    span.col,
+                    span.start,
+                    span.end
+                )),
+                (tok, _) => tokens.push(tok.to_string()),
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                "define: #define A(x) x + 1 /* one\ntwo */ at 1:1 [0, 32]",
+                "if: #if A(0) at 3:1 [33, 41]",
+                "a",
+                "\n",
+                "else: #else at 5:1 [44, 49]",
+                "endif: # endif FOO at 7:1 [52, 63]",
+                "ifdef: #ifdef B at 8:1 [64, 72]",
+                "elif: #elif 1 // \"d at 10:1 [75, 88]",
+                "2",
+                "+",
+                "1",
+                "\n",
+                "endif: #endif at 12:1 [94, 100]",
+                "undef: %:undef A at 13:1 [101, 110]",
+                ": # at 14:1 [111, 112]",
+                "error: #error don't at 15:1 [113, 125]",
+                "A",
+            ]
+        );
+        assert!(!p.context.defined("A"));
+        assert_eq!(p.errors().len(), 1);
+    }
+
+    #[test]
+    fn test_inactive_ranges() {
+        let source = concat!(
+            "#if 0\n",
+            "int a;\n",
+            "  #if 1\n",
+            "  int b;\n",
+            "  #endif\n",
+            "#else\n",
+            "int c;\n",
+            "#endif\n",
+            "#ifdef FOO\n",
+            "#endif\n",
+            "#ifndef BAR // comment\n",
+            "int d;\n",
+            "#elif 1\n",
+            "int e;",
+        );
+        let mut ctx = PContext::default();
+        let (tokens, ranges) =
+            preprocess_with_inactive_ranges(source.as_bytes(), &mut ctx).unwrap();
+        assert_eq!(tokens.len(), 6);
+
+        let ranges: Vec<_> = ranges
+            .iter()
+            .map(|span| (&source[span.start as usize..span.end as usize], span.line))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![("int a;\n  #if 1\n  int b;\n  #endif\n", 2), ("int e;", 14),]
+        );
+    }
+
+    #[test]
+    fn test_preprocess_spans() {
+        let mut ctx = PContext::default();
+        let tokens = preprocess(b"#define F(x) x + b\nF(a)\n  F(c) d", &mut ctx).unwrap();
+
+        let span = |start, end, line, col| Span {
+            start,
+            end,
+            line,
+            col,
+        };
+        assert_eq!(
+            tokens,
+            vec![
+                (Token::Identifier("a".into()), span(19, 23, 2, 1)),
+                (Token::Plus, span(19, 23, 2, 1)),
+                (Token::Identifier("b".into()), span(19, 23, 2, 1)),
+                (Token::Identifier("c".into()), span(26, 30, 3, 3)),
+                (Token::Plus, span(26, 30, 3, 3)),
+                (Token::Identifier("b".into()), span(26, 30, 3, 3)),
+                (Token::Identifier("d".into()), span(31, 32, 3, 8)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collect_identifiers() {
+        let source = concat!(
+            "#define SQUARE(x) ((x) * (x))\n",
+            "int norm(int a, int b) {\n",
+            "    int n = SQUARE(a) + SQUARE(b);\n",
+            "    return n;\n",
+            "}\n",
+        );
+        let mut ctx = PContext::default();
+        let ids = collect_identifiers(preprocess(source.as_bytes(), &mut ctx).unwrap());
+
+        let mut counts: Vec<_> = ids
+            .iter()
+            .map(|(id, spans)| (id.as_str(), spans.len()))
+            .collect();
+        counts.sort_unstable();
+        assert_eq!(counts, vec![("a", 3), ("b", 3), ("n", 2), ("norm", 1)]);
+
+        let spans: Vec<_> = ids["n"]
+            .iter()
+            .map(|span| (span.line, &source[span.start as usize..span.end as usize]))
+            .collect();
+        assert_eq!(spans, vec![(3, "n"), (4, "n")]);
+    }
+
+    #[test]
+    fn test_preprocess_to_string() {
+        let mut ctx = PContext::default();
+        let out = preprocess_to_string(
+            concat!(
+                "#define MAX(a, b) ((a) > (b) ? (a) : (b))\n",
+                "#define NEG -\n",
+                "#define ID(x) x\n",
+                "int x = MAX(y,  4); // max\n",
+                "int z = -NEG 1, w = ID(-)-1;\n",
+                "a ID(b)c\n",
+                "#pragma message(\"hi\")\n",
+                "b _Pragma(\"message(\\\"hi\\\")\") c\n",
+            )
+            .as_bytes(),
+            &mut ctx,
+        )
+        .unwrap();
+
+        assert_eq!(
+            out,
+            concat!(
+                "int x = ((y)>(4)?(y):(4));\n",
+                "int z = - - 1, w = - -1;\n",
+                "a b c\n",
+                "#pragma message(\"hi\")\n",
+                "b\n",
+                "#pragma message(\"hi\")\n",
+                "c",
+            )
+        );
+    }
+
+    #[test]
+    fn test_preprocess_error() {
+        let mut ctx = PContext::default();
+        let res = preprocess(
+            b"#define A\n#ifdef A\n#error no A\n#endif\nint x;",
+            &mut ctx,
+        );
+
+        assert_eq!(
+            res,
+            Err(PreprocError::Error {
+                message: "no A".to_string(),
+                line: 3,
+                column: 2,
+            })
+        );
+    }
 }
@@ -0,0 +1,284 @@
+use super::pmacros::PContext;
+
+// The builtins known by gcc and clang (for __has_builtin)
+const GNU_BUILTINS: [&str; 12] = [
+    "__builtin_addressof",
+    "__builtin_bswap32",
+    "__builtin_bswap64",
+    "__builtin_clz",
+    "__builtin_constant_p",
+    "__builtin_ctz",
+    "__builtin_expect",
+    "__builtin_is_constant_evaluated",
+    "__builtin_offsetof",
+    "__builtin_popcount",
+    "__builtin_trap",
+    "__builtin_unreachable",
+];
+
+// The features of clang in C++17 mode (for __has_feature)
+const CLANG_FEATURES: [&str; 12] = [
+    "cxx_alias_templates",
+    "cxx_constexpr",
+    "cxx_decltype",
+    "cxx_exceptions",
+    "cxx_lambdas",
+    "cxx_noexcept",
+    "cxx_nullptr",
+    "cxx_override_control",
+    "cxx_rtti",
+    "cxx_rvalue_references",
+    "cxx_static_assert",
+    "cxx_variadic_templates",
+];
+
+/// The macros predefined by a compiler (e.g. to parse the headers checking `__GNUC__`)
+/// with the names known by `__has_builtin` and `__has_feature`
+///
+/// The presets describe a compiler in C++17 mode (`__cplusplus` is defined):
+/// the macros can be changed with [`CompilerProfile::define`] and [`CompilerProfile::undefine`]
+/// or merged with a custom set with [`CompilerProfile::merge`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompilerProfile {
+    macros: Vec<(String, String)>,
+    builtins: Vec<String>,
+    features: Vec<String>,
+}
+
+impl CompilerProfile {
+    /// Create a profile from some (name, value) pairs
+    pub fn new(macros: &[(&str, &str)]) -> Self {
+        macros
+            .iter()
+            .fold(Self::default(), |profile, (name, value)| {
+                profile.define(name, value)
+            })
+    }
+
+    /// The macros predefined by gcc 11
+    pub fn gcc() -> Self {
+        Self::new(&[
+            ("__GNUC__", "11"),
+            ("__GNUC_MINOR__", "2"),
+            ("__GNUC_PATCHLEVEL__", "0"),
+            ("__STDC__", "1"),
+            ("__STDC_HOSTED__", "1"),
+            ("__cplusplus", "201703L"),
+        ])
+        .add_builtins(&GNU_BUILTINS)
+    }
+
+    /// The macros predefined by clang 13 (clang claims to be gcc 4.2.1)
+    pub fn clang() -> Self {
+        Self::new(&[
+            ("__clang__", "1"),
+            ("__clang_major__", "13"),
+            ("__clang_minor__", "0"),
+            ("__clang_patchlevel__", "0"),
+            ("__GNUC__", "4"),
+            ("__GNUC_MINOR__", "2"),
+            ("__GNUC_PATCHLEVEL__", "1"),
+            ("__STDC__", "1"),
+            ("__STDC_HOSTED__", "1"),
+            ("__cplusplus", "201703L"),
+        ])
+        .add_builtins(&GNU_BUILTINS)
+        .builtin("__builtin_assume")
+        .add_features(&CLANG_FEATURES)
+    }
+
+    /// The macros predefined by MSVC 2019 (`__cplusplus` is 199711L without /Zc:__cplusplus)
+    pub fn msvc() -> Self {
+        Self::new(&[
+            ("_MSC_VER", "1929"),
+            ("_MSC_FULL_VER", "192930133"),
+            ("_WIN32", "1"),
+            ("_MSVC_LANG", "201703L"),
+            ("__cplusplus", "199711L"),
+        ])
+    }
+
+    /// Define a macro (as with the -D option of a compiler: see [`PContext::define_from_str`])
+    /// or change its value
+    pub fn define(mut self, name: &str, value: &str) -> Self {
+        if let Some(mac) = self.macros.iter_mut().find(|(n, _)| n == name) {
+            mac.1 = value.to_string();
+        } else {
+            self.macros.push((name.to_string(), value.to_string()));
+        }
+        self
+    }
+
+    /// Remove a macro from the profile
+    pub fn undefine(mut self, name: &str) -> Self {
+        self.macros.retain(|(n, _)| n != name);
+        self
+    }
+
+    /// Make `__has_builtin(name)` true
+    pub fn builtin(mut self, name: &str) -> Self {
+        if !self.builtins.iter().any(|n| n == name) {
+            self.builtins.push(name.to_string());
+        }
+        self
+    }
+
+    /// Make `__has_feature(name)` true
+    pub fn feature(mut self, name: &str) -> Self {
+        if !self.features.iter().any(|n| n == name) {
+            self.features.push(name.to_string());
+        }
+        self
+    }
+
+    fn add_builtins(self, names: &[&str]) -> Self {
+        names
+            .iter()
+            .fold(self, |profile, name| profile.builtin(name))
+    }
+
+    fn add_features(self, names: &[&str]) -> Self {
+        names
+            .iter()
+            .fold(self, |profile, name| profile.feature(name))
+    }
+
+    /// Add the macros, the builtins and the features of another profile:
+    /// its macro values replace the ones of this profile
+    pub fn merge(self, other: &CompilerProfile) -> Self {
+        let profile = other
+            .macros
+            .iter()
+            .fold(self, |profile, (name, value)| profile.define(name, value));
+        let profile = other
+            .builtins
+            .iter()
+            .fold(profile, |profile, name| profile.builtin(name));
+        other
+            .features
+            .iter()
+            .fold(profile, |profile, name| profile.feature(name))
+    }
+
+    /// Get the macros with their values
+    pub fn macros(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.macros
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn builtins(&self) -> impl Iterator<Item = &str> {
+        self.builtins.iter().map(String::as_str)
+    }
+
+    pub fn features(&self) -> impl Iterator<Item = &str> {
+        self.features.iter().map(String::as_str)
+    }
+}
+
+impl PContext {
+    /// Create a context with the macros of a compiler profile
+    pub fn with_profile(profile: &CompilerProfile) -> Self {
+        let mut context = Self::default();
+        context.add_profile(profile);
+        context
+    }
+
+    /// Define the macros of a compiler profile (the existing definitions are replaced)
+    /// and add its builtins and features
+    pub fn add_profile(&mut self, profile: &CompilerProfile) {
+        for (name, value) in profile.macros() {
+            self.define_from_str(&format!("{}={}", name, value));
+        }
+        for name in profile.builtins() {
+            self.add_builtin(name);
+        }
+        for name in profile.features() {
+            self.add_feature(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::lexer::{Token, Tokenizer};
+
+    #[test]
+    fn test_profile() {
+        let context = PContext::with_profile(&CompilerProfile::gcc());
+        assert_eq!(context.definition_text("__GNUC__"), Some("11".to_string()));
+        assert_eq!(context.definition_text("_MSC_VER"), None);
+
+        let source = concat!(
+            "#ifdef _MSC_VER\n",
+            "int msvc;\n",
+            "#elif defined(__clang__)\n",
+            "int clang;\n",
+            "#elif __GNUC__ >= 4 && __cplusplus >= 201103L\n",
+            "int gcc;\n",
+            "#endif\n",
+        );
+        let names = |profile: &CompilerProfile| -> Vec<String> {
+            let mut ctx = PContext::with_profile(profile);
+            Tokenizer::new(source.as_bytes(), &mut ctx)
+                .filter(|tok| *tok != Token::Eol)
+                .map(|tok| tok.to_string())
+                .collect()
+        };
+        assert_eq!(names(&CompilerProfile::gcc()), vec!["int", "gcc", ";"]);
+        assert_eq!(names(&CompilerProfile::clang())[1], "clang");
+        assert_eq!(names(&CompilerProfile::msvc())[1], "msvc");
+
+        // a custom set merged in a preset
+        let custom = CompilerProfile::new(&[("__GNUC__", "3"), ("MY_MACRO", "x")]);
+        let profile = CompilerProfile::gcc()
+            .merge(&custom)
+            .undefine("__cplusplus");
+        let context = PContext::with_profile(&profile);
+        assert_eq!(context.definition_text("__GNUC__"), Some("3".to_string()));
+        assert_eq!(context.definition_text("MY_MACRO"), Some("x".to_string()));
+        assert_eq!(context.definition_text("__cplusplus"), None);
+        assert!(names(&profile).is_empty());
+    }
+
+    #[test]
+    fn test_builtins_features() {
+        let source = concat!(
+            "#if __has_builtin(__builtin_expect)\n",
+            "int expect;\n",
+            "#endif\n",
+            "#if __has_builtin(__builtin_assume)\n",
+            "int assume;\n",
+            "#endif\n",
+            "#if __has_feature(cxx_rvalue_references) && __has_feature(__cxx_lambdas__)\n",
+            "int rvalue;\n",
+            "#endif\n",
+            "#if __has_feature(my_feature) || __has_builtin(__builtin_mine)\n",
+            "int mine;\n",
+            "#endif\n",
+        );
+        let names = |profile: &CompilerProfile| -> Vec<String> {
+            let mut ctx = PContext::with_profile(profile);
+            Tokenizer::new(source.as_bytes(), &mut ctx)
+                .filter_map(|tok| match tok {
+                    Token::Identifier(id) => Some(id.to_string()),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(names(&CompilerProfile::gcc()), vec!["expect"]);
+        assert_eq!(
+            names(&CompilerProfile::clang()),
+            vec!["expect", "assume", "rvalue"]
+        );
+        assert!(names(&CompilerProfile::msvc()).is_empty());
+
+        // the sets can be changed to match a toolchain
+        let profile = CompilerProfile::msvc()
+            .feature("my_feature")
+            .merge(&CompilerProfile::default().builtin("__builtin_expect"));
+        assert_eq!(names(&profile), vec!["expect", "mine"]);
+    }
+}
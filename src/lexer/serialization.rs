@@ -0,0 +1,185 @@
+// The byte payloads of the tokens (string literals, comments, ...) are serialized as strings
+// when they're valid utf-8 (as they almost always are) and as bytes otherwise.
+// They're deserialized as bytes (a format such as JSON gives a string or an array):
+// they're borrowed when possible (e.g. a JSON string without any escaped character)
+// and owned otherwise.
+pub(crate) mod bytes {
+    use serde::de::{Deserializer, Error, SeqAccess, Visitor};
+    use serde::Serializer;
+    use std::borrow::Cow;
+    use std::fmt;
+
+    pub(crate) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if let Ok(s) = std::str::from_utf8(bytes) {
+            serializer.serialize_str(s)
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Cow<'de, [u8]>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "a string or a byte array")
+        }
+
+        fn visit_borrowed_str<E: Error>(self, s: &'de str) -> Result<Self::Value, E> {
+            Ok(Cow::Borrowed(s.as_bytes()))
+        }
+
+        fn visit_str<E: Error>(self, s: &str) -> Result<Self::Value, E> {
+            Ok(Cow::Owned(s.as_bytes().to_vec()))
+        }
+
+        fn visit_string<E: Error>(self, s: String) -> Result<Self::Value, E> {
+            Ok(Cow::Owned(s.into_bytes()))
+        }
+
+        fn visit_borrowed_bytes<E: Error>(self, bytes: &'de [u8]) -> Result<Self::Value, E> {
+            Ok(Cow::Borrowed(bytes))
+        }
+
+        fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+            Ok(Cow::Owned(bytes.to_vec()))
+        }
+
+        fn visit_byte_buf<E: Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(Cow::Owned(bytes))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(Cow::Owned(bytes))
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Cow<'de, [u8]>, D::Error> {
+        deserializer.deserialize_bytes(BytesVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::lexer::preprocessor::IncludeType;
+    use crate::lexer::{Lexer, LexerOptions, Span, Token, UdLiteral};
+    use std::borrow::Cow;
+
+    #[test]
+    fn test_serde_tokens() {
+        let tokens = vec![
+            Token::Int,
+            Token::Identifier("x".into()),
+            Token::LiteralChar(0x61),
+            Token::LiteralUUChar(0x1F600),
+            Token::LiteralDecimal(1.5),
+            Token::LiteralULongLong(u64::MAX),
+            Token::LiteralString(b"hello".into()),
+            Token::LiteralU8RString(b"a(b)c".into()),
+            Token::LiteralUserDefined(UdLiteral::String(b"abc".into()), "_s".into()),
+            Token::LiteralUserDefined(UdLiteral::Char(0x7A), "_c".into()),
+            Token::Comment {
+                block: true,
+                text: b" a comment ".into(),
+            },
+            Token::PreprocInclude(IncludeType::Angle(b"vector".into())),
+            Token::Invalid(b"@".into()),
+        ];
+
+        let json = serde_json::to_string(&tokens).unwrap();
+        let res: Vec<Token> = serde_json::from_str(&json).unwrap();
+        assert_eq!(res, tokens);
+
+        assert_eq!(
+            serde_json::to_string(&tokens[..7]).unwrap(),
+            concat!(
+                r#"["Int",{"Identifier":"x"},{"LiteralChar":97},{"LiteralUUChar":128512},"#,
+                r#"{"LiteralDecimal":1.5},{"LiteralULongLong":18446744073709551615},"#,
+                r#"{"LiteralString":"hello"}]"#
+            )
+        );
+        // the non utf-8 contents are bytes
+        assert_eq!(
+            serde_json::to_string(&Token::LiteralString(b"\xe9t\xe9".into())).unwrap(),
+            r#"{"LiteralString":[233,116,233]}"#
+        );
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        // the escaped strings are owned once deserialized
+        let json = r#"[{"LiteralString":"hi\n"},{"Identifier":"a\u0062"},{"Invalid":"@"}]"#;
+        let tokens: Vec<Token> = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::LiteralString(b"hi\n".into()),
+                Token::Identifier("ab".into()),
+                Token::Invalid(b"@".into()),
+            ]
+        );
+        assert!(matches!(tokens[0], Token::LiteralString(Cow::Owned(_))));
+        assert!(matches!(tokens[1], Token::Identifier(Cow::Owned(_))));
+        assert!(matches!(tokens[2], Token::Invalid(Cow::Borrowed(b"@"))));
+        assert_eq!(
+            serde_json::to_string(&tokens).unwrap(),
+            json.replace("a\\u0062", "ab")
+        );
+
+        // the non utf-8 contents are read back from the bytes
+        let tokens = vec![
+            Token::LiteralString(b"\xe9t\xe9".into()),
+            Token::Comment {
+                block: false,
+                text: b"\xff\n\"".into(),
+            },
+            Token::PreprocInclude(IncludeType::Quote(b"caf\xe9.h".into())),
+            Token::LiteralUserDefined(UdLiteral::String(b"\x80".into()), "_s".into()),
+        ];
+        let json = serde_json::to_string(&tokens).unwrap();
+        let res: Vec<Token> = serde_json::from_str(&json).unwrap();
+        assert_eq!(res, tokens);
+        let res: Vec<Token> = serde_json::from_slice(json.as_bytes()).unwrap();
+        assert_eq!(res, tokens);
+    }
+
+    #[test]
+    fn test_serde_spans() {
+        let options = LexerOptions {
+            preprocessor: false,
+            ..Default::default()
+        };
+        let mut lexer =
+            Lexer::with_options(b"int x = 'a' + 0x12;\n  f(\"str\", 1.5e3_km);", options);
+        let mut tokens = Vec::new();
+        loop {
+            let (tok, span) = lexer.next_with_span();
+            if tok == Token::Eof {
+                break;
+            }
+            tokens.push((tok, span));
+        }
+
+        let json = serde_json::to_string(&tokens).unwrap();
+        let res: Vec<(Token, Span)> = serde_json::from_str(&json).unwrap();
+        assert_eq!(res, tokens);
+
+        let span = Span {
+            start: 3,
+            end: 5,
+            line: 2,
+            col: 4,
+        };
+        let json = serde_json::to_string(&span).unwrap();
+        assert_eq!(json, r#"{"start":3,"end":5,"line":2,"col":4}"#);
+        assert_eq!(serde_json::from_str::<Span>(&json).unwrap(), span);
+    }
+}
@@ -0,0 +1,30 @@
+use typed_arena::Arena;
+
+/// The buffers made while lexing (a source after the line splicing, the included files)
+///
+/// When it's given to the lexer (see [`LexerBuilder::sources`](super::LexerBuilder::sources)),
+/// it outlives it: the tokens read in these buffers borrow their payloads
+/// instead of owning a copy.
+#[derive(Default)]
+pub struct Sources {
+    bufs: Arena<Vec<u8>>,
+}
+
+impl Sources {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of stored buffers
+    pub fn len(&self) -> usize {
+        self.bufs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn add(&self, buf: Vec<u8>) -> &[u8] {
+        self.bufs.alloc(buf)
+    }
+}
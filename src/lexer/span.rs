@@ -0,0 +1,327 @@
+use super::errors::Diagnostic;
+use super::lexer::{Lexer, Token};
+
+/// The location of a token in the source
+///
+/// A token coming from a macro expansion has the span of the macro invocation
+/// and a token from an included file has offsets in this file.
+/// The offsets are the ones in the source before the line splicing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    /// Offset of the first byte of the token
+    pub start: u32,
+    /// Offset just after the last byte of the token
+    pub end: u32,
+    /// Line of the first byte (starting at 1)
+    pub line: u32,
+    /// Column of the first byte (starting at 1), tabs move to the next tab stop
+    pub col: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Position {
+    pub(crate) pos: usize,
+    pub(crate) line: usize,
+    pub(crate) lpos: usize,
+}
+
+/// Get the column after buf[from..to] when starting at col:
+/// a tab moves to the next tab stop and an utf-8 char counts for one
+#[inline(always)]
+pub(crate) fn advance_column(
+    buf: &[u8],
+    from: usize,
+    to: usize,
+    mut col: usize,
+    tab_width: usize,
+) -> usize {
+    for c in buf.get(from..to).unwrap_or(&[]) {
+        if *c < 0x80 {
+            if *c == b'\t' {
+                col = (col - 1) / tab_width * tab_width + tab_width + 1;
+            } else {
+                col += 1;
+            }
+        } else if *c & 0xC0 != 0x80 {
+            // not a continuation byte
+            col += 1;
+        }
+    }
+    col
+}
+
+// Last computed column: avoid to rescan the whole line for each token
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ColumnCache {
+    buf: usize,
+    lpos: usize,
+    pos: usize,
+    col: usize,
+}
+
+impl<'a> Lexer<'a> {
+    /// Get the next token and its location
+    pub fn next_with_span(&mut self) -> (Token<'a>, Span) {
+        let tok = self.next();
+        let span = if let Token::Directive { .. } = tok {
+            self.directive_span
+        } else if self.preproc_use {
+            self.get_span(self.sbuf, self.expansion_start, self.spos)
+        } else if tok == Token::Eof {
+            self.mark_start();
+            self.get_span(self.buf, self.start, self.pos)
+        } else {
+            self.get_span(self.buf, self.start, self.pos)
+        };
+        (tok, span)
+    }
+
+    pub(crate) fn get_tab_width(&self) -> usize {
+        (self.options.tab_width as usize).max(1)
+    }
+
+    /// Emit an error diagnostic for the current token and get it as invalid
+    pub(crate) fn get_invalid(&mut self, message: String) -> Token<'a> {
+        let start = self.start;
+        let col = self.get_span_column(self.buf, start);
+        let span = self.get_span(self.buf, start, self.pos);
+        self.context
+            .add_diagnostic(Diagnostic::error(message, start.line, col).with_span(span));
+        let token = unsafe { self.buf.get_unchecked(start.pos..self.pos) };
+        Token::Invalid(token.into())
+    }
+
+    #[inline(always)]
+    pub(crate) fn mark_start(&mut self) {
+        let (line, lpos) = self.get_line_start();
+        self.start = Position {
+            pos: self.pos,
+            line,
+            lpos,
+        };
+    }
+
+    /// Set the span of the current macro invocation for the expansion observer
+    pub(crate) fn mark_expansion_span(&mut self) {
+        if self.context.has_observer() {
+            let span = self.get_span(self.buf, self.start, self.pos);
+            self.context.set_expansion_span(span);
+        }
+    }
+
+    /// Get the span of the directive name which has just been read
+    pub(crate) fn get_directive_span(&mut self, name: &str) -> Span {
+        let (line, lpos) = self.get_line_start();
+        let start = Position {
+            pos: self.pos - name.len(),
+            line,
+            lpos,
+        };
+        self.get_span(self.buf, start, self.pos)
+    }
+
+    pub(crate) fn get_span(&mut self, buf: &[u8], start: Position, end: usize) -> Span {
+        Span {
+            start: self.source_map.original_pos(start.pos) as u32,
+            end: self.source_map.original_pos(end) as u32,
+            line: start.line as u32,
+            col: self.get_span_column(buf, start) as u32,
+        }
+    }
+
+    pub(crate) fn get_span_column(&mut self, buf: &[u8], start: Position) -> usize {
+        let cache = self.col_cache;
+        let (pos, col) = if cache.buf == buf.as_ptr() as usize
+            && cache.lpos == start.lpos
+            && cache.pos <= start.pos
+        {
+            (cache.pos, cache.col)
+        } else {
+            (start.lpos, 1)
+        };
+
+        let col = advance_column(buf, pos, start.pos, col, self.get_tab_width());
+
+        self.col_cache = ColumnCache {
+            buf: buf.as_ptr() as usize,
+            lpos: start.lpos,
+            pos: start.pos,
+            col,
+        };
+        col
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::lexer::LexerOptions;
+
+    fn get_spans<'a>(lexer: &mut Lexer<'a>) -> Vec<(Token<'a>, Span)> {
+        let mut res = Vec::new();
+        loop {
+            let (tok, span) = lexer.next_with_span();
+            if tok == Token::Eof {
+                break;
+            }
+            if tok != Token::Eol {
+                res.push((tok, span));
+            }
+        }
+        res
+    }
+
+    fn span(start: u32, end: u32, line: u32, col: u32) -> Span {
+        Span {
+            start,
+            end,
+            line,
+            col,
+        }
+    }
+
+    #[test]
+    fn test_span() {
+        let options = LexerOptions {
+            comments: true,
+            ..Default::default()
+        };
+        let mut lexer =
+            Lexer::with_options(b"int a = b+c;\n  s = \"hello\";\n/* x\n */ a->b", options);
+        let spans = get_spans(&mut lexer);
+
+        assert_eq!(
+            spans,
+            vec![
+                (Token::Int, span(0, 3, 1, 1)),
+                (Token::Identifier("a".into()), span(4, 5, 1, 5)),
+                (Token::Equal, span(6, 7, 1, 7)),
+                (Token::Identifier("b".into()), span(8, 9, 1, 9)),
+                (Token::Plus, span(9, 10, 1, 10)),
+                (Token::Identifier("c".into()), span(10, 11, 1, 11)),
+                (Token::SemiColon, span(11, 12, 1, 12)),
+                (Token::Identifier("s".into()), span(15, 16, 2, 3)),
+                (Token::Equal, span(17, 18, 2, 5)),
+                (Token::LiteralString(b"hello".into()), span(19, 26, 2, 7)),
+                (Token::SemiColon, span(26, 27, 2, 14)),
+                (
+                    Token::Comment {
+                        block: true,
+                        text: b" x\n ".into(),
+                    },
+                    span(28, 36, 3, 1),
+                ),
+                (Token::Identifier("a".into()), span(37, 38, 4, 5)),
+                (Token::Arrow, span(38, 40, 4, 6)),
+                (Token::Identifier("b".into()), span(40, 41, 4, 8)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_span_tab() {
+        let source = b"\ta\t+ b\n  \tc";
+
+        let mut lexer = Lexer::new(source);
+        let spans = get_spans(&mut lexer);
+        assert_eq!(
+            spans,
+            vec![
+                (Token::Identifier("a".into()), span(1, 2, 1, 9)),
+                (Token::Plus, span(3, 4, 1, 17)),
+                (Token::Identifier("b".into()), span(5, 6, 1, 19)),
+                (Token::Identifier("c".into()), span(10, 11, 2, 9)),
+            ]
+        );
+
+        let options = LexerOptions {
+            tab_width: 4,
+            ..Default::default()
+        };
+        let mut lexer = Lexer::with_options(source, options);
+        let spans = get_spans(&mut lexer);
+        assert_eq!(
+            spans,
+            vec![
+                (Token::Identifier("a".into()), span(1, 2, 1, 5)),
+                (Token::Plus, span(3, 4, 1, 9)),
+                (Token::Identifier("b".into()), span(5, 6, 1, 11)),
+                (Token::Identifier("c".into()), span(10, 11, 2, 5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_span_utf8() {
+        let options = LexerOptions {
+            comments: true,
+            ..Default::default()
+        };
+        let mut lexer = Lexer::with_options("/* é */ a \"€\" b\n// ü\nc".as_bytes(), options);
+        let spans = get_spans(&mut lexer);
+
+        assert_eq!(
+            spans,
+            vec![
+                (
+                    Token::Comment {
+                        block: true,
+                        text: " é ".as_bytes().into(),
+                    },
+                    span(0, 8, 1, 1),
+                ),
+                (Token::Identifier("a".into()), span(9, 10, 1, 9)),
+                (
+                    Token::LiteralString("€".as_bytes().into()),
+                    span(11, 16, 1, 11)
+                ),
+                (Token::Identifier("b".into()), span(17, 18, 1, 15)),
+                (
+                    Token::Comment {
+                        block: false,
+                        text: " ü".as_bytes().into(),
+                    },
+                    span(19, 24, 2, 1),
+                ),
+                (Token::Identifier("c".into()), span(25, 26, 3, 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_span_splice() {
+        let mut lexer = Lexer::new(b"ab\\\ncd = \\\n  e\n f");
+        let spans = get_spans(&mut lexer);
+
+        assert_eq!(
+            spans,
+            vec![
+                (Token::Identifier("abcd".into()), span(0, 6, 1, 1)),
+                (Token::Equal, span(7, 8, 2, 4)),
+                (Token::Identifier("e".into()), span(13, 14, 3, 3)),
+                (Token::Identifier("f".into()), span(16, 17, 4, 2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_span_macro() {
+        let mut lexer = Lexer::new(b"#define F(x) x + 1\nint a = F(2);");
+        let spans = get_spans(&mut lexer);
+
+        assert_eq!(
+            spans,
+            vec![
+                (Token::Int, span(19, 22, 2, 1)),
+                (Token::Identifier("a".into()), span(23, 24, 2, 5)),
+                (Token::Equal, span(25, 26, 2, 7)),
+                (Token::LiteralInt(2), span(27, 31, 2, 9)),
+                (Token::Plus, span(27, 31, 2, 9)),
+                (Token::LiteralInt(1), span(27, 31, 2, 9)),
+                (Token::SemiColon, span(31, 32, 2, 13)),
+            ]
+        );
+    }
+}
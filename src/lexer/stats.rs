@@ -0,0 +1,132 @@
+use super::lexer::{Lexer, LexerOptions, Token};
+
+/// The line counts of a source (e.g. for some code metrics)
+///
+/// Each physical line is either blank (only whites), a comment line (only comments
+/// and whites) or a code line (with at least one token). The logical lines are
+/// the ones remaining once the lines ending with a backslash are spliced.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SourceStats {
+    pub physical_lines: usize,
+    pub logical_lines: usize,
+    pub blank_lines: usize,
+    pub comment_lines: usize,
+    pub code_lines: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LineKind {
+    Blank,
+    Comment,
+    Code,
+}
+
+impl SourceStats {
+    /// Lex a source (without preprocessing it) to count its lines
+    pub fn new(buf: &[u8]) -> Self {
+        // the offsets where the lines start
+        let starts: Vec<usize> = std::iter::once(0)
+            .chain(
+                buf.iter()
+                    .enumerate()
+                    .filter(|(_, c)| **c == b'\n')
+                    .map(|(pos, _)| pos + 1),
+            )
+            .filter(|pos| *pos < buf.len())
+            .collect();
+        let line_of = |pos: usize| starts.partition_point(|start| *start <= pos) - 1;
+        let mut kinds = vec![LineKind::Blank; starts.len()];
+
+        let mut lexer = Lexer::with_options(
+            buf,
+            LexerOptions {
+                preprocessor: false,
+                comments: true,
+                ..Default::default()
+            },
+        );
+        loop {
+            let (tok, span) = lexer.next_with_span();
+            let kind = match tok {
+                Token::Eof => break,
+                Token::Eol => continue,
+                Token::Comment { .. } => LineKind::Comment,
+                _ => LineKind::Code,
+            };
+            let start = span.start as usize;
+            let end = (span.end as usize).max(start + 1);
+            for line_kind in &mut kinds[line_of(start)..=line_of(end - 1)] {
+                if *line_kind != LineKind::Code {
+                    *line_kind = kind;
+                }
+            }
+        }
+
+        let count = |kind| kinds.iter().filter(|k| **k == kind).count();
+        let continued = starts
+            .iter()
+            .skip(1)
+            .filter(|start| {
+                // the line before can end with \r\n
+                let before = &buf[..**start - 1];
+                before
+                    .strip_suffix(b"\r")
+                    .unwrap_or(before)
+                    .ends_with(b"\\")
+            })
+            .count();
+        Self {
+            physical_lines: starts.len(),
+            logical_lines: starts.len() - continued,
+            blank_lines: count(LineKind::Blank),
+            comment_lines: count(LineKind::Comment),
+            code_lines: count(LineKind::Code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_source_stats() {
+        let source = concat!(
+            "// header\n",
+            "\n",
+            "int a; // trailing\n",
+            "/* block\n",
+            "   comment */\n",
+            "#define F(x) \\\n",
+            "    (x + 1)\n",
+            "   \t\n",
+            "int b = /* c */ 2;\n",
+            "const char *s = R\"(\n",
+            ")\";",
+        );
+        assert_eq!(
+            SourceStats::new(source.as_bytes()),
+            SourceStats {
+                physical_lines: 11,
+                logical_lines: 10,
+                blank_lines: 2,
+                comment_lines: 3,
+                code_lines: 6,
+            }
+        );
+        assert_eq!(SourceStats::new(b""), SourceStats::default());
+
+        let source = "#define F(x) \\\r\n    (x + 1)\r\n\r\nint a;\r\n";
+        assert_eq!(
+            SourceStats::new(source.as_bytes()),
+            SourceStats {
+                physical_lines: 4,
+                logical_lines: 3,
+                blank_lines: 1,
+                comment_lines: 0,
+                code_lines: 3,
+            }
+        );
+    }
+}
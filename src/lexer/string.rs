@@ -1,4 +1,7 @@
-use super::lexer::{Lexer, Token};
+use std::borrow::Cow;
+
+use super::cchar::CharType;
+use super::lexer::{Lexer, LexerOptions, Token};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub(crate) enum StringType {
@@ -19,6 +22,108 @@ pub(crate) enum StringCharType {
     C(StringType),
 }
 
+fn check_utf8(s: &[u8]) -> Result<(), String> {
+    std::str::from_utf8(s)
+        .map(|_| ())
+        .map_err(|_| "invalid UTF-8 in u8 string literal".to_string())
+}
+
+// A char coming from the source or from an universal character name,
+// or the value of a numeric or simple escape sequence (a code unit)
+enum Unit {
+    Char(char),
+    Value(u32),
+}
+
+/// Replace the escape sequences in the content of a (non-raw) string literal:
+/// the value of a numeric escape is a code unit and mustn't be greater than max
+fn decode_string<F: FnMut(Unit)>(content: &[u8], max: u32, mut push: F) -> Result<(), String> {
+    let source = std::str::from_utf8(content).map_err(|_| {
+        format!(
+            "invalid UTF-8 in {} string literal",
+            match max {
+                0xFF => "u8",
+                0xFFFF => "u",
+                _ => "U",
+            }
+        )
+    })?;
+    let mut lexer = Lexer::new_translated(content, LexerOptions::default());
+    while lexer.pos < lexer.len {
+        if lexer.next_char(0) != b'\\' {
+            let c = source[lexer.pos..].chars().next().unwrap();
+            lexer.pos += c.len_utf8();
+            push(Unit::Char(c));
+            continue;
+        }
+        lexer.pos += 1;
+        let kind = if lexer.pos < lexer.len {
+            lexer.next_char(0)
+        } else {
+            0
+        };
+        let e = lexer.get_escape()?;
+        if kind | 0x20 == b'u' {
+            // the surrogates and the values greater than 0x10FFFF aren't chars
+            let c = std::char::from_u32(e).ok_or_else(|| {
+                if kind == b'u' {
+                    format!("\\u{:04x} is not a valid universal character", e)
+                } else {
+                    format!("\\U{:08x} is not a valid universal character", e)
+                }
+            })?;
+            push(Unit::Char(c));
+        } else if e > max {
+            return Err(if kind == b'x' {
+                "hex escape sequence out of range".to_string()
+            } else {
+                "octal escape sequence out of range".to_string()
+            });
+        } else {
+            push(Unit::Value(e));
+        }
+    }
+    Ok(())
+}
+
+/// Get the code units of the content of a u8 string literal (without the quotes):
+/// the escape sequences are replaced by their value and the chars are encoded in UTF-8
+pub fn get_u8_string(content: &[u8]) -> Result<Vec<u8>, String> {
+    let mut units = Vec::with_capacity(content.len());
+    decode_string(content, 0xFF, |unit| match unit {
+        Unit::Char(c) => {
+            let mut buf = [0; 4];
+            units.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+        }
+        Unit::Value(e) => units.push(e as u8),
+    })?;
+    Ok(units)
+}
+
+/// Get the code units of the content of a u string literal:
+/// the chars are encoded in UTF-16 (a surrogate pair for the ones out of the BMP)
+pub fn get_u16_string(content: &[u8]) -> Result<Vec<u16>, String> {
+    let mut units = Vec::with_capacity(content.len());
+    decode_string(content, 0xFFFF, |unit| match unit {
+        Unit::Char(c) => {
+            let mut buf = [0; 2];
+            units.extend_from_slice(c.encode_utf16(&mut buf));
+        }
+        Unit::Value(e) => units.push(e as u16),
+    })?;
+    Ok(units)
+}
+
+/// Get the code units of the content of a U string literal: the chars are encoded in UTF-32
+pub fn get_u32_string(content: &[u8]) -> Result<Vec<u32>, String> {
+    let mut units = Vec::with_capacity(content.len());
+    decode_string(content, 0xFFFF_FFFF, |unit| match unit {
+        Unit::Char(c) => units.push(u32::from(c)),
+        Unit::Value(e) => units.push(e),
+    })?;
+    Ok(units)
+}
+
 impl<'a> Lexer<'a> {
     #[inline(always)]
     pub(crate) fn get_string_char_prefix(&mut self, typ: StringType) -> Option<StringCharType> {
@@ -80,76 +185,152 @@ impl<'a> Lexer<'a> {
         None
     }
 
+    /// Check that the prefix of a string or a char exists in the language
+    fn has_prefix(&self, typ: StringCharType) -> bool {
+        let language = self.options.language;
+        match typ {
+            StringCharType::S(StringType::L) | StringCharType::C(StringType::L) => true,
+            StringCharType::S(StringType::R)
+            | StringCharType::S(StringType::LR)
+            | StringCharType::S(StringType::UUR)
+            | StringCharType::S(StringType::UR)
+            | StringCharType::S(StringType::U8R) => language.is_cpp(),
+            StringCharType::C(StringType::U8) => language.has_u8_chars(),
+            StringCharType::S(_)
+            | StringCharType::C(StringType::U)
+            | StringCharType::C(StringType::UU) => language.has_unicode_strings(),
+            // R'c' isn't a char
+            StringCharType::C(_) => false,
+        }
+    }
+
     #[inline(always)]
     pub(crate) fn get_special_string_char(&mut self, typ: StringType) -> Option<Token<'a>> {
-        if let Some(typ) = self.get_string_char_prefix(typ) {
-            Some(match typ {
-                StringCharType::S(typ) => match typ {
-                    StringType::L => Token::LiteralLString(self.get_string_content()),
-                    StringType::UU => Token::LiteralUUString(self.get_string_content()),
-                    StringType::R => Token::LiteralRString(self.get_r_string_content()),
-                    StringType::U => Token::LiteralUString(self.get_string_content()),
-                    StringType::U8 => Token::LiteralU8String(self.get_string_content()),
-                    StringType::LR => Token::LiteralLRString(self.get_r_string_content()),
-                    StringType::UUR => Token::LiteralUURString(self.get_r_string_content()),
-                    StringType::UR => Token::LiteralURString(self.get_r_string_content()),
-                    StringType::U8R => Token::LiteralU8RString(self.get_r_string_content()),
-                },
-                StringCharType::C(typ) => match typ {
-                    StringType::L => Token::LiteralLChar(self.get_c_char_u32()),
-                    StringType::UU => Token::LiteralUUChar(self.get_c_char_u32()),
-                    StringType::U => Token::LiteralUChar(self.get_c_char_u32()),
-                    StringType::U8 => Token::LiteralU8Char(self.get_c_char_u32()),
-                    _ => unreachable!(),
-                },
-            })
-        } else {
-            None
+        let spos = self.pos;
+        let typ = self.get_string_char_prefix(typ);
+        if typ.is_some_and(|typ| !self.has_prefix(typ)) {
+            // the prefix is an identifier (e.g. u8'c' is u8 followed by 'c' in C11)
+            self.pos = spos;
+            return None;
         }
+        typ.map(|typ| match typ {
+            StringCharType::S(typ) => match typ {
+                StringType::L => self.get_string_literal(Token::LiteralLString),
+                StringType::UU => self.get_checked_string(false, Token::LiteralUUString, |s| {
+                    get_u32_string(s).map(drop)
+                }),
+                StringType::R => self.get_r_string(Token::LiteralRString),
+                StringType::U => self.get_checked_string(false, Token::LiteralUString, |s| {
+                    get_u16_string(s).map(drop)
+                }),
+                StringType::U8 => self.get_checked_string(false, Token::LiteralU8String, |s| {
+                    get_u8_string(s).map(drop)
+                }),
+                StringType::LR => self.get_r_string(Token::LiteralLRString),
+                StringType::UUR => self.get_r_string(Token::LiteralUURString),
+                StringType::UR => self.get_r_string(Token::LiteralURString),
+                StringType::U8R => {
+                    self.get_checked_string(true, Token::LiteralU8RString, check_utf8)
+                }
+            },
+            StringCharType::C(typ) => match typ {
+                StringType::L => self.get_char_literal(Some(CharType::L)),
+                StringType::UU => self.get_char_literal(Some(CharType::UU)),
+                StringType::U => self.get_char_literal(Some(CharType::U)),
+                StringType::U8 => self.get_char_literal(Some(CharType::U8)),
+                _ => unreachable!(),
+            },
+        })
     }
 
     #[inline(always)]
     pub(crate) fn get_string(&mut self) -> Token<'a> {
-        return Token::LiteralString(self.get_string_content());
+        self.get_string_literal(Token::LiteralString)
+    }
+
+    #[inline(always)]
+    pub(crate) fn get_string_literal(&mut self, tok: fn(Cow<'a, [u8]>) -> Token<'a>) -> Token<'a> {
+        match self.get_string_content() {
+            Ok(s) => tok(Cow::Borrowed(s)),
+            Err(message) => self.get_invalid(message),
+        }
+    }
+
+    /// Get a string literal whose content must be valid in its encoding
+    #[inline(always)]
+    pub(crate) fn get_checked_string(
+        &mut self,
+        raw: bool,
+        tok: fn(Cow<'a, [u8]>) -> Token<'a>,
+        check: fn(&[u8]) -> Result<(), String>,
+    ) -> Token<'a> {
+        let content = if raw {
+            self.get_r_string_content()
+        } else {
+            self.get_string_content()
+        };
+        match content.and_then(|s| check(s).map(|_| s)) {
+            Ok(s) => tok(Cow::Borrowed(s)),
+            Err(message) => self.get_invalid(message),
+        }
     }
 
+    /// Get the content of a string up to the closing quote: the literal is unterminated
+    /// when a newline (which isn't a part of the invalid token) or the end of the input comes first
     #[inline(always)]
-    pub(crate) fn get_string_content(&mut self) -> &'a [u8] {
+    pub(crate) fn get_string_content(&mut self) -> Result<&'a [u8], String> {
         let spos = self.pos;
         loop {
             if self.pos < self.len {
                 let c = self.next_char(0);
-                if c == b'\\' {
+                if c == b'\\' && self.pos + 1 < self.len && self.next_char(1) != b'\n' {
                     self.pos += 2;
                 } else if c == b'\"' {
                     let s = unsafe { &self.buf.get_unchecked(spos..self.pos) };
                     self.pos += 1;
-                    return s;
+                    return Ok(s);
+                } else if c == b'\n' {
+                    break;
                 } else {
                     self.pos += 1;
                 }
             } else {
-                return unsafe { &self.buf.get_unchecked(spos..) };
+                break;
             }
         }
+        Err("missing terminating \" character".to_string())
+    }
+
+    #[inline(always)]
+    pub(crate) fn get_r_string(&mut self, tok: fn(Cow<'a, [u8]>) -> Token<'a>) -> Token<'a> {
+        match self.get_r_string_content() {
+            Ok(s) => tok(Cow::Borrowed(s)),
+            Err(message) => self.get_invalid(message),
+        }
     }
 
     #[inline(always)]
-    pub(crate) fn get_r_string_content(&mut self) -> &'a [u8] {
+    pub(crate) fn get_r_string_content(&mut self) -> Result<&'a [u8], String> {
         let spos = self.pos;
         loop {
             if self.pos < self.len {
                 let c = self.next_char(0);
-                self.pos += 1;
                 if c == b'(' {
+                    self.pos += 1;
                     break;
+                } else if c == b')' || c == b'\\' || c.is_ascii_whitespace() {
+                    // delimiter doesn't contain parenthesis, spaces or backslashes
+                    return Err(format!(
+                        "invalid character {:?} in raw string delimiter",
+                        char::from(c)
+                    ));
                 }
+                self.pos += 1;
             } else {
-                return unsafe { &self.buf.get_unchecked(spos..) };
+                return Err("unterminated raw string".to_string());
             }
         }
 
-        // delimiter doesn't contain parenthesis, spaces or backslashes
         let delimiter = unsafe { &self.buf.get_unchecked(spos..self.pos - 1) };
         let delim_len = delimiter.len();
 
@@ -161,7 +342,7 @@ impl<'a> Lexer<'a> {
                     let rspos = self.pos;
                     let mut delim_pos = 0;
                     self.pos += 1;
-                    loop {
+                    while self.pos < self.len {
                         let c = self.next_char(0);
 
                         if delim_pos < delim_len {
@@ -175,21 +356,25 @@ impl<'a> Lexer<'a> {
                             delim_pos += 1;
                         } else if c == b'\"' {
                             self.pos += 1;
-                            return unsafe { &self.buf.get_unchecked(spos..rspos) };
-                        } else if c == b'\n' {
-                            self.add_new_line();
+                            if delim_len > 16 {
+                                return Err(format!(
+                                    "raw string delimiter longer than 16 characters: {}",
+                                    String::from_utf8_lossy(delimiter)
+                                ));
+                            }
+                            return Ok(unsafe { self.buf.get_unchecked(spos..rspos) });
                         } else {
                             break;
                         }
                     }
                 } else if c == b'\n' {
-                    self.add_new_line();
                     self.pos += 1;
+                    self.add_new_line();
                 } else {
                     self.pos += 1;
                 }
             } else {
-                return unsafe { &self.buf.get_unchecked(spos..) };
+                return Err("unterminated raw string".to_string());
             }
         }
     }
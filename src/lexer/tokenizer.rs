@@ -0,0 +1,148 @@
+use super::lexer::{Lexer, LexerBuilder, LexerOptions, Token};
+use super::pmacros::PContext;
+
+/// An iterator over the tokens of a C/C++ source
+///
+/// The iteration stops at the end of the input (Token::Eof isn't returned).
+/// ctx contains the initial macros and gets the state once the tokenizer is dropped.
+pub struct Tokenizer<'a> {
+    lexer: Lexer<'a>,
+    ctx: &'a mut PContext,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(buf: &'a [u8], ctx: &'a mut PContext) -> Self {
+        Self::with_options(buf, LexerOptions::default(), ctx)
+    }
+
+    pub fn with_options(buf: &'a [u8], options: LexerOptions, ctx: &'a mut PContext) -> Self {
+        let lexer = LexerBuilder::new()
+            .options(options)
+            .context(std::mem::take(ctx))
+            .build(buf);
+        Self { lexer, ctx }
+    }
+
+    pub fn lexer(&mut self) -> &mut Lexer<'a> {
+        &mut self.lexer
+    }
+}
+
+impl<'a> Drop for Tokenizer<'a> {
+    fn drop(&mut self) {
+        *self.ctx = std::mem::take(&mut self.lexer.context);
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.lexer.next() {
+            Token::Eof => None,
+            tok => Some(tok),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_tokenizer() {
+        let mut ctx = PContext::default();
+        let tokens: Vec<_> = Tokenizer::new(b"#define N 3\nint a[N];\n", &mut ctx)
+            .filter(|tok| *tok != Token::Eol)
+            .collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Int,
+                Token::Identifier("a".into()),
+                Token::LeftBrack,
+                Token::LiteralInt(3),
+                Token::RightBrack,
+                Token::SemiColon,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenizer_collect() {
+        let mut source = String::from("#define A(x) alpha_##x beta_##x\n");
+        for i in 0..200 {
+            source.push_str(&format!("A(v{})\n", i));
+        }
+
+        let mut ctx = PContext::default();
+        let mut tokenizer = Tokenizer::new(source.as_bytes(), &mut ctx);
+        let tokens: Vec<_> = tokenizer
+            .by_ref()
+            .filter(|tok| *tok != Token::Eol)
+            .collect();
+        // the expansion buffers aren't kept
+        assert!(tokenizer.lexer().include_bufs.is_empty());
+        drop(tokenizer);
+
+        assert_eq!(tokens.len(), 400);
+        for (i, pair) in tokens.chunks(2).enumerate() {
+            assert_eq!(pair[0], Token::Identifier(format!("alpha_v{}", i).into()));
+            assert_eq!(pair[1], Token::Identifier(format!("beta_v{}", i).into()));
+        }
+        assert!(ctx.defined("A"));
+    }
+
+    #[test]
+    fn test_tokenizer_no_preprocessor() {
+        let options = LexerOptions {
+            preprocessor: false,
+            ..Default::default()
+        };
+        let mut ctx = PContext::default();
+        let tokens: Vec<_> = Tokenizer::with_options(
+            b"#include <a.h>\n#define CAT(a, b) a ## b\nint x = CAT(1, 2);",
+            options,
+            &mut ctx,
+        )
+        .collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Hash,
+                Token::Identifier("include".into()),
+                Token::Lower,
+                Token::Identifier("a".into()),
+                Token::Dot,
+                Token::Identifier("h".into()),
+                Token::Greater,
+                Token::Eol,
+                Token::Hash,
+                Token::Identifier("define".into()),
+                Token::Identifier("CAT".into()),
+                Token::LeftParen,
+                Token::Identifier("a".into()),
+                Token::Comma,
+                Token::Identifier("b".into()),
+                Token::RightParen,
+                Token::Identifier("a".into()),
+                Token::HashHash,
+                Token::Identifier("b".into()),
+                Token::Eol,
+                Token::Int,
+                Token::Identifier("x".into()),
+                Token::Equal,
+                Token::Identifier("CAT".into()),
+                Token::LeftParen,
+                Token::LiteralInt(1),
+                Token::Comma,
+                Token::LiteralInt(2),
+                Token::RightParen,
+                Token::SemiColon,
+            ]
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use super::lexer::{Lexer, Token};
+use super::pmacros::{ExpansionObserver, MacroOutput, PContext};
+use super::span::Span;
+
+/// An expansion of a macro with the expansions nested in it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExpansionNode {
+    pub name: String,
+    /// The unexpanded arguments of a function-like macro
+    pub args: Option<Vec<String>>,
+    /// The span of the outermost invocation in the source
+    pub span: Span,
+    /// The tokens given by the expansion (separated by a space)
+    pub output: String,
+    /// The expansions done while this one was evaluated, in their order
+    pub children: Vec<ExpansionNode>,
+}
+
+/// The tree of the expansions done to evaluate some macros
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExpansionTree {
+    pub roots: Vec<ExpansionNode>,
+}
+
+impl ExpansionNode {
+    fn write(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(f, "{:width$}{}", "", self.name, width = 2 * depth)?;
+        if let Some(args) = self.args.as_ref() {
+            write!(f, "({})", args.join(", "))?;
+        }
+        writeln!(f, " -> {}", self.output)?;
+        for child in self.children.iter() {
+            child.write(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ExpansionTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for root in self.roots.iter() {
+            root.write(f, 0)?;
+        }
+        Ok(())
+    }
+}
+
+// Build the tree from the notifications and forward them to the previous observer
+#[derive(Debug)]
+struct TreeBuilder {
+    stack: RefCell<Vec<ExpansionNode>>,
+    roots: RefCell<Vec<ExpansionNode>>,
+    next: Option<Rc<dyn ExpansionObserver>>,
+}
+
+impl ExpansionObserver for TreeBuilder {
+    fn enter(&self, name: &str, args: Option<&[String]>, span: Span) {
+        self.stack.borrow_mut().push(ExpansionNode {
+            name: name.to_string(),
+            args: args.map(|args| args.to_vec()),
+            span,
+            ..Default::default()
+        });
+        if let Some(next) = self.next.as_ref() {
+            next.enter(name, args, span);
+        }
+    }
+
+    fn leave(&self, name: &str, tokens: &[Token]) {
+        let mut stack = self.stack.borrow_mut();
+        if let Some(mut node) = stack.pop() {
+            let tokens: Vec<_> = tokens.iter().map(|tok| tok.to_string()).collect();
+            node.output = tokens.join(" ");
+            if let Some(parent) = stack.last_mut() {
+                parent.children.push(node);
+            } else {
+                self.roots.borrow_mut().push(node);
+            }
+        }
+        if let Some(next) = self.next.as_ref() {
+            next.leave(name, tokens);
+        }
+    }
+}
+
+impl PContext {
+    /// Evaluate the macro name (its arguments if any are read from lexer)
+    /// and get its expansion with the tree of all the expansions done to get it
+    pub fn eval_with_trace(&mut self, name: &str, lexer: &mut Lexer) -> (String, ExpansionTree) {
+        let next = self.take_observer();
+        let builder = Rc::new(TreeBuilder {
+            stack: RefCell::new(Vec::new()),
+            roots: RefCell::new(Vec::new()),
+            next: next.clone(),
+        });
+        self.set_observer(builder.clone());
+
+        let mut out = MacroOutput::default();
+        self.eval(name, lexer, &mut out);
+
+        self.take_observer();
+        if let Some(next) = next {
+            self.set_observer(next);
+        }
+        let tree = ExpansionTree {
+            roots: builder.roots.take(),
+        };
+        (String::from_utf8_lossy(&out).into_owned(), tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_eval_with_trace() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define xstr(s) str(s)\n",
+                "#define str(s) #s\n",
+                "#define foo 4\n",
+                "#define test xstr(foo)\n",
+            )
+            .as_bytes(),
+        );
+        p.consume_tokens(4);
+        let mut context = p.context.clone();
+
+        let (output, tree) = context.eval_with_trace("test", &mut p);
+        assert_eq!(output, "\"4\"");
+        assert_eq!(tree.roots.len(), 1);
+        let test = &tree.roots[0];
+        assert_eq!(test.name, "test");
+        assert_eq!(test.args, None);
+        assert_eq!(test.output, "\"4\"");
+        assert_eq!(test.children.len(), 1);
+        let xstr = &test.children[0];
+        assert_eq!(xstr.args, Some(vec!["foo".to_string()]));
+        let names: Vec<_> = xstr.children.iter().map(|n| n.name.as_str()).collect();
+        assert_eq!(names, vec!["foo", "str"]);
+        assert_eq!(xstr.children[1].args, Some(vec!["4".to_string()]));
+        assert!(xstr.children.iter().all(|n| n.children.is_empty()));
+    }
+
+    #[test]
+    fn test_trace_reentrant() {
+        let mut p = Lexer::new(
+            concat!(
+                "#define FOO x rab bar\n",
+                "#define test FOO\n",
+                "#define bar FOO\n",
+                "#define rab oof\n",
+                "#define oof y FOO\n",
+            )
+            .as_bytes(),
+        );
+        p.consume_tokens(5);
+        let mut context = p.context.clone();
+
+        let (output, tree) = context.eval_with_trace("test", &mut p);
+        assert_eq!(output, "x y FOO FOO");
+        assert_eq!(
+            tree.to_string(),
+            concat!(
+                "test -> x y FOO FOO\n",
+                "  FOO -> x y FOO FOO\n",
+                "    rab -> y FOO\n",
+                "      oof -> y FOO\n",
+                "    bar -> FOO\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,459 @@
+use super::lexer::Lexer;
+
+#[inline(always)]
+fn get_trigraph(c: u8) -> Option<u8> {
+    Some(match c {
+        b'=' => b'#',
+        b'/' => b'\\',
+        b'\'' => b'^',
+        b'(' => b'[',
+        b')' => b']',
+        b'!' => b'|',
+        b'<' => b'{',
+        b'>' => b'}',
+        b'-' => b'~',
+        _ => return None,
+    })
+}
+
+/// Where the backslash-newlines have been removed in a translated buffer
+/// (to get the original lines and offsets)
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SourceMap {
+    // positions in the translated buffer of the removed backslash-newlines
+    splices: Vec<usize>,
+    // (position in the translated buffer, number of bytes removed before it)
+    shifts: Vec<(usize, usize)>,
+}
+
+impl SourceMap {
+    #[inline(always)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.shifts.is_empty()
+    }
+
+    /// Number of backslash-newlines in [start, end)
+    pub(crate) fn splices_between(&self, start: usize, end: usize) -> usize {
+        let start = self.splices.partition_point(|p| *p < start);
+        let end = self.splices.partition_point(|p| *p < end);
+        end - start
+    }
+
+    /// The line and the line start of pos in the line starting at lpos
+    pub(crate) fn get_line_start(&self, line: usize, lpos: usize, pos: usize) -> (usize, usize) {
+        let start = self.splices.partition_point(|p| *p < lpos);
+        let end = self.splices.partition_point(|p| *p <= pos);
+        if start == end {
+            (line, lpos)
+        } else {
+            (line + end - start, self.splices[end - 1])
+        }
+    }
+
+    /// Get the offset in the source from the one in the translated buffer
+    pub(crate) fn original_pos(&self, pos: usize) -> usize {
+        let i = self.shifts.partition_point(|(p, _)| *p <= pos);
+        if i == 0 {
+            pos
+        } else {
+            pos + self.shifts[i - 1].1
+        }
+    }
+}
+
+#[inline(always)]
+fn get_newline_len(buf: &[u8], pos: usize) -> usize {
+    match buf.get(pos) {
+        Some(b'\n') => 1,
+        Some(b'\r') if buf.get(pos + 1) == Some(&b'\n') => 2,
+        Some(b'\r') => 1,
+        _ => 0,
+    }
+}
+
+#[inline(always)]
+fn is_pp_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'\'' || c == b'.'
+}
+
+// Check if the ' at the end of out is a digit separator (e.g. 1'000)
+fn is_digit_separator(out: &[u8]) -> bool {
+    let end = out.len() - 1;
+    let start = out[..end]
+        .iter()
+        .rposition(|c| !is_pp_char(*c))
+        .map_or(0, |p| p + 1);
+    // a pp-number starts with a digit or a dot
+    start < end && (out[start].is_ascii_digit() || out[start] == b'.')
+}
+
+// Check if the " at the end of out starts a raw string
+fn is_raw_string(out: &[u8]) -> bool {
+    let end = out.len() - 1;
+    let start = out[..end]
+        .iter()
+        .rposition(|c| !(c.is_ascii_alphanumeric() || *c == b'_'))
+        .map_or(0, |p| p + 1);
+    matches!(&out[start..end], b"R" | b"u8R" | b"uR" | b"UR" | b"LR")
+}
+
+// Get the end of the raw string starting at pos (just after the ")
+fn get_raw_string_end(buf: &[u8], pos: usize) -> Option<usize> {
+    let paren = pos + buf[pos..].iter().take(17).position(|c| *c == b'(')?;
+    let delimiter = &buf[pos..paren];
+    if delimiter
+        .iter()
+        .any(|c| *c == b')' || *c == b'\\' || c.is_ascii_whitespace())
+    {
+        return None;
+    }
+
+    let mut i = paren + 1;
+    while i < buf.len() {
+        if buf[i] == b')'
+            && buf[i + 1..].starts_with(delimiter)
+            && buf.get(i + 1 + delimiter.len()) == Some(&b'"')
+        {
+            return Some(i + delimiter.len() + 2);
+        }
+        i += 1;
+    }
+    Some(buf.len())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Code,
+    Literal(u8),
+    Escape(u8),
+    LineComment,
+    // the position where the comment content starts
+    BlockComment(usize),
+}
+
+// Copy a part of the source with the line endings (\r\n or \r) replaced by \n
+fn copy_lines(buf: &[u8], out: &mut Vec<u8>, map: &mut SourceMap, removed: &mut usize) {
+    let mut pos = 0;
+    while pos < buf.len() {
+        let nl = get_newline_len(buf, pos);
+        if nl != 0 {
+            out.push(b'\n');
+            pos += nl;
+            if nl == 2 {
+                *removed += 1;
+                map.shifts.push((out.len(), *removed));
+            }
+        } else {
+            out.push(buf[pos]);
+            pos += 1;
+        }
+    }
+}
+
+/// Apply the translation phases 1 and 2:
+///  - replace the line endings (\r\n or \r) by \n,
+///  - replace the trigraphs (??= ??/ ??' ??( ??) ??! ??< ??> ??-) if enabled,
+///  - remove the backslash-newlines (line splicing).
+///
+/// The raw strings are kept as is (except their line endings).
+/// Get None if there is nothing to change.
+pub(crate) fn translate(buf: &[u8], trigraphs: bool) -> Option<(Vec<u8>, SourceMap)> {
+    let has_work = buf.contains(&b'\r')
+        || buf.windows(2).any(|w| match w {
+            [b'\\', b'\n'] => true,
+            [b'?', b'?'] => trigraphs,
+            _ => false,
+        });
+    if !has_work {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(buf.len());
+    let mut map = SourceMap::default();
+    let mut removed = 0;
+    let mut pos = 0;
+    // just enough lexing to find the raw strings
+    let mut state = State::Code;
+    let mut slash = None;
+
+    while pos < buf.len() {
+        let c = buf[pos];
+        let (c, len) = if c == b'\r' {
+            (b'\n', get_newline_len(buf, pos))
+        } else if trigraphs && c == b'?' && buf.get(pos + 1) == Some(&b'?') {
+            if let Some(t) = buf.get(pos + 2).and_then(|c| get_trigraph(*c)) {
+                (t, 3)
+            } else {
+                (c, 1)
+            }
+        } else {
+            (c, 1)
+        };
+
+        if c == b'\\' {
+            let nl = get_newline_len(buf, pos + len);
+            if nl != 0 {
+                removed += len + nl;
+                pos += len + nl;
+                map.splices.push(out.len());
+                map.shifts.push((out.len(), removed));
+                continue;
+            }
+        }
+
+        out.push(c);
+        pos += len;
+        if len != 1 {
+            removed += len - 1;
+            map.shifts.push((out.len(), removed));
+        }
+
+        state = match state {
+            State::Code => match c {
+                b'"' => {
+                    if is_raw_string(&out) {
+                        if let Some(end) = get_raw_string_end(buf, pos) {
+                            copy_lines(&buf[pos..end], &mut out, &mut map, &mut removed);
+                            pos = end;
+                            State::Code
+                        } else {
+                            State::Literal(c)
+                        }
+                    } else {
+                        State::Literal(c)
+                    }
+                }
+                b'\'' if !is_digit_separator(&out) => State::Literal(c),
+                b'/' if slash == out.len().checked_sub(2) => State::LineComment,
+                b'/' => {
+                    slash = Some(out.len() - 1);
+                    State::Code
+                }
+                b'*' if slash == out.len().checked_sub(2) => State::BlockComment(out.len()),
+                _ => State::Code,
+            },
+            State::Literal(d) => match c {
+                b'\\' => State::Escape(d),
+                b'\n' => State::Code,
+                _ if c == d => State::Code,
+                _ => state,
+            },
+            State::Escape(d) => State::Literal(d),
+            State::LineComment if c == b'\n' => State::Code,
+            State::BlockComment(start) if c == b'/' && out.len() - start >= 2 => {
+                if out[out.len() - 2] == b'*' {
+                    State::Code
+                } else {
+                    state
+                }
+            }
+            _ => state,
+        };
+    }
+
+    if map.is_empty() && !buf.contains(&b'\r') {
+        None
+    } else {
+        Some((out, map))
+    }
+}
+
+impl<'a> Lexer<'a> {
+    /// Apply the translation phases to the current buffer
+    pub(crate) fn translate_buffer(&mut self) {
+        if let Some((buf, map)) = translate(self.buf, self.options.trigraphs) {
+            self.buf = self.add_buffer(buf);
+            self.len = self.buf.len();
+            self.source_map = map;
+        } else {
+            self.source_map = SourceMap::default();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::lexer::{LexerBuilder, LexerOptions, Sources, Token};
+    use std::borrow::Cow;
+
+    fn get_options() -> LexerOptions {
+        LexerOptions {
+            trigraphs: true,
+            ..Default::default()
+        }
+    }
+
+    fn translate_str(buf: &[u8], trigraphs: bool) -> Option<Vec<u8>> {
+        translate(buf, trigraphs).map(|(buf, _)| buf)
+    }
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(
+            translate_str(b"??= ??/ ??' ??( ??) ??! ??< ??> ??-", true).unwrap(),
+            b"# \\ ^ [ ] | { } ~"
+        );
+        assert_eq!(translate_str(b"???=??x??", true), Some(b"?#??x??".to_vec()));
+        assert_eq!(translate_str(b"a ? b : c ?? d", true), None);
+        assert_eq!(translate_str(b"??=", false), None);
+        assert_eq!(
+            translate_str(b"a\\\nb\\\r\nc??/\nd \\ e", true),
+            Some(b"abcd \\ e".to_vec())
+        );
+
+        // raw strings are kept as is
+        let buf = b"a\\\nb R\"x(\\\n??=)x\" u8R\"(\\\n)\" \"R\\\"(\\\n)\" 1'2\\\n R\"(\\\n)\"";
+        assert_eq!(
+            translate_str(buf, true).unwrap(),
+            b"ab R\"x(\\\n??=)x\" u8R\"(\\\n)\" \"R\\\"()\" 1'2 R\"(\\\n)\"".to_vec()
+        );
+        let buf = b"// R\"(\n\\\n)\" /* R\"( */ R\"(\\\n)\" '\"' R\"(\\\n)\"";
+        assert_eq!(
+            translate_str(buf, true).unwrap(),
+            b"// R\"(\n)\" /* R\"( */ R\"(\\\n)\" '\"' R\"(\\\n)\"".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_line_endings() {
+        assert_eq!(
+            translate_str(b"a\r\nb\rc\r\r\nd\\\re", false),
+            Some(b"a\nb\nc\n\nde".to_vec())
+        );
+        assert_eq!(
+            translate_str(b"R\"(a\r\nb)\" \"\\\r\n\"", false),
+            Some(b"R\"(a\nb)\" \"\"".to_vec())
+        );
+        let (_, map) = translate(b"ab\r\ncd\r\ne", false).unwrap();
+        assert_eq!(map.original_pos(2), 2);
+        assert_eq!(map.original_pos(3), 4);
+        assert_eq!(map.original_pos(6), 8);
+
+        let mut p =
+            Lexer::new(b"#define A 1 + \\\r\n  2\r\nint x = A;\r\n\r\n  \"a\\\r\nb\";\rfoo");
+        let mut tokens = Vec::new();
+        loop {
+            match p.next_with_span() {
+                (Token::Eof, _) => break,
+                (Token::Eol, _) => {}
+                (tok, span) => tokens.push((tok, span.line, span.col, span.start)),
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                (Token::Int, 3, 1, 22),
+                (Token::Identifier("x".into()), 3, 5, 26),
+                (Token::Equal, 3, 7, 28),
+                (Token::LiteralInt(1), 3, 9, 30),
+                (Token::Plus, 3, 9, 30),
+                (Token::LiteralInt(2), 3, 9, 30),
+                (Token::SemiColon, 3, 10, 31),
+                (Token::LiteralString(b"ab".into()), 5, 3, 38),
+                (Token::SemiColon, 6, 3, 45),
+                (Token::Identifier("foo".into()), 7, 1, 47),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_source_map() {
+        let (buf, map) = translate(b"ab\\\ncd??=e\\\r\n\\\nf", true).unwrap();
+        assert_eq!(buf, b"abcd#ef");
+        assert_eq!(map.original_pos(1), 1);
+        assert_eq!(map.original_pos(2), 4);
+        assert_eq!(map.original_pos(4), 6);
+        assert_eq!(map.original_pos(5), 9);
+        assert_eq!(map.original_pos(6), 15);
+        assert_eq!(map.splices_between(0, 7), 3);
+        assert_eq!(map.get_line_start(1, 0, 1), (1, 0));
+        assert_eq!(map.get_line_start(1, 0, 3), (2, 2));
+        assert_eq!(map.get_line_start(1, 0, 6), (4, 6));
+    }
+
+    #[test]
+    fn test_trigraphs() {
+        let mut p = Lexer::with_options(
+            b"??=define A(x) x ??/\n + 1\nint a??(2??) = ??< A(1) ??! ??-0 ??' 3 ??>;",
+            get_options(),
+        );
+        assert_eq!(p.next(), Token::Int);
+        assert_eq!(p.next(), Token::Identifier("a".into()));
+        assert_eq!(p.next(), Token::LeftBrack);
+        assert_eq!(p.next(), Token::LiteralInt(2));
+        assert_eq!(p.next(), Token::RightBrack);
+        assert_eq!(p.next(), Token::Equal);
+        assert_eq!(p.next(), Token::LeftBrace);
+        assert_eq!(p.next(), Token::LiteralInt(1));
+        assert_eq!(p.next(), Token::Plus);
+        assert_eq!(p.next(), Token::LiteralInt(1));
+        assert_eq!(p.next(), Token::Or);
+        assert_eq!(p.next(), Token::Tilde);
+        assert_eq!(p.next(), Token::LiteralInt(0));
+        assert_eq!(p.next(), Token::Xor);
+        assert_eq!(p.next(), Token::LiteralInt(3));
+        assert_eq!(p.next(), Token::RightBrace);
+        assert_eq!(p.next(), Token::SemiColon);
+        assert_eq!(p.next(), Token::Eof);
+
+        let mut p = Lexer::with_options(b"a ??/\nb", get_options());
+        assert_eq!(p.next(), Token::Identifier("a".into()));
+        assert_eq!(p.next(), Token::Identifier("b".into()));
+        assert_eq!(p.get_line(), 2);
+
+        // disabled by default
+        let mut p = Lexer::new(b"??=");
+        assert_eq!(p.next(), Token::Question);
+        assert_eq!(p.next(), Token::Question);
+        assert_eq!(p.next(), Token::Equal);
+    }
+
+    #[test]
+    fn test_splice() {
+        let mut p = Lexer::new(b"ab\\\ncd \"ef\\\r\ngh\"\n#def\\\nine A 1\\\n+ 2\nA x");
+        assert_eq!(p.next(), Token::Identifier("abcd".into()));
+        assert_eq!(p.get_line(), 2);
+        assert_eq!(p.next(), Token::LiteralString(b"efgh".into()));
+        assert_eq!(p.get_line(), 3);
+        assert_eq!(p.next(), Token::Eol);
+        assert_eq!(p.next(), Token::LiteralInt(1));
+        assert_eq!(p.next(), Token::Plus);
+        assert_eq!(p.next(), Token::LiteralInt(2));
+        assert_eq!(p.next(), Token::Identifier("x".into()));
+        assert_eq!(p.get_line(), 7);
+        assert_eq!(p.get_column(), 4);
+        assert_eq!(p.next(), Token::Eof);
+    }
+
+    #[test]
+    fn test_translated_sources() {
+        let source = b"ab\\\ncd \"ef\\\ngh\"";
+
+        // the spliced source is owned by the lexer: the tokens own their payloads
+        let mut p = Lexer::new(source);
+        let tokens = [p.next(), p.next()];
+        drop(p);
+        assert_eq!(tokens[0], Token::Identifier(Cow::Owned("abcd".to_string())));
+        assert_eq!(
+            tokens[1],
+            Token::LiteralString(Cow::Owned(b"efgh".to_vec()))
+        );
+
+        // the spliced source is in sources: the tokens borrow it
+        let sources = Sources::new();
+        let mut p = LexerBuilder::new().sources(&sources).build(source);
+        let tokens = [p.next(), p.next()];
+        drop(p);
+        assert_eq!(sources.len(), 1);
+        assert!(matches!(
+            tokens[0],
+            Token::Identifier(Cow::Borrowed("abcd"))
+        ));
+        assert!(matches!(
+            tokens[1],
+            Token::LiteralString(Cow::Borrowed(b"efgh"))
+        ));
+    }
+}